@@ -3,9 +3,8 @@
 use service::docker::DockerContainer;
 use service::Service;
 use std::sync::Arc;
-use task::FileEventTask;
+use task::{FileEventTask, LogFormat};
 use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
 
 const SERVICE_STR: &str = r"
 name: jellyfin
@@ -14,20 +13,14 @@ host: localhost
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
-    tracing::subscriber::set_global_default(
-        FmtSubscriber::builder()
-            .with_max_level(Level::TRACE)
-            .with_thread_ids(true)
-            .finish(),
-    )
-    .unwrap();
+    task::init_logging(LogFormat::from_env(), Level::TRACE).unwrap();
     color_eyre::install().unwrap();
 
     let mut jellyfin = serde_yaml::from_str::<DockerContainer>(SERVICE_STR)?;
     jellyfin.connect().await?;
     info!(?jellyfin);
     let jellyfin = Arc::new(jellyfin);
-    let status = (&jellyfin).status().await?;
+    let status = jellyfin.status().await?;
     info!(%status);
 
     /*info!("Loading from file");
@@ -36,7 +29,7 @@ async fn main() -> color_eyre::Result<()> {
     eprintln!("{file_task:#?}");
     let file_task = Arc::new(file_task);
     info!("Activating");
-    file_task.activate().await?.await?;
+    file_task.activate().await?.wait().await?;
     info!("Job done");*/
     Ok(())
 }
@@ -0,0 +1,132 @@
+use crate::{Result, ServiceStatus};
+use serde::{Deserialize, Deserializer};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::trace;
+
+/// A service's last status, reused for a time so that tasks sharing a
+/// dependency don't each check it
+///
+/// Configured on a service by `status_ttl`, a duration (e.g. `2s`), with
+/// nothing cached by default.
+/// The trade-off is staleness: a status up to `status_ttl` old may be
+/// reported, missing the service going down (or coming back up) since.
+/// Errors aren't cached, and starting, stopping, or restarting the service
+/// through Overseer forgets the cached status, so remediation is always
+/// checked afresh
+#[derive(Debug)]
+pub(crate) struct StatusCache {
+    ttl: Option<Duration>,
+    /// Read without waiting on any check in progress, which are made
+    /// without holding anything, so a few might overlap when the status
+    /// runs out
+    last: watch::Sender<Cached>,
+}
+
+/// The last status and when it was checked
+#[derive(Debug, Clone, Copy, Default)]
+struct Cached {
+    status: Option<(Instant, ServiceStatus)>,
+    /// How many times the status has been forgotten, so a check started
+    /// before the latest time isn't cached
+    forgotten: u64,
+}
+
+impl StatusCache {
+    fn new(ttl: Option<Duration>) -> Self {
+        StatusCache {
+            ttl,
+            last: watch::channel(Cached::default()).0,
+        }
+    }
+
+    /// The cached status if it's fresh enough, otherwise the result of
+    /// `check`, which is cached
+    pub(crate) async fn get_or_check(
+        &self,
+        check: impl Future<Output = Result<ServiceStatus>>,
+    ) -> Result<ServiceStatus> {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return check.await,
+        };
+        let cached = *self.last.borrow();
+        if let Some((checked, status)) = cached.status {
+            if checked.elapsed() < ttl {
+                trace!(%status, "Using cached status");
+                return Ok(status);
+            }
+        }
+        let status = check.await?;
+        let checked = Instant::now();
+        self.last.send_modify(|last| {
+            if last.forgotten == cached.forgotten {
+                last.status = Some((checked, status));
+            }
+        });
+        Ok(status)
+    }
+
+    /// Runs `action` on the service, then forgets the cached status, as
+    /// it's probably changed
+    pub(crate) async fn invalidating<T>(
+        &self,
+        action: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let result = action.await;
+        self.last.send_modify(|last| {
+            last.status = None;
+            last.forgotten += 1;
+        });
+        result
+    }
+
+    /// An empty cache with the same TTL
+    pub(crate) fn emptied(&self) -> Self {
+        StatusCache::new(self.ttl)
+    }
+}
+
+impl Default for StatusCache {
+    fn default() -> Self {
+        StatusCache::new(None)
+    }
+}
+
+impl<'de> Deserialize<'de> for StatusCache {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        Ok(StatusCache::new(Some(humantime_serde::deserialize(
+            deserializer,
+        )?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn checks_made_before_forgetting_arent_cached() {
+        let cache = StatusCache::new(Some(Duration::from_secs(60)));
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let check = cache.get_or_check(async {
+            rx.await.unwrap();
+            Ok(ServiceStatus::Offline)
+        });
+        let restart = async {
+            cache.invalidating(async { Ok(()) }).await.unwrap();
+            tx.send(()).unwrap();
+        };
+        let (status, ()) = tokio::join!(check, restart);
+        assert_eq!(status.unwrap(), ServiceStatus::Offline);
+
+        // The restart might have fixed it, so it's checked again
+        let status = cache.get_or_check(async { Ok(ServiceStatus::Healthy) });
+        assert_eq!(status.await.unwrap(), ServiceStatus::Healthy);
+        let status = cache.get_or_check(async { Ok(ServiceStatus::Offline) });
+        assert_eq!(status.await.unwrap(), ServiceStatus::Healthy);
+    }
+}
@@ -1,78 +1,79 @@
+use crate::cache::StatusCache;
 use crate::error::{
     DockerComposeInitError, DockerComposeInitErrorType, ServiceError,
 };
-use crate::{Result, Service, ServiceStatus};
+use crate::path::{deserialize_vars, expand_home};
+use crate::retry::ConnectRetry;
+use crate::{ConnectionInfo, Result, Service, ServiceStatus};
 use async_trait::async_trait;
+use bollard::container::{
+    ListContainersOptions, RestartContainerOptions, StopContainerOptions,
+};
 use bollard::errors::Error as BollardError;
-use bollard::{Docker, API_DEFAULT_VERSION};
+use bollard::{ClientVersion, Docker, API_DEFAULT_VERSION};
 use camino::Utf8PathBuf;
 use docker_compose_types::Compose;
-use openssh::{KnownHosts, Session};
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt};
 use serde::Deserialize;
-use std::ffi::OsString;
+use std::collections::HashMap;
 use std::fmt;
-use std::os::unix::ffi::OsStringExt;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
 use std::path::Path;
-use std::sync::Arc;
-use tracing::{error, trace};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{error, info, trace};
 
 #[derive(Debug, Deserialize)]
 pub struct DockerCompose {
     name: String,
     host: String,
+    /// The compose file, on `host`, where a leading `~` is the home
+    /// directory of Overseer's user (or the SSH user, for remote hosts)
+    #[serde(deserialize_with = "deserialize_vars")]
     path: Utf8PathBuf,
+    /// The Compose project the containers belong to, by default named after
+    /// the directory containing `path`, as Compose does
+    #[serde(default)]
+    project: Option<String>,
+    #[serde(default = "default_grace_period", with = "humantime_serde")]
+    grace_period: Duration,
+    /// How many of the project's services have their status checked at
+    /// once, all of them by default
+    #[serde(default)]
+    max_concurrent_checks: Option<NonZeroUsize>,
+    #[serde(default)]
+    connect_retry: ConnectRetry,
+    #[serde(default, rename = "status_ttl")]
+    cache: StatusCache,
     #[serde(skip)]
     inner: Option<DockerComposeInner>,
 }
 
 impl DockerCompose {
     pub async fn initialise(&mut self) -> Result<(), DockerComposeInitError> {
+        let project = self.project().ok_or_else(|| DockerComposeInitError {
+            target: self.clone(),
+            r#type: DockerComposeInitErrorType::NoProject,
+        })?;
+
         // Connect to host
-        let conn = docker_connect(&self.host).await.map_err(|err| {
-            DockerComposeInitError {
+        let conn = docker_connect(&self.host, &self.connect_retry)
+            .await
+            .map_err(|err| DockerComposeInitError {
                 target: self.clone(),
                 r#type: err.into(),
-            }
-        })?;
+            })?;
 
         // Get service names out of docker-compose.yml
         let bytes = match self.host.as_str() {
-            "localhost" => {
-                tokio::fs::read(&self.path).await.map_err(|err| {
-                    DockerComposeInitError {
-                        target: self.clone(),
-                        r#type: err.into(),
-                    }
-                })?
-            }
-            _ => {
-                trace!(
-                    "Setting up remote connection to read docker-compose.yml"
-                );
-                let session = Session::connect(&self.host, KnownHosts::Strict)
-                    .await
-                    .map_err(|err| DockerComposeInitError {
-                        target: self.clone(),
-                        r#type: err.into(),
-                    })?;
-                let output = session
-                    .shell(format!("cat {}", self.path))
-                    .output()
-                    .await
-                    .map_err(|err| DockerComposeInitError {
-                        target: self.clone(),
-                        r#type: err.into(),
-                    })?;
-                if !output.status.success() {
-                    let stderr = OsString::from_vec(output.stderr);
-                    return Err(DockerComposeInitError {
-                        target: self.clone(),
-                        r#type: DockerComposeInitErrorType::RemoteCmd(stderr),
-                    });
-                } else {
-                    output.stdout
-                }
-            }
+            "localhost" => tokio::fs::read(expand_home(&self.path))
+                .await
+                .map_err(|err| DockerComposeInitError {
+                    target: self.clone(),
+                    r#type: err.into(),
+                })?,
+            _ => self.read_remote_compose().await?,
         };
         let compose =
             serde_yaml::from_slice::<Compose>(&bytes).map_err(|err| {
@@ -93,32 +94,181 @@ impl DockerCompose {
         let names = services.keys().cloned().collect::<Vec<String>>();
 
         // Set & return
-        self.inner = Some(DockerComposeInner { names, conn });
+        self.inner = Some(DockerComposeInner {
+            project,
+            names,
+            conn,
+        });
         Ok(())
     }
+
+    /// The Compose project's name, either as configured or from the name of
+    /// the directory containing the compose file, normalised as Compose does
+    fn project(&self) -> Option<String> {
+        if let Some(project) = &self.project {
+            return Some(project.clone());
+        }
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_str().is_empty() => dir.to_owned(),
+            // Relative to Overseer's working directory, which isn't known
+            // for remote hosts
+            _ if self.host == "localhost" => {
+                Utf8PathBuf::try_from(std::env::current_dir().ok()?).ok()?
+            }
+            _ => return None,
+        };
+        let project = dir
+            .file_name()?
+            .chars()
+            .filter_map(|c| match c {
+                'a'..='z' | '0'..='9' | '_' | '-' => Some(c),
+                'A'..='Z' => Some(c.to_ascii_lowercase()),
+                _ => None,
+            })
+            .collect::<String>();
+        (!project.is_empty()).then_some(project)
+    }
+
+    /// Reads docker-compose.yml from a remote host over SSH
+    #[cfg(unix)]
+    async fn read_remote_compose(
+        &self,
+    ) -> Result<Vec<u8>, DockerComposeInitError> {
+        use openssh::{KnownHosts, Session};
+
+        trace!("Setting up remote connection to read docker-compose.yml");
+        let session = self
+            .connect_retry
+            .run(&self.host, || {
+                Session::connect(&self.host, KnownHosts::Strict)
+            })
+            .await
+            .map_err(|err| DockerComposeInitError {
+                target: self.clone(),
+                r#type: err.into(),
+            })?;
+        let path = sftp_path(self.path.as_str());
+        crate::sftp::read_file(session, path).await.map_err(|err| {
+            DockerComposeInitError {
+                target: self.clone(),
+                r#type: err.into(),
+            }
+        })
+    }
+
+    #[cfg(not(unix))]
+    async fn read_remote_compose(
+        &self,
+    ) -> Result<Vec<u8>, DockerComposeInitError> {
+        Err(DockerComposeInitError {
+            target: self.clone(),
+            r#type: DockerComposeInitErrorType::RemoteUnsupported,
+        })
+    }
+
+    /// The connection to Docker, once initialised
+    pub fn connection(&self) -> Option<&DockerConnection> {
+        self.inner.as_ref().map(|inner| &inner.conn)
+    }
 }
 
 #[async_trait]
 impl Service for DockerCompose {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn host(&self) -> &str {
+        &self.host
+    }
+
     async fn status(self: &Arc<Self>) -> Result<ServiceStatus, ServiceError> {
-        use ServiceStatus::*;
-        let DockerComposeInner { names, conn } =
-            self.inner.as_ref().ok_or(ServiceError::NotConnected)?;
-        let mut current = Healthy;
-        /*
-        Go over statuses of each service. If any error, fail fast. If any are
-        offline, return Ok(Offline) fast. Otherwise, return the lowest value
-        (i.e. unhealthy if seen but healthy otherwise)
-         */
-        for fut in names.iter().map(|name| docker_status(conn, name)) {
-            match fut.await {
-                Ok(Offline) => return Ok(Offline),
-                Ok(this) if current > this => current = this,
-                Err(why) => return Err(why),
-                _ => {}
-            }
-        }
-        Ok(current)
+        self.cache
+            .get_or_check(async {
+                let DockerComposeInner {
+                    project,
+                    names,
+                    conn,
+                } = self.inner.as_ref().ok_or(ServiceError::NotConnected)?;
+                let limit = self
+                    .max_concurrent_checks
+                    .map_or(usize::MAX, NonZeroUsize::get);
+                let statuses = names
+                    .iter()
+                    .map(|name| compose_service_status(conn, project, name))
+                    .collect::<Vec<_>>();
+                worst_status(stream::iter(statuses).buffer_unordered(limit))
+                    .await
+            })
+            .await
+    }
+
+    /// Starts every container of every service, erroring if a service has
+    /// no containers, as they can only be created by Compose
+    async fn start(self: &Arc<Self>) -> Result<()> {
+        self.cache
+            .invalidating(async {
+                let DockerComposeInner {
+                    project,
+                    names,
+                    conn,
+                } = self.inner.as_ref().ok_or(ServiceError::NotConnected)?;
+                for name in names {
+                    let ids = compose_containers(conn, project, name).await?;
+                    if ids.is_empty() {
+                        return Err(ServiceError::NoContainers(name.clone()));
+                    }
+                    for id in ids {
+                        docker_start(conn, &id).await?;
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    async fn stop(self: &Arc<Self>) -> Result<()> {
+        self.cache
+            .invalidating(async {
+                let DockerComposeInner {
+                    project,
+                    names,
+                    conn,
+                } = self.inner.as_ref().ok_or(ServiceError::NotConnected)?;
+                for name in names {
+                    for id in compose_containers(conn, project, name).await? {
+                        docker_stop(conn, &id, self.grace_period).await?;
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    async fn restart(self: &Arc<Self>) -> Result<()> {
+        self.cache
+            .invalidating(async {
+                let DockerComposeInner {
+                    project,
+                    names,
+                    conn,
+                } = self.inner.as_ref().ok_or(ServiceError::NotConnected)?;
+                for name in names {
+                    let ids = compose_containers(conn, project, name).await?;
+                    if ids.is_empty() {
+                        return Err(ServiceError::NoContainers(name.clone()));
+                    }
+                    for id in ids {
+                        docker_restart(conn, &id, self.grace_period).await?;
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    async fn connection_info(self: &Arc<Self>) -> Option<ConnectionInfo> {
+        Some(self.connection()?.info().await)
     }
 }
 
@@ -139,6 +289,11 @@ impl Clone for DockerCompose {
             name: self.name.clone(),
             host: self.host.clone(),
             path: self.path.clone(),
+            project: self.project.clone(),
+            grace_period: self.grace_period,
+            max_concurrent_checks: self.max_concurrent_checks,
+            connect_retry: self.connect_retry,
+            cache: self.cache.emptied(),
             inner: None,
         }
     }
@@ -146,34 +301,187 @@ impl Clone for DockerCompose {
 
 #[derive(Debug)]
 struct DockerComposeInner {
+    project: String,
     names: Vec<String>,
-    conn: Docker,
+    conn: DockerConnection,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DockerContainer {
     name: String,
     host: String,
+    #[serde(default = "default_grace_period", with = "humantime_serde")]
+    grace_period: Duration,
+    #[serde(default)]
+    connect_retry: ConnectRetry,
+    #[serde(default, rename = "status_ttl")]
+    cache: StatusCache,
     #[serde(skip)]
-    conn: Option<Docker>,
+    conn: Option<DockerConnection>,
 }
 
 impl DockerContainer {
     pub async fn connect(&mut self) -> Result<(), BollardError> {
-        self.conn = Some(docker_connect(&self.host).await?);
+        self.conn =
+            Some(docker_connect(&self.host, &self.connect_retry).await?);
         Ok(())
     }
+
+    /// The connection to Docker, once connected
+    pub fn connection(&self) -> Option<&DockerConnection> {
+        self.conn.as_ref()
+    }
 }
 
 #[async_trait]
 impl Service for DockerContainer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn host(&self) -> &str {
+        &self.host
+    }
+
     async fn status(self: &Arc<Self>) -> Result<ServiceStatus, ServiceError> {
-        let conn = self.conn.as_ref().ok_or(ServiceError::NotConnected)?;
-        docker_status(conn, &self.name).await
+        self.cache
+            .get_or_check(async {
+                let conn =
+                    self.conn.as_ref().ok_or(ServiceError::NotConnected)?;
+                docker_status(conn, &self.name).await
+            })
+            .await
+    }
+
+    async fn start(self: &Arc<Self>) -> Result<()> {
+        self.cache
+            .invalidating(async {
+                let conn =
+                    self.conn.as_ref().ok_or(ServiceError::NotConnected)?;
+                docker_start(conn, &self.name).await
+            })
+            .await
+    }
+
+    async fn stop(self: &Arc<Self>) -> Result<()> {
+        self.cache
+            .invalidating(async {
+                let conn =
+                    self.conn.as_ref().ok_or(ServiceError::NotConnected)?;
+                docker_stop(conn, &self.name, self.grace_period).await
+            })
+            .await
+    }
+
+    /// Restarts the container in one Docker API call
+    async fn restart(self: &Arc<Self>) -> Result<()> {
+        self.cache
+            .invalidating(async {
+                let conn =
+                    self.conn.as_ref().ok_or(ServiceError::NotConnected)?;
+                docker_restart(conn, &self.name, self.grace_period).await
+            })
+            .await
+    }
+
+    async fn connection_info(self: &Arc<Self>) -> Option<ConnectionInfo> {
+        Some(self.connection()?.info().await)
+    }
+}
+
+/// How long a container is given to stop before it's killed, matching
+/// Docker's default
+fn default_grace_period() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// A connection to a Docker instance, using the API version negotiated when
+/// it was made
+#[derive(Debug, Clone)]
+pub struct DockerConnection {
+    docker: Docker,
+    api_version: ClientVersion,
+}
+
+impl DockerConnection {
+    /// The API version negotiated with Docker, the highest both support
+    pub fn api_version(&self) -> &ClientVersion {
+        &self.api_version
+    }
+
+    /// How long Docker takes to respond to a ping
+    pub async fn ping_latency(&self) -> Result<Duration, BollardError> {
+        let started = Instant::now();
+        self.docker.ping().await?;
+        Ok(started.elapsed())
+    }
+
+    async fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            api_version: self.api_version.to_string(),
+            ping_latency: self
+                .ping_latency()
+                .await
+                .map_err(|why| why.to_string()),
+        }
+    }
+}
+
+impl Deref for DockerConnection {
+    type Target = Docker;
+
+    fn deref(&self) -> &Docker {
+        &self.docker
     }
 }
 
-async fn docker_connect(host: &str) -> Result<Docker, BollardError> {
+/// Docker clients by host, shared so services on the same host reuse one
+/// connection
+static CONNECTIONS: OnceLock<Mutex<HashMap<String, DockerConnection>>> =
+    OnceLock::new();
+
+fn connections() -> MutexGuard<'static, HashMap<String, DockerConnection>> {
+    CONNECTIONS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Gets a connection to the local Docker instance, shared with any services
+/// on `localhost`
+///
+/// Connecting is retried with the default [`ConnectRetry`]
+pub async fn connect_local() -> Result<DockerConnection, BollardError> {
+    docker_connect("localhost", &ConnectRetry::default()).await
+}
+
+/// Gets a connection to the Docker instance on `host`, reusing an existing
+/// one if it still responds to a ping
+async fn docker_connect(
+    host: &str,
+    retry: &ConnectRetry,
+) -> Result<DockerConnection, BollardError> {
+    let cached = connections().get(host).cloned();
+    if let Some(conn) = cached {
+        match conn.ping().await {
+            Ok(_) => {
+                trace!(%host, "Reusing Docker connection");
+                return Ok(conn);
+            }
+            Err(why) => {
+                trace!(%host, "Cached Docker connection failed ping: {why}");
+                connections().remove(host);
+            }
+        }
+    }
+    let conn = retry.run(host, || docker_connect_new(host)).await?;
+    connections().insert(host.to_owned(), conn.clone());
+    Ok(conn)
+}
+
+async fn docker_connect_new(
+    host: &str,
+) -> Result<DockerConnection, BollardError> {
     let conn = match host {
         "localhost" => Docker::connect_with_local_defaults(),
         _ => {
@@ -188,9 +496,121 @@ async fn docker_connect(host: &str) -> Result<Docker, BollardError> {
             )
         }
     }?;
-    let conn = conn.negotiate_version().await?;
-    conn.ping().await?;
-    Ok(conn)
+    let docker = conn.negotiate_version().await?;
+    docker.ping().await?;
+    let api_version = docker.client_version();
+    trace!(%host, %api_version, "Connected to Docker");
+    Ok(DockerConnection {
+        docker,
+        api_version,
+    })
+}
+
+/// `path` as given to SFTP, which gets it as-is, never through a shell, so
+/// a leading `~` has to be expanded as the shell would have.
+/// SFTP resolves relative paths from the home directory anyway.
+/// `~user` is left for the server to expand, if it can
+#[cfg(unix)]
+fn sftp_path(path: &str) -> &str {
+    match path.strip_prefix('~') {
+        Some("" | "/") => ".",
+        Some(rest) if rest.starts_with('/') => &rest[1..],
+        _ => path,
+    }
+}
+
+/// The lowest of `statuses`, checked as they complete, returning early if
+/// any is offline or errors
+async fn worst_status(
+    statuses: impl Stream<Item = Result<ServiceStatus>>,
+) -> Result<ServiceStatus> {
+    futures::pin_mut!(statuses);
+    let mut worst = ServiceStatus::Healthy;
+    while let Some(status) = statuses.next().await {
+        match status? {
+            ServiceStatus::Offline => return Ok(ServiceStatus::Offline),
+            status => worst = worst.min(status),
+        }
+    }
+    Ok(worst)
+}
+
+/// The lowest status of the containers of `service`, or offline if it has
+/// none
+async fn compose_service_status(
+    conn: &Docker,
+    project: &str,
+    service: &str,
+) -> Result<ServiceStatus> {
+    let ids = compose_containers(conn, project, service).await?;
+    if ids.is_empty() {
+        return Ok(ServiceStatus::Offline);
+    }
+    let statuses = ids
+        .iter()
+        .map(|id| docker_status(conn, id))
+        .collect::<FuturesUnordered<_>>();
+    worst_status(statuses).await
+}
+
+/// The IDs of the containers Compose created for `service` in `project`,
+/// found by the labels Compose gives them, whether they're running or not
+async fn compose_containers(
+    conn: &Docker,
+    project: &str,
+    service: &str,
+) -> Result<Vec<String>> {
+    let labels = vec![
+        format!("com.docker.compose.project={project}"),
+        format!("com.docker.compose.service={service}"),
+    ];
+    let options = ListContainersOptions {
+        all: true,
+        filters: HashMap::from([(String::from("label"), labels)]),
+        ..Default::default()
+    };
+    let containers = conn.list_containers(Some(options)).await?;
+    trace!(%project, %service, count = containers.len(), "Found Compose containers");
+    containers
+        .into_iter()
+        .map(|container| {
+            container
+                .id
+                .ok_or(ServiceError::MissingInfo("container ID"))
+        })
+        .collect()
+}
+
+async fn docker_start(conn: &Docker, name: &str) -> Result<()> {
+    info!(%name, "Starting container");
+    conn.start_container::<String>(name, None).await?;
+    Ok(())
+}
+
+async fn docker_stop(
+    conn: &Docker,
+    name: &str,
+    grace_period: Duration,
+) -> Result<()> {
+    info!(%name, "Stopping container");
+    let options = StopContainerOptions {
+        t: grace_period.as_secs() as i64,
+    };
+    conn.stop_container(name, Some(options)).await?;
+    Ok(())
+}
+
+async fn docker_restart(
+    conn: &Docker,
+    name: &str,
+    grace_period: Duration,
+) -> Result<()> {
+    info!(%name, "Restarting container");
+    let options = RestartContainerOptions {
+        t: grace_period.as_secs() as isize,
+    };
+    conn.restart_container(name, Some(options)).await?;
+    Ok(())
 }
 
 async fn docker_status(
@@ -213,9 +633,11 @@ async fn docker_status(
     use ServiceStatus::*;
     match (status, health) {
         (Some(Healthy), Some(Healthy)) => Ok(Healthy),
+        (Some(Healthy), Some(Starting)) => Ok(Starting),
+        (Some(Starting), None) => Ok(Starting),
         (Some(Healthy), None) => Ok(Healthy),
         (Some(Unhealthy), Some(Healthy)) => Ok(Healthy),
-        (Some(Unhealthy), Some(Unhealthy) | None) => Ok(Unhealthy),
+        (Some(Unhealthy), Some(Starting | Unhealthy) | None) => Ok(Unhealthy),
         (Some(Offline), Some(Unhealthy) | None) => Ok(Offline),
         (None, Some(s)) => Ok(s),
         (None, None) => Err(MissingInfo("health or status")),
@@ -223,3 +645,62 @@ async fn docker_status(
         (Some(a), Some(b)) => Err(Conflicting(a, b)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ServiceStatus::*;
+
+    /// The worst of `statuses`, as if they were a project's containers
+    async fn project_status(
+        statuses: Vec<Result<ServiceStatus>>,
+    ) -> Result<ServiceStatus> {
+        worst_status(stream::iter(statuses)).await
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sftp_paths_expand_home() {
+        assert_eq!(sftp_path("~"), ".");
+        assert_eq!(sftp_path("~/"), ".");
+        assert_eq!(
+            sftp_path("~/my app/docker-compose.yml"),
+            "my app/docker-compose.yml",
+        );
+        assert_eq!(
+            sftp_path("/srv/my app/docker-compose.yml"),
+            "/srv/my app/docker-compose.yml",
+        );
+        assert_eq!(sftp_path("~deploy/compose.yml"), "~deploy/compose.yml");
+    }
+
+    #[tokio::test]
+    async fn project_is_as_bad_as_its_worst_container() {
+        let status =
+            project_status(vec![Ok(Healthy), Ok(Unhealthy), Ok(Starting)]);
+        assert_eq!(status.await.unwrap(), Unhealthy);
+        let status = project_status(vec![Ok(Healthy), Ok(Starting)]);
+        assert_eq!(status.await.unwrap(), Starting);
+        assert_eq!(project_status(vec![Ok(Healthy)]).await.unwrap(), Healthy);
+    }
+
+    #[tokio::test]
+    async fn offline_container_stops_the_check() {
+        let status = project_status(vec![
+            Ok(Starting),
+            Ok(Offline),
+            Err(ServiceError::NotConnected),
+        ]);
+        assert_eq!(status.await.unwrap(), Offline);
+    }
+
+    #[tokio::test]
+    async fn failed_check_fails_the_project() {
+        let status = project_status(vec![
+            Ok(Unhealthy),
+            Err(ServiceError::NotConnected),
+            Ok(Offline),
+        ]);
+        assert!(matches!(status.await, Err(ServiceError::NotConnected)));
+    }
+}
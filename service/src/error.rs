@@ -1,6 +1,6 @@
 use crate::docker::DockerCompose;
 use crate::ServiceStatus;
-use std::ffi::OsString;
+use camino::Utf8PathBuf;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -15,6 +15,45 @@ pub enum ServiceError {
         "Docker API gave conflicting information, status: {0}, health: {1}"
     )]
     Conflicting(ServiceStatus, ServiceStatus),
+    #[error("no containers found for Compose service {0:?}")]
+    NoContainers(String),
+    #[error("{0}")]
+    Unsupported(&'static str),
+    #[error("systemctl failed: {0}")]
+    Systemctl(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] hyper::Error),
+    #[cfg(unix)]
+    #[error("remote communication error: {0}")]
+    OpenSsh(#[from] openssh::Error),
+}
+
+/// Errors that occur when connecting to a configured [`AnyService`]
+///
+/// [`AnyService`]: crate::AnyService
+#[derive(Debug, Error)]
+pub enum ServiceConnectError {
+    #[error(transparent)]
+    DockerCompose(#[from] Box<DockerComposeInitError>),
+    #[error("failed to connect to Docker: {0}")]
+    Docker(#[from] bollard::errors::Error),
+}
+
+/// Errors that occur when loading a [`ServiceRegistry`]
+///
+/// [`ServiceRegistry`]: crate::registry::ServiceRegistry
+#[derive(Debug, Error)]
+pub enum ServiceRegistryError {
+    #[error("couldn't read {0}: {1}")]
+    Io(Utf8PathBuf, std::io::Error),
+    #[error("couldn't deserialise services: {0}")]
+    De(#[from] serde_yaml::Error),
+    #[error("more than one service is called {0:?}")]
+    DuplicateName(String),
+    #[error("couldn't connect to service {0:?}: {1}")]
+    Connect(String, ServiceConnectError),
 }
 
 #[derive(Debug, Error)]
@@ -32,10 +71,29 @@ pub(crate) enum DockerComposeInitErrorType {
     De(#[from] serde_yaml::Error),
     #[error("required information not found in docker-compose.yml")]
     MissingFields,
+    #[error("couldn't tell the Compose project from the path, set `project`")]
+    NoProject,
     #[error(transparent)]
     Bollard(#[from] bollard::errors::Error),
+    #[cfg(unix)]
     #[error("remote communication error: {0}")]
     OpenSsh(#[from] openssh::Error),
-    #[error("remote cat failed: {}", .0.to_string_lossy())]
-    RemoteCmd(OsString),
+    #[cfg(unix)]
+    #[error("couldn't read over SFTP: {0}")]
+    Sftp(#[from] SftpError),
+    #[cfg(not(unix))]
+    #[error("remote hosts are only supported on Unix")]
+    RemoteUnsupported,
+}
+
+/// Errors reading a file over SFTP
+#[cfg(unix)]
+#[derive(Debug, Error)]
+pub(crate) enum SftpError {
+    #[error(transparent)]
+    Sftp(#[from] openssh_sftp_client::Error),
+    #[error("server can't expand `~user` in {0:?}, use an absolute path")]
+    CantExpand(String),
+    #[error("file is larger than {0} bytes")]
+    TooLarge(u64),
 }
@@ -0,0 +1,114 @@
+use crate::cache::StatusCache;
+use crate::error::ServiceError;
+use crate::{Result, Service, ServiceStatus};
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::{Client, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::{Deserialize, Deserializer};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::trace;
+
+/// An HTTP(S) endpoint, healthy while a GET of its URL succeeds
+///
+/// A 2xx response is healthy, any other response unhealthy, and no response
+/// (refused, or not within `timeout`) offline.
+/// It can't be started or stopped
+#[derive(Debug, Deserialize)]
+pub struct HttpService {
+    name: String,
+    #[serde(deserialize_with = "deserialize_uri")]
+    url: Uri,
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    timeout: Duration,
+    #[serde(default, rename = "status_ttl")]
+    cache: StatusCache,
+    #[serde(skip, default = "https_client")]
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+#[async_trait]
+impl Service for HttpService {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The URL's host
+    fn host(&self) -> &str {
+        self.url.host().unwrap_or_default()
+    }
+
+    async fn status(self: &Arc<Self>) -> Result<ServiceStatus> {
+        self.cache
+            .get_or_check(async {
+                let response = tokio::time::timeout(
+                    self.timeout,
+                    self.client.get(self.url.clone()),
+                )
+                .await;
+                match response {
+                    Ok(Ok(response)) if response.status().is_success() => {
+                        Ok(ServiceStatus::Healthy)
+                    }
+                    Ok(Ok(response)) => {
+                        trace!(
+                            %self.name,
+                            status = %response.status(),
+                            "Endpoint responded with an error",
+                        );
+                        Ok(ServiceStatus::Unhealthy)
+                    }
+                    Ok(Err(why)) if why.is_connect() => {
+                        trace!(
+                            %self.name,
+                            "Couldn't connect to endpoint: {why}",
+                        );
+                        Ok(ServiceStatus::Offline)
+                    }
+                    Ok(Err(why)) => Err(why.into()),
+                    Err(_) => {
+                        trace!(%self.name, "Endpoint didn't respond in time");
+                        Ok(ServiceStatus::Offline)
+                    }
+                }
+            })
+            .await
+    }
+
+    async fn start(self: &Arc<Self>) -> Result<()> {
+        Err(ServiceError::Unsupported("HTTP services can't be started"))
+    }
+
+    async fn stop(self: &Arc<Self>) -> Result<()> {
+        Err(ServiceError::Unsupported("HTTP services can't be stopped"))
+    }
+
+    async fn restart(self: &Arc<Self>) -> Result<()> {
+        Err(ServiceError::Unsupported(
+            "HTTP services can't be restarted",
+        ))
+    }
+}
+
+fn deserialize_uri<'de, D>(deserializer: D) -> Result<Uri, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn https_client() -> Client<HttpsConnector<HttpConnector>> {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
@@ -1,26 +1,161 @@
-use crate::error::ServiceError;
+use crate::docker::{DockerCompose, DockerContainer};
+use crate::error::{ServiceConnectError, ServiceError};
+use crate::http::HttpService;
+use crate::systemd::SystemdService;
 use async_trait::async_trait;
 use bollard::models::{ContainerStateStatusEnum, HealthStatusEnum};
+use serde::Deserialize;
 use std::fmt;
 use std::sync::Arc;
+use std::time::Duration;
 
+mod cache;
 pub mod docker;
 pub mod error;
+pub mod http;
+pub mod path;
+pub mod registry;
+pub mod retry;
+#[cfg(unix)]
+mod sftp;
+pub mod systemd;
 
 type Result<T, E = ServiceError> = std::result::Result<T, E>;
 
 #[async_trait]
-pub trait Service {
+pub trait Service: Send + Sync {
+    /// The service's name, as configured
+    fn name(&self) -> &str;
+    /// The host the service runs on
+    fn host(&self) -> &str;
     async fn status(self: &Arc<Self>) -> Result<ServiceStatus>;
-    //async fn start(self: Arc<Self>);
-    //async fn stop(self: Arc<Self>);
+    async fn start(self: &Arc<Self>) -> Result<()>;
+    /// Stops the service, giving it its configured grace period to exit
+    /// before it's killed
+    async fn stop(self: &Arc<Self>) -> Result<()>;
+    /// Stops then starts the service
+    async fn restart(self: &Arc<Self>) -> Result<()> {
+        self.stop().await?;
+        self.start().await
+    }
+
+    /// Diagnostics about the service's connection to its host, for services
+    /// that keep one
+    async fn connection_info(self: &Arc<Self>) -> Option<ConnectionInfo> {
+        None
+    }
+}
+
+/// An object-safe view of a [`Service`], so services of different types can
+/// be stored together as `Arc<dyn DynService>`
+///
+/// Implemented for every `Arc<S>` where `S` is a [`Service`]
+#[async_trait]
+pub trait DynService: Send + Sync {
+    fn name(&self) -> &str;
+    fn host(&self) -> &str;
+    async fn status(&self) -> Result<ServiceStatus>;
+    async fn start(&self) -> Result<()>;
+    async fn stop(&self) -> Result<()>;
+    async fn restart(&self) -> Result<()>;
+    async fn connection_info(&self) -> Option<ConnectionInfo>;
 }
 
+#[async_trait]
+impl<S: Service + 'static> DynService for Arc<S> {
+    fn name(&self) -> &str {
+        Service::name(self.as_ref())
+    }
+
+    fn host(&self) -> &str {
+        Service::host(self.as_ref())
+    }
+
+    async fn status(&self) -> Result<ServiceStatus> {
+        Service::status(self).await
+    }
+
+    async fn start(&self) -> Result<()> {
+        Service::start(self).await
+    }
+
+    async fn stop(&self) -> Result<()> {
+        Service::stop(self).await
+    }
+
+    async fn restart(&self) -> Result<()> {
+        Service::restart(self).await
+    }
+
+    async fn connection_info(&self) -> Option<ConnectionInfo> {
+        Service::connection_info(self).await
+    }
+}
+
+/// Diagnostics about a service's connection to its host
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// The API version negotiated with the host
+    pub api_version: String,
+    /// How long the host took to respond to a ping, or why it didn't
+    pub ping_latency: Result<Duration, String>,
+}
+
+/// A service of any type, so configuration can mix them, tagged by `type`
+/// (`docker_compose`, `docker_container`, `systemd`, or `http`)
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnyService {
+    DockerCompose(DockerCompose),
+    DockerContainer(DockerContainer),
+    Systemd(SystemdService),
+    Http(HttpService),
+}
+
+impl AnyService {
+    /// The service's name, as configured
+    pub fn name(&self) -> &str {
+        use AnyService::*;
+        match self {
+            DockerCompose(service) => Service::name(service),
+            DockerContainer(service) => Service::name(service),
+            Systemd(service) => Service::name(service),
+            Http(service) => Service::name(service),
+        }
+    }
+
+    /// Connects to the service (for those that need it), ready to be used
+    pub async fn connect(
+        self,
+    ) -> Result<Arc<dyn DynService>, ServiceConnectError> {
+        use AnyService::*;
+        Ok(match self {
+            DockerCompose(mut service) => {
+                service.initialise().await.map_err(Box::new)?;
+                Arc::new(Arc::new(service))
+            }
+            DockerContainer(mut service) => {
+                service.connect().await?;
+                Arc::new(Arc::new(service))
+            }
+            Systemd(service) => Arc::new(Arc::new(service)),
+            Http(service) => Arc::new(Arc::new(service)),
+        })
+    }
+}
+
+/// How a service is doing
+///
+/// Ordered from worst to best (`Offline < Unhealthy < Starting < Healthy`),
+/// so the overall status of a group of services is the minimum of theirs.
+/// Variants are declared in that order, which the derived ordering follows
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum ServiceStatus {
-    Healthy,
-    Unhealthy,
     Offline,
+    Unhealthy,
+    /// Running, but still passing its first health checks
+    Starting,
+    Healthy,
 }
 
 impl ServiceStatus {
@@ -28,7 +163,8 @@ impl ServiceStatus {
     fn from_health(health: HealthStatusEnum) -> Option<Self> {
         use bollard::models::HealthStatusEnum::*;
         match health {
-            STARTING | HEALTHY => Some(ServiceStatus::Healthy),
+            HEALTHY => Some(ServiceStatus::Healthy),
+            STARTING => Some(ServiceStatus::Starting),
             UNHEALTHY => Some(ServiceStatus::Unhealthy),
             NONE | EMPTY => None,
         }
@@ -51,8 +187,26 @@ impl fmt::Display for ServiceStatus {
         use ServiceStatus::*;
         match *self {
             Healthy => write!(f, "healthy"),
+            Starting => write!(f, "starting"),
             Unhealthy => write!(f, "unhealthy"),
             Offline => write!(f, "offline"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_order_worst_first() {
+        use ServiceStatus::*;
+        let mut statuses = [Healthy, Offline, Starting, Unhealthy];
+        statuses.sort();
+        assert_eq!(statuses, [Offline, Unhealthy, Starting, Healthy]);
+        assert_eq!(
+            [Healthy, Starting, Healthy].into_iter().min(),
+            Some(Starting)
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Expanding environment variables and `~` in configured paths, as a shell
+//! would
+//!
+//! Variables are always expanded when the path is loaded, from Overseer's
+//! environment.
+//! `~` is only expanded for paths used on the machine running Overseer: a
+//! path used on a remote host is left for its shell to expand (or, for
+//! Compose files read over SFTP, treated as relative to the remote user's
+//! home)
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::env::VarError;
+
+/// `path` with environment variables replaced by their values
+///
+/// Variables are written `$NAME` or `${NAME}`, with `${NAME:-default}` giving
+/// a default for when it's unset or empty (so `${NAME:-}` allows it to be
+/// missing), and `$$` being a literal `$`.
+/// A `$` not followed by a name is left as it is.
+/// Fails naming the variable if one without a default isn't set
+pub fn expand_vars(path: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, default, after) = match rest.strip_prefix('{') {
+            Some(braced) => {
+                let end = braced
+                    .find('}')
+                    .ok_or_else(|| format!("unclosed ${{ in path {path:?}"))?;
+                let (name, default) = match braced[..end].split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (&braced[..end], None),
+                };
+                if !is_var_name(name) {
+                    return Err(format!(
+                        "invalid variable name {name:?} in path {path:?}"
+                    ));
+                }
+                (name, default, &braced[end + 1..])
+            }
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                if !is_var_name(&rest[..end]) {
+                    expanded.push('$');
+                    continue;
+                }
+                (&rest[..end], None, &rest[end..])
+            }
+        };
+        match (std::env::var(name), default) {
+            (Ok(value), Some(default)) if value.is_empty() => {
+                expanded.push_str(default)
+            }
+            (Ok(value), _) => expanded.push_str(&value),
+            (Err(VarError::NotPresent), Some(default)) => {
+                expanded.push_str(default)
+            }
+            (Err(VarError::NotPresent), None) => {
+                return Err(format!(
+                    "environment variable {name} in path {path:?} isn't set"
+                ));
+            }
+            (Err(VarError::NotUnicode(_)), _) => {
+                return Err(format!(
+                    "environment variable {name} in path {path:?} isn't \
+                    valid Unicode"
+                ));
+            }
+        }
+        rest = after;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn is_var_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `path` with a leading `~` (or `~user`) replaced by the user's home
+/// directory
+///
+/// `~` alone is Overseer's user, from `HOME` if it's set.
+/// Paths without a leading `~` are returned as they are, as are those whose
+/// user (or home directory) can't be found, which then fail as missing
+pub fn expand_home(path: &Utf8Path) -> Utf8PathBuf {
+    let Some(rest) = path.as_str().strip_prefix('~') else {
+        return path.to_owned();
+    };
+    let (user, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let home = match user {
+        "" => std::env::home_dir()
+            .and_then(|home| Utf8PathBuf::from_path_buf(home).ok()),
+        user => user_home(user),
+    };
+    match home {
+        Some(home) => Utf8PathBuf::from(format!("{home}{rest}")),
+        None => path.to_owned(),
+    }
+}
+
+/// Whether `path` starts with a `~` to be expanded, so isn't relative to the
+/// working directory (or a `base_dir`), even though it isn't absolute
+pub fn is_home_relative(path: &Utf8Path) -> bool {
+    path.as_str().starts_with('~')
+}
+
+/// Expands a path used on the machine running Overseer, both its variables
+/// and any `~`
+fn expand_local(path: &str) -> Result<Utf8PathBuf, String> {
+    expand_vars(path).map(|path| expand_home(Utf8Path::new(&path)))
+}
+
+/// Deserialises a path, expanding its variables
+pub fn deserialize_vars<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Utf8PathBuf, D::Error> {
+    let path = String::deserialize(deserializer)?;
+    expand_vars(&path)
+        .map(Utf8PathBuf::from)
+        .map_err(D::Error::custom)
+}
+
+/// Deserialises an optional path, expanding its variables
+pub fn deserialize_vars_opt<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Utf8PathBuf>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|path| expand_vars(&path).map(Utf8PathBuf::from))
+        .transpose()
+        .map_err(D::Error::custom)
+}
+
+/// Deserialises an optional local path, expanding its variables and any `~`
+pub fn deserialize_expanded_opt<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Utf8PathBuf>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|path| expand_local(&path))
+        .transpose()
+        .map_err(D::Error::custom)
+}
+
+/// Deserialises a list of local paths, expanding their variables and any
+/// `~`s
+pub fn deserialize_expanded_vec<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Utf8PathBuf>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|path| expand_local(path))
+        .collect::<Result<_, _>>()
+        .map_err(D::Error::custom)
+}
+
+/// Deserialises a local path, expanding its variables and any `~`
+pub fn deserialize_expanded<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Utf8PathBuf, D::Error> {
+    let path = String::deserialize(deserializer)?;
+    expand_local(&path).map_err(D::Error::custom)
+}
+
+/// The home directory of the user called `name`
+#[cfg(unix)]
+fn user_home(name: &str) -> Option<Utf8PathBuf> {
+    use libc::c_char;
+    use std::ffi::{CStr, CString};
+
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0 as c_char; 1024];
+    loop {
+        // SAFETY: passwd is a plain C struct, for which all zeroes is valid
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        // SAFETY: every pointer is valid for the call, `buf.len()` is the
+        // buffer's length, and `name` is NUL-terminated
+        let code = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match code {
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            0 if !result.is_null() && !pwd.pw_dir.is_null() => {
+                // SAFETY: set to a NUL-terminated string within `buf`
+                let dir = unsafe { CStr::from_ptr(pwd.pw_dir) };
+                return dir.to_str().ok().map(Utf8PathBuf::from);
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn user_home(_: &str) -> Option<Utf8PathBuf> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn home() -> Utf8PathBuf {
+        Utf8PathBuf::from_path_buf(std::env::home_dir().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn vars_expand_from_environment() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(expand_vars("$PATH/logs").unwrap(), format!("{path}/logs"));
+        assert_eq!(expand_vars("${PATH}s").unwrap(), format!("{path}s"));
+        assert_eq!(
+            expand_vars("/srv/${OVERSEER_NO_SUCH_VAR:-default}").unwrap(),
+            "/srv/default",
+        );
+        assert_eq!(
+            expand_vars("/srv${OVERSEER_NO_SUCH_VAR:-}").unwrap(),
+            "/srv"
+        );
+        assert_eq!(
+            expand_vars("cost $$5, $ or $1").unwrap(),
+            "cost $5, $ or $1"
+        );
+    }
+
+    #[test]
+    fn bad_vars_fail_naming_them() {
+        let err = expand_vars("/srv/$OVERSEER_NO_SUCH_VAR").unwrap_err();
+        assert!(err.contains("OVERSEER_NO_SUCH_VAR"), "{err}");
+        let err = expand_vars("/srv/${1abc}").unwrap_err();
+        assert!(err.contains("invalid variable name \"1abc\""), "{err}");
+        let err = expand_vars("/srv/${HOME").unwrap_err();
+        assert!(err.contains("unclosed"), "{err}");
+    }
+
+    #[test]
+    fn tilde_expands_to_home() {
+        assert_eq!(expand_home(Utf8Path::new("~")), home());
+        assert_eq!(
+            expand_home(Utf8Path::new("~/sub/dir")),
+            home().join("sub/dir"),
+        );
+    }
+
+    #[test]
+    fn literal_paths_are_left_alone() {
+        for path in ["/srv/~backups", "relative/~", "/srv/my app"] {
+            assert_eq!(expand_home(Utf8Path::new(path)), path);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tilde_user_expands_to_their_home() {
+        assert_eq!(expand_home(Utf8Path::new("~root/sub")), "/root/sub");
+        let unknown = Utf8Path::new("~overseer-no-such-user/sub");
+        assert_eq!(expand_home(unknown), unknown);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::error::ServiceRegistryError;
+use crate::{AnyService, DynService};
+use camino::Utf8Path;
+use futures::future;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use tracing::info;
+
+/// A collection of connected services, keyed by name, for resolving tasks'
+/// dependencies
+///
+/// Services are configured as a YAML list of [`AnyService`]s, e.g.
+/// ```yaml
+/// - type: docker_container
+///   name: jellyfin
+///   host: localhost
+/// - type: http
+///   name: website
+///   url: https://example.com
+/// ```
+#[derive(Default)]
+pub struct ServiceRegistry {
+    services: HashMap<String, Arc<dyn DynService>>,
+}
+
+impl ServiceRegistry {
+    /// Loads the services configured in the file at `path`, connecting to
+    /// each of them
+    ///
+    /// Otherwise the same as [`ServiceRegistry::connect`]
+    pub async fn load_from<P>(
+        path: P,
+    ) -> Result<Self, Vec<ServiceRegistryError>>
+    where
+        P: AsRef<Utf8Path>,
+    {
+        let path = path.as_ref();
+        let yaml = tokio::fs::read(path).await.map_err(|why| {
+            vec![ServiceRegistryError::Io(path.to_owned(), why)]
+        })?;
+        let services = serde_yaml::from_slice(&yaml)
+            .map_err(|why| vec![ServiceRegistryError::from(why)])?;
+        ServiceRegistry::connect(services).await
+    }
+
+    /// Connects to each of `services` at once
+    ///
+    /// All services are connected to even if some fail, with every failure
+    /// (including two services using the same name) returned together
+    pub async fn connect(
+        services: Vec<AnyService>,
+    ) -> Result<Self, Vec<ServiceRegistryError>> {
+        let mut errors = Vec::new();
+        let mut names = Vec::with_capacity(services.len());
+        let mut unique = Vec::with_capacity(services.len());
+        for service in services {
+            let name = service.name().to_owned();
+            match names.contains(&name) {
+                true => errors.push(ServiceRegistryError::DuplicateName(name)),
+                false => {
+                    names.push(name);
+                    unique.push(service);
+                }
+            }
+        }
+
+        let connected =
+            future::join_all(unique.into_iter().map(AnyService::connect)).await;
+        let mut registry = ServiceRegistry::default();
+        for (name, result) in names.into_iter().zip(connected) {
+            match result {
+                Ok(service) => {
+                    registry.services.insert(name, service);
+                }
+                Err(why) => {
+                    errors.push(ServiceRegistryError::Connect(name, why))
+                }
+            }
+        }
+        match errors.is_empty() {
+            true => {
+                info!(count = registry.len(), "Loaded service registry");
+                Ok(registry)
+            }
+            false => Err(errors),
+        }
+    }
+
+    /// Gets the service called `name`, if there is one
+    pub fn get(&self, name: &str) -> Option<Arc<dyn DynService>> {
+        self.services.get(name).cloned()
+    }
+
+    /// The names of all the services, in no particular order
+    pub fn names(&self) -> Vec<String> {
+        self.services.keys().cloned().collect()
+    }
+
+    /// The number of services
+    pub fn len(&self) -> usize {
+        self.services.len()
+    }
+
+    /// Whether there are no services
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+}
+
+impl fmt::Debug for ServiceRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.services.keys()).finish()
+    }
+}
@@ -0,0 +1,57 @@
+use serde::Deserialize;
+use std::fmt;
+use std::future::Future;
+use std::num::NonZeroU32;
+use std::time::Duration;
+use tracing::warn;
+
+/// How connecting to a service's host (Docker, or SSH) is retried, for hosts
+/// that come up after Overseer does
+///
+/// The delay doubles after each failed attempt, so the defaults of 5
+/// attempts 1s apart give up after 15s of waiting
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConnectRetry {
+    max_attempts: NonZeroU32,
+    #[serde(with = "humantime_serde")]
+    base_delay: Duration,
+}
+
+impl ConnectRetry {
+    /// Calls `connect` until it succeeds or attempts run out, returning the
+    /// last error
+    pub(crate) async fn run<T, E, F, Fut>(
+        &self,
+        host: &str,
+        mut connect: F,
+    ) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: fmt::Display,
+    {
+        let mut delay = self.base_delay;
+        let mut attempt = 1;
+        loop {
+            match connect().await {
+                Err(why) if attempt < self.max_attempts.get() => {
+                    warn!(%host, attempt, ?delay, "Couldn't connect, retrying: {why}");
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        ConnectRetry {
+            max_attempts: NonZeroU32::new(5).unwrap(),
+            base_delay: Duration::from_secs(1),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! Reading files over SFTP, for hosts that might not have a shell worth
+//! using
+
+use crate::error::SftpError;
+use openssh::Session;
+use openssh_sftp_client::{Sftp, SftpOptions};
+use tracing::trace;
+
+/// The largest file that will be read, to not exhaust memory on a mistake
+const MAX_FILE_LEN: u64 = 16 * 1024 * 1024;
+
+/// Reads the whole of the file at `path` on the session's host, closing the
+/// session afterwards
+///
+/// Paths starting `~user` are only understood by servers with the
+/// `expand-path` extension (OpenSSH 8.9 and later)
+pub(crate) async fn read_file(
+    session: Session,
+    path: &str,
+) -> Result<Vec<u8>, SftpError> {
+    let sftp = Sftp::from_session(session, SftpOptions::default()).await?;
+    let contents = read(&sftp, path).await;
+    // Nothing more to do if closing fails, the file's been read regardless
+    let _ = sftp.close().await;
+    contents
+}
+
+async fn read(sftp: &Sftp, path: &str) -> Result<Vec<u8>, SftpError> {
+    let mut fs = sftp.fs();
+    let path = match path.starts_with('~') {
+        true if sftp.support_expand_path() => fs.canonicalize(path).await?,
+        true => return Err(SftpError::CantExpand(path.to_owned())),
+        false => path.into(),
+    };
+    let len = fs.metadata(&path).await?.len().unwrap_or_default();
+    if len > MAX_FILE_LEN {
+        return Err(SftpError::TooLarge(MAX_FILE_LEN));
+    }
+    let contents = fs.read(&path).await?;
+    trace!(?path, len = contents.len(), "Read file over SFTP");
+    Ok(contents.to_vec())
+}
@@ -0,0 +1,148 @@
+use crate::cache::StatusCache;
+use crate::error::ServiceError;
+use crate::retry::ConnectRetry;
+use crate::{Result, Service, ServiceStatus};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::process::Output;
+use std::sync::Arc;
+use tracing::{info, trace};
+
+/// A systemd unit, managed with `systemctl` locally or over SSH
+///
+/// Set `user: true` for units of the (remote) user's service manager,
+/// rather than the system's
+#[derive(Debug, Deserialize)]
+pub struct SystemdService {
+    name: String,
+    host: String,
+    #[serde(default)]
+    user: bool,
+    #[serde(default)]
+    connect_retry: ConnectRetry,
+    #[serde(default, rename = "status_ttl")]
+    cache: StatusCache,
+}
+
+impl SystemdService {
+    /// Runs `systemctl [verb] [unit]`, returning its output whether it
+    /// succeeded or not
+    async fn systemctl(&self, verb: &str) -> Result<Output> {
+        let mut args = Vec::with_capacity(3);
+        if self.user {
+            args.push("--user");
+        }
+        args.extend([verb, self.name.as_str()]);
+        trace!(unit = %self.name, host = %self.host, ?args, "Running systemctl");
+        match self.host.as_str() {
+            "localhost" => Ok(tokio::process::Command::new("systemctl")
+                .args(&args)
+                .output()
+                .await?),
+            host => remote_systemctl(host, &args, &self.connect_retry).await,
+        }
+    }
+
+    /// Runs `systemctl [verb] [unit]`, erroring if it fails
+    async fn control(&self, verb: &str) -> Result<()> {
+        let output = self.systemctl(verb).await?;
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(ServiceError::Systemctl(
+                String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl Service for SystemdService {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn host(&self) -> &str {
+        &self.host
+    }
+
+    async fn status(self: &Arc<Self>) -> Result<ServiceStatus> {
+        self.cache
+            .get_or_check(async {
+                // Exits non-zero unless the unit is active, but always gives its
+                // state
+                let output = self.systemctl("is-active").await?;
+                let state = String::from_utf8_lossy(&output.stdout);
+                use ServiceStatus::*;
+                match state.trim() {
+                    "active" | "reloading" | "refreshing" => Ok(Healthy),
+                    "activating" => Ok(Starting),
+                    "deactivating" | "failed" => Ok(Unhealthy),
+                    "inactive" | "maintenance" => Ok(Offline),
+                    "" => Err(ServiceError::Systemctl(
+                        String::from_utf8_lossy(&output.stderr)
+                            .trim()
+                            .to_owned(),
+                    )),
+                    state => Err(ServiceError::Systemctl(format!(
+                        "unknown unit state {state:?}"
+                    ))),
+                }
+            })
+            .await
+    }
+
+    async fn start(self: &Arc<Self>) -> Result<()> {
+        self.cache
+            .invalidating(async {
+                info!(unit = %self.name, "Starting unit");
+                self.control("start").await
+            })
+            .await
+    }
+
+    /// Stops the unit, which systemd gives its own `TimeoutStopSec` to exit
+    async fn stop(self: &Arc<Self>) -> Result<()> {
+        self.cache
+            .invalidating(async {
+                info!(unit = %self.name, "Stopping unit");
+                self.control("stop").await
+            })
+            .await
+    }
+
+    async fn restart(self: &Arc<Self>) -> Result<()> {
+        self.cache
+            .invalidating(async {
+                info!(unit = %self.name, "Restarting unit");
+                self.control("restart").await
+            })
+            .await
+    }
+}
+
+#[cfg(unix)]
+async fn remote_systemctl(
+    host: &str,
+    args: &[&str],
+    retry: &ConnectRetry,
+) -> Result<Output> {
+    use openssh::{KnownHosts, Session};
+
+    let session = retry
+        .run(host, || Session::connect(host, KnownHosts::Strict))
+        .await?;
+    let output = session.command("systemctl").args(args).output().await?;
+    let _ = session.close().await;
+    Ok(output)
+}
+
+#[cfg(not(unix))]
+async fn remote_systemctl(
+    _: &str,
+    _: &[&str],
+    _: &ConnectRetry,
+) -> Result<Output> {
+    Err(ServiceError::Unsupported(
+        "remote hosts are only supported on Unix",
+    ))
+}
@@ -0,0 +1,125 @@
+use async_trait::async_trait;
+use futures::future;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::TaskRunReport;
+
+static HANDLERS: Mutex<Vec<Arc<dyn TaskFailureHandler>>> =
+    Mutex::new(Vec::new());
+
+/// Something to alert when a task run fails, such as a pager or chat webhook
+///
+/// Register handlers with [`add_failure_handler`].
+/// Every registered handler is called concurrently after a failed run,
+/// before the run returns.
+/// Dry runs don't trigger handlers
+#[async_trait]
+pub trait TaskFailureHandler: Send + Sync {
+    /// Called with the details of a run where at least one command failed
+    async fn on_failure(&self, report: &TaskRunReport);
+
+    /// Called when the cron task `task` is deactivated after `failures`
+    /// scheduled runs in a row failed, reaching its
+    /// `max_consecutive_failures`
+    ///
+    /// Does nothing by default, as the last failure was already reported
+    async fn on_deactivated(&self, _task: &str, _failures: u32) {}
+}
+
+/// Registers a handler to be called whenever any task run fails
+pub fn add_failure_handler(handler: impl TaskFailureHandler + 'static) {
+    HANDLERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Arc::new(handler));
+}
+
+/// Calls every registered [`TaskFailureHandler`] about a failed run
+pub(crate) async fn report_failure(report: &TaskRunReport) {
+    let handlers = HANDLERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    future::join_all(handlers.iter().map(|h| h.on_failure(report))).await;
+}
+
+/// Calls every registered [`TaskFailureHandler`] about a task deactivated
+/// for failing too many times in a row
+pub(crate) async fn report_deactivated(task: &str, failures: u32) {
+    let handlers = HANDLERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    future::join_all(handlers.iter().map(|h| h.on_deactivated(task, failures)))
+        .await;
+}
+
+/// A [`TaskFailureHandler`] that runs a local shell command, for piping
+/// alerts into `curl`, `mail`, or the like
+///
+/// Requires the `failure-command` feature
+///
+/// The command is run as `[shell] -c "[script]"` (or `cmd /C [script]` on
+/// Windows), with the report in its environment:
+/// - `OVERSEER_TASK`: the task's name
+/// - `OVERSEER_TASK_KIND`: `cron` or `file`
+/// - `OVERSEER_DURATION_MS`: how long the run took, in milliseconds
+/// - `OVERSEER_ERRORS`: each error, one per line
+///
+/// If the command itself fails, that's logged, not reported again
+#[cfg(feature = "failure-command")]
+#[derive(Debug, Clone)]
+pub struct CommandFailureHandler {
+    shell: String,
+    script: String,
+}
+
+#[cfg(feature = "failure-command")]
+impl CommandFailureHandler {
+    /// Creates a handler running `script` with `sh`, or `cmd` on Windows
+    pub fn new(script: impl Into<String>) -> Self {
+        CommandFailureHandler {
+            shell: crate::default_shell(),
+            script: script.into(),
+        }
+    }
+
+    /// Sets the shell `script` is run with, `sh` (`cmd` on Windows) by default
+    pub fn shell(mut self, shell: impl Into<String>) -> Self {
+        self.shell = shell.into();
+        self
+    }
+}
+
+#[cfg(feature = "failure-command")]
+#[async_trait]
+impl TaskFailureHandler for CommandFailureHandler {
+    async fn on_failure(&self, report: &TaskRunReport) {
+        let mut command = std::process::Command::new(&self.shell);
+        crate::add_shell_script(&mut command, &self.shell, &self.script);
+        let result = tokio::process::Command::from(command)
+            .env("OVERSEER_TASK", &report.task)
+            .env("OVERSEER_TASK_KIND", report.kind.as_str())
+            .env(
+                "OVERSEER_DURATION_MS",
+                report.duration.as_millis().to_string(),
+            )
+            .env("OVERSEER_ERRORS", report.errors.join("\n"))
+            .stdin(std::process::Stdio::null())
+            .status()
+            .await;
+        match result {
+            Ok(status) if status.success() => {
+                tracing::trace!(%report.task, "Failure command completed");
+            }
+            Ok(status) => tracing::warn!(
+                %report.task,
+                "Failure command exited with {status}",
+            ),
+            Err(why) => tracing::warn!(
+                %report.task,
+                "Couldn't run failure command: {why}",
+            ),
+        }
+    }
+}
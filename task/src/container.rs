@@ -0,0 +1,325 @@
+use bollard::container::LogOutput;
+use bollard::errors::Error as BollardError;
+use bollard::exec::{CreateExecOptions, StartExecResults};
+use futures::{Stream, StreamExt};
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::{info, instrument, trace, warn};
+
+use crate::error::CommandRunErrorType;
+use crate::{
+    acquire, feed_stdin, forward_lines, metrics, redacted, shell_quote,
+    CommandOutcome, CommandRunError, EnvVar, OutputStream, RunContext, Tail,
+    TaskCommand,
+};
+
+/// How much output is buffered between Docker and the command's output
+/// handling, per stream
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Somewhere a stream of a container's output is written
+type Sink = Box<dyn AsyncWrite + Send + Unpin>;
+
+impl TaskCommand {
+    /// Runs the command in the running container `container` on the local
+    /// Docker instance, with `docker exec`
+    #[instrument(
+        name = "command",
+        skip_all,
+        fields(command = %self.name, host = %format_args!("container:{container}")),
+    )]
+    pub(crate) async fn run_in_container(
+        self: Arc<Self>,
+        container: &str,
+        ctx: Arc<RunContext>,
+    ) -> Result<CommandOutcome, CommandRunError> {
+        if ctx.cancel.is_cancelled() {
+            return Err(self.cancelled());
+        }
+        let label = format!("container:{container}");
+        let working_dir = self.resolved_working_dir(ctx.base_dir.as_deref());
+        match &working_dir {
+            Some(dir) if !dir.is_absolute() => {
+                warn!(%self.name, working_dir = ?dir, "Working directory for container command is not absolute");
+            }
+            _ => {}
+        }
+        let run = self.rendered(&ctx, &label, shell_quote)?;
+        let mut env_vars = self.env_vars().await?;
+        self.record_execution(&label, &env_vars, &ctx);
+        let cmd = match self.is_shell() {
+            true => vec![
+                self.shell(&ctx.shell).to_owned(),
+                "-c".into(),
+                run.raw.clone(),
+            ],
+            false => std::iter::once(&run.program)
+                .chain(&run.args)
+                .cloned()
+                .collect(),
+        };
+        if ctx.dry_run {
+            info!(
+                %self.name,
+                host = %label,
+                ?cmd,
+                env_vars = ?redacted(&env_vars),
+                ?working_dir,
+                "Dry run, not executing TaskCommand in container",
+            );
+            return Ok(self.dry_run_outcome());
+        }
+        let secrets = self.resolve_secrets(&mut env_vars).await?;
+        ctx.paced(|| self.cancelled()).await?;
+        let _permit = tokio::select! {
+            permit = acquire(ctx.global_limit.clone()) => permit,
+            _ = ctx.cancel.cancelled() => return Err(self.cancelled()),
+        };
+        let docker = tokio::select! {
+            docker = service::docker::connect_local() => docker,
+            _ = ctx.cancel.cancelled() => return Err(self.cancelled()),
+        }
+        .map_err(|why| CommandRunError {
+            name: self.name.clone(),
+            r#type: CommandRunErrorType::Connect(
+                label.clone(),
+                why.to_string(),
+            ),
+        })?;
+        let exec_failed = |why: BollardError| CommandRunError {
+            name: self.name.clone(),
+            r#type: CommandRunErrorType::Exec(why.to_string()),
+        };
+
+        let input = self.stdin_bytes().await?;
+        let streaming = ctx.output.is_some();
+        let options = CreateExecOptions {
+            attach_stdin: Some(input.is_some()),
+            attach_stdout: Some(streaming || self.stdout_file.is_some()),
+            // stderr is always captured, so failures can explain themselves
+            attach_stderr: Some(true),
+            env: Some(
+                env_vars.iter().map(EnvVar::to_string).collect::<Vec<_>>(),
+            ),
+            cmd: Some(cmd),
+            working_dir: working_dir.map(String::from),
+            ..Default::default()
+        };
+        let exec = docker
+            .create_exec(container, options)
+            .await
+            .map_err(exec_failed)?;
+        let stdout_file = match &self.stdout_file {
+            Some(path) => Some(self.open_output(path, &ctx).await?),
+            None => None,
+        };
+        let stderr_file = match &self.stderr_file {
+            Some(path) => Some(self.open_output(path, &ctx).await?),
+            None => None,
+        };
+        let started = Instant::now();
+        let (output, mut stdin) = match docker
+            .start_exec(&exec.id, None)
+            .await
+            .map_err(exec_failed)?
+        {
+            StartExecResults::Attached { output, input } => (output, input),
+            // Shouldn't happen, as it wasn't asked to detach
+            StartExecResults::Detached => {
+                return Err(CommandRunError {
+                    name: self.name.clone(),
+                    r#type: CommandRunErrorType::ExecDetached,
+                })
+            }
+        };
+
+        // Output is demultiplexed into pipes (or the files it's redirected
+        // to), so it's handled the same as any other command's
+        let (stdout_sink, stdout_pipe) = match stdout_file {
+            Some(file) => (Some(file_sink(file)), None),
+            None if streaming => {
+                let (sink, pipe) = tokio::io::duplex(PIPE_CAPACITY);
+                (Some(Box::new(sink) as Sink), Some(pipe))
+            }
+            None => (None, None),
+        };
+        let (stderr_sink, stderr_pipe) = match stderr_file {
+            Some(file) => (Some(file_sink(file)), None),
+            None => {
+                let (sink, pipe) = tokio::io::duplex(PIPE_CAPACITY);
+                (Some(Box::new(sink) as Sink), Some(pipe))
+            }
+        };
+        let demux = demultiplex(output, stdout_sink, stderr_sink);
+        let feed = async {
+            if input.is_none() {
+                return Ok(());
+            }
+            feed_stdin(Some(&mut stdin), input.as_deref()).await?;
+            stdin.shutdown().await
+        };
+        let stdout = forward_lines(
+            stdout_pipe,
+            ctx.output.as_ref(),
+            &self.name,
+            OutputStream::Stdout,
+            self.output_encoding,
+            None,
+        );
+        let mut stderr_tail = Tail::new(self.max_output_bytes());
+        let stderr = forward_lines(
+            stderr_pipe,
+            ctx.output.as_ref(),
+            &self.name,
+            OutputStream::Stderr,
+            self.output_encoding,
+            Some(&mut stderr_tail),
+        );
+        let finished = tokio::select! {
+            finished = async {
+                tokio::join!(feed, demux, stdout, stderr)
+            } => Some(finished),
+            _ = ctx.cancel.cancelled() => None,
+        };
+        let (fed, demuxed, stdout, stderr) = match finished {
+            Some(finished) => finished,
+            None => {
+                // Docker can't kill an exec, so this only stops following it
+                warn!(%self.name, "TaskCommand cancelled, detaching from container");
+                return Err(self.cancelled());
+            }
+        };
+        if let Err(why) = fed.and(demuxed).and(stdout).and(stderr) {
+            return Err(CommandRunError {
+                name: self.name.clone(),
+                r#type: CommandRunErrorType::Io(why),
+            });
+        }
+        let exit_code = docker
+            .inspect_exec(&exec.id)
+            .await
+            .map_err(exec_failed)?
+            .exit_code
+            .and_then(|code| i32::try_from(code).ok());
+        trace!(%self.name, ?exit_code, "Container command finished");
+        metrics().record_exit_code(&ctx.task_name, &self.name, exit_code);
+        stderr_tail.scrub(&secrets);
+        match self.check_exit(
+            exit_code,
+            Some(stderr_tail),
+            Some(started.elapsed()),
+        ) {
+            // Docker exits with 127 when the program isn't in the container
+            Err(CommandRunError {
+                r#type: CommandRunErrorType::ExitStatus(127, Some(stderr), ..),
+                ..
+            }) if !self.is_shell() && stderr.contains("not found") => {
+                Err(self.not_found(&run.program, None))
+            }
+            result => result,
+        }
+    }
+}
+
+fn file_sink(file: std::fs::File) -> Sink {
+    Box::new(tokio::fs::File::from_std(file))
+}
+
+/// Writes each chunk of a container's `output` to the sink for its stream,
+/// if there is one, closing them once it ends
+async fn demultiplex(
+    mut output: impl Stream<Item = Result<LogOutput, BollardError>> + Unpin,
+    mut stdout: Option<Sink>,
+    mut stderr: Option<Sink>,
+) -> io::Result<()> {
+    while let Some(chunk) = output.next().await {
+        let chunk = chunk.map_err(|why| io::Error::other(why.to_string()))?;
+        let (sink, message) = match chunk {
+            LogOutput::StdOut { message } => (&mut stdout, message),
+            LogOutput::StdErr { message } => (&mut stderr, message),
+            _ => continue,
+        };
+        if let Some(sink) = sink {
+            sink.write_all(&message).await?;
+        }
+    }
+    for sink in [stdout, stderr].iter_mut().flatten() {
+        sink.shutdown().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CronTask, MockExecutor, Task};
+    use tokio::io::AsyncReadExt;
+
+    /// Reads all of `pipe`, once whatever writes to it has finished
+    async fn read_all(mut pipe: impl tokio::io::AsyncRead + Unpin) -> Vec<u8> {
+        let mut read = Vec::new();
+        pipe.read_to_end(&mut read).await.unwrap();
+        read
+    }
+
+    #[tokio::test]
+    async fn output_is_demultiplexed_by_stream() {
+        let output = futures::stream::iter([
+            Ok(LogOutput::StdOut {
+                message: b"out one\n".to_vec().into(),
+            }),
+            Ok(LogOutput::StdErr {
+                message: b"err\n".to_vec().into(),
+            }),
+            Ok(LogOutput::Console {
+                message: b"ignored\n".to_vec().into(),
+            }),
+            Ok(LogOutput::StdOut {
+                message: b"out two\n".to_vec().into(),
+            }),
+        ]);
+        let (stdout, stdout_pipe) = tokio::io::duplex(PIPE_CAPACITY);
+        let (stderr, stderr_pipe) = tokio::io::duplex(PIPE_CAPACITY);
+        demultiplex(output, Some(Box::new(stdout)), Some(Box::new(stderr)))
+            .await
+            .unwrap();
+        assert_eq!(read_all(stdout_pipe).await, b"out one\nout two\n");
+        assert_eq!(read_all(stderr_pipe).await, b"err\n");
+    }
+
+    #[tokio::test]
+    async fn commands_run_in_container_by_label() {
+        let executor = Arc::new(MockExecutor::new());
+        let task = CronTask::from_yaml_str(
+            "name: contained
+schedule: '@daily'
+host:
+  container: app
+commands:
+  - name: migrate
+    run: ./manage.py migrate",
+        )
+        .unwrap()
+        .with_executor(executor.clone());
+        Arc::new(task).run().await.unwrap();
+        let executed = executor.executed();
+        assert_eq!(executed, [("migrate".into(), "container:app".into())]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_in_container_needs_no_docker() {
+        let task = CronTask::from_yaml_str(
+            "name: contained
+schedule: '@daily'
+dry_run: true
+host:
+  container: overseer-no-such-container
+commands:
+  - run: ./manage.py migrate",
+        )
+        .unwrap();
+        Arc::new(task).run().await.unwrap();
+    }
+}
@@ -1,42 +1,222 @@
 use async_trait::async_trait;
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::DateTime;
 use delay_timer::prelude::*;
-use futures::future;
-use serde::Deserialize;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use tracing::{info, trace, warn};
+use serde::{Deserialize, Serialize};
+use service::path::{
+    deserialize_expanded_opt, deserialize_vars_opt, expand_home,
+};
+use service::registry::ServiceRegistry;
+use service::DynService;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, instrument, trace, Level};
 
+use crate::error::{
+    ActivateError, ActivateErrorType, BuildError, ScheduleError,
+};
+use crate::log_level::{deserialize_log_level, serialize_log_level};
 use crate::{
-    CommandRunError, CommandRunErrorType, Commands, Host, ReadError, Task,
+    check_command_hosts, check_command_names, check_command_shells,
+    check_dependencies, check_identities, check_identities_permitted,
+    check_limits, check_limits_enforceable, check_nice, check_shell,
+    check_umask, default_shell, deserialize_commands, finish_commands,
+    missing_command_paths, report_deactivated, shutting_down, CommandBuilder,
+    CommandDefaults, CommandRunError, Commands, ConcurrencyLimit, Dependency,
+    DependencyReport, Executor, Hosts, LastRunCell, LockFile, OutputLine,
+    Prerequisites, ReadError, ReadErrorType, Remediate, RunContext, ServiceMap,
+    SpawnLimit, SpawnRate, Successes, Task, TaskExecutor, TaskKind, TaskRun,
+    TaskRunReport, UnreachablePolicy, Validate,
 };
 
 /// A task that is run on a time-periodic basis
 ///
 /// Uses a cron schedule to determine when it's run.
-#[derive(Debug, Deserialize)]
+///
+/// Serialises to an equivalent task file, with every option (and any
+/// `defaults`) spelled out
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct CronTask {
     name: String,
     #[serde(default)]
     id: AtomicU64,
-    #[allow(dead_code)]
     #[serde(default)]
-    dependencies: Vec<()>, // TODO: populate with services
+    depends_on: Vec<String>,
+    #[serde(skip)]
+    prerequisites: Prerequisites,
+    #[serde(default)]
+    dependencies: Vec<Dependency>,
+    #[serde(skip)]
+    services: ServiceMap,
     schedule: String,
-    #[allow(dead_code)]
+    #[serde(default = "default_repeat")]
+    repeat: bool,
+    #[serde(default)]
+    run_on_activate: bool,
+    #[serde(default, with = "humantime_serde")]
+    jitter: Option<Duration>,
+    #[serde(default)]
+    jitter_seed: Option<u64>,
+    #[serde(skip)]
+    rng: AtomicU64,
+    #[serde(default, with = "humantime_serde")]
+    cooldown: Option<Duration>,
+    #[serde(skip)]
+    last_finished: Mutex<Option<Instant>>,
+    #[serde(default)]
+    max_consecutive_failures: Option<NonZeroU32>,
+    #[serde(skip)]
+    consecutive_failures: AtomicU32,
+    #[serde(skip)]
+    tripped: AtomicBool,
+    #[serde(default, rename = "host")]
+    hosts: Hosts,
+    /// Every host the commands run on, including their own hosts
+    #[serde(skip)]
+    targets: Hosts,
     #[serde(default)]
-    host: Host,
+    require_host_reachable: Option<UnreachablePolicy>,
+    #[serde(skip)]
+    deferred: Mutex<Option<JoinHandle<()>>>,
+    #[serde(default = "default_shell")]
+    shell: String,
+    #[serde(default, deserialize_with = "deserialize_vars_opt")]
+    base_dir: Option<Utf8PathBuf>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_log_level",
+        serialize_with = "serialize_log_level"
+    )]
+    log_level: Option<Level>,
+    #[serde(default)]
+    max_concurrent: Option<NonZeroUsize>,
+    #[serde(default)]
+    spawn_rate: Option<SpawnRate>,
+    #[serde(default, with = "humantime_serde")]
+    task_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_expanded_opt")]
+    lock_file: Option<Utf8PathBuf>,
+    #[serde(skip)]
+    lock: Mutex<Option<Arc<LockFile>>>,
+    #[serde(skip)]
+    limit: ConcurrencyLimit,
+    #[serde(skip)]
+    spawn_limit: SpawnLimit,
+    #[serde(skip)]
+    last_run: LastRunCell,
+    #[serde(skip)]
+    cancel: CancellationToken,
+    #[serde(skip)]
+    executor: TaskExecutor,
+    #[serde(default)]
+    defaults: CommandDefaults,
+    #[serde(default, deserialize_with = "deserialize_commands")]
+    before: Commands,
+    #[serde(deserialize_with = "deserialize_commands")]
     commands: Commands,
+    #[serde(default, deserialize_with = "deserialize_commands")]
+    after: Commands,
 }
 
 impl CronTask {
-    /// Loads a task from file, asynchronously
+    /// Provides the service a dependency of the task is named after, used to
+    /// check (and remediate) it before each run
     ///
-    /// Cron strings accepted by [`cron_clock`](https://docs.rs/cron_clock) are
-    /// supported, including shortcut expressions
+    /// A dependency whose service hasn't been provided is never healthy
+    pub fn provide_service(
+        &self,
+        name: impl Into<String>,
+        service: Arc<dyn DynService>,
+    ) {
+        self.services.insert(name.into(), service);
+    }
+
+    /// Provides the services the task's dependencies are named after from
+    /// `services`, warning about any it doesn't have
+    pub fn provide_services(&self, services: &ServiceRegistry) {
+        self.services
+            .provide_from(&self.name, &self.dependencies, services);
+    }
+
+    /// The names of the tasks this one runs after
+    pub(crate) fn depends_on(&self) -> &[String] {
+        &self.depends_on
+    }
+
+    pub(crate) fn link_prerequisites(
+        &self,
+        prerequisites: impl IntoIterator<Item = (String, Successes)>,
+    ) {
+        self.prerequisites.link(prerequisites);
+    }
+
+    /// When the task succeeds, for the tasks that run after it
+    pub(crate) fn successes(&self) -> Successes {
+        self.last_run.successes()
+    }
+
+    /// The token that cancels the task's runs when cancelled
     ///
-    /// Environment variables should be specified as KEY=value
+    /// Cancelling it kills the commands of any runs in progress (closing the
+    /// SSH session of remote commands), failing them as cancelled.
+    /// A cancelled token stays cancelled, so later runs fail immediately
+    ///
+    /// Dropping a run without cancelling it (e.g. aborting the Tokio task
+    /// it's in, or on shutdown) still kills its local commands' processes.
+    /// Remote commands' SSH sessions are closed too, but a remote process
+    /// that isn't reading or writing its standard streams may live on, so
+    /// prefer cancelling
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Executes the task's commands with `executor`, instead of
+    /// [`SystemExecutor`](crate::SystemExecutor)
+    pub fn with_executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.executor = TaskExecutor(executor);
+        self
+    }
+
+    /// The report of the task's most recent completed run, whether it
+    /// succeeded or failed
+    ///
+    /// Dry runs aren't counted
+    pub fn last_run(&self) -> Option<TaskRunReport> {
+        self.last_run.get()
+    }
+
+    /// The task's cron schedule, as written
+    pub fn schedule(&self) -> &str {
+        &self.schedule
+    }
+
+    /// When the task is next scheduled to run, not accounting for jitter
+    ///
+    /// `None` if the schedule is invalid or will never fire again, including
+    /// once a non-repeating task has run
+    pub fn next_run(&self) -> Option<SystemTime> {
+        if !self.repeat && self.last_finished().is_some() {
+            return None;
+        }
+        let schedule = cron_clock::Schedule::from_str(&self.schedule).ok()?;
+        let next = schedule.upcoming(Local).next()?;
+        let secs = u64::try_from(next.timestamp()).ok()?;
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Loads a task from file, asynchronously
+    ///
+    /// See the [task file reference](crate#task-files) for every option
     ///
     /// Example task file:
     /// ```yml
@@ -49,73 +229,933 @@ impl CronTask {
     {
         crate::load_from(path).await
     }
+
+    /// Parses a task from a YAML string, without touching the filesystem
+    ///
+    /// Formatted the same as a task file, see [`CronTask::load_from`].
+    /// Checks that depend on the machine running the task (that its shells
+    /// and paths exist, and the like) are left until it's activated
+    pub fn from_yaml_str(yaml: &str) -> Result<Self, ReadError> {
+        crate::from_yaml_slice(yaml.as_bytes(), Utf8Path::new("<string>"))
+    }
+
+    /// Reads a task from `reader` (e.g. stdin) until it ends, asynchronously
+    ///
+    /// Formatted the same as a task file, see [`CronTask::load_from`], and
+    /// checked as [`CronTask::from_yaml_str`] is
+    pub async fn from_reader<R>(reader: R) -> Result<Self, ReadError>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        crate::from_reader(reader, Utf8Path::new("<reader>")).await
+    }
+
     /// Schedules the task using the given `delay_timer`
     ///
     /// The `id` given must be unique for the `delay_timer` or else the task
     /// with the same ID will be overwritten.
     /// This is considered the responsibility of the caller
-    /// [for now](https://github.com/BinChengZhao/delay-timer/issues/41)
+    /// [for now](https://github.com/BinChengZhao/delay-timer/issues/41).
+    /// [`CronScheduler::activate`](crate::CronScheduler::activate) picks an
+    /// unused ID, and keeps track of what's scheduled
+    ///
+    /// Note: this does not run the task, unless `run_on_activate` is set, in
+    /// which case it's run in the background straight away
     ///
-    /// Note: this does not run the task
-    // TODO: check ID isn't in use and error if so
-    //       https://github.com/BinChengZhao/delay-timer/issues/41
-    pub fn activate(
+    /// With `require_host_reachable`, the task's hosts are checked once its
+    /// `lock_file` is locked.
+    /// When skipping, this errors if any can't be reached.
+    /// When deferring, the task is added to the timer in the background once
+    /// they all can, and failing to add it then is only logged.
+    /// Either way, this must be called from within a Tokio runtime
+    ///
+    /// The task is checked against this machine again first (see
+    /// [`CronTask::from_yaml_str`]), and errors if that fails, the task's
+    /// `lock_file` is held by another process, any of its hosts can't be
+    /// reached when skipping, or the task couldn't be added to the timer
+    pub async fn activate(
         self: &Arc<Self>,
         delay_timer: &DelayTimer,
         id: u64,
-    ) -> Result<u64, TaskError> {
-        warn!("Unable to check dependencies as that isn't implemented yet");
+    ) -> Result<u64, ActivateError> {
+        // TODO: check ID isn't in use and error if so
+        //       https://github.com/BinChengZhao/delay-timer/issues/41
+        let error = |r#type| ActivateError {
+            name: self.name.clone(),
+            r#type,
+        };
+        self.check_environment().map_err(|why| error(why.into()))?;
+        let lock = self
+            .lock_file
+            .as_ref()
+            .map(LockFile::acquire)
+            .transpose()
+            .map_err(|why| error(why.into()))?;
         self.id.store(id, Ordering::SeqCst);
+        let seed = self.jitter_seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default()
+                ^ id
+        });
+        self.rng.store(seed, Ordering::SeqCst);
+        self.consecutive_failures.store(0, Ordering::SeqCst);
+        self.tripped.store(false, Ordering::SeqCst);
+        match self.require_host_reachable {
+            None => self.add_to_timer(delay_timer, id)?,
+            Some(UnreachablePolicy::Skip) => {
+                let unreachable = self.targets.unreachable().await;
+                if !unreachable.is_empty() {
+                    return Err(error(ActivateErrorType::Unreachable(
+                        unreachable,
+                    )));
+                }
+                self.add_to_timer(delay_timer, id)?;
+            }
+            Some(policy @ UnreachablePolicy::Defer) => {
+                let task = self.clone();
+                let delay_timer = delay_timer.clone();
+                let deferred = tokio::spawn(async move {
+                    if !task.targets.await_reachable(&task.name, policy).await {
+                        return;
+                    }
+                    if let Err(why) = task.add_to_timer(&delay_timer, id) {
+                        error!("{why}");
+                    }
+                });
+                *self.deferred() = Some(deferred);
+            }
+        }
+        *self.lock.lock().unwrap_or_else(PoisonError::into_inner) = lock;
+        Ok(id)
+    }
+
+    /// Adds the task to `delay_timer` with `id`, running it straight away if
+    /// it's set to
+    fn add_to_timer(
+        self: &Arc<Self>,
+        delay_timer: &DelayTimer,
+        id: u64,
+    ) -> Result<(), ActivateError> {
+        let error = |r#type| ActivateError {
+            name: self.name.clone(),
+            r#type,
+        };
+        if self.run_on_activate {
+            let task = self.clone();
+            tokio::spawn(async move {
+                info!(%task.name, "Running task on activation");
+                if let Err(why) = task.run().await {
+                    why.into_iter().for_each(|err| error!("{err}"));
+                }
+            });
+        }
         let closure = {
             let new_self = self.clone();
-            move || CronTask::run(new_self.clone())
+            let delay_timer = delay_timer.clone();
+            move || {
+                let task = new_self.clone();
+                let delay_timer = delay_timer.clone();
+                async move {
+                    if task.cooling_down() {
+                        info!(%task.name, "Still cooling down, skipping run");
+                        return Ok(());
+                    }
+                    if let Some(delay) = task.jitter_delay() {
+                        trace!(%task.name, ?delay, "Delaying run for jitter");
+                        tokio::time::sleep(delay).await;
+                    }
+                    if shutting_down() {
+                        info!(%task.name, "Shutting down, skipping run");
+                        return Ok(());
+                    }
+                    let result = task.clone().run().await;
+                    *task.last_finished() = Some(Instant::now());
+                    task.count_run(result.is_ok(), &delay_timer).await;
+                    result
+                }
+            }
+        };
+        let mut builder = TaskBuilder::default();
+        builder.set_task_id(id).set_maximum_parallel_runnable_num(1);
+        match self.repeat {
+            true => builder.set_frequency_repeated_by_cron_str(&self.schedule),
+            false => {
+                builder.set_frequency_count_down_by_cron_str(&self.schedule, 1)
+            }
         };
-        let task = TaskBuilder::default()
-            .set_task_id(id)
-            .set_frequency_repeated_by_cron_str(&self.schedule)
-            .set_maximum_parallel_runnable_num(1)
-            .spawn_async_routine(closure)?;
-        delay_timer.add_task(task)?;
+        let task = builder
+            .spawn_async_routine(closure)
+            .map_err(|why| error(why.into()))?;
+        delay_timer
+            .add_task(task)
+            .map_err(|why| error(why.into()))?;
         info!(%id, %self.name, "Scheduled task started");
-        Ok(id)
+        Ok(())
+    }
+
+    /// Runs the task, sending each line of command output to `tx` as soon as
+    /// it's produced
+    ///
+    /// This is otherwise the same as [`Task::run`].
+    /// Streams redirected with `stdout_file` or `stderr_file` go to their
+    /// files instead of being sent.
+    /// See [`OutputLine`] for ordering guarantees
+    pub async fn run_streaming(
+        self: Arc<Self>,
+        tx: Sender<OutputLine>,
+    ) -> Result<(), Vec<CommandRunError>> {
+        let ctx = RunContext::new(
+            &self.name,
+            self.dry_run,
+            Some(tx),
+            &self.shell,
+            self.base_dir.as_deref(),
+            &self.targets,
+            self.spawn_limit.limiter(self.spawn_rate),
+            self.cancel.child_token(),
+            &self.executor,
+        );
+        self.run_with(ctx).await
+    }
+
+    #[instrument(
+        name = "task",
+        skip_all,
+        fields(
+            task = %self.name,
+            trigger = "cron",
+            log_level = self.log_level.map(|level| level.as_str()),
+        ),
+    )]
+    async fn run_with(
+        self: Arc<Self>,
+        ctx: Arc<RunContext>,
+    ) -> Result<(), Vec<CommandRunError>> {
+        info!(?self.id, %self.name, "Task triggered");
+        let run = TaskRun {
+            name: &self.name,
+            kind: self.kind(),
+            depends_on: &self.depends_on,
+            prerequisites: &self.prerequisites,
+            dependencies: &self.dependencies,
+            services: &self.services,
+            hosts: &self.targets,
+            task_hosts: &self.hosts,
+            max_concurrent: self.max_concurrent,
+            task_timeout: self.task_timeout,
+            limit: &self.limit,
+            last_run: &self.last_run,
+            before: &self.before,
+            commands: &self.commands,
+            after: &self.after,
+            failure_level: Level::WARN,
+        };
+        run.run(&ctx).await
+    }
+}
+
+impl CronTask {
+    /// Picks a delay less than `jitter` (if set), using SplitMix64 so the
+    /// sequence of delays is determined by the task's seed
+    fn jitter_delay(&self) -> Option<Duration> {
+        const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+        let jitter = self.jitter.filter(|jitter| !jitter.is_zero())?;
+        let mut z = self
+            .rng
+            .fetch_add(GAMMA, Ordering::Relaxed)
+            .wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        let nanos = u64::try_from(jitter.as_nanos()).unwrap_or(u64::MAX);
+        Some(Duration::from_nanos(z % nanos))
+    }
+
+    /// Whether the last scheduled run finished less than `cooldown` ago
+    fn cooling_down(&self) -> bool {
+        match (self.cooldown, *self.last_finished()) {
+            (Some(cooldown), Some(finished)) => finished.elapsed() < cooldown,
+            _ => false,
+        }
+    }
+
+    /// Counts a scheduled run towards `max_consecutive_failures`,
+    /// deactivating the task on `delay_timer` once it's reached
+    async fn count_run(&self, succeeded: bool, delay_timer: &DelayTimer) {
+        if succeeded {
+            self.consecutive_failures.store(0, Ordering::SeqCst);
+            return;
+        }
+        let failures =
+            self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        let Some(max) = self.max_consecutive_failures else {
+            return;
+        };
+        // Only the run reaching the limit deactivates it
+        if failures < max.get() || self.tripped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let id = self.id.load(Ordering::SeqCst);
+        error!(%id, %self.name, %failures, "Task failed too many times in a row, deactivating it");
+        self.deactivated();
+        if let Err(why) = delay_timer.remove_task(id) {
+            error!(%id, %self.name, "Couldn't remove task from the timer: {why}");
+        }
+        report_deactivated(&self.name, failures).await;
+    }
+
+    /// Whether the task deactivated itself after too many failures, since it
+    /// was last activated
+    pub(crate) fn tripped(&self) -> bool {
+        self.tripped.load(Ordering::SeqCst)
+    }
+
+    /// Releases the task's `lock_file`, if it's holding it, and abandons an
+    /// activation waiting for its hosts to be reachable
+    pub(crate) fn deactivated(&self) {
+        self.lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(deferred) = self.deferred().take() {
+            deferred.abort();
+        }
+    }
+
+    fn deferred(&self) -> MutexGuard<'_, Option<JoinHandle<()>>> {
+        self.deferred.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn last_finished(&self) -> MutexGuard<'_, Option<Instant>> {
+        self.last_finished
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Validate for CronTask {
+    fn apply_defaults(&mut self) {
+        self.defaults.apply([
+            &mut self.before,
+            &mut self.commands,
+            &mut self.after,
+        ]);
+        self.targets = self.hosts.targets(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+        );
+    }
+
+    fn validate(&self) -> Result<(), ReadErrorType> {
+        if self.commands.is_empty() {
+            return Err(ReadErrorType::NoCommands);
+        }
+        if let Err(ScheduleError { schedule, why }) =
+            parse_schedule(&self.schedule)
+        {
+            return Err(ReadErrorType::InvalidSchedule(schedule, why));
+        }
+        self.hosts.check()?;
+        check_command_hosts(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+        )?;
+        if let Some(rate) = &self.spawn_rate {
+            rate.check()?;
+        }
+        check_limits(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+            self.targets.has_remote(),
+        )?;
+        check_nice(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+            self.targets.has_remote(),
+        )?;
+        check_umask(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+            self.targets.has_remote(),
+        )?;
+        check_identities(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+            self.targets.has_remote(),
+        )?;
+        check_command_names(&self.before)?;
+        check_command_names(&self.commands)?;
+        check_command_names(&self.after)
+    }
+
+    fn check_environment(&self) -> Result<(), ReadErrorType> {
+        self.targets.check_environment()?;
+        if self.targets.has_local() {
+            check_shell(&self.shell)?;
+        }
+        let commands =
+            || self.before.iter().chain(&self.commands).chain(&self.after);
+        check_command_shells(commands(), &self.hosts)?;
+        check_limits_enforceable(commands())?;
+        check_identities_permitted(commands())
+    }
+
+    fn missing_paths(&self) -> Vec<ReadErrorType> {
+        let commands =
+            self.before.iter().chain(&self.commands).chain(&self.after);
+        let local = self.targets.has_local();
+        let mut missing = Vec::new();
+        if let (true, Some(base_dir)) = (local, &self.base_dir) {
+            if !expand_home(base_dir).is_dir() {
+                missing.push(ReadErrorType::MissingPath(
+                    "base_dir",
+                    base_dir.clone(),
+                ));
+            }
+        }
+        missing.extend(missing_command_paths(
+            commands,
+            self.base_dir.as_deref(),
+            &self.targets,
+        ));
+        missing
     }
 }
 
 #[async_trait]
 impl Task for CronTask {
-    // TODO
-    async fn check_dependencies(self: Arc<Self>) -> bool {
-        unimplemented!("Need to write services first!")
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> TaskKind {
+        TaskKind::Cron
+    }
+
+    async fn check_dependencies(self: Arc<Self>) -> DependencyReport {
+        check_dependencies(&self.name, &self.dependencies, &self.services).await
     }
 
     async fn run(self: Arc<Self>) -> Result<(), Vec<CommandRunError>> {
-        info!(?self.id, %self.name, "Task triggered");
-        let handle_iter = self.commands.iter().cloned().map(|cmd| match &self
-            .host
-        {
-            Host::Local => tokio::spawn(cmd.run_local()),
-            Host::Remote(addr) => tokio::spawn(cmd.run_remote(addr.clone())),
-        });
+        let ctx = RunContext::new(
+            &self.name,
+            self.dry_run,
+            None,
+            &self.shell,
+            self.base_dir.as_deref(),
+            &self.targets,
+            self.spawn_limit.limiter(self.spawn_rate),
+            self.cancel.child_token(),
+            &self.executor,
+        );
+        self.run_with(ctx).await
+    }
+}
+
+/// Builds a [`CronTask`] programmatically, without needing a task file
+///
+/// See the [task file reference](crate#task-files) for what each option does
+#[derive(Debug)]
+pub struct CronTaskBuilder {
+    name: String,
+    schedule: String,
+    repeat: bool,
+    run_on_activate: bool,
+    jitter: Option<Duration>,
+    jitter_seed: Option<u64>,
+    cooldown: Option<Duration>,
+    max_consecutive_failures: Option<NonZeroU32>,
+    hosts: Hosts,
+    require_host_reachable: Option<UnreachablePolicy>,
+    shell: String,
+    base_dir: Option<Utf8PathBuf>,
+    dry_run: bool,
+    log_level: Option<Level>,
+    max_concurrent: Option<NonZeroUsize>,
+    spawn_rate: Option<SpawnRate>,
+    task_timeout: Option<Duration>,
+    lock_file: Option<Utf8PathBuf>,
+    depends_on: Vec<String>,
+    dependencies: Vec<Dependency>,
+    before: Vec<CommandBuilder>,
+    commands: Vec<CommandBuilder>,
+    after: Vec<CommandBuilder>,
+}
+
+impl CronTaskBuilder {
+    /// Starts building a task called `name`, run according to the cron
+    /// `schedule`
+    pub fn new(name: impl Into<String>, schedule: impl Into<String>) -> Self {
+        CronTaskBuilder {
+            name: name.into(),
+            schedule: schedule.into(),
+            repeat: default_repeat(),
+            run_on_activate: false,
+            jitter: None,
+            jitter_seed: None,
+            cooldown: None,
+            max_consecutive_failures: None,
+            hosts: Hosts::default(),
+            require_host_reachable: None,
+            shell: default_shell(),
+            base_dir: None,
+            dry_run: false,
+            log_level: None,
+            max_concurrent: None,
+            spawn_rate: None,
+            task_timeout: None,
+            lock_file: None,
+            depends_on: Vec::new(),
+            dependencies: Vec::new(),
+            before: Vec::new(),
+            commands: Vec::new(),
+            after: Vec::new(),
+        }
+    }
+
+    /// Sets whether the task keeps running on its schedule, or only runs once
+    pub fn repeat(mut self, repeat: bool) -> Self {
+        self.repeat = repeat;
+        self
+    }
+
+    /// Sets whether the task is run once straight away when activated, as
+    /// well as on its schedule
+    pub fn run_on_activate(mut self, run_on_activate: bool) -> Self {
+        self.run_on_activate = run_on_activate;
+        self
+    }
+
+    /// Delays each run by a random amount less than `jitter`
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    /// Seeds the random delays used for jitter, making them reproducible
+    pub fn jitter_seed(mut self, seed: u64) -> Self {
+        self.jitter_seed = Some(seed);
+        self
+    }
+
+    /// Skips scheduled runs until `cooldown` after the last one finished
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = Some(cooldown);
+        self
+    }
+
+    /// Deactivates the task once `failures` scheduled runs in a row have
+    /// failed
+    pub fn max_consecutive_failures(mut self, failures: NonZeroU32) -> Self {
+        self.max_consecutive_failures = Some(failures);
+        self
+    }
+
+    /// Sets the host the task's commands are run on, replacing any others
+    pub fn host(mut self, host: impl AsRef<str>) -> Self {
+        self.hosts = Hosts::from_iter([host]);
+        self
+    }
+
+    /// Sets the hosts the task's commands are run on, each running the whole
+    /// batch in parallel with the others
+    pub fn hosts<S: AsRef<str>>(
+        mut self,
+        hosts: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.hosts = Hosts::from_iter(hosts);
+        self
+    }
+
+    /// Runs the task's commands in the container `name` on the local Docker
+    /// instance, replacing any hosts
+    pub fn container(mut self, name: impl Into<String>) -> Self {
+        self.hosts = Hosts::container(name.into());
+        self
+    }
+
+    /// Checks the task's hosts can be reached when it's activated, doing
+    /// what `policy` says if they can't
+    pub fn require_host_reachable(mut self, policy: UnreachablePolicy) -> Self {
+        self.require_host_reachable = Some(policy);
+        self
+    }
+
+    /// Sets the shell used for shell invocations, `sh` (`cmd` on Windows) by
+    /// default
+    pub fn shell(mut self, shell: impl Into<String>) -> Self {
+        self.shell = shell.into();
+        self
+    }
+
+    /// Sets the directory relative command working directories are joined
+    /// onto
+    pub fn base_dir(mut self, dir: impl Into<Utf8PathBuf>) -> Self {
+        self.base_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets whether the task only logs what it would execute
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets the level the task's own logs are emitted up to, when using a
+    /// `TaskLevelFilter`
+    pub fn log_level(mut self, level: Level) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Limits how many of the task's commands may run at once
+    pub fn max_concurrent(mut self, max: NonZeroUsize) -> Self {
+        self.max_concurrent = Some(max);
+        self
+    }
 
-        let results = future::join_all(handle_iter).await;
-        trace!(?self.id, %self.name, "Processing task command results");
-        let errors = results
-            .into_iter()
-            .filter_map(|nested_result| match nested_result {
-                Ok(Ok(())) => None,
-                Ok(Err(cre)) => Some(cre),
-                Err(join_err) => Some(CommandRunError {
-                    name: self.name.clone(),
-                    r#type: CommandRunErrorType::Async(join_err),
-                }),
+    /// Limits how fast the task's commands start, to `per_second` on
+    /// average, with up to `burst` at once
+    pub fn spawn_rate(mut self, per_second: f64, burst: NonZeroU32) -> Self {
+        self.spawn_rate = Some(SpawnRate::new(per_second, burst));
+        self
+    }
+
+    /// Limits how long a whole run of the task may take
+    ///
+    /// Commands still running when it's up are killed, except those in
+    /// containers, which Docker can't kill, so are only stopped being
+    /// followed
+    pub fn task_timeout(mut self, limit: Duration) -> Self {
+        self.task_timeout = Some(limit);
+        self
+    }
+
+    /// Locks the file at `path` while the task is activated, so another
+    /// process can't activate it too
+    pub fn lock_file(mut self, path: impl Into<Utf8PathBuf>) -> Self {
+        self.lock_file = Some(path.into());
+        self
+    }
+
+    /// Adds a task that must succeed before each run of this one
+    ///
+    /// Only tasks in the same [`TaskRegistry`](crate::TaskRegistry) can be
+    /// waited for, see [Dependencies](crate#dependencies)
+    pub fn depends_on(mut self, task: impl Into<String>) -> Self {
+        self.depends_on.push(task.into());
+        self
+    }
+
+    /// Adds a dependency on the service called `service`, remediated
+    /// according to `remediate` if it isn't healthy before a run
+    ///
+    /// The service itself must be given to the built task with
+    /// [`CronTask::provide_service`]
+    pub fn dependency(
+        mut self,
+        service: impl Into<String>,
+        remediate: Remediate,
+    ) -> Self {
+        self.dependencies
+            .push(Dependency::new(service.into(), remediate));
+        self
+    }
+
+    /// Adds a command to run (in order) before the task's main commands
+    pub fn before(mut self, command: CommandBuilder) -> Self {
+        self.before.push(command);
+        self
+    }
+
+    /// Adds a command to the task
+    pub fn command(mut self, command: CommandBuilder) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Adds a command to run (in order) after the task's main commands,
+    /// whether or not they succeeded
+    pub fn after(mut self, command: CommandBuilder) -> Self {
+        self.after.push(command);
+        self
+    }
+
+    /// Builds the task, checking it in the same way as
+    /// [`CronTask::load_from`] would
+    pub fn build(self) -> Result<CronTask, BuildError> {
+        let finish = |commands| {
+            finish_commands(commands).map_err(|r#type| BuildError {
+                name: self.name.clone(),
+                r#type,
             })
-            .collect::<Vec<CommandRunError>>();
-        if errors.is_empty() {
-            info!(%self.name, "Task completed successfully");
-            Ok(())
-        } else {
-            warn!(%self.name, "Task completed with errors");
-            Err(errors)
+        };
+        let before = finish(self.before)?;
+        let commands = finish(self.commands)?;
+        let after = finish(self.after)?;
+        let targets = self
+            .hosts
+            .targets(before.iter().chain(&commands).chain(&after));
+        let task = CronTask {
+            name: self.name,
+            id: AtomicU64::default(),
+            depends_on: self.depends_on,
+            prerequisites: Prerequisites::default(),
+            dependencies: self.dependencies,
+            services: ServiceMap::default(),
+            schedule: self.schedule,
+            repeat: self.repeat,
+            run_on_activate: self.run_on_activate,
+            jitter: self.jitter,
+            jitter_seed: self.jitter_seed,
+            rng: AtomicU64::default(),
+            cooldown: self.cooldown,
+            last_finished: Mutex::default(),
+            max_consecutive_failures: self.max_consecutive_failures,
+            consecutive_failures: AtomicU32::default(),
+            tripped: AtomicBool::default(),
+            hosts: self.hosts,
+            targets,
+            require_host_reachable: self.require_host_reachable,
+            deferred: Mutex::default(),
+            shell: self.shell,
+            base_dir: self.base_dir,
+            dry_run: self.dry_run,
+            log_level: self.log_level,
+            max_concurrent: self.max_concurrent,
+            spawn_rate: self.spawn_rate,
+            task_timeout: self.task_timeout,
+            lock_file: self.lock_file,
+            lock: Mutex::default(),
+            limit: ConcurrencyLimit::default(),
+            spawn_limit: SpawnLimit::default(),
+            last_run: LastRunCell::default(),
+            cancel: CancellationToken::new(),
+            executor: TaskExecutor::default(),
+            defaults: CommandDefaults::default(),
+            before,
+            commands,
+            after,
+        };
+        task.validate()
+            .and_then(|()| task.check_environment())
+            .map_err(|r#type| BuildError {
+                name: task.name.clone(),
+                r#type,
+            })?;
+        Ok(task)
+    }
+}
+
+fn default_repeat() -> bool {
+    true
+}
+
+/// The next `count` times the cron schedule `expr` fires after `from`, in
+/// `from`'s timezone, for checking a schedule does what's expected
+///
+/// `expr` is parsed as a task's `schedule` is (see [Schedules](crate#schedules-cron-tasks)).
+/// Fewer than `count` times are returned if the schedule stops firing (e.g.
+/// it names a year that's reached), and none if it never fires again.
+/// Doesn't account for jitter or cooldowns
+pub fn schedule_preview<Z: TimeZone>(
+    expr: &str,
+    from: &DateTime<Z>,
+    count: usize,
+) -> Result<Vec<DateTime<Z>>, ScheduleError> {
+    Ok(parse_schedule(expr)?.after(from).take(count).collect())
+}
+
+fn parse_schedule(expr: &str) -> Result<cron_clock::Schedule, ScheduleError> {
+    cron_clock::Schedule::from_str(expr).map_err(|why| ScheduleError {
+        schedule: expr.to_owned(),
+        why: why.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CronScheduler, MockExecutor};
+
+    #[test]
+    fn from_yaml_str_still_validates() {
+        let task = CronTask::from_yaml_str(
+            "name: elsewhere
+schedule: '@daily'
+shell: /nonexistent/overseer/sh
+commands:
+  - run: 'true'
+    shell: true",
+        )
+        .unwrap();
+        assert!(matches!(
+            task.check_environment(),
+            Err(ReadErrorType::ShellNotFound(_)),
+        ));
+        let err = CronTask::from_yaml_str(
+            "name: nothing
+schedule: '@daily'
+commands: []",
+        )
+        .unwrap_err();
+        assert!(matches!(err.r#type, ReadErrorType::NoCommands), "{err}");
+    }
+
+    #[test]
+    fn schedule_preview_lists_fire_times() {
+        let at = |time: &str| DateTime::parse_from_rfc3339(time).unwrap();
+        let from = at("2026-10-14T12:00:00Z");
+        let times = schedule_preview("0 30 2 * * *", &from, 3).unwrap();
+        let expected = ["15", "16", "17"]
+            .map(|day| at(&format!("2026-10-{day}T02:30:00Z")));
+        assert_eq!(times, expected);
+        // Stops when the schedule does
+        let times = schedule_preview("0 0 0 15 10 * 2026", &from, 5).unwrap();
+        assert_eq!(times, [at("2026-10-15T00:00:00Z")]);
+        assert!(schedule_preview("0 0 0 1 1 * 2020", &from, 5)
+            .unwrap()
+            .is_empty());
+        let err = schedule_preview("every day", &from, 1).unwrap_err();
+        assert!(err.to_string().contains(r#""every day""#), "{err}");
+    }
+
+    #[test]
+    fn serialized_task_loads_the_same() {
+        let task = CronTask::from_yaml_str(
+            "name: round trip
+schedule: '0 30 2 * * *'
+jitter: 5m
+host: [localhost, deploy@web1]
+log_level: debug
+defaults:
+  working_dir: /srv
+  env_vars: [STAGE=prod]
+before:
+  - run: mkdir -p /srv/backups
+commands:
+  - name: dump
+    run: pg_dump --file backup.sql app
+    env_vars:
+      - PGHOST=db
+    success_codes: [0, 1]
+    timeout: 10m
+  - run: grep -c error log.txt | tee count.txt
+    shell: true
+    continue_on_error: true
+after:
+  - run: rm -f backup.sql
+    host: localhost",
+        )
+        .unwrap();
+        let yaml = serde_yaml::to_string(&task).unwrap();
+        let reloaded = CronTask::from_yaml_str(&yaml).unwrap();
+        assert_eq!(serde_yaml::to_string(&reloaded).unwrap(), yaml);
+        assert_eq!(reloaded.name, "round trip");
+        assert_eq!(reloaded.commands[0].name, "dump");
+        for form in
+            ["pg_dump --file backup.sql app", "PGHOST=db", "deploy@web1"]
+        {
+            assert!(yaml.contains(form), "{form} not in {yaml}");
         }
     }
+
+    /// Runs `task` and counts it towards `max_consecutive_failures`, as a
+    /// scheduled run does
+    async fn scheduled_run(task: &Arc<CronTask>, scheduler: &CronScheduler) {
+        let result = task.clone().run().await;
+        task.count_run(result.is_ok(), scheduler.delay_timer())
+            .await;
+    }
+
+    /// A task failing every run, which can fail `max` times in a row
+    fn failing_task(max: u32) -> Arc<CronTask> {
+        let executor = Arc::new(MockExecutor::new().exit_code("flaky", 1));
+        let task = CronTask::from_yaml_str(&format!(
+            "name: flaky
+schedule: '@yearly'
+max_consecutive_failures: {max}
+commands:
+  - name: flaky
+    run: 'true'"
+        ))
+        .unwrap()
+        .with_executor(executor);
+        Arc::new(task)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn consecutive_failures_deactivate_task() {
+        let scheduler =
+            CronScheduler::new(DelayTimerBuilder::default().build());
+        let task = failing_task(3);
+        scheduler.activate(&task).await.unwrap();
+        for _ in 0..2 {
+            scheduled_run(&task, &scheduler).await;
+        }
+        assert!(!task.tripped());
+        assert_eq!(scheduler.scheduled().len(), 1);
+        scheduled_run(&task, &scheduler).await;
+        assert!(task.tripped());
+        assert!(scheduler.scheduled().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn success_resets_consecutive_failures() {
+        let scheduler =
+            CronScheduler::new(DelayTimerBuilder::default().build());
+        let task = failing_task(2);
+        scheduler.activate(&task).await.unwrap();
+        scheduled_run(&task, &scheduler).await;
+        task.count_run(true, scheduler.delay_timer()).await;
+        scheduled_run(&task, &scheduler).await;
+        assert!(!task.tripped());
+        scheduled_run(&task, &scheduler).await;
+        assert!(task.tripped());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn activating_again_clears_tripped() {
+        let scheduler =
+            CronScheduler::new(DelayTimerBuilder::default().build());
+        let task = failing_task(1);
+        scheduler.activate(&task).await.unwrap();
+        scheduled_run(&task, &scheduler).await;
+        assert!(task.tripped());
+        let id = scheduler.activate(&task).await.unwrap();
+        assert!(!task.tripped());
+        assert_eq!(scheduler.scheduled()[0].id, id);
+        // The count starts again too
+        let task = failing_task(2);
+        scheduler.activate(&task).await.unwrap();
+        scheduled_run(&task, &scheduler).await;
+        scheduler.activate(&task).await.unwrap();
+        scheduled_run(&task, &scheduler).await;
+        assert!(!task.tripped());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn skipping_unreachable_hosts_fails_activation() {
+        let control = crate::tests::scratch_dir("activate-unreachable");
+        let task = Arc::new(
+            CronTask::from_yaml_str(&format!(
+                "name: unreachable
+schedule: '@daily'
+require_host_reachable: skip
+host:
+  - address: overseer.invalid
+    ssh:
+      connect_timeout: 2s
+      control_directory: '{control}'
+commands:
+  - run: 'true'"
+            ))
+            .unwrap(),
+        );
+        let scheduler =
+            crate::CronScheduler::new(DelayTimerBuilder::default().build());
+        let err = scheduler.activate(&task).await.unwrap_err();
+        assert!(
+            matches!(&err.r#type, ActivateErrorType::Unreachable(hosts) if hosts[0].0 == "overseer.invalid"),
+            "{err}",
+        );
+        assert!(scheduler.scheduled().is_empty());
+    }
 }
@@ -0,0 +1,265 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use service::registry::ServiceRegistry;
+use service::{DynService, ServiceStatus};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+use tracing::{error, info, warn};
+
+/// A service a task depends on, checked before each run
+///
+/// In a task file, either just the service's name, or a map with `service`
+/// and `remediate` keys
+#[derive(Debug, Clone)]
+pub(crate) struct Dependency {
+    service: String,
+    remediate: Remediate,
+}
+
+impl Dependency {
+    pub(crate) fn new(service: String, remediate: Remediate) -> Self {
+        Dependency { service, remediate }
+    }
+}
+
+impl<'de> Deserialize<'de> for Dependency {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Name(String),
+            Full {
+                service: String,
+                #[serde(default)]
+                remediate: Remediate,
+            },
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Name(service) => Dependency::new(service, Remediate::None),
+            Repr::Full { service, remediate } => {
+                Dependency::new(service, remediate)
+            }
+        })
+    }
+}
+
+impl Serialize for Dependency {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        #[serde(untagged)]
+        enum Repr<'a> {
+            Name(&'a str),
+            Full {
+                service: &'a str,
+                remediate: Remediate,
+            },
+        }
+
+        match self.remediate {
+            Remediate::None => Repr::Name(&self.service),
+            remediate => Repr::Full {
+                service: &self.service,
+                remediate,
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+/// What a task does when a dependency isn't healthy before it runs
+#[derive(
+    Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Remediate {
+    /// Give up, skipping the run
+    #[default]
+    None,
+    /// Try starting the service once, then check again
+    Start,
+    /// Try restarting the service once, then check again
+    Restart,
+}
+
+/// The services provided to a task for its dependencies, by name
+#[derive(Default)]
+pub(crate) struct ServiceMap(Mutex<HashMap<String, Arc<dyn DynService>>>);
+
+impl ServiceMap {
+    pub(crate) fn insert(&self, name: String, service: Arc<dyn DynService>) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name, service);
+    }
+
+    /// Inserts the service of each of `dependencies` from `services`
+    pub(crate) fn provide_from(
+        &self,
+        task: &str,
+        dependencies: &[Dependency],
+        services: &ServiceRegistry,
+    ) {
+        for dependency in dependencies {
+            let name = &dependency.service;
+            match services.get(name) {
+                Some(service) => self.insert(name.clone(), service),
+                None => {
+                    warn!(%task, service = %name, "Dependency isn't a known service")
+                }
+            }
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<Arc<dyn DynService>> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .cloned()
+    }
+}
+
+impl fmt::Debug for ServiceMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let map = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        f.debug_set().entries(map.keys()).finish()
+    }
+}
+
+/// The status of each of a task's dependencies, as checked before a run
+#[derive(Debug, Clone, Default)]
+pub struct DependencyReport {
+    /// Each dependency, in the order the task lists them
+    pub dependencies: Vec<DependencyStatus>,
+}
+
+impl DependencyReport {
+    /// Whether every dependency is healthy, so the task can run
+    pub fn all_healthy(&self) -> bool {
+        self.dependencies.iter().all(DependencyStatus::is_healthy)
+    }
+
+    /// The dependencies that aren't healthy
+    pub fn unhealthy(&self) -> impl Iterator<Item = &DependencyStatus> {
+        self.dependencies.iter().filter(|dep| !dep.is_healthy())
+    }
+}
+
+/// How one of a task's dependencies was found, after any remediation
+#[derive(Debug, Clone)]
+pub struct DependencyStatus {
+    /// The name of the service
+    pub service: String,
+    /// The service's status, or why it couldn't be found out (including if
+    /// the service wasn't provided)
+    pub status: Result<ServiceStatus, String>,
+}
+
+impl DependencyStatus {
+    /// Whether the dependency is healthy
+    pub fn is_healthy(&self) -> bool {
+        matches!(self.status, Ok(ServiceStatus::Healthy))
+    }
+}
+
+impl fmt::Display for DependencyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.status {
+            Ok(status) => write!(f, "{} ({status})", self.service),
+            Err(why) => write!(f, "{} ({why})", self.service),
+        }
+    }
+}
+
+/// Checks each dependency, remediating according to each one's policy
+pub(crate) async fn check_dependencies(
+    task: &str,
+    dependencies: &[Dependency],
+    services: &ServiceMap,
+) -> DependencyReport {
+    let mut report = DependencyReport::default();
+    for dependency in dependencies {
+        report.dependencies.push(DependencyStatus {
+            service: dependency.service.clone(),
+            status: check_dependency(task, dependency, services).await,
+        });
+    }
+    report
+}
+
+async fn check_dependency(
+    task: &str,
+    dependency: &Dependency,
+    services: &ServiceMap,
+) -> Result<ServiceStatus, String> {
+    let name = dependency.service.as_str();
+    let service = match services.get(name) {
+        Some(service) => service,
+        None => {
+            error!(%task, service = %name, "Dependency wasn't provided");
+            return Err(String::from("not provided"));
+        }
+    };
+    let status = match service.status().await {
+        Ok(ServiceStatus::Healthy) => return Ok(ServiceStatus::Healthy),
+        // Remediating would only interrupt it
+        Ok(ServiceStatus::Starting) => {
+            info!(%task, service = %name, "Dependency is still starting");
+            return Ok(ServiceStatus::Starting);
+        }
+        Ok(status) => Ok(status),
+        Err(why) => Err(why.to_string()),
+    };
+    let shown = match &status {
+        Ok(status) => status.to_string(),
+        Err(why) => why.clone(),
+    };
+    let attempt = match dependency.remediate {
+        Remediate::None => {
+            warn!(%task, service = %name, status = %shown, "Dependency isn't healthy");
+            return status;
+        }
+        Remediate::Start => {
+            info!(%task, service = %name, status = %shown, "Starting dependency");
+            service.start().await
+        }
+        Remediate::Restart => {
+            info!(%task, service = %name, status = %shown, "Restarting dependency");
+            service.restart().await
+        }
+    };
+    if let Err(why) = attempt {
+        error!(%task, service = %name, "Failed to remediate dependency: {why}");
+        return Err(format!("couldn't remediate: {why}"));
+    }
+    match service.status().await {
+        Ok(ServiceStatus::Healthy) => {
+            info!(%task, service = %name, "Dependency remediated");
+            Ok(ServiceStatus::Healthy)
+        }
+        Ok(status) => {
+            warn!(
+                %task,
+                service = %name,
+                %status,
+                "Dependency still isn't healthy after remediation",
+            );
+            Ok(status)
+        }
+        Err(why) => {
+            error!(
+                %task,
+                service = %name,
+                "Couldn't check dependency after remediation: {why}",
+            );
+            Err(why.to_string())
+        }
+    }
+}
@@ -1,9 +1,12 @@
 use camino::Utf8PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
+use crate::round_millis;
+
 /// Errors that occur while reading or parsing a task YAML file
 ///
-/// See [`CronTask::load_from`](crate::CronTask::load_from) for guidance on correct formatting
+/// See the [task file reference](crate#task-files) for guidance on correct formatting
 #[derive(Debug, Error)]
 #[error("failed to read {}: {r#type}", .path)]
 pub struct ReadError {
@@ -12,12 +15,188 @@ pub struct ReadError {
 }
 
 #[derive(Debug, Error)]
-#[error(transparent)]
 pub(crate) enum ReadErrorType {
+    #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error(transparent)]
     De(#[from] serde_yaml::Error),
+    #[error("more than one command is named {0:?}")]
+    DuplicateCommandName(String),
+    #[error("invalid command {0:?}: {1}")]
+    InvalidCommand(String, String),
+    #[error("expected a list of tasks, or a map of task names to tasks")]
+    NotTaskList,
+    #[error(
+        "couldn't tell if this is a cron task (with `schedule`) or a file \
+        task (with `triggers`)"
+    )]
+    AmbiguousTaskKind,
+    #[error("{0}: {1}")]
+    Entry(String, Box<ReadErrorType>),
+    #[error("a task named {0:?} was already loaded from {1}")]
+    DuplicateTaskName(String, Utf8PathBuf),
+    #[error("shell {0:?} could not be found")]
+    ShellNotFound(String),
+    #[error("task has no commands to run")]
+    NoCommands,
+    #[error("task has no triggers, so would never run")]
+    NoTriggers,
+    #[error("invalid schedule {0:?}: {1}")]
+    InvalidSchedule(String, String),
+    #[error("invalid active window {0:?}: {1}")]
+    InvalidWindow(String, String),
+    #[error("invalid spawn rate {0}, it must be above 0")]
+    InvalidSpawnRate(f64),
+    #[error("{0} {1} doesn't exist")]
+    MissingPath(&'static str, Utf8PathBuf),
+    #[error("task has no hosts to run on")]
+    NoHosts,
+    #[error("host {0:?} is given more than once")]
+    DuplicateHost(String),
+    #[error(
+        "host {0:?} is remote, but remote hosts are only supported on Unix"
+    )]
+    UnsupportedRemoteHost(String),
+    #[error("command {0:?} sets resource limits, but {1}")]
+    UnsupportedLimits(String, &'static str),
+    #[error("command {0:?} sets nice, but {1}")]
+    UnsupportedNice(String, &'static str),
+    #[error("command {0:?} sets umask, but {1}")]
+    UnsupportedUmask(String, &'static str),
+    #[error("command {0:?} can't run as its user/group: {1}")]
+    InvalidIdentity(String, String),
+    #[error("task depends on task {0:?}, which isn't loaded")]
+    UnknownPrerequisite(String),
+    #[error("tasks depend on each other in a cycle: {}", cycle(.0))]
+    PrerequisiteCycle(Vec<String>),
+}
+
+/// Shows a cycle of tasks, from the first task back around to it
+fn cycle(tasks: &[String]) -> String {
+    let mut shown = tasks.join(" -> ");
+    if let Some(first) = tasks.first() {
+        shown.push_str(" -> ");
+        shown.push_str(first);
+    }
+    shown
+}
+
+/// Lists hosts that can't be reached, with why
+fn unreachable_list(hosts: &[(String, String)]) -> String {
+    hosts
+        .iter()
+        .map(|(host, why)| format!("{host} ({why})"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Errors that occur when a task built programmatically is invalid
+///
+/// Returned by [`CronTaskBuilder::build`](crate::CronTaskBuilder::build) and
+/// [`FileEventTaskBuilder::build`](crate::FileEventTaskBuilder::build)
+#[derive(Debug, Error)]
+#[error("failed to build {name}: {r#type}")]
+pub struct BuildError {
+    pub(crate) name: String,
+    pub(crate) r#type: ReadErrorType,
+}
+
+/// Errors that occur when a [`FileEventTask`](crate::FileEventTask) can't
+/// start watching for activity
+///
+/// Returned by [`FileEventTask::activate`](crate::FileEventTask::activate)
+#[derive(Debug, Error)]
+#[error("failed to activate {name}: {r#type}")]
+pub struct WatchError {
+    pub(crate) name: String,
+    pub(crate) r#type: WatchErrorType,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum WatchErrorType {
+    #[error("couldn't create watcher: {0}")]
+    Watcher(#[from] notify::Error),
+    #[error("none of the {} paths could be watched", .0.len())]
+    NoPathsWatched(Vec<(Utf8PathBuf, notify::Error)>),
+    #[error(transparent)]
+    Invalid(#[from] ReadErrorType),
+    #[error(transparent)]
+    Lock(#[from] LockError),
+    #[error("hosts can't be reached: {}", unreachable_list(.0))]
+    Unreachable(Vec<(String, String)>),
+}
+
+/// Errors that occur when a [`CronTask`](crate::CronTask) can't be
+/// scheduled
+///
+/// Returned by [`CronTask::activate`](crate::CronTask::activate) and
+/// [`CronScheduler::activate`](crate::CronScheduler::activate)
+#[derive(Debug, Error)]
+#[error("failed to activate {name}: {r#type}")]
+pub struct ActivateError {
+    pub(crate) name: String,
+    pub(crate) r#type: ActivateErrorType,
 }
 
+#[derive(Debug, Error)]
+pub(crate) enum ActivateErrorType {
+    #[error("couldn't schedule task: {0}")]
+    Schedule(#[from] delay_timer::prelude::TaskError),
+    #[error(transparent)]
+    Invalid(#[from] ReadErrorType),
+    #[error(transparent)]
+    Lock(#[from] LockError),
+    #[error("hosts can't be reached: {}", unreachable_list(.0))]
+    Unreachable(Vec<(String, String)>),
+}
+
+/// Returned by [`schedule_preview`](crate::schedule_preview) if the schedule
+/// couldn't be parsed
+#[derive(Debug, Error)]
+#[error("invalid schedule {schedule:?}: {why}")]
+pub struct ScheduleError {
+    pub(crate) schedule: String,
+    pub(crate) why: String,
+}
+
+/// Returned by [`LockFile::acquire`](crate::LockFile::acquire) if the file
+/// couldn't be locked
+#[derive(Debug, Error)]
+#[error("couldn't lock {path}: {r#type}")]
+pub struct LockError {
+    pub(crate) path: Utf8PathBuf,
+    pub(crate) r#type: LockErrorType,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum LockErrorType {
+    #[error("it's held by another process{}", match .0 {
+        Some(pid) => format!(" (last locked by PID {pid})"),
+        None => String::new(),
+    })]
+    Held(Option<u32>),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Returned by [`install_metrics`](crate::install_metrics) if a sink has
+/// already been installed
+#[derive(Debug, Error)]
+#[error("a metrics sink is already installed")]
+pub struct MetricsInstallError;
+
+/// Returned by [`install_secret_provider`](crate::install_secret_provider)
+/// if a provider has already been installed
+#[derive(Debug, Error)]
+#[error("a secret provider is already installed")]
+pub struct SecretProviderInstallError;
+
+/// Returned by [`set_global_concurrency`](crate::set_global_concurrency) if
+/// the limit has already been set
+#[derive(Debug, Error)]
+#[error("the global concurrency limit is already set")]
+pub struct GlobalConcurrencyError;
+
 /// Errors that occur when attempting to execute a command
 ///
 /// Returned by [`CronTask::run`](crate::CronTask::run)
@@ -28,14 +207,120 @@ pub struct CommandRunError {
     pub(crate) r#type: CommandRunErrorType,
 }
 
+impl CommandRunError {
+    /// Whether the command never ran because its remote host couldn't be
+    /// reached, as opposed to it running and failing
+    pub fn is_connect_error(&self) -> bool {
+        matches!(self.r#type, CommandRunErrorType::Connect(..))
+    }
+
+    /// How long the command ran for, if it was run and exited (or was
+    /// killed by a signal)
+    pub fn elapsed(&self) -> Option<Duration> {
+        use CommandRunErrorType::*;
+        match self.r#type {
+            ExitStatus(.., elapsed)
+            | Killed(elapsed)
+            | UnexpectedSuccess(_, elapsed) => elapsed,
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub(crate) enum CommandRunErrorType {
     #[error("future panicked: {0}")]
     Async(#[from] tokio::task::JoinError),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
-    #[error("command completed with non-zero status {0}")]
-    ExitStatus(i32),
-    #[error(transparent)]
-    Ssh(#[from] openssh::Error),
+    #[error(
+        "command completed with non-zero status {0}{}{}",
+        elapsed_suffix(*.3),
+        stderr_suffix(.1.as_deref(), *.2)
+    )]
+    ExitStatus(i32, Option<String>, bool, Option<Duration>),
+    #[error("command was killed by a signal{}", elapsed_suffix(*.0))]
+    Killed(Option<Duration>),
+    #[error(
+        "command succeeded with exit code {0}{}, but was expected to fail",
+        elapsed_suffix(*.1)
+    )]
+    UnexpectedSuccess(i32, Option<Duration>),
+    #[error("couldn't substitute placeholders: {0}")]
+    Template(String),
+    #[error("couldn't load env file {0}: {1}")]
+    EnvFile(Utf8PathBuf, String),
+    #[error(
+        "program {program:?} not found{}",
+        searched_suffix(.searched_path.as_deref())
+    )]
+    ProgramNotFound {
+        program: String,
+        /// The `PATH` the program was looked for on, if it was and it's known
+        searched_path: Option<String>,
+    },
+    #[error("couldn't connect to {0}: {1}")]
+    Connect(String, String),
+    #[error("couldn't run as the given user/group: {0}")]
+    Identity(String),
+    #[error("cancelled before completion")]
+    Cancelled,
+    #[error("timed out after {0:?}, so was killed")]
+    Timeout(Duration),
+    #[error("couldn't resolve secret for {0}: {1}")]
+    Secret(String, String),
+    #[error("dependencies aren't healthy: {}", .0.join(", "))]
+    Dependencies(Vec<String>),
+    #[error(
+        "task timed out after {0:?}, cancelling {}",
+        still_running(.1)
+    )]
+    TaskTimeout(Duration, Vec<String>),
+    #[error("{0}")]
+    Ssh(String),
+    #[error("couldn't run in container: {0}")]
+    Exec(String),
+    #[error("couldn't run in container: Docker didn't attach to the exec")]
+    ExecDetached,
+    #[error("prerequisite task {0:?} isn't loaded")]
+    Prerequisite(String),
+    #[error("another run is already waiting for prerequisite tasks")]
+    AlreadyWaiting,
+}
+
+/// Lists the commands a timed out task was still running
+fn still_running(commands: &[String]) -> String {
+    match commands.is_empty() {
+        true => String::from("nothing (it was between commands)"),
+        false => commands.join(", "),
+    }
+}
+
+/// Formats the `PATH` a program was looked for on for the end of an error
+/// message, if it's known
+fn searched_suffix(path: Option<&str>) -> String {
+    match path {
+        Some(path) => format!(" (searched PATH {path:?})"),
+        None => String::new(),
+    }
+}
+
+/// Formats how long a command ran for, to the millisecond, if it's known
+fn elapsed_suffix(elapsed: Option<Duration>) -> String {
+    match elapsed {
+        Some(elapsed) => format!(" after {:?}", round_millis(elapsed)),
+        None => String::new(),
+    }
+}
+
+/// Formats captured stderr for the end of an error message, if there is any,
+/// saying if it's only the end of it
+fn stderr_suffix(stderr: Option<&str>, truncated: bool) -> String {
+    match (stderr.map(str::trim_end), truncated) {
+        (Some(stderr), false) if !stderr.is_empty() => {
+            format!(", stderr:\n{stderr}")
+        }
+        (Some(stderr), true) => format!(", stderr (truncated):\n{stderr}"),
+        _ => String::new(),
+    }
 }
@@ -0,0 +1,244 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::{CommandOutcome, CommandRunError, RunContext, TaskCommand};
+
+/// Executes a task's commands
+///
+/// Tasks use [`SystemExecutor`] unless given another, e.g. a
+/// [`MockExecutor`] so a task's orchestration can be tested without
+/// spawning processes.
+/// Commands are executed with the host they're run on (`None` for local
+/// commands, and `container:[name]` for those run in a container) and the
+/// context of the task run they're part of
+#[async_trait]
+pub trait Executor: fmt::Debug + Send + Sync {
+    /// Executes `command`, returning how it finished if it succeeded
+    async fn execute(
+        &self,
+        command: Arc<TaskCommand>,
+        host: Option<&str>,
+        ctx: Arc<RunContext>,
+    ) -> Result<CommandOutcome, CommandRunError>;
+}
+
+/// Executes commands for real, as local processes, over SSH, or in Docker
+/// containers
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemExecutor;
+
+#[async_trait]
+impl Executor for SystemExecutor {
+    async fn execute(
+        &self,
+        command: Arc<TaskCommand>,
+        host: Option<&str>,
+        ctx: Arc<RunContext>,
+    ) -> Result<CommandOutcome, CommandRunError> {
+        match host.map(|host| (host, ctx.container(host))) {
+            None => command.run_local(ctx).await,
+            Some((_, Some(container))) => {
+                let container = container.to_owned();
+                command.run_in_container(&container, ctx).await
+            }
+            Some((addr, None)) => command.run_remote(addr, ctx).await,
+        }
+    }
+}
+
+/// Pretends to execute commands, recording each one instead
+///
+/// Every command exits with `0` unless given another exit code with
+/// [`MockExecutor::exit_code`].
+/// Exit codes are judged as a real run would, going by each command's
+/// `success_codes` and `expect_failure`.
+/// Dry runs are recorded too, and always succeed
+#[derive(Debug, Default)]
+pub struct MockExecutor {
+    exit_codes: HashMap<String, i32>,
+    executed: Mutex<Vec<(String, String)>>,
+}
+
+impl MockExecutor {
+    /// Creates an executor where every command succeeds
+    pub fn new() -> Self {
+        MockExecutor::default()
+    }
+
+    /// Makes the command named `command` exit with `code`
+    pub fn exit_code(mut self, command: impl Into<String>, code: i32) -> Self {
+        self.exit_codes.insert(command.into(), code);
+        self
+    }
+
+    /// The name of each command executed so far, in order, with the host it
+    /// was executed on (`localhost` for local commands)
+    pub fn executed(&self) -> Vec<(String, String)> {
+        self.executed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+#[async_trait]
+impl Executor for MockExecutor {
+    async fn execute(
+        &self,
+        command: Arc<TaskCommand>,
+        host: Option<&str>,
+        ctx: Arc<RunContext>,
+    ) -> Result<CommandOutcome, CommandRunError> {
+        let host = host.unwrap_or("localhost").to_owned();
+        self.executed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((command.name().to_owned(), host));
+        if ctx.dry_run() {
+            return Ok(command.dry_run_outcome());
+        }
+        let code = self.exit_codes.get(command.name()).copied().unwrap_or(0);
+        command.check_exit(Some(code), None, None)
+    }
+}
+
+/// The executor a task runs its commands with
+#[derive(Debug, Clone)]
+pub(crate) struct TaskExecutor(pub(crate) Arc<dyn Executor>);
+
+impl Default for TaskExecutor {
+    fn default() -> Self {
+        TaskExecutor(Arc::new(SystemExecutor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::CommandRunErrorType;
+    use crate::{CronTask, Task};
+
+    /// Runs `yaml` as a cron task with `executor`, returning its failures
+    async fn run(
+        yaml: &str,
+        executor: &Arc<MockExecutor>,
+    ) -> Vec<CommandRunError> {
+        let task = CronTask::from_yaml_str(yaml)
+            .unwrap()
+            .with_executor(executor.clone());
+        Arc::new(task).run().await.err().unwrap_or_default()
+    }
+
+    /// The exit codes of the commands in `errors` that failed with one
+    fn failed_codes(errors: &[CommandRunError]) -> Vec<(&str, i32)> {
+        errors
+            .iter()
+            .filter_map(|err| match err.r#type {
+                CommandRunErrorType::ExitStatus(code, ..) => {
+                    Some((err.name.as_str(), code))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn success_codes_accept_listed_exit_codes() {
+        let executor = Arc::new(MockExecutor::new().exit_code("grep", 1));
+        let errors = run(
+            "name: codes
+schedule: '@daily'
+commands:
+  - name: grep
+    run: grep foo bar
+    success_codes: [0, 1]",
+            &executor,
+        )
+        .await;
+        assert!(errors.is_empty(), "{errors:?}");
+    }
+
+    #[tokio::test]
+    async fn success_codes_reject_unlisted_exit_codes() {
+        let executor = Arc::new(
+            MockExecutor::new()
+                .exit_code("grep", 2)
+                .exit_code("plain", 1),
+        );
+        let errors = run(
+            "name: codes
+schedule: '@daily'
+commands:
+  - name: grep
+    run: grep foo bar
+    success_codes: [0, 1]
+  - name: plain
+    run: 'true'",
+            &executor,
+        )
+        .await;
+        let mut failed = failed_codes(&errors);
+        failed.sort();
+        assert_eq!(failed, [("grep", 2), ("plain", 1)]);
+    }
+
+    const PHASES: &str = "name: phases
+schedule: '@daily'
+before:
+  - name: prepare
+    run: 'true'
+  - name: check
+    run: 'true'
+commands:
+  - name: main
+    run: 'true'
+after:
+  - name: clean up
+    run: 'true'
+  - name: report
+    run: 'true'";
+
+    /// The names of the commands `executor` executed, in order
+    fn executed_names(executor: &MockExecutor) -> Vec<String> {
+        executor
+            .executed()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn before_runs_before_commands_and_after() {
+        let executor = Arc::new(MockExecutor::new());
+        let errors = run(PHASES, &executor).await;
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(
+            executed_names(&executor),
+            ["prepare", "check", "main", "clean up", "report"],
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_before_skips_commands_but_not_after() {
+        let executor = Arc::new(MockExecutor::new().exit_code("prepare", 1));
+        let errors = run(PHASES, &executor).await;
+        assert_eq!(failed_codes(&errors), [("prepare", 1)]);
+        assert_eq!(
+            executed_names(&executor),
+            ["prepare", "clean up", "report"]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_commands_still_run_after() {
+        let executor = Arc::new(MockExecutor::new().exit_code("main", 1));
+        let errors = run(PHASES, &executor).await;
+        assert_eq!(failed_codes(&errors), [("main", 1)]);
+        assert_eq!(
+            executed_names(&executor),
+            ["prepare", "check", "main", "clean up", "report"],
+        );
+    }
+}
@@ -1,39 +1,206 @@
+use crate::error::{BuildError, WatchError, WatchErrorType};
+use crate::log_level::{deserialize_log_level, serialize_log_level};
 use crate::{
-    CommandRunError, CommandRunErrorType, Commands, Host, ReadError, Task,
+    check_command_hosts, check_command_names, check_command_shells,
+    check_dependencies, check_identities, check_identities_permitted,
+    check_limits, check_limits_enforceable, check_nice, check_shell,
+    check_umask, default_shell, deserialize_commands, finish_commands,
+    missing_command_paths, shutting_down, CommandBuilder, CommandDefaults,
+    CommandRunError, Commands, ConcurrencyLimit, Dependency, DependencyReport,
+    Executor, Hosts, LastRunCell, LockFile, OutputLine, Prerequisites,
+    ReadError, ReadErrorType, Remediate, RunContext, ServiceMap, SpawnLimit,
+    SpawnRate, Successes, Task, TaskExecutor, TaskKind, TaskRun, TaskRunReport,
+    TriggerEvent, UnreachablePolicy, Validate,
 };
 use async_trait::async_trait;
 use camino::{Utf8Path, Utf8PathBuf};
-use futures::future;
+use delay_timer::prelude::cron_clock::Schedule;
+use delay_timer::prelude::Local;
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
-use serde::Deserialize;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use service::path::{
+    deserialize_expanded_opt, deserialize_expanded_vec, deserialize_vars_opt,
+    expand_home,
+};
+use service::registry::ServiceRegistry;
+use service::DynService;
+use std::collections::{HashMap, HashSet};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::io::AsyncRead;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::task::JoinHandle;
-use tracing::{error, info, trace, warn};
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, instrument, trace, warn, Level};
 
 /// A task that runs based on filesystem activity
 ///
 /// Watches files, folders, or a combination thereof, and triggers on any
 /// activity (except accesses)
-#[derive(Debug, Deserialize)]
+///
+/// Serialises to an equivalent task file, with every option (and any
+/// `defaults`) spelled out
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct FileEventTask {
     name: String,
-    #[allow(dead_code)]
     #[serde(default)]
-    dependencies: Vec<()>, // TODO: populate with services
-    #[serde(rename = "triggers")]
+    depends_on: Vec<String>,
+    #[serde(skip)]
+    prerequisites: Prerequisites,
+    #[serde(default)]
+    dependencies: Vec<Dependency>,
+    #[serde(skip)]
+    services: ServiceMap,
+    #[serde(
+        rename = "triggers",
+        deserialize_with = "deserialize_expanded_vec"
+    )]
     watch_paths: Vec<Utf8PathBuf>,
-    #[allow(dead_code)]
     #[serde(default)]
-    host: Host,
+    allow_missing: bool,
+    #[serde(default)]
+    max_depth: usize,
+    #[serde(default)]
+    run_on_activate: bool,
+    #[serde(default)]
+    process_existing: bool,
+    #[serde(default)]
+    active_window: Option<ActiveWindows>,
+    #[serde(default, with = "humantime_serde")]
+    min_interval: Option<Duration>,
+    #[serde(default = "default_event_capacity")]
+    event_capacity: NonZeroUsize,
+    #[serde(default, rename = "host")]
+    hosts: Hosts,
+    /// Every host the commands run on, including their own hosts
+    #[serde(skip)]
+    targets: Hosts,
+    #[serde(default)]
+    require_host_reachable: Option<UnreachablePolicy>,
+    #[serde(default = "default_shell")]
+    shell: String,
+    #[serde(default, deserialize_with = "deserialize_vars_opt")]
+    base_dir: Option<Utf8PathBuf>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_log_level",
+        serialize_with = "serialize_log_level"
+    )]
+    log_level: Option<Level>,
+    #[serde(default)]
+    max_concurrent: Option<NonZeroUsize>,
+    #[serde(default)]
+    spawn_rate: Option<SpawnRate>,
+    #[serde(default, with = "humantime_serde")]
+    task_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_expanded_opt")]
+    lock_file: Option<Utf8PathBuf>,
+    #[serde(skip)]
+    limit: ConcurrencyLimit,
+    #[serde(skip)]
+    spawn_limit: SpawnLimit,
+    #[serde(skip)]
+    last_run: LastRunCell,
+    #[serde(skip)]
+    cancel: CancellationToken,
+    #[serde(skip)]
+    executor: TaskExecutor,
+    #[serde(default)]
+    defaults: CommandDefaults,
+    #[serde(default, deserialize_with = "deserialize_commands")]
+    before: Commands,
+    #[serde(deserialize_with = "deserialize_commands")]
     commands: Commands,
+    #[serde(default, deserialize_with = "deserialize_commands")]
+    after: Commands,
 }
 
 impl FileEventTask {
+    /// Provides the service a dependency of the task is named after, used to
+    /// check (and remediate) it before each run
+    ///
+    /// A dependency whose service hasn't been provided is never healthy
+    pub fn provide_service(
+        &self,
+        name: impl Into<String>,
+        service: Arc<dyn DynService>,
+    ) {
+        self.services.insert(name.into(), service);
+    }
+
+    /// Provides the services the task's dependencies are named after from
+    /// `services`, warning about any it doesn't have
+    pub fn provide_services(&self, services: &ServiceRegistry) {
+        self.services
+            .provide_from(&self.name, &self.dependencies, services);
+    }
+
+    /// The names of the tasks this one runs after
+    pub(crate) fn depends_on(&self) -> &[String] {
+        &self.depends_on
+    }
+
+    pub(crate) fn link_prerequisites(
+        &self,
+        prerequisites: impl IntoIterator<Item = (String, Successes)>,
+    ) {
+        self.prerequisites.link(prerequisites);
+    }
+
+    /// When the task succeeds, for the tasks that run after it
+    pub(crate) fn successes(&self) -> Successes {
+        self.last_run.successes()
+    }
+
+    /// The token that cancels the task's runs when cancelled
+    ///
+    /// Cancelling it kills the commands of any runs in progress (closing the
+    /// SSH session of remote commands), failing them as cancelled.
+    /// A cancelled token stays cancelled, so later runs fail immediately
+    ///
+    /// Dropping a run without cancelling it (e.g. aborting the Tokio task
+    /// it's in, or on shutdown) still kills its local commands' processes.
+    /// Remote commands' SSH sessions are closed too, but a remote process
+    /// that isn't reading or writing its standard streams may live on, so
+    /// prefer cancelling
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Executes the task's commands with `executor`, instead of
+    /// [`SystemExecutor`](crate::SystemExecutor)
+    pub fn with_executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.executor = TaskExecutor(executor);
+        self
+    }
+
+    /// The report of the task's most recent completed run, whether it
+    /// succeeded or failed
+    ///
+    /// Dry runs aren't counted
+    pub fn last_run(&self) -> Option<TaskRunReport> {
+        self.last_run.get()
+    }
+
     /// Loads a task from file, asynchronously
+    ///
+    /// See the [task file reference](crate#task-files) for every option,
+    /// with `triggers` (see [Triggers](crate#triggers-file-tasks)) in place
+    /// of `schedule` and `jitter`
+    ///
+    /// Example task file:
+    /// ```yml
+    #[doc = include_str!("../examples/file_task.yml")]
+    /// ```
     #[inline(always)]
     pub async fn load_from<P>(path: P) -> Result<Self, ReadError>
     where
@@ -42,77 +209,737 @@ impl FileEventTask {
         crate::load_from(path).await
     }
 
+    /// Parses a task from a YAML string, without touching the filesystem
+    ///
+    /// Formatted the same as a task file, see [`FileEventTask::load_from`].
+    /// Checks that depend on the machine running the task (that its shells
+    /// and triggers exist, and the like) are left until it's activated
+    pub fn from_yaml_str(yaml: &str) -> Result<Self, ReadError> {
+        crate::from_yaml_slice(yaml.as_bytes(), Utf8Path::new("<string>"))
+    }
+
+    /// Reads a task from `reader` (e.g. stdin) until it ends, asynchronously
+    ///
+    /// Formatted the same as a task file, see [`FileEventTask::load_from`],
+    /// and checked as [`FileEventTask::from_yaml_str`] is
+    pub async fn from_reader<R>(reader: R) -> Result<Self, ReadError>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        crate::from_reader(reader, Utf8Path::new("<reader>")).await
+    }
+
     /// Starts watching the files for activity
     ///
     /// While active, if a file/folder being watched is created, modified, or
     /// deleted, the task is run (see [`FileEventTask::run`])
     ///
-    /// Errors only if the watcher couldn't be created.
-    /// Other errors that derive from paths not being watchable are only
-    /// logged.
-    /// There is no check to ensure any paths are successfully watched
-    pub async fn activate(
+    /// Errors if the watcher couldn't be created, or if none of the task's
+    /// paths could be watched.
+    /// If only some paths couldn't be watched, the task is still activated,
+    /// and the paths that failed are listed in the returned [`WatchHandle`].
+    /// Paths that don't exist yet (with `allow_missing`) are waited for
+    /// rather than failing
+    ///
+    /// If a watched path is deleted or renamed away (e.g. by log rotation),
+    /// its watch is lost, so the closest directory above it that exists is
+    /// watched instead, until it reappears and can be watched again, which
+    /// also runs the task
+    ///
+    /// With `run_on_activate` (or `process_existing`, and there are existing
+    /// files), the task is also run once the watcher is set up, ahead of any
+    /// events
+    ///
+    /// The task's `lock_file` (see [Running](crate#running))
+    /// is locked first, failing if another process holds it, and is held by
+    /// the [`WatchHandle`]
+    ///
+    /// With `require_host_reachable`, the task's hosts are checked next.
+    /// When skipping, this errors if any can't be reached.
+    /// When deferring, the returned [`WatchHandle`] doesn't watch anything
+    /// until they all can, and then only logs if watching fails
+    pub async fn activate(self: &Arc<Self>) -> Result<WatchHandle, WatchError> {
+        self.check_environment().map_err(|why| WatchError {
+            name: self.name.clone(),
+            r#type: why.into(),
+        })?;
+        let lock = self
+            .lock_file
+            .as_ref()
+            .map(LockFile::acquire)
+            .transpose()
+            .map_err(|why| WatchError {
+            name: self.name.clone(),
+            r#type: why.into(),
+        })?;
+        match self.require_host_reachable {
+            None => self.watch(lock),
+            Some(UnreachablePolicy::Skip) => {
+                let unreachable = self.targets.unreachable().await;
+                if !unreachable.is_empty() {
+                    return Err(WatchError {
+                        name: self.name.clone(),
+                        r#type: WatchErrorType::Unreachable(unreachable),
+                    });
+                }
+                self.watch(lock)
+            }
+            Some(policy @ UnreachablePolicy::Defer) => {
+                let task = self.clone();
+                let deferred_lock = lock.clone();
+                let handle = tokio::spawn(async move {
+                    if !task.targets.await_reachable(&task.name, policy).await {
+                        return;
+                    }
+                    match task.watch(deferred_lock) {
+                        Ok(handle) => {
+                            if let Err(why) = handle.wait().await {
+                                error!(%task.name, "Watcher failed: {why}");
+                            }
+                        }
+                        Err(why) => error!("{why}"),
+                    }
+                });
+                Ok(WatchHandle {
+                    handle: Some(handle),
+                    _lock: lock,
+                    watched: Vec::new(),
+                    pending: Vec::new(),
+                    failed: Vec::new(),
+                })
+            }
+        }
+    }
+
+    /// Sets up the watcher, holding `lock` for as long as it's active
+    fn watch(
         self: &Arc<Self>,
-    ) -> Result<JoinHandle<()>, notify::Error> {
-        warn!("Unable to check dependencies as that isn't implemented yet");
-        let (tx, rx) = mpsc::channel::<Event>(1);
+        lock: Option<Arc<LockFile>>,
+    ) -> Result<WatchHandle, WatchError> {
+        let (tx, rx) = mpsc::channel::<Event>(self.event_capacity.get());
 
-        let mut watcher = RecommendedWatcher::new(PreEventHandler::new(tx))?;
+        let mut watcher = RecommendedWatcher::new(PreEventHandler::new(tx))
+            .map_err(|why| WatchError {
+                name: self.name.clone(),
+                r#type: why.into(),
+            })?;
+        let mut watched = Vec::with_capacity(self.watch_paths.len());
+        let mut pending = Vec::new();
+        let mut failed = Vec::new();
+        let mut subdirs = HashMap::new();
         self.watch_paths.iter().for_each(|path| {
-            // TODO: expose RecursiveMode to config files
-            // https://docs.rs/notify/latest/5.0.0-pre.15/enum.RecursiveMode.html
-            if let Err(why) =
-                watcher.watch(path.as_std_path(), RecursiveMode::NonRecursive)
+            if self.allow_missing && !path.exists() {
+                info!(%self.name, %path, "Trigger doesn't exist yet, waiting for it to appear");
+                pending.push(path.clone());
+                return;
+            }
+            match watcher.watch(path.as_std_path(), RecursiveMode::NonRecursive)
             {
-                error!("Couldn't watch {path}: {why}");
+                Ok(()) => {
+                    let root = WatchRoot::new(path);
+                    watch_subdirs(
+                        &mut watcher,
+                        &root.absolute,
+                        0,
+                        self.max_depth,
+                        &mut subdirs,
+                    );
+                    watched.push(path.clone());
+                }
+                Err(why) => {
+                    error!("Couldn't watch {path}: {why}");
+                    failed.push((path.clone(), why));
+                }
             }
         });
-        info!(%self.name, "Created watcher");
+        if watched.is_empty() && pending.is_empty() {
+            return Err(WatchError {
+                name: self.name.clone(),
+                r#type: WatchErrorType::NoPathsWatched(failed),
+            });
+        }
+        info!(%self.name, subdirectories = subdirs.len(), "Created watcher");
 
+        let windows = self
+            .active_window
+            .as_ref()
+            // Checked when loaded
+            .and_then(|windows| windows.schedules().ok());
         let handler = PostEventHandler {
             parent: self.clone(),
+            windows,
             rx,
-            _watcher: watcher,
+            roots: watched
+                .iter()
+                .map(WatchRoot::new)
+                .chain(pending.iter().map(WatchRoot::lost))
+                .collect(),
+            subdirs,
+            waiting: HashSet::new(),
+            watcher,
+            trigger: None,
         };
-        Ok(tokio::spawn(handler.monitor()))
+        Ok(WatchHandle {
+            handle: Some(tokio::spawn(handler.monitor())),
+            _lock: lock,
+            watched,
+            pending,
+            failed,
+        })
+    }
+
+    /// Runs the task, sending each line of command output to `tx` as soon as
+    /// it's produced
+    ///
+    /// This is otherwise the same as [`Task::run`].
+    /// Streams redirected with `stdout_file` or `stderr_file` go to their
+    /// files instead of being sent.
+    /// See [`OutputLine`] for ordering guarantees
+    pub async fn run_streaming(
+        self: Arc<Self>,
+        tx: Sender<OutputLine>,
+    ) -> Result<(), Vec<CommandRunError>> {
+        let ctx = RunContext::new(
+            &self.name,
+            self.dry_run,
+            Some(tx),
+            &self.shell,
+            self.base_dir.as_deref(),
+            &self.targets,
+            self.spawn_limit.limiter(self.spawn_rate),
+            self.cancel.child_token(),
+            &self.executor,
+        );
+        self.run_with(ctx).await
+    }
+
+    /// Runs the task for `event`, which its commands' `{{event_path}}` and
+    /// `{{event_kind}}` are substituted from
+    async fn run_triggered(
+        self: Arc<Self>,
+        event: Option<TriggerEvent>,
+    ) -> Result<(), Vec<CommandRunError>> {
+        let ctx = RunContext::new(
+            &self.name,
+            self.dry_run,
+            None,
+            &self.shell,
+            self.base_dir.as_deref(),
+            &self.targets,
+            self.spawn_limit.limiter(self.spawn_rate),
+            self.cancel.child_token(),
+            &self.executor,
+        );
+        if let Some(event) = event {
+            ctx.triggered_by(event);
+        }
+        self.run_with(ctx).await
+    }
+
+    #[instrument(
+        name = "task",
+        skip_all,
+        fields(
+            task = %self.name,
+            trigger = "file",
+            log_level = self.log_level.map(|level| level.as_str()),
+        ),
+    )]
+    async fn run_with(
+        self: Arc<Self>,
+        ctx: Arc<RunContext>,
+    ) -> Result<(), Vec<CommandRunError>> {
+        info!(%self.name, "Task triggered");
+        let run = TaskRun {
+            name: &self.name,
+            kind: self.kind(),
+            depends_on: &self.depends_on,
+            prerequisites: &self.prerequisites,
+            dependencies: &self.dependencies,
+            services: &self.services,
+            hosts: &self.targets,
+            task_hosts: &self.hosts,
+            max_concurrent: self.max_concurrent,
+            task_timeout: self.task_timeout,
+            limit: &self.limit,
+            last_run: &self.last_run,
+            before: &self.before,
+            commands: &self.commands,
+            after: &self.after,
+            failure_level: Level::ERROR,
+        };
+        run.run(&ctx).await
+    }
+}
+
+impl Validate for FileEventTask {
+    fn apply_defaults(&mut self) {
+        self.defaults.apply([
+            &mut self.before,
+            &mut self.commands,
+            &mut self.after,
+        ]);
+        self.targets = self.hosts.targets(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+        );
+    }
+
+    fn validate(&self) -> Result<(), ReadErrorType> {
+        if self.commands.is_empty() {
+            return Err(ReadErrorType::NoCommands);
+        }
+        if self.watch_paths.is_empty() {
+            return Err(ReadErrorType::NoTriggers);
+        }
+        if let Some(windows) = &self.active_window {
+            windows.schedules()?;
+        }
+        if let Some(rate) = &self.spawn_rate {
+            rate.check()?;
+        }
+        self.hosts.check()?;
+        check_command_hosts(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+        )?;
+        check_limits(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+            self.targets.has_remote(),
+        )?;
+        check_nice(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+            self.targets.has_remote(),
+        )?;
+        check_umask(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+            self.targets.has_remote(),
+        )?;
+        check_identities(
+            self.before.iter().chain(&self.commands).chain(&self.after),
+            self.targets.has_remote(),
+        )?;
+        check_command_names(&self.before)?;
+        check_command_names(&self.commands)?;
+        check_command_names(&self.after)
+    }
+
+    fn check_environment(&self) -> Result<(), ReadErrorType> {
+        if !self.allow_missing {
+            if let Some(path) = self.watch_paths.iter().find(|p| !p.exists()) {
+                return Err(ReadErrorType::MissingPath(
+                    "trigger",
+                    path.clone(),
+                ));
+            }
+        }
+        self.targets.check_environment()?;
+        if self.targets.has_local() {
+            check_shell(&self.shell)?;
+        }
+        let commands =
+            || self.before.iter().chain(&self.commands).chain(&self.after);
+        check_command_shells(commands(), &self.hosts)?;
+        check_limits_enforceable(commands())?;
+        check_identities_permitted(commands())
+    }
+
+    fn missing_paths(&self) -> Vec<ReadErrorType> {
+        let commands =
+            self.before.iter().chain(&self.commands).chain(&self.after);
+        let local = self.targets.has_local();
+        let mut missing = Vec::new();
+        if let (true, Some(base_dir)) = (local, &self.base_dir) {
+            if !expand_home(base_dir).is_dir() {
+                missing.push(ReadErrorType::MissingPath(
+                    "base_dir",
+                    base_dir.clone(),
+                ));
+            }
+        }
+        missing.extend(missing_command_paths(
+            commands,
+            self.base_dir.as_deref(),
+            &self.targets,
+        ));
+        missing
     }
 }
 
 #[async_trait]
 impl Task for FileEventTask {
-    // TODO
-    async fn check_dependencies(self: Arc<Self>) -> bool {
-        unimplemented!("Need to write services first!")
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> TaskKind {
+        TaskKind::FileEvent
+    }
+
+    async fn check_dependencies(self: Arc<Self>) -> DependencyReport {
+        check_dependencies(&self.name, &self.dependencies, &self.services).await
     }
 
     async fn run(self: Arc<Self>) -> Result<(), Vec<CommandRunError>> {
-        info!(%self.name, "Task triggered");
-        let handle_iter = self.commands.iter().cloned().map(|cmd| match &self
-            .host
-        {
-            Host::Local => tokio::spawn(cmd.run_local()),
-            Host::Remote(addr) => tokio::spawn(cmd.run_remote(addr.clone())),
-        });
+        self.run_triggered(None).await
+    }
+}
+
+/// Builds a [`FileEventTask`] programmatically, without needing a task file
+///
+/// See the [task file reference](crate#task-files) for what each option does
+#[derive(Debug)]
+pub struct FileEventTaskBuilder {
+    name: String,
+    watch_paths: Vec<Utf8PathBuf>,
+    allow_missing: bool,
+    max_depth: usize,
+    run_on_activate: bool,
+    process_existing: bool,
+    active_window: Option<ActiveWindows>,
+    min_interval: Option<Duration>,
+    event_capacity: NonZeroUsize,
+    hosts: Hosts,
+    require_host_reachable: Option<UnreachablePolicy>,
+    shell: String,
+    base_dir: Option<Utf8PathBuf>,
+    dry_run: bool,
+    log_level: Option<Level>,
+    max_concurrent: Option<NonZeroUsize>,
+    spawn_rate: Option<SpawnRate>,
+    task_timeout: Option<Duration>,
+    lock_file: Option<Utf8PathBuf>,
+    depends_on: Vec<String>,
+    dependencies: Vec<Dependency>,
+    before: Vec<CommandBuilder>,
+    commands: Vec<CommandBuilder>,
+    after: Vec<CommandBuilder>,
+}
+
+impl FileEventTaskBuilder {
+    /// Starts building a task called `name`
+    pub fn new(name: impl Into<String>) -> Self {
+        FileEventTaskBuilder {
+            name: name.into(),
+            watch_paths: Vec::new(),
+            allow_missing: false,
+            max_depth: 0,
+            run_on_activate: false,
+            process_existing: false,
+            active_window: None,
+            min_interval: None,
+            event_capacity: default_event_capacity(),
+            hosts: Hosts::default(),
+            require_host_reachable: None,
+            shell: default_shell(),
+            base_dir: None,
+            dry_run: false,
+            log_level: None,
+            max_concurrent: None,
+            spawn_rate: None,
+            task_timeout: None,
+            lock_file: None,
+            depends_on: Vec::new(),
+            dependencies: Vec::new(),
+            before: Vec::new(),
+            commands: Vec::new(),
+            after: Vec::new(),
+        }
+    }
+
+    /// Adds a file or folder to watch for activity
+    pub fn trigger(mut self, path: impl Into<Utf8PathBuf>) -> Self {
+        self.watch_paths.push(path.into());
+        self
+    }
+
+    /// Sets whether triggers may not exist yet, by default they must
+    pub fn allow_missing(mut self, allow_missing: bool) -> Self {
+        self.allow_missing = allow_missing;
+        self
+    }
+
+    /// Sets how many levels of subdirectories below directory triggers are
+    /// watched too, none by default
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets whether the task is run once straight away when activated, as
+    /// well as on events
+    pub fn run_on_activate(mut self, run_on_activate: bool) -> Self {
+        self.run_on_activate = run_on_activate;
+        self
+    }
+
+    /// Sets whether the task is run when activated if its triggers already
+    /// exist, to process files that appeared while it wasn't watching
+    pub fn process_existing(mut self, process_existing: bool) -> Self {
+        self.process_existing = process_existing;
+        self
+    }
+
+    /// Adds a cron expression of times when events trigger the task, outside
+    /// of which they're ignored
+    ///
+    /// Without any, events always trigger the task
+    pub fn active_window(mut self, cron: impl Into<String>) -> Self {
+        self.active_window
+            .get_or_insert_with(ActiveWindows::default)
+            .0
+            .push(cron.into());
+        self
+    }
+
+    /// Sets the least time between the starts of consecutive runs
+    pub fn min_interval(mut self, interval: Duration) -> Self {
+        self.min_interval = Some(interval);
+        self
+    }
+
+    /// Sets how many filesystem events can be queued before more are dropped
+    pub fn event_capacity(mut self, capacity: NonZeroUsize) -> Self {
+        self.event_capacity = capacity;
+        self
+    }
+
+    /// Sets the host the task's commands are run on, replacing any others
+    pub fn host(mut self, host: impl AsRef<str>) -> Self {
+        self.hosts = Hosts::from_iter([host]);
+        self
+    }
+
+    /// Sets the hosts the task's commands are run on, each running the whole
+    /// batch in parallel with the others
+    pub fn hosts<S: AsRef<str>>(
+        mut self,
+        hosts: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.hosts = Hosts::from_iter(hosts);
+        self
+    }
+
+    /// Runs the task's commands in the container `name` on the local Docker
+    /// instance, replacing any hosts
+    pub fn container(mut self, name: impl Into<String>) -> Self {
+        self.hosts = Hosts::container(name.into());
+        self
+    }
+
+    /// Checks the task's hosts can be reached when it's activated, doing
+    /// what `policy` says if they can't
+    pub fn require_host_reachable(mut self, policy: UnreachablePolicy) -> Self {
+        self.require_host_reachable = Some(policy);
+        self
+    }
+
+    /// Sets the shell used for shell invocations, `sh` (`cmd` on Windows) by
+    /// default
+    pub fn shell(mut self, shell: impl Into<String>) -> Self {
+        self.shell = shell.into();
+        self
+    }
+
+    /// Sets the directory relative command working directories are joined
+    /// onto
+    pub fn base_dir(mut self, dir: impl Into<Utf8PathBuf>) -> Self {
+        self.base_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets whether the task only logs what it would execute
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets the level the task's own logs are emitted up to, when using a
+    /// `TaskLevelFilter`
+    pub fn log_level(mut self, level: Level) -> Self {
+        self.log_level = Some(level);
+        self
+    }
 
-        let results = future::join_all(handle_iter).await;
-        trace!(%self.name, "Processing task command results");
-        let errors = results
-            .into_iter()
-            .filter_map(|nested_result| match nested_result {
-                Ok(Ok(())) => None,
-                Ok(Err(cre)) => Some(cre),
-                Err(join_err) => Some(CommandRunError {
-                    name: self.name.clone(),
-                    r#type: CommandRunErrorType::Async(join_err),
-                }),
+    /// Limits how many of the task's commands may run at once
+    pub fn max_concurrent(mut self, max: NonZeroUsize) -> Self {
+        self.max_concurrent = Some(max);
+        self
+    }
+
+    /// Limits how fast the task's commands start, to `per_second` on
+    /// average, with up to `burst` at once
+    pub fn spawn_rate(mut self, per_second: f64, burst: NonZeroU32) -> Self {
+        self.spawn_rate = Some(SpawnRate::new(per_second, burst));
+        self
+    }
+
+    /// Limits how long a whole run of the task may take
+    ///
+    /// Commands still running when it's up are killed, except those in
+    /// containers, which Docker can't kill, so are only stopped being
+    /// followed
+    pub fn task_timeout(mut self, limit: Duration) -> Self {
+        self.task_timeout = Some(limit);
+        self
+    }
+
+    /// Locks the file at `path` while the task is activated, so another
+    /// process can't activate it too
+    pub fn lock_file(mut self, path: impl Into<Utf8PathBuf>) -> Self {
+        self.lock_file = Some(path.into());
+        self
+    }
+
+    /// Adds a task that must succeed before each run of this one
+    ///
+    /// Only tasks in the same [`TaskRegistry`](crate::TaskRegistry) can be
+    /// waited for, see [Dependencies](crate#dependencies)
+    pub fn depends_on(mut self, task: impl Into<String>) -> Self {
+        self.depends_on.push(task.into());
+        self
+    }
+
+    /// Adds a dependency on the service called `service`, remediated
+    /// according to `remediate` if it isn't healthy before a run
+    ///
+    /// The service itself must be given to the built task with
+    /// [`FileEventTask::provide_service`]
+    pub fn dependency(
+        mut self,
+        service: impl Into<String>,
+        remediate: Remediate,
+    ) -> Self {
+        self.dependencies
+            .push(Dependency::new(service.into(), remediate));
+        self
+    }
+
+    /// Adds a command to run (in order) before the task's main commands
+    pub fn before(mut self, command: CommandBuilder) -> Self {
+        self.before.push(command);
+        self
+    }
+
+    /// Adds a command to the task
+    pub fn command(mut self, command: CommandBuilder) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Adds a command to run (in order) after the task's main commands,
+    /// whether or not they succeeded
+    pub fn after(mut self, command: CommandBuilder) -> Self {
+        self.after.push(command);
+        self
+    }
+
+    /// Builds the task, checking it in the same way as
+    /// [`FileEventTask::load_from`] would
+    pub fn build(self) -> Result<FileEventTask, BuildError> {
+        let finish = |commands| {
+            finish_commands(commands).map_err(|r#type| BuildError {
+                name: self.name.clone(),
+                r#type,
             })
-            .collect::<Vec<CommandRunError>>();
-        if errors.is_empty() {
-            info!(%self.name, "Task completed successfully");
-            Ok(())
-        } else {
-            error!(%self.name, "Task completed with errors");
-            Err(errors)
+        };
+        let before = finish(self.before)?;
+        let commands = finish(self.commands)?;
+        let after = finish(self.after)?;
+        let targets = self
+            .hosts
+            .targets(before.iter().chain(&commands).chain(&after));
+        let task = FileEventTask {
+            name: self.name,
+            depends_on: self.depends_on,
+            prerequisites: Prerequisites::default(),
+            dependencies: self.dependencies,
+            services: ServiceMap::default(),
+            watch_paths: self.watch_paths,
+            allow_missing: self.allow_missing,
+            max_depth: self.max_depth,
+            run_on_activate: self.run_on_activate,
+            process_existing: self.process_existing,
+            active_window: self.active_window,
+            min_interval: self.min_interval,
+            event_capacity: self.event_capacity,
+            hosts: self.hosts,
+            targets,
+            require_host_reachable: self.require_host_reachable,
+            shell: self.shell,
+            base_dir: self.base_dir,
+            dry_run: self.dry_run,
+            log_level: self.log_level,
+            max_concurrent: self.max_concurrent,
+            spawn_rate: self.spawn_rate,
+            task_timeout: self.task_timeout,
+            lock_file: self.lock_file,
+            limit: ConcurrencyLimit::default(),
+            spawn_limit: SpawnLimit::default(),
+            last_run: LastRunCell::default(),
+            cancel: CancellationToken::new(),
+            executor: TaskExecutor::default(),
+            defaults: CommandDefaults::default(),
+            before,
+            commands,
+            after,
+        };
+        task.validate()
+            .and_then(|()| task.check_environment())
+            .map_err(|r#type| BuildError {
+                name: task.name.clone(),
+                r#type,
+            })?;
+        Ok(task)
+    }
+}
+
+/// An active [`FileEventTask`], returned by [`FileEventTask::activate`]
+///
+/// The task stays active for as long as this is kept.
+/// Dropping it (or calling [`WatchHandle::stop`]) stops the green thread
+/// responding to activity, which tears down the watcher
+#[derive(Debug)]
+#[must_use = "the task is deactivated when its WatchHandle is dropped"]
+pub struct WatchHandle {
+    handle: Option<JoinHandle<()>>,
+    _lock: Option<Arc<LockFile>>,
+    watched: Vec<Utf8PathBuf>,
+    pending: Vec<Utf8PathBuf>,
+    failed: Vec<(Utf8PathBuf, notify::Error)>,
+}
+
+impl WatchHandle {
+    /// The paths being watched
+    pub fn watched(&self) -> &[Utf8PathBuf] {
+        &self.watched
+    }
+
+    /// The paths that didn't exist yet, which are watched once they appear
+    pub fn pending(&self) -> &[Utf8PathBuf] {
+        &self.pending
+    }
+
+    /// The paths that couldn't be watched, and why
+    pub fn failed(&self) -> &[(Utf8PathBuf, notify::Error)] {
+        &self.failed
+    }
+
+    /// Deactivates the task, the same as dropping the handle
+    pub fn stop(self) {}
+
+    /// Waits for the task to stop of its own accord, which only happens if
+    /// the watcher shuts down
+    pub async fn wait(mut self) -> Result<(), JoinError> {
+        match self.handle.take() {
+            Some(handle) => handle.await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
         }
     }
 }
@@ -121,6 +948,8 @@ impl Task for FileEventTask {
 struct PreEventHandler {
     inner: Option<PreEventHandlerInner>,
     channel: Sender<Event>,
+    /// How many events have been dropped as the channel was full
+    dropped: u64,
 }
 
 impl PreEventHandler {
@@ -130,6 +959,7 @@ impl PreEventHandler {
         PreEventHandler {
             inner: None,
             channel: tx,
+            dropped: 0,
         }
     }
 
@@ -139,6 +969,14 @@ impl PreEventHandler {
         matches!(event.kind, Create(_) | Modify(Data(_)) | Remove(_))
     }
 
+    /// Whether the event could mean a watched path has gone, so its watch
+    /// may have been lost
+    fn removal(event: &Event) -> bool {
+        use notify::event::ModifyKind::*;
+        use notify::EventKind::*;
+        matches!(event.kind, Modify(Name(_)) | Remove(_))
+    }
+
     fn debouncing(&self, event: &Event) -> bool {
         match &self.inner {
             Some(inner) => {
@@ -160,12 +998,25 @@ impl notify::EventHandler for PreEventHandler {
     fn handle_event(&mut self, event_result: Result<Event, notify::Error>) {
         match event_result {
             Ok(event) => {
-                if PreEventHandler::relevant(&event) {
+                if PreEventHandler::relevant(&event)
+                    || PreEventHandler::removal(&event)
+                {
                     if !self.debouncing(&event) {
                         // Event must be cloned here so it can be remembered
                         // later
-                        match self.channel.blocking_send(event.clone()) {
+                        // Never block, or the OS watcher stalls. If the
+                        // channel's full, runs are already queued, which will
+                        // see this event's changes anyway
+                        match self.channel.try_send(event.clone()) {
                             Ok(()) => info!(?event, "Event forwarded"),
+                            Err(TrySendError::Full(_)) => {
+                                self.dropped += 1;
+                                warn!(
+                                    ?event,
+                                    total_dropped = self.dropped,
+                                    "Event queue full, dropped event",
+                                );
+                            }
                             Err(why) => {
                                 error!(?event, "Failed to send event: {why}")
                             }
@@ -200,24 +1051,543 @@ impl From<Event> for PreEventHandlerInner {
 
 struct PostEventHandler<W: Watcher> {
     parent: Arc<FileEventTask>,
+    /// When events may trigger a run, always if `None`
+    windows: Option<Vec<Schedule>>,
     rx: Receiver<Event>,
-    _watcher: W,
+    roots: Vec<WatchRoot>,
+    /// The subdirectories being watched (within `max_depth`), as they appear
+    /// in events, and how many levels below their trigger they are
+    subdirs: HashMap<PathBuf, usize>,
+    /// The directories watched only to notice lost roots being recreated,
+    /// each the closest existing one above one of them
+    waiting: HashSet<PathBuf>,
+    watcher: W,
+    /// The last relevant event, which the coming run is for
+    trigger: Option<TriggerEvent>,
 }
 
 impl<W: Watcher> PostEventHandler<W> {
     async fn monitor(mut self) {
+        let mut last_started: Option<Instant> = None;
+        let existing = match self.parent.process_existing {
+            true => self.existing().await,
+            false => 0,
+        };
+        let name = &self.parent.name;
+        if existing > 0 && !self.parent.run_on_activate && !self.in_window() {
+            debug!(%name, existing, "Ignoring existing files outside the task's active window");
+        } else if shutting_down() {
+            debug!(%name, "Shutting down, not running task on activation");
+        } else if existing > 0 || self.parent.run_on_activate {
+            match existing {
+                0 => info!(%name, "Running task on activation"),
+                _ => info!(%name, existing, "Running task for existing files"),
+            }
+            last_started = Some(Instant::now());
+            if let Err(why) = self.parent.clone().run().await {
+                why.into_iter().for_each(|err| error!("{err}"));
+            }
+        }
+        // Missing triggers might have appeared since being looked for
+        let mut restored = self.rewatch();
         loop {
-            match self.rx.recv().await {
-                Some(_) => {
-                    if let Err(why) = self.parent.clone().run().await {
-                        why.into_iter().for_each(|err| error!("{err}"));
+            self.trigger = None;
+            let triggered = match std::mem::take(&mut restored) {
+                true => true,
+                false => match self.rx.recv().await {
+                    Some(event) => self.handle(&event),
+                    None => {
+                        info!("EventHandler shutdown on receiving None");
+                        return;
                     }
+                },
+            };
+            if !triggered {
+                continue;
+            }
+            if !self.in_window() {
+                let name = &self.parent.name;
+                debug!(%name, "Ignoring event outside the task's active window");
+                continue;
+            }
+            if shutting_down() {
+                let name = &self.parent.name;
+                debug!(%name, "Shutting down, ignoring event");
+                continue;
+            }
+            if let Some(ready) = self.next_allowed(last_started) {
+                trace!(?ready, "Rate limiting task run");
+                tokio::time::sleep_until(ready.into()).await;
+            }
+            // Whatever arrived during the last run (or while waiting) is
+            // covered by the coming run
+            let mut coalesced = 0;
+            while let Ok(event) = self.rx.try_recv() {
+                self.handle(&event);
+                coalesced += 1;
+            }
+            if coalesced > 0 {
+                debug!(coalesced, "Coalesced events into one run");
+            }
+            last_started = Some(Instant::now());
+            let trigger = self.trigger.take();
+            if let Err(why) = self.parent.clone().run_triggered(trigger).await {
+                why.into_iter().for_each(|err| error!("{err}"));
+            }
+        }
+    }
+
+    /// Notes any watches lost (or restored) by the event, returning whether
+    /// it should trigger a run
+    fn handle(&mut self, event: &Event) -> bool {
+        // Anything else in the directories waited on doesn't matter
+        let waited_on_only = event.paths.iter().all(|path| {
+            let in_waited = self.waiting.contains(path)
+                || path.parent().is_some_and(|dir| self.waiting.contains(dir));
+            in_waited && !self.is_watched(path)
+        });
+        if PreEventHandler::removal(event) {
+            let name = &self.parent.name;
+            for root in self.roots.iter_mut() {
+                if root.lost
+                    || !event.paths.contains(&root.absolute)
+                    || root.path.exists()
+                {
+                    continue;
+                }
+                // The OS drops the watch with the path, or follows it if
+                // it was renamed, so stop watching until it's back
+                let _ = self.watcher.unwatch(root.path.as_std_path());
+                root.lost = true;
+                forget_subdirs(
+                    &mut self.watcher,
+                    &root.absolute,
+                    &mut self.subdirs,
+                );
+                warn!(%name, path = %root.path, "Watched path removed, waiting for it to reappear");
+            }
+        }
+        if self.parent.max_depth > 0 {
+            self.track_subdirs(event);
+        }
+        let restored =
+            self.roots.iter().any(|root| root.lost) && self.rewatch();
+        let relevant = PreEventHandler::relevant(event) && !waited_on_only;
+        if relevant {
+            self.trigger = Some(TriggerEvent::new(event));
+        }
+        relevant || restored
+    }
+
+    /// Whether `path` is a watched root or subdirectory, or directly within
+    /// one
+    fn is_watched(&self, path: &Path) -> bool {
+        self.depth_of(path).is_some()
+            || path.parent().and_then(|dir| self.depth_of(dir)).is_some()
+    }
+
+    /// Watches any subdirectories the event created (or moved in) within
+    /// `max_depth`, and stops watching any it removed
+    fn track_subdirs(&mut self, event: &Event) {
+        for path in event.paths.iter() {
+            if self.subdirs.contains_key(path) {
+                if !path.exists() {
+                    forget_subdirs(&mut self.watcher, path, &mut self.subdirs);
+                }
+                continue;
+            }
+            let is_dir =
+                std::fs::symlink_metadata(path).is_ok_and(|meta| meta.is_dir());
+            let depth = match path.parent().and_then(|dir| self.depth_of(dir)) {
+                Some(depth) if is_dir && depth < self.parent.max_depth => {
+                    depth + 1
+                }
+                _ => continue,
+            };
+            match self.watcher.watch(path, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    trace!(path = %path.display(), depth, "Watching new subdirectory");
+                    self.subdirs.insert(path.clone(), depth);
+                    // Whatever came with it (e.g. `mkdir -p`, or a moved
+                    // tree) won't have had events of its own
+                    watch_subdirs(
+                        &mut self.watcher,
+                        path,
+                        depth,
+                        self.parent.max_depth,
+                        &mut self.subdirs,
+                    );
                 }
-                None => {
-                    info!("EventHandler shutdown on receiving None");
-                    return;
+                Err(why) => {
+                    warn!(path = %path.display(), "Couldn't watch new subdirectory: {why}")
                 }
             }
         }
     }
+
+    /// How many levels below its trigger a watched directory is
+    fn depth_of(&self, dir: &Path) -> Option<usize> {
+        let is_root = self
+            .roots
+            .iter()
+            .any(|root| !root.lost && root.absolute == dir);
+        match is_root {
+            true => Some(0),
+            false => self.subdirs.get(dir).copied(),
+        }
+    }
+
+    /// Watches any removed paths that have since reappeared, and the
+    /// closest existing directory above each one still missing, so it's
+    /// noticed when it (or a directory on the way to it) is created.
+    /// Returns whether any were restored, as that's a change worth a run
+    fn rewatch(&mut self) -> bool {
+        let mut restored = false;
+        // Until nothing changes, as what's waited on could be created just
+        // before it's watched
+        loop {
+            restored |= self.restore();
+            let wanted = self
+                .roots
+                .iter()
+                .filter(|root| root.lost)
+                .filter_map(|root| {
+                    root.absolute.ancestors().skip(1).find(|dir| dir.is_dir())
+                })
+                .map(Path::to_path_buf)
+                .collect::<HashSet<_>>();
+            if wanted == self.waiting {
+                return restored;
+            }
+            let waiting = std::mem::take(&mut self.waiting);
+            // Directories watched anyway are left be
+            for dir in waiting.difference(&wanted) {
+                if self.depth_of(dir).is_none() {
+                    let _ = self.watcher.unwatch(dir);
+                }
+            }
+            for dir in wanted.difference(&waiting) {
+                if self.depth_of(dir).is_some() {
+                    continue;
+                }
+                match self.watcher.watch(dir, RecursiveMode::NonRecursive) {
+                    Ok(()) => {
+                        trace!(path = %dir.display(), "Waiting for a missing trigger")
+                    }
+                    Err(why) => {
+                        let name = &self.parent.name;
+                        warn!(%name, path = %dir.display(), "Couldn't watch for a missing trigger: {why}")
+                    }
+                }
+            }
+            self.waiting = wanted;
+        }
+    }
+
+    /// Tries to watch any removed paths that exist again, returning
+    /// whether any were
+    fn restore(&mut self) -> bool {
+        let name = &self.parent.name;
+        let mut restored = false;
+        for root in self.roots.iter_mut().filter(|root| root.lost) {
+            if !root.path.exists() {
+                continue;
+            }
+            match self
+                .watcher
+                .watch(root.path.as_std_path(), RecursiveMode::NonRecursive)
+            {
+                Ok(()) => {
+                    root.lost = false;
+                    restored = true;
+                    watch_subdirs(
+                        &mut self.watcher,
+                        &root.absolute,
+                        0,
+                        self.parent.max_depth,
+                        &mut self.subdirs,
+                    );
+                    info!(%name, path = %root.path, "Watch restored");
+                }
+                Err(why) => {
+                    warn!(%name, path = %root.path, "Couldn't restore watch: {why}")
+                }
+            }
+        }
+        restored
+    }
+
+    /// How many of the watched paths exist, counting what's in directories
+    /// rather than the directories themselves
+    async fn existing(&self) -> usize {
+        let mut existing = 0;
+        for root in self.roots.iter().filter(|root| !root.lost) {
+            match tokio::fs::read_dir(&root.path).await {
+                Ok(mut entries) => {
+                    while let Ok(Some(_)) = entries.next_entry().await {
+                        existing += 1;
+                    }
+                }
+                Err(_) if root.path.is_file() => existing += 1,
+                Err(why) => {
+                    let name = &self.parent.name;
+                    warn!(%name, path = %root.path, "Couldn't look for existing files: {why}");
+                }
+            }
+        }
+        existing
+    }
+
+    /// Whether it's now within any of the task's active windows
+    fn in_window(&self) -> bool {
+        let now = Local::now();
+        self.windows.as_ref().is_none_or(|windows| {
+            windows.iter().any(|window| window.includes(now))
+        })
+    }
+
+    /// When the next run may start, if that's still to come, given when the
+    /// last one started
+    fn next_allowed(&self, last_started: Option<Instant>) -> Option<Instant> {
+        let ready = last_started? + self.parent.min_interval?;
+        (ready > Instant::now()).then_some(ready)
+    }
+}
+
+/// A path given to the watcher
+struct WatchRoot {
+    path: Utf8PathBuf,
+    /// The path as it appears in events
+    absolute: PathBuf,
+    /// Whether the path was removed, taking its watch with it
+    lost: bool,
+}
+
+impl WatchRoot {
+    fn new(path: &Utf8PathBuf) -> Self {
+        let absolute = std::env::current_dir()
+            .map(|cwd| cwd.join(path))
+            .unwrap_or_else(|_| path.clone().into());
+        WatchRoot {
+            path: path.clone(),
+            absolute,
+            lost: false,
+        }
+    }
+
+    /// A path that's yet to be watched, as it doesn't exist
+    fn lost(path: &Utf8PathBuf) -> Self {
+        WatchRoot {
+            lost: true,
+            ..WatchRoot::new(path)
+        }
+    }
+}
+
+/// Watches the directories in `dir` (itself `depth` levels below its
+/// trigger), and theirs, down to `max_depth` levels below the trigger,
+/// recording each one in `subdirs`
+fn watch_subdirs<W: Watcher>(
+    watcher: &mut W,
+    dir: &Path,
+    depth: usize,
+    max_depth: usize,
+    subdirs: &mut HashMap<PathBuf, usize>,
+) {
+    if depth >= max_depth {
+        return;
+    }
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(why) => {
+            warn!(path = %dir.display(), "Couldn't list directory to watch its subdirectories: {why}");
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        // Not following symlinks, which could loop
+        if !entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            continue;
+        }
+        let path = entry.path();
+        if subdirs.contains_key(&path) {
+            continue;
+        }
+        match watcher.watch(&path, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                watch_subdirs(watcher, &path, depth + 1, max_depth, subdirs);
+                subdirs.insert(path, depth + 1);
+            }
+            Err(why) => {
+                warn!(path = %path.display(), "Couldn't watch subdirectory: {why}")
+            }
+        }
+    }
+}
+
+/// Stops watching the subdirectories at or under `dir`
+fn forget_subdirs<W: Watcher>(
+    watcher: &mut W,
+    dir: &Path,
+    subdirs: &mut HashMap<PathBuf, usize>,
+) {
+    subdirs.retain(|subdir, _| {
+        let under = subdir.starts_with(dir);
+        if under {
+            // Usually gone with the directory already
+            let _ = watcher.unwatch(subdir);
+        }
+        !under
+    });
+}
+
+fn default_event_capacity() -> NonZeroUsize {
+    NonZeroUsize::new(16).expect("16 is non-zero")
+}
+
+/// The cron expressions of when a file task's events trigger it, either one
+/// or a non-empty list in a task file
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ActiveWindows(Vec<String>);
+
+impl ActiveWindows {
+    fn schedules(&self) -> Result<Vec<Schedule>, ReadErrorType> {
+        self.0
+            .iter()
+            .map(|window| {
+                Schedule::from_str(window).map_err(|why| {
+                    ReadErrorType::InvalidWindow(
+                        window.clone(),
+                        why.to_string(),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+impl<'de> Deserialize<'de> for ActiveWindows {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::One(window) => Ok(ActiveWindows(vec![window])),
+            Repr::Many(windows) if windows.is_empty() => {
+                Err(D::Error::custom("active_window list must not be empty"))
+            }
+            Repr::Many(windows) => Ok(ActiveWindows(windows)),
+        }
+    }
+}
+
+impl Serialize for ActiveWindows {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match self.0.as_slice() {
+            [window] => window.serialize(serializer),
+            windows => windows.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+
+    const MISSING_TRIGGER: &str = "name: elsewhere
+triggers: [/nonexistent/overseer/trigger]
+shell: /nonexistent/overseer/sh
+commands:
+  - run: 'true'
+";
+
+    #[test]
+    fn from_yaml_str_leaves_environment_alone() {
+        let task = FileEventTask::from_yaml_str(MISSING_TRIGGER).unwrap();
+        assert!(matches!(
+            task.check_environment(),
+            Err(ReadErrorType::MissingPath("trigger", _)),
+        ));
+    }
+
+    #[tokio::test]
+    async fn load_from_checks_environment() {
+        let path = scratch_dir("file-environment").join("task.yml");
+        std::fs::write(&path, MISSING_TRIGGER).unwrap();
+        let err = FileEventTask::load_from(&path).await.unwrap_err();
+        assert!(
+            matches!(err.r#type, ReadErrorType::MissingPath("trigger", _)),
+            "{err}",
+        );
+    }
+
+    #[tokio::test]
+    async fn activate_checks_environment() {
+        let task =
+            Arc::new(FileEventTask::from_yaml_str(MISSING_TRIGGER).unwrap());
+        let err = task.activate().await.unwrap_err();
+        assert!(matches!(err.r#type, WatchErrorType::Invalid(_)), "{err}");
+    }
+
+    #[test]
+    fn allow_missing_accepts_absent_triggers() {
+        let dir = scratch_dir("file-allow-missing");
+        let yaml = |allow_missing| {
+            format!(
+                "name: later
+triggers: ['{dir}', '{dir}/not yet']
+allow_missing: {allow_missing}
+commands:
+  - run: 'true'"
+            )
+        };
+        let task = FileEventTask::from_yaml_str(&yaml(true)).unwrap();
+        task.check_environment().unwrap();
+        let task = FileEventTask::from_yaml_str(&yaml(false)).unwrap();
+        assert!(matches!(
+            task.check_environment(),
+            Err(ReadErrorType::MissingPath("trigger", path)) if path.ends_with("not yet"),
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn missing_triggers_are_watched_once_they_appear() {
+        let dir = scratch_dir("file-appearing");
+        let ran = dir.join("ran");
+        let yaml = format!(
+            "name: appearing
+triggers: ['{dir}/logs/app.log']
+allow_missing: true
+commands:
+  - run: touch {ran}"
+        );
+        let task = Arc::new(FileEventTask::from_yaml_str(&yaml).unwrap());
+        let _handle = task.activate().await.unwrap();
+
+        // Only the trigger runs the task, not what's around it
+        std::fs::write(dir.join("unrelated"), "").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!ran.exists());
+
+        std::fs::create_dir(dir.join("logs")).unwrap();
+        std::fs::write(dir.join("logs/app.log"), "").unwrap();
+        for _ in 0..50 {
+            if ran.exists() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("the task didn't run once its trigger appeared");
+    }
 }
@@ -0,0 +1,194 @@
+use std::sync::Arc;
+
+use crate::{ReadErrorType, TaskCommand};
+
+/// The user and group ids a local command is run as
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Identity {
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+}
+
+impl TaskCommand {
+    /// Resolves the command's `user` and `group` to ids, if either is set
+    ///
+    /// Without a `group`, the user's primary group is used.
+    /// Without a `user`, the command runs as Overseer's user
+    pub(crate) fn identity(&self) -> Result<Option<Identity>, String> {
+        if self.user.is_none() && self.group.is_none() {
+            return Ok(None);
+        }
+        imp::resolve(self.user.as_deref(), self.group.as_deref()).map(Some)
+    }
+}
+
+/// Ensures no `user` or `group` is set on `commands` run remotely, as it's
+/// only supported for local commands
+pub(crate) fn check_identities<'a>(
+    commands: impl IntoIterator<Item = &'a Arc<TaskCommand>>,
+    remote: bool,
+) -> Result<(), ReadErrorType> {
+    match commands
+        .into_iter()
+        .find(|cmd| cmd.user.is_some() || cmd.group.is_some())
+    {
+        Some(cmd) if remote => Err(ReadErrorType::InvalidIdentity(
+            cmd.name.clone(),
+            String::from("it's only supported on localhost"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Ensures any `user` and `group` set on `commands` can be switched to,
+/// which needs them to exist, Overseer to be allowed to change identity, and
+/// Unix
+pub(crate) fn check_identities_permitted<'a>(
+    commands: impl IntoIterator<Item = &'a Arc<TaskCommand>>,
+) -> Result<(), ReadErrorType> {
+    for cmd in commands {
+        if cmd.user.is_none() && cmd.group.is_none() {
+            continue;
+        }
+        let invalid =
+            |why: String| ReadErrorType::InvalidIdentity(cmd.name.clone(), why);
+        if let Some(identity) = cmd.identity().map_err(invalid)? {
+            imp::check_permitted(identity).map_err(invalid)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+mod imp {
+    use libc::{c_char, c_int};
+    use std::ffi::CString;
+    use std::io;
+
+    use super::Identity;
+
+    pub(super) fn resolve(
+        user: Option<&str>,
+        group: Option<&str>,
+    ) -> Result<Identity, String> {
+        let (uid, primary_gid) = match user {
+            Some(user) => user_ids(user)?,
+            // SAFETY: always successful, with no preconditions
+            None => unsafe { (libc::geteuid(), libc::getegid()) },
+        };
+        let gid = match group {
+            Some(group) => group_id(group)?,
+            None => primary_gid,
+        };
+        Ok(Identity { uid, gid })
+    }
+
+    /// Ensures Overseer can switch to `identity`, which needs root unless
+    /// it's Overseer's own
+    pub(super) fn check_permitted(identity: Identity) -> Result<(), String> {
+        // SAFETY: always successful, with no preconditions
+        let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        match euid == 0 || (identity.uid == euid && identity.gid == egid) {
+            true => Ok(()),
+            false => Err(String::from(
+                "Overseer isn't running as root, so can't change identity",
+            )),
+        }
+    }
+
+    /// Whether Overseer has root privileges, e.g. to create system scopes
+    pub(crate) fn is_root() -> bool {
+        // SAFETY: always successful, with no preconditions
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    /// Looks up a user by name (or numeric id), returning its uid and
+    /// primary gid
+    fn user_ids(user: &str) -> Result<(u32, u32), String> {
+        let found = match user.parse::<u32>() {
+            Ok(uid) => lookup(|pwd: &mut libc::passwd, buf, len, result| {
+                // SAFETY: every pointer is valid for the call, and `len` is
+                // the buffer's length
+                unsafe { libc::getpwuid_r(uid, pwd, buf, len, result) }
+            }),
+            Err(_) => {
+                let name = c_string(user)?;
+                lookup(|pwd: &mut libc::passwd, buf, len, result| {
+                    // SAFETY: as above, with `name` NUL-terminated
+                    unsafe {
+                        libc::getpwnam_r(name.as_ptr(), pwd, buf, len, result)
+                    }
+                })
+            }
+        };
+        match found {
+            Ok(Some(pwd)) => Ok((pwd.pw_uid, pwd.pw_gid)),
+            Ok(None) => Err(format!("user {user} doesn't exist")),
+            Err(why) => Err(format!("couldn't look up user {user}: {why}")),
+        }
+    }
+
+    /// Looks up a group by name (or numeric id), returning its gid
+    fn group_id(group: &str) -> Result<u32, String> {
+        if let Ok(gid) = group.parse::<u32>() {
+            return Ok(gid);
+        }
+        let name = c_string(group)?;
+        let found = lookup(|grp: &mut libc::group, buf, len, result| {
+            // SAFETY: every pointer is valid for the call, `len` is the
+            // buffer's length, and `name` is NUL-terminated
+            unsafe { libc::getgrnam_r(name.as_ptr(), grp, buf, len, result) }
+        });
+        match found {
+            Ok(Some(grp)) => Ok(grp.gr_gid),
+            Ok(None) => Err(format!("group {group} doesn't exist")),
+            Err(why) => Err(format!("couldn't look up group {group}: {why}")),
+        }
+    }
+
+    fn c_string(name: &str) -> Result<CString, String> {
+        CString::new(name).map_err(|_| format!("{name:?} contains a NUL byte"))
+    }
+
+    /// Calls one of the reentrant `get*_r` functions, growing the buffer
+    /// until the entry fits, returning the entry if one was found
+    fn lookup<T>(
+        mut call: impl FnMut(&mut T, *mut c_char, usize, *mut *mut T) -> c_int,
+    ) -> io::Result<Option<T>> {
+        let mut buf = vec![0 as c_char; 1024];
+        loop {
+            // SAFETY: passwd and group are plain C structs, for which all
+            // zeroes is valid
+            let mut entry: T = unsafe { std::mem::zeroed() };
+            let mut result = std::ptr::null_mut();
+            match call(&mut entry, buf.as_mut_ptr(), buf.len(), &mut result) {
+                0 if result.is_null() => return Ok(None),
+                0 => return Ok(Some(entry)),
+                libc::ERANGE => buf.resize(buf.len() * 2, 0),
+                code => return Err(io::Error::from_raw_os_error(code)),
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::Identity;
+
+    pub(super) fn resolve(
+        _: Option<&str>,
+        _: Option<&str>,
+    ) -> Result<Identity, String> {
+        Err(String::from("it's only supported on Unix"))
+    }
+
+    pub(super) fn check_permitted(_: Identity) -> Result<(), String> {
+        Ok(())
+    }
+
+    pub(crate) fn is_root() -> bool {
+        false
+    }
+}
+
+pub(crate) use imp::is_root;
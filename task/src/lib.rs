@@ -15,289 +15,3213 @@
 //! file-based ([`FileEventTask`])
 //!
 //! A **command** is an executable (and arguments, if any), or a shell
-//! invocation.
-//! Shell invocations are wrapped in `sh -c "[your-command]"`, meaning the
-//! system default shell is used
-
+//! invocation (with `shell: true`).
+//! Shell invocations are wrapped in `[shell] -c "[your-command]"`, where the
+//! shell is configurable per task (or per command) and defaults to `sh`.
+//! On Windows, the shell defaults to `cmd`, which is given `/C` instead of
+//! `-c`.
+//! Remote hosts, resource limits, `user`/`group`, `nice`, and `umask` are
+//! only supported on Unix.
+//! Only shell invocations support pipes, globs, and redirects
+//!
+#![doc = include_str!("../TASK_FILES.md")]
 #![warn(missing_docs)]
 
 use async_trait::async_trait;
 use camino::{Utf8Path, Utf8PathBuf};
-use openssh::{KnownHosts, Session};
 use serde::de::{DeserializeOwned, Error};
-use serde::{Deserialize, Deserializer};
-use std::sync::Arc;
-use tokio::process::Command;
-use tracing::{error, info, trace, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_yaml::Value;
+use service::path::{
+    deserialize_expanded, deserialize_expanded_opt, deserialize_vars,
+    deserialize_vars_opt, expand_home, is_home_relative,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, instrument, trace, warn};
+
+mod alert;
+#[doc(inline)]
+pub use alert::*;
+
+mod container;
 
 mod cron;
 #[doc(inline)]
 pub use cron::*;
 
+mod dependency;
+#[doc(inline)]
+pub use dependency::*;
+
+mod executor;
+#[doc(inline)]
+pub use executor::*;
+
 mod file;
 #[doc(inline)]
 pub use file::*;
 
+mod identity;
+pub(crate) use identity::*;
+
+mod limits;
+pub(crate) use limits::*;
+
+mod lock;
+#[doc(inline)]
+pub use lock::*;
+
+mod log_level;
+#[cfg(feature = "log-filter")]
+#[doc(inline)]
+pub use log_level::*;
+
+#[cfg(feature = "logging")]
+mod logging;
+#[cfg(feature = "logging")]
+#[doc(inline)]
+pub use logging::*;
+
+mod output;
+#[doc(inline)]
+pub use output::*;
+
+mod prerequisite;
+pub(crate) use prerequisite::*;
+
+mod reachability;
+#[doc(inline)]
+pub use reachability::*;
+
+mod registry;
+#[doc(inline)]
+pub use registry::*;
+
+mod run;
+pub(crate) use run::*;
+
+mod scheduler;
+#[doc(inline)]
+pub use scheduler::*;
+
+mod secrets;
+#[doc(inline)]
+pub use secrets::*;
+
+mod shutdown;
+#[doc(inline)]
+pub use shutdown::*;
+
+mod spawn_rate;
+pub(crate) use spawn_rate::*;
+
+#[cfg(feature = "status-server")]
+mod status;
+#[cfg(feature = "status-server")]
+#[doc(inline)]
+pub use status::*;
+
+mod ssh;
+pub(crate) use ssh::*;
+
+mod telemetry;
+#[doc(inline)]
+pub use telemetry::*;
+
+mod template;
+pub(crate) use template::*;
+
 /// Contains error types relating to tasks and commands
 pub mod error;
 use crate::error::*;
 
 pub(crate) type Commands = Vec<Arc<TaskCommand>>;
 
+/// Checks on a loaded task that can't be made while deserialising it
+pub(crate) trait Validate {
+    /// Fills in each command's unset options from the task's `defaults`,
+    /// done before validating
+    fn apply_defaults(&mut self);
+
+    /// Checks that don't depend on the machine running the task, so can be
+    /// made without touching the filesystem
+    fn validate(&self) -> Result<(), ReadErrorType>;
+
+    /// Checks that the machine running the task can run it, e.g. that its
+    /// shells exist, made when loading from a file and when activating
+    fn check_environment(&self) -> Result<(), ReadErrorType>;
+
+    /// Finds paths the task refers to that don't exist (yet), which isn't
+    /// checked when loading as they may be created before the task runs
+    fn missing_paths(&self) -> Vec<ReadErrorType>;
+}
+
+/// Options shared by all of a task's commands, unless a command sets its own
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CommandDefaults {
+    #[serde(default, deserialize_with = "deserialize_vars_opt")]
+    working_dir: Option<Utf8PathBuf>,
+    #[serde(default)]
+    env_vars: Vec<EnvVar>,
+    #[serde(default, deserialize_with = "deserialize_expanded_opt")]
+    env_file: Option<Utf8PathBuf>,
+    #[serde(default)]
+    host: Option<Hosts>,
+    #[serde(default, with = "humantime_serde")]
+    timeout: Option<Duration>,
+}
+
+impl CommandDefaults {
+    /// Applies the defaults to every command in each of `lists`
+    ///
+    /// Must be called before the commands are shared
+    pub(crate) fn apply(&self, lists: [&mut Commands; 3]) {
+        for command in lists.into_iter().flatten() {
+            let command = Arc::get_mut(command)
+                .expect("commands shouldn't be shared before they're loaded");
+            self.apply_to(command);
+        }
+    }
+
+    fn apply_to(&self, command: &mut TaskCommand) {
+        if command.working_dir_opt().is_none() {
+            if let Some(dir) = &self.working_dir {
+                command.working_dir = dir.clone();
+            }
+        }
+        if command.env_file.is_none() {
+            command.env_file = self.env_file.clone();
+        }
+        if command.host.is_none() {
+            command.host = self.host.clone();
+        }
+        if command.timeout.is_none() {
+            command.timeout = self.timeout;
+        }
+        if !self.env_vars.is_empty() {
+            let mut env_vars = self
+                .env_vars
+                .iter()
+                .filter(|EnvVar(key, _)| {
+                    !command.env_vars.iter().any(|EnvVar(k, _)| k == key)
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            env_vars.append(&mut command.env_vars);
+            command.env_vars = env_vars;
+        }
+    }
+}
+
+/// Checks the hosts commands are run on instead of their task's, see
+/// [`Hosts::check`]
+pub(crate) fn check_command_hosts<'a>(
+    commands: impl IntoIterator<Item = &'a Arc<TaskCommand>>,
+) -> Result<(), ReadErrorType> {
+    commands
+        .into_iter()
+        .filter_map(|cmd| cmd.host.as_ref())
+        .try_for_each(Hosts::check)
+}
+
+/// The commands run on `host`, given the task's hosts are `task_hosts`
+pub(crate) fn commands_on(
+    commands: &Commands,
+    host: &Host,
+    task_hosts: &Hosts,
+) -> Commands {
+    commands
+        .iter()
+        .filter(|cmd| cmd.host.as_ref().unwrap_or(task_hosts).contains(host))
+        .cloned()
+        .collect()
+}
+
+/// Collects the paths missing from all of a task's commands
+fn missing_command_paths<'a>(
+    commands: impl IntoIterator<Item = &'a Arc<TaskCommand>>,
+    base_dir: Option<&Utf8Path>,
+    hosts: &Hosts,
+) -> Vec<ReadErrorType> {
+    let local = hosts.has_local();
+    commands
+        .into_iter()
+        .flat_map(|cmd| cmd.missing_paths(base_dir, local))
+        .collect()
+}
+
 /// Defines required functionality of a **task**
 #[async_trait]
 pub trait Task {
-    /// Checks that all the dependent services of a task are alive and well
+    /// The task's name, as given in its task file
+    fn name(&self) -> &str;
+    /// What triggers the task
+    fn kind(&self) -> TaskKind;
+    /// Checks the dependent services of a task, reporting how each one is
+    /// (see [`DependencyReport::all_healthy`])
     ///
     /// Expected to be checked before activating a task
-    async fn check_dependencies(self: Arc<Self>) -> bool;
+    async fn check_dependencies(self: Arc<Self>) -> DependencyReport;
     /// Manually runs the task
     ///
     /// This is what's called automatically when a task is activated
     ///
-    /// Each command is run in a separate green thread.
-    /// If all commands complete successfully, `Ok` will be returned, otherwise
-    /// the first error will be
+    /// `before` runs one command at a time, then (if it succeeded) `commands`
+    /// all at once, then `after` one at a time, whatever happened.
+    /// Every command's error is returned, not just the first
     async fn run(self: Arc<Self>) -> Result<(), Vec<CommandRunError>>;
 }
 
+/// What triggers a task to run, distinguishing tasks behind a `dyn Task`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum TaskKind {
+    /// A [`CronTask`], run on a schedule
+    Cron,
+    /// A [`FileEventTask`], run on filesystem activity
+    FileEvent,
+}
+
+impl TaskKind {
+    /// The kind as a lowercase string, as used in status reports
+    pub fn as_str(&self) -> &'static str {
+        use TaskKind::*;
+        match *self {
+            Cron => "cron",
+            FileEvent => "file",
+        }
+    }
+}
+
+impl fmt::Display for TaskKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 pub(crate) async fn load_from<T>(
     path: impl AsRef<Utf8Path>,
 ) -> Result<T, ReadError>
 where
-    T: Task + DeserializeOwned,
+    T: Task + Validate + DeserializeOwned,
 {
-    // Could consider tokio_uring for the 'proper' way to do this
-    let bytes =
-        tokio::fs::read(path.as_ref())
-            .await
-            .map_err(|e| ReadError {
-                path: path.as_ref().to_owned(),
-                r#type: ReadErrorType::Io(e),
-            })?;
-    let task = serde_yaml::from_slice::<T>(&bytes).map_err(|e| ReadError {
-        path: path.as_ref().to_owned(),
-        r#type: ReadErrorType::De(e),
+    let path = path.as_ref();
+    let file = tokio::fs::File::open(path).await.map_err(|e| ReadError {
+        path: path.to_owned(),
+        r#type: ReadErrorType::Io(e),
+    })?;
+    let task = from_reader::<T, _>(file, path).await?;
+    task.check_environment().map_err(|r#type| ReadError {
+        path: path.to_owned(),
+        r#type,
     })?;
     info!("Loaded task from file");
     Ok(task)
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename = "snake_case", deny_unknown_fields)]
-struct TaskCommand {
-    name: String,
-    #[serde(default)]
-    working_dir: Utf8PathBuf,
-    #[serde(default)]
-    env_vars: Vec<EnvVar>,
-    #[serde(rename = "run")]
-    inner: MyCommand,
+/// Reads all of `reader`, then parses and checks it as a task, with errors
+/// attributed to `source`
+pub(crate) async fn from_reader<T, R>(
+    mut reader: R,
+    source: &Utf8Path,
+) -> Result<T, ReadError>
+where
+    T: Task + Validate + DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|e| ReadError {
+            path: source.to_owned(),
+            r#type: ReadErrorType::Io(e),
+        })?;
+    from_yaml_slice(&bytes, source)
 }
 
-impl TaskCommand {
-    async fn run_local(self: Arc<Self>) -> Result<(), CommandRunError> {
-        info!(%self.name, "TaskCommand triggered");
-        let mut command = Command::new(&self.inner.program);
-        command
-            .args(&self.inner.args)
-            .current_dir(&self.working_dir)
-            .envs(self.env_vars.iter().map(|EnvVar(k, v)| (k, v)));
-        // This is ugly but without making an async closure I can't use
-        // and_then
-        let exit = match command.spawn() {
-            // Could get command output by changing to wait_with_output
-            Ok(mut child) => match child.wait().await {
-                Ok(exit) => exit,
-                Err(why) => {
-                    return Err(CommandRunError {
-                        name: self.name.clone(),
-                        r#type: CommandRunErrorType::Io(why),
-                    })
+/// Parses and checks a task, with errors attributed to `source`
+pub(crate) fn from_yaml_slice<T>(
+    yaml: &[u8],
+    source: &Utf8Path,
+) -> Result<T, ReadError>
+where
+    T: Task + Validate + DeserializeOwned,
+{
+    let mut task =
+        serde_yaml::from_slice::<T>(yaml).map_err(|e| ReadError {
+            path: source.to_owned(),
+            r#type: ReadErrorType::De(e),
+        })?;
+    task.apply_defaults();
+    task.validate().map_err(|r#type| ReadError {
+        path: source.to_owned(),
+        r#type,
+    })?;
+    Ok(task)
+}
+
+/// Loads every task in a single file, asynchronously
+///
+/// The file should contain either a list of tasks, or a map of task names to
+/// tasks (in which case the tasks don't need a `name` of their own).
+/// Each task is formatted as it would be in its own file.
+/// Tasks with a `schedule` are loaded as [`CronTask`]s, and tasks with
+/// `triggers` are loaded as [`FileEventTask`]s
+///
+/// Every task is checked, and all errors are returned (rather than just the
+/// first), each saying which task it relates to
+pub async fn load_many_from<P>(
+    path: P,
+) -> Result<Vec<Box<dyn Task>>, Vec<ReadError>>
+where
+    P: AsRef<Utf8Path> + Send + Sync,
+{
+    let path = path.as_ref();
+    let bytes = read_bytes(path).await.map_err(|e| vec![e])?;
+    let document = serde_yaml::from_slice::<Value>(&bytes).map_err(|e| {
+        vec![ReadError {
+            path: path.to_owned(),
+            r#type: ReadErrorType::De(e),
+        }]
+    })?;
+    let entries = match document {
+        Value::Sequence(tasks) => tasks
+            .into_iter()
+            .enumerate()
+            .map(|(index, task)| {
+                let entry = match task.get("name").and_then(Value::as_str) {
+                    Some(name) => format!("task #{} ({name})", index + 1),
+                    None => format!("task #{}", index + 1),
+                };
+                (entry, task)
+            })
+            .collect::<Vec<_>>(),
+        Value::Mapping(tasks) => tasks
+            .into_iter()
+            .map(|(name, mut task)| {
+                let name = match name {
+                    Value::String(name) => name,
+                    other => format!("{other:?}"),
+                };
+                if let Value::Mapping(task) = &mut task {
+                    task.entry("name".into())
+                        .or_insert_with(|| name.clone().into());
                 }
-            },
-            Err(why) => {
-                return Err(CommandRunError {
-                    name: self.name.clone(),
-                    r#type: CommandRunErrorType::Io(why),
-                })
+                (format!("task {name:?}"), task)
+            })
+            .collect(),
+        _ => {
+            return Err(vec![ReadError {
+                path: path.to_owned(),
+                r#type: ReadErrorType::NotTaskList,
+            }])
+        }
+    };
+
+    let mut tasks = Vec::with_capacity(entries.len());
+    let mut errors = Vec::new();
+    for (entry, task) in entries {
+        match AnyTask::from_value(task) {
+            Ok(task) => tasks.push(task.into_boxed()),
+            Err(inner) => errors.push(ReadError {
+                path: path.to_owned(),
+                r#type: ReadErrorType::Entry(entry, Box::new(inner)),
+            }),
+        }
+    }
+    if errors.is_empty() {
+        info!(count = tasks.len(), "Loaded tasks from file");
+        Ok(tasks)
+    } else {
+        Err(errors)
+    }
+}
+
+/// A task of either kind, before it's been put to use
+#[derive(Debug)]
+pub(crate) enum AnyTask {
+    Cron(CronTask),
+    FileEvent(FileEventTask),
+}
+
+impl AnyTask {
+    /// Deserialises and checks a task, telling what kind it is from whether
+    /// it has a `schedule` or `triggers`
+    pub(crate) fn from_value(task: Value) -> Result<Self, ReadErrorType> {
+        let is_cron = task.get("schedule").is_some();
+        let is_file = task.get("triggers").is_some();
+        match (is_cron, is_file) {
+            (true, false) => {
+                let mut task = serde_yaml::from_value::<CronTask>(task)?;
+                task.apply_defaults();
+                task.validate()?;
+                task.check_environment()?;
+                Ok(AnyTask::Cron(task))
             }
-        };
-        match exit.success() {
-            true => {
-                info!(%self.name, "TaskCommand completed successfully");
-                Ok(())
-            }
-            false => {
-                let exit_code = exit.code().expect("No exit code");
-                error!(%self.name, "TaskCommand failed with exit code {exit_code}");
-                Err(CommandRunError {
-                    name: self.name.clone(),
-                    r#type: CommandRunErrorType::ExitStatus(exit_code),
-                })
+            (false, true) => {
+                let mut task = serde_yaml::from_value::<FileEventTask>(task)?;
+                task.apply_defaults();
+                task.validate()?;
+                task.check_environment()?;
+                Ok(AnyTask::FileEvent(task))
             }
+            _ => Err(ReadErrorType::AmbiguousTaskKind),
         }
     }
 
-    async fn run_remote(
-        self: Arc<Self>,
-        destination: impl AsRef<str>,
-    ) -> Result<(), CommandRunError> {
-        let wd_opt = self.working_dir_opt();
-        if wd_opt.is_some() && !self.working_dir.is_absolute() {
-            warn!(%self.name, ?self.working_dir, "Working directory for remote command is not absolute");
+    fn into_boxed(self) -> Box<dyn Task> {
+        match self {
+            AnyTask::Cron(task) => Box::new(task),
+            AnyTask::FileEvent(task) => Box::new(task),
         }
-        let session = Session::connect(destination, KnownHosts::Strict)
-            .await
-            .map_err(|ssh_err| CommandRunError {
-            name: self.name.clone(),
-            r#type: ssh_err.into(),
-        })?;
+    }
+}
 
-        /*
-        Making the openssh::Command - a short story
-        The problem is that unlike regular Command, we can't specify working
-        directory and environment variables easily. Session::shell bundles
-        the invocation into sh -c for us, which is nice, but we have to declare
-        all environment variables manually, and cd into the working directory.
-        This leads to a lot of hassle
-         */
-        let mut command = {
-            let mut invocation = String::new();
-            // Add export command for environment variables, if any
-            if !self.env_vars.is_empty() {
-                invocation.push_str("export ");
-                self.env_vars
-                    .iter()
-                    .map(ToString::to_string)
-                    .for_each(|env| {
-                        invocation.push(' ');
-                        invocation.push_str(&env);
-                    });
-                invocation.push_str(" && ");
-            }
-            // cd into custom working directory, if specified
-            if let Some(dir) = wd_opt {
-                invocation.push_str("cd ");
-                invocation.push_str(dir.as_str());
-                invocation.push_str(" && ");
-            }
-            // add the command with its arguments
-            invocation.push_str(&self.inner.program);
-            self.inner.args.iter().for_each(|arg| {
-                invocation.push(' ');
-                invocation.push_str(arg);
-            });
-            trace!(%invocation, "Built remote command");
-            session.shell(invocation)
+pub(crate) async fn read_bytes(path: &Utf8Path) -> Result<Vec<u8>, ReadError> {
+    // Could consider tokio_uring for the 'proper' way to do this
+    tokio::fs::read(path).await.map_err(|e| ReadError {
+        path: path.to_owned(),
+        r#type: ReadErrorType::Io(e),
+    })
+}
+
+/// Details of the task run a command is being executed as part of
+#[derive(Debug)]
+pub struct RunContext {
+    task_name: String,
+    /// When the task was triggered, shared so all its commands agree on it
+    started: SystemTime,
+    /// Log what would be executed instead of executing it
+    dry_run: bool,
+    /// Where to stream command output to, if anywhere
+    output: Option<Sender<OutputLine>>,
+    /// The shell used for shell invocations
+    shell: String,
+    /// What relative working directories are relative to, if set
+    base_dir: Option<Utf8PathBuf>,
+    /// The supervisor-wide limit on running commands, if one is set
+    global_limit: Option<Arc<Semaphore>>,
+    /// Paces the task's commands, if it has a `spawn_rate`
+    spawn_limiter: Option<Arc<SpawnLimiter>>,
+    /// Cancelled to kill the run's commands
+    cancel: CancellationToken,
+    /// What the run's commands are executed with
+    executor: Arc<dyn Executor>,
+    /// How to connect to each remote host, by address
+    ssh: HashMap<String, SshOptions>,
+    /// The names of the containers run in, by label
+    containers: HashMap<String, String>,
+    /// The file event that triggered the run, if it was triggered by one
+    event: OnceLock<TriggerEvent>,
+    /// The commands currently executing, by name (and host, if remote)
+    in_flight: Mutex<Vec<String>>,
+    /// How each command was executed, for the run's report
+    executions: Mutex<Vec<CommandExecution>>,
+}
+
+impl RunContext {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        task_name: &str,
+        dry_run: bool,
+        output: Option<Sender<OutputLine>>,
+        shell: &str,
+        base_dir: Option<&Utf8Path>,
+        hosts: &Hosts,
+        spawn_limiter: Option<Arc<SpawnLimiter>>,
+        cancel: CancellationToken,
+        executor: &TaskExecutor,
+    ) -> Arc<Self> {
+        Arc::new(RunContext {
+            task_name: task_name.to_owned(),
+            started: SystemTime::now(),
+            dry_run,
+            output,
+            shell: shell.to_owned(),
+            base_dir: base_dir.map(ToOwned::to_owned),
+            global_limit: GLOBAL_LIMIT.get().cloned(),
+            spawn_limiter,
+            cancel,
+            executor: executor.0.clone(),
+            ssh: hosts.ssh_options(),
+            containers: hosts.containers(),
+            event: OnceLock::new(),
+            in_flight: Mutex::default(),
+            executions: Mutex::default(),
+        })
+    }
+
+    /// Records that the run was triggered by `event`
+    pub(crate) fn triggered_by(&self, event: TriggerEvent) {
+        let _ = self.event.set(event);
+    }
+
+    /// The file event that triggered the run, if it was triggered by one
+    pub(crate) fn event(&self) -> Option<&TriggerEvent> {
+        self.event.get()
+    }
+
+    /// The name of the task being run
+    pub fn task_name(&self) -> &str {
+        &self.task_name
+    }
+
+    /// Whether this is a dry run, so commands shouldn't actually be executed
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Executes `command` on `host` (`None` for local) with the run's
+    /// executor
+    async fn execute(
+        self: &Arc<Self>,
+        command: Arc<TaskCommand>,
+        host: Option<&str>,
+    ) -> Result<CommandOutcome, CommandRunError> {
+        let label = match host {
+            Some(host) => format!("{} on {host}", command.name()),
+            None => command.name().to_owned(),
+        };
+        self.in_flight().push(label.clone());
+        let timeout = command.timeout;
+        let name = command.name().to_owned();
+        let run = self.executor.execute(command, host, self.clone());
+        // Dropping the run kills the command, as it would the task's run
+        let result = match timeout {
+            Some(limit) => match tokio::time::timeout(limit, run).await {
+                Ok(result) => result,
+                Err(_) => {
+                    warn!(command = %name, host, "TaskCommand timed out, killing it");
+                    Err(CommandRunError {
+                        name,
+                        r#type: CommandRunErrorType::Timeout(limit),
+                    })
+                }
+            },
+            None => run.await,
         };
+        let mut in_flight = self.in_flight();
+        if let Some(index) = in_flight.iter().position(|l| *l == label) {
+            in_flight.swap_remove(index);
+        }
+        result
+    }
 
-        // Could collect output with output()
-        let exit =
-            command.status().await.map_err(|ssh_err| CommandRunError {
-                name: self.name.clone(),
-                r#type: ssh_err.into(),
-            })?;
-        match exit.success() {
-            true => {
-                info!(%self.name, "TaskCommand completed successfully");
-                Ok(())
-            }
-            false => {
-                let exit_code = exit.code().expect("No exit code");
-                error!(%self.name, "TaskCommand failed with exit code {exit_code}");
-                Err(CommandRunError {
-                    name: self.name.clone(),
-                    r#type: CommandRunErrorType::ExitStatus(exit_code),
-                })
-            }
+    /// Waits for the task's `spawn_rate` to allow another command to start,
+    /// failing with `cancelled` if the run's cancelled first
+    async fn paced(
+        &self,
+        cancelled: impl FnOnce() -> CommandRunError,
+    ) -> Result<(), CommandRunError> {
+        let Some(limiter) = &self.spawn_limiter else {
+            return Ok(());
+        };
+        tokio::select! {
+            _ = limiter.wait() => Ok(()),
+            _ = self.cancel.cancelled() => Err(cancelled()),
         }
     }
 
-    fn working_dir_opt(&self) -> Option<&Utf8Path> {
-        if self.working_dir != Utf8PathBuf::default() {
-            Some(self.working_dir.as_path())
-        } else {
-            None
+    /// How to connect to the remote host at `address`
+    fn ssh_options(&self, address: &str) -> SshOptions {
+        self.ssh.get(address).cloned().unwrap_or_default()
+    }
+
+    /// The name of the container `host` is the label of, if it is one
+    fn container(&self, host: &str) -> Option<&str> {
+        self.containers.get(host).map(String::as_str)
+    }
+
+    fn in_flight(&self) -> MutexGuard<'_, Vec<String>> {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn executions(&self) -> MutexGuard<'_, Vec<CommandExecution>> {
+        self.executions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes the executions on `host` recorded so far
+    pub(crate) fn take_executions(&self, host: &str) -> Vec<CommandExecution> {
+        let mut executions = self.executions();
+        let (taken, kept) = executions
+            .drain(..)
+            .partition(|execution| execution.host == host);
+        *executions = kept;
+        taken
+    }
+}
+
+/// Awaits `run`, cancelling it if it takes longer than `limit` (if set)
+///
+/// If it was cancelled, the error to report is also returned, listing the
+/// commands that were still running
+pub(crate) async fn with_task_timeout<F: Future>(
+    run: F,
+    limit: Option<Duration>,
+    ctx: &RunContext,
+) -> (F::Output, Option<CommandRunErrorType>) {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return (run.await, None),
+    };
+    tokio::pin!(run);
+    tokio::select! {
+        output = &mut run => (output, None),
+        _ = tokio::time::sleep(limit) => {
+            let mut running = ctx.in_flight().clone();
+            running.sort();
+            warn!(task = %ctx.task_name, "Task timed out, cancelling it");
+            ctx.cancel.cancel();
+            // Cancelled commands finish promptly
+            (run.await, Some(CommandRunErrorType::TaskTimeout(limit, running)))
         }
     }
 }
 
-#[derive(Debug, Clone)]
-struct EnvVar(String, String);
+/// Limits how many of a task's commands may run at once
+///
+/// The semaphore is created on first use and shared by every run of the task,
+/// so the limit holds even when runs overlap
+#[derive(Debug, Default)]
+struct ConcurrencyLimit(OnceLock<Arc<Semaphore>>);
 
-impl ToString for EnvVar {
-    fn to_string(&self) -> String {
-        format!("{}={}", self.0, self.1)
+impl ConcurrencyLimit {
+    fn semaphore(&self, max: Option<NonZeroUsize>) -> Option<Arc<Semaphore>> {
+        max.map(|max| {
+            self.0
+                .get_or_init(|| Arc::new(Semaphore::new(max.get())))
+                .clone()
+        })
     }
 }
 
-impl<'de> Deserialize<'de> for EnvVar {
-    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
-        let s = String::deserialize(d)?;
-        match s.split_once('=') {
-            Some((key, val)) => {
-                if key.chars().any(|c| c.is_ascii_lowercase()) {
-                    warn!(%key, "Lowercase environment variable");
-                }
-                Ok(EnvVar(
-                    key.trim_end().to_owned(),
-                    val.trim_start().to_owned(),
-                ))
-            }
-            None => Err(D::Error::custom(
-                "incorrect environment variable syntax: no = in line",
-            )),
+/// Runs `fut` once a permit is available from `semaphore` (if there is one)
+async fn run_limited<F: Future>(
+    semaphore: Option<Arc<Semaphore>>,
+    fut: F,
+) -> F::Output {
+    let _permit = acquire(semaphore).await;
+    fut.await
+}
+
+/// Waits for a permit from `semaphore`, if there is one
+async fn acquire(
+    semaphore: Option<Arc<Semaphore>>,
+) -> Option<OwnedSemaphorePermit> {
+    match semaphore {
+        Some(semaphore) => Some(
+            semaphore
+                .acquire_owned()
+                .await
+                .expect("Concurrency limit semaphore closed"),
+        ),
+        None => None,
+    }
+}
+
+static GLOBAL_LIMIT: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+/// Limits how many commands may run at once across every task, on top of any
+/// task's own `max_concurrent`
+///
+/// Unbounded unless set, which can only be done once, subsequent calls will
+/// error.
+/// Only task runs started after the limit is set are bound by it.
+/// A command waiting for a permit counts as running for its task's own limit
+pub fn set_global_concurrency(
+    max: NonZeroUsize,
+) -> Result<(), GlobalConcurrencyError> {
+    GLOBAL_LIMIT
+        .set(Arc::new(Semaphore::new(max.get())))
+        .map_err(|_| GlobalConcurrencyError)
+}
+
+/// The shell used if a task doesn't specify one, `cmd` on Windows and `sh`
+/// everywhere else
+pub(crate) fn default_shell() -> String {
+    match cfg!(windows) {
+        true => String::from("cmd"),
+        false => String::from("sh"),
+    }
+}
+
+/// Adds the arguments that make `shell` run `script`, which are
+/// `-c [script]`, or `/C [script]` for Windows' `cmd`
+pub(crate) fn add_shell_script(
+    command: &mut std::process::Command,
+    shell: &str,
+    script: &str,
+) {
+    match is_cmd(shell) {
+        true => add_cmd_script(command, script),
+        false => {
+            command.arg("-c").arg(script);
         }
     }
 }
 
-#[derive(Debug)]
-struct MyCommand {
-    program: String,
-    args: Vec<String>,
+/// Whether `shell` is Windows' `cmd`, which takes `/C` instead of `-c`
+fn is_cmd(shell: &str) -> bool {
+    cfg!(windows) && names_cmd(shell)
 }
 
-impl<'de> Deserialize<'de> for MyCommand {
-    fn deserialize<D: Deserializer<'de>>(
-        deserializer: D,
-    ) -> Result<Self, D::Error> {
-        let input = String::deserialize(deserializer)?;
-        let command = match input.split_once(' ') {
-            Some((program, args)) => MyCommand {
-                program: program.to_owned(),
-                args: args.split_whitespace().map(ToOwned::to_owned).collect(),
-            },
-            None => MyCommand {
-                program: input,
-                args: Vec::new(),
-            },
-        };
-        Ok(command)
+/// Whether `shell` names `cmd`, given as either a Windows or Unix path
+fn names_cmd(shell: &str) -> bool {
+    let file = shell.rsplit(['/', '\\']).next().unwrap_or(shell);
+    let stem = match file.len().checked_sub(4) {
+        Some(dot) if file[dot..].eq_ignore_ascii_case(".exe") => &file[..dot],
+        _ => file,
+    };
+    stem.eq_ignore_ascii_case("cmd")
+}
+
+#[cfg(windows)]
+fn add_cmd_script(command: &mut std::process::Command, script: &str) {
+    use std::os::windows::process::CommandExt;
+    // cmd parses its own command line, so mustn't get the quoting that
+    // other programs' arguments do
+    command.arg("/C").raw_arg(script);
+}
+
+#[cfg(not(windows))]
+fn add_cmd_script(command: &mut std::process::Command, script: &str) {
+    command.arg("/C").arg(script);
+}
+
+/// Checks that `shell` exists locally, either as a path or on `PATH`
+pub(crate) fn check_shell(shell: &str) -> Result<(), ReadErrorType> {
+    match on_path(shell) {
+        true => Ok(()),
+        false => Err(ReadErrorType::ShellNotFound(shell.to_owned())),
     }
 }
 
-#[derive(Debug, Clone)]
-enum Host {
-    Local,
-    Remote(String),
+/// Checks that the shells commands run locally use instead of their task's
+/// exist, see [`check_shell`]
+pub(crate) fn check_command_shells<'a>(
+    commands: impl IntoIterator<Item = &'a Arc<TaskCommand>>,
+    task_hosts: &Hosts,
+) -> Result<(), ReadErrorType> {
+    commands
+        .into_iter()
+        .filter(|cmd| cmd.host.as_ref().unwrap_or(task_hosts).has_local())
+        .filter_map(|cmd| cmd.shell.program())
+        .try_for_each(check_shell)
 }
 
-impl Default for Host {
-    fn default() -> Self {
-        Host::Local
+/// Whether `program` exists locally, either as a path or on `PATH`
+pub(crate) fn on_path(program: &str) -> bool {
+    match program.contains(std::path::is_separator) {
+        true => is_program(Path::new(program)),
+        false => std::env::var_os("PATH")
+            .map(|path| {
+                std::env::split_paths(&path)
+                    .any(|dir| is_program(&dir.join(program)))
+            })
+            .unwrap_or_default(),
     }
 }
 
-impl<'de> Deserialize<'de> for Host {
-    fn deserialize<D: Deserializer<'de>>(
-        deserializer: D,
-    ) -> Result<Self, D::Error> {
-        use Host::*;
-        let s = String::deserialize(deserializer)?.to_ascii_lowercase();
-        match s.as_str() {
-            "local" | "localhost" | "127.0.0.1" | "::1" => Ok(Local),
-            _ => Ok(Remote(s)),
-        }
+/// The `PATH` a command with `env_vars` searches for programs on, its own if
+/// it sets one, otherwise Overseer's
+fn effective_path(env_vars: &[EnvVar]) -> String {
+    match env_vars.iter().find(|EnvVar(key, _)| key == "PATH") {
+        Some(EnvVar(_, path)) => path.clone(),
+        None => std::env::var("PATH").unwrap_or_default(),
+    }
+}
+
+/// Whether `path` is a file, or on Windows, whether it is with one of the
+/// extensions in `PATHEXT` (e.g. `cmd` for `cmd.exe`)
+fn is_program(path: &Path) -> bool {
+    if path.is_file() {
+        return true;
+    }
+    if !cfg!(windows) {
+        return false;
+    }
+    let extensions = std::env::var("PATHEXT")
+        .unwrap_or_else(|_| String::from(".COM;.EXE;.BAT;.CMD"));
+    extensions
+        .split(';')
+        .filter(|extension| !extension.is_empty())
+        .any(|extension| {
+            let mut with_extension = path.as_os_str().to_owned();
+            with_extension.push(extension);
+            Path::new(&with_extension).is_file()
+        })
+}
+
+/// Runs `commands` one after another, stopping at the first failure
+///
+/// The outcome of each command that succeeded is pushed to `outcomes`
+pub(crate) async fn run_sequentially(
+    commands: &Commands,
+    host: &Host,
+    ctx: &Arc<RunContext>,
+    outcomes: &mut Vec<CommandOutcome>,
+    errors: &mut Vec<CommandRunError>,
+) -> Result<(), CommandRunError> {
+    for cmd in commands.iter().cloned() {
+        let continue_on_error = cmd.continue_on_error;
+        match ctx.execute(cmd, host.remote()).await {
+            Ok(outcome) => outcomes.push(outcome),
+            // Cancellation stops everything, whatever the command allows
+            Err(why)
+                if continue_on_error
+                    && !matches!(
+                        why.r#type,
+                        CommandRunErrorType::Cancelled
+                    ) =>
+            {
+                warn!(
+                    command = %why.name,
+                    host = host.as_str(),
+                    "TaskCommand failed, continuing: {why}",
+                );
+                errors.push(why);
+            }
+            Err(why) => return Err(why),
+        }
+    }
+    Ok(())
+}
+
+/// Deserialises a task's commands, naming any that weren't given a name
+///
+/// Unnamed commands are named after their program and (1-based) position in
+/// the task, e.g. `cargo #2`
+pub(crate) fn deserialize_commands<'de, D>(
+    deserializer: D,
+) -> Result<Commands, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let commands = Vec::<TaskCommand>::deserialize(deserializer)?;
+    Ok(name_commands(commands))
+}
+
+/// Names any unnamed commands, and makes them ready for use in a task
+pub(crate) fn name_commands(mut commands: Vec<TaskCommand>) -> Commands {
+    commands
+        .iter_mut()
+        .enumerate()
+        .filter(|(_, cmd)| cmd.name.is_empty())
+        .for_each(|(index, cmd)| {
+            cmd.name = format!("{} #{}", cmd.inner.program, index + 1);
+        });
+    commands.into_iter().map(Arc::new).collect()
+}
+
+/// Deserialises a command's name, trimming it and rejecting it if empty
+///
+/// Only called if a name is given, unnamed commands are named later by
+/// [`name_commands`]
+fn deserialize_name<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    check_name(&name).map_err(D::Error::custom)
+}
+
+fn check_name(name: &str) -> Result<String, &'static str> {
+    match name.trim() {
+        "" => Err("command name must not be empty or only whitespace"),
+        trimmed => Ok(trimmed.to_owned()),
+    }
+}
+
+/// Ensures no two commands in a task share a name, so logs are unambiguous
+pub(crate) fn check_command_names(
+    commands: &Commands,
+) -> Result<(), ReadErrorType> {
+    let mut seen = HashSet::with_capacity(commands.len());
+    match commands.iter().find(|cmd| !seen.insert(cmd.name.as_str())) {
+        Some(duplicate) => {
+            Err(ReadErrorType::DuplicateCommandName(duplicate.name.clone()))
+        }
+        None => Ok(()),
+    }
+}
+
+/// A command in a task, as configured
+///
+/// Given to an [`Executor`] to execute
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename = "snake_case", deny_unknown_fields)]
+pub struct TaskCommand {
+    #[serde(default, deserialize_with = "deserialize_name")]
+    name: String,
+    #[serde(default, deserialize_with = "deserialize_vars")]
+    working_dir: Utf8PathBuf,
+    #[serde(default)]
+    env_vars: Vec<EnvVar>,
+    #[serde(default, deserialize_with = "deserialize_expanded_opt")]
+    env_file: Option<Utf8PathBuf>,
+    #[serde(default)]
+    stdin: Option<StdinSource>,
+    #[serde(default)]
+    stdout_file: Option<OutputPath>,
+    #[serde(default)]
+    stderr_file: Option<OutputPath>,
+    #[serde(default)]
+    append_output: bool,
+    #[serde(default)]
+    output_encoding: OutputEncoding,
+    #[serde(default)]
+    max_output_bytes: Option<NonZeroUsize>,
+    #[serde(default)]
+    shell: CommandShell,
+    #[serde(default = "default_success_codes")]
+    success_codes: Vec<i32>,
+    #[serde(default)]
+    expect_failure: bool,
+    #[serde(default)]
+    continue_on_error: bool,
+    #[serde(default)]
+    host: Option<Hosts>,
+    #[serde(default, with = "humantime_serde")]
+    timeout: Option<Duration>,
+    #[serde(default)]
+    memory_limit: Option<MemoryLimit>,
+    #[serde(default)]
+    cpu_quota: Option<CpuQuota>,
+    #[serde(default)]
+    nice: Option<Nice>,
+    #[serde(default)]
+    umask: Option<Umask>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(rename = "run")]
+    inner: MyCommand,
+}
+
+fn default_success_codes() -> Vec<i32> {
+    vec![0]
+}
+
+/// Whether a command is a shell invocation, from its `shell`, either `true`
+/// to use the task's shell, or the shell to use instead
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+enum CommandShell {
+    Enabled(bool),
+    Program(String),
+}
+
+impl CommandShell {
+    /// The shell used instead of the task's, if any
+    fn program(&self) -> Option<&str> {
+        match self {
+            CommandShell::Enabled(_) => None,
+            CommandShell::Program(shell) => Some(shell),
+        }
+    }
+}
+
+impl Default for CommandShell {
+    fn default() -> Self {
+        CommandShell::Enabled(false)
+    }
+}
+
+impl TaskCommand {
+    /// The command's name, unique within its task
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The program the command runs
+    pub fn program(&self) -> &str {
+        &self.inner.program
+    }
+
+    /// The arguments the program is run with
+    pub fn args(&self) -> &[String] {
+        &self.inner.args
+    }
+
+    /// The command as written, which is what's run for shell invocations
+    pub fn script(&self) -> &str {
+        &self.inner.raw
+    }
+
+    /// Whether the command is run as a shell invocation
+    pub fn is_shell(&self) -> bool {
+        matches!(
+            self.shell,
+            CommandShell::Enabled(true) | CommandShell::Program(_)
+        )
+    }
+
+    /// The shell the command's shell invocations use, its own if it has one,
+    /// otherwise `task_shell`
+    fn shell<'a>(&'a self, task_shell: &'a str) -> &'a str {
+        self.shell.program().unwrap_or(task_shell)
+    }
+
+    /// The most output kept from each of the command's streams at once
+    fn max_output_bytes(&self) -> usize {
+        self.max_output_bytes
+            .map_or(Tail::DEFAULT_LIMIT, NonZeroUsize::get)
+    }
+
+    #[instrument(
+        name = "command",
+        skip_all,
+        fields(command = %self.name, host = "localhost"),
+    )]
+    async fn run_local(
+        self: Arc<Self>,
+        ctx: Arc<RunContext>,
+    ) -> Result<CommandOutcome, CommandRunError> {
+        if ctx.cancel.is_cancelled() {
+            return Err(self.cancelled());
+        }
+        info!(%self.name, "TaskCommand triggered");
+        let quote = match is_cmd(self.shell(&ctx.shell)) {
+            true => cmd_quote,
+            false => shell_quote,
+        };
+        let run = self.rendered(&ctx, "localhost", quote)?;
+        let mut env_vars = self.env_vars().await?;
+        self.record_execution("localhost", &env_vars, &ctx);
+        if ctx.dry_run && self.is_shell() {
+            info!(
+                %self.name,
+                host = "localhost",
+                shell = %self.shell(&ctx.shell),
+                script = %run.raw,
+                env_vars = ?redacted(&env_vars),
+                working_dir = ?self.resolved_working_dir(ctx.base_dir.as_deref()),
+                "Dry run, not executing TaskCommand",
+            );
+            return Ok(self.dry_run_outcome());
+        } else if ctx.dry_run {
+            info!(
+                %self.name,
+                host = "localhost",
+                program = %run.program,
+                args = ?run.args,
+                env_vars = ?redacted(&env_vars),
+                working_dir = ?self.resolved_working_dir(ctx.base_dir.as_deref()),
+                "Dry run, not executing TaskCommand",
+            );
+            return Ok(self.dry_run_outcome());
+        }
+        let secrets = self.resolve_secrets(&mut env_vars).await?;
+        ctx.paced(|| self.cancelled()).await?;
+        let _permit = tokio::select! {
+            permit = acquire(ctx.global_limit.clone()) => permit,
+            _ = ctx.cancel.cancelled() => return Err(self.cancelled()),
+        };
+        let identity = self.identity().map_err(|why| CommandRunError {
+            name: self.name.clone(),
+            r#type: CommandRunErrorType::Identity(why),
+        })?;
+        let mut command =
+            tokio::process::Command::from(match self.is_shell() {
+                true => {
+                    let shell = self.shell(&ctx.shell);
+                    let mut command = self.limited_command(shell, identity);
+                    add_shell_script(&mut command, shell, &run.raw);
+                    command
+                }
+                false => {
+                    let mut command =
+                        self.limited_command(&run.program, identity);
+                    command.args(&run.args);
+                    command
+                }
+            });
+        command.envs(env_vars.iter().map(|EnvVar(k, v)| (k, v)));
+        let working_dir = self
+            .resolved_working_dir(ctx.base_dir.as_deref())
+            .map(|dir| expand_home(&dir));
+        if let Some(dir) = &working_dir {
+            command.current_dir(dir);
+        }
+        let input = self.stdin_bytes().await?;
+        if input.is_some() {
+            command.stdin(Stdio::piped());
+        }
+        let streaming = ctx.output.is_some();
+        if let Some(path) = &self.stdout_file {
+            command.stdout(self.open_output(path, &ctx).await?);
+        } else if streaming {
+            command.stdout(Stdio::piped());
+        }
+        // stderr is always captured (unless redirected), so failures can
+        // explain themselves
+        match &self.stderr_file {
+            Some(path) => command.stderr(self.open_output(path, &ctx).await?),
+            None => command.stderr(Stdio::piped()),
+        };
+        let started = Instant::now();
+        let mut stderr_tail = Tail::new(self.max_output_bytes());
+        // This is ugly but without making an async closure I can't use
+        // and_then
+        command.kill_on_drop(true);
+        let exit = match command.spawn() {
+            // Could get command output by changing to wait_with_output
+            Ok(mut child) => {
+                let feed = feed_stdin(child.stdin.take(), input.as_deref());
+                let stdout = forward_lines(
+                    child.stdout.take(),
+                    ctx.output.as_ref(),
+                    &self.name,
+                    OutputStream::Stdout,
+                    self.output_encoding,
+                    None,
+                );
+                let stderr = forward_lines(
+                    child.stderr.take(),
+                    ctx.output.as_ref(),
+                    &self.name,
+                    OutputStream::Stderr,
+                    self.output_encoding,
+                    Some(&mut stderr_tail),
+                );
+                let finished = tokio::select! {
+                    finished = async {
+                        tokio::join!(feed, stdout, stderr, child.wait())
+                    } => Some(finished),
+                    _ = ctx.cancel.cancelled() => None,
+                };
+                let (fed, stdout, stderr, exit) = match finished {
+                    Some(finished) => finished,
+                    None => {
+                        warn!(%self.name, "TaskCommand cancelled, killing it");
+                        if let Err(why) = child.kill().await {
+                            error!(%self.name, "Failed to kill TaskCommand: {why}");
+                        }
+                        return Err(self.cancelled());
+                    }
+                };
+                match fed.and(stdout).and(stderr).and(exit) {
+                    Ok(exit) => exit,
+                    Err(why) => {
+                        return Err(CommandRunError {
+                            name: self.name.clone(),
+                            r#type: CommandRunErrorType::Io(why),
+                        })
+                    }
+                }
+            }
+            // A missing working directory is also reported as not found
+            Err(why)
+                if why.kind() == io::ErrorKind::NotFound
+                    && working_dir.as_ref().is_none_or(|dir| dir.is_dir()) =>
+            {
+                let program = match self.is_shell() {
+                    true => self.shell(&ctx.shell),
+                    false => &run.program,
+                };
+                let searched_path =
+                    match program.contains(std::path::is_separator) {
+                        true => None,
+                        false => Some(effective_path(&env_vars)),
+                    };
+                error!(%self.name, %program, "Program not found");
+                return Err(self.not_found(program, searched_path));
+            }
+            Err(why) => {
+                return Err(CommandRunError {
+                    name: self.name.clone(),
+                    r#type: CommandRunErrorType::Io(why),
+                })
+            }
+        };
+        metrics().record_exit_code(&ctx.task_name, &self.name, exit.code());
+        stderr_tail.scrub(&secrets);
+        let stderr = self.stderr_file.is_none().then_some(stderr_tail);
+        self.check_exit(exit.code(), stderr, Some(started.elapsed()))
+    }
+
+    #[instrument(
+        name = "command",
+        skip_all,
+        fields(command = %self.name, host = destination.as_ref()),
+    )]
+    async fn run_remote(
+        self: Arc<Self>,
+        destination: impl AsRef<str>,
+        ctx: Arc<RunContext>,
+    ) -> Result<CommandOutcome, CommandRunError> {
+        if ctx.cancel.is_cancelled() {
+            return Err(self.cancelled());
+        }
+        match self.resolved_working_dir(ctx.base_dir.as_deref()) {
+            Some(dir) if !dir.is_absolute() => {
+                warn!(%self.name, working_dir = ?dir, "Working directory for remote command is not absolute");
+            }
+            _ => {}
+        }
+        let env_vars = self.env_vars().await?;
+        self.record_execution(destination.as_ref(), &env_vars, &ctx);
+        // Secrets are sent over stdin rather than in the invocation
+        let (mut secret_vars, env_vars): (Vec<_>, Vec<_>) =
+            env_vars.into_iter().partition(references_secret);
+        // Dry runs log secret references, never the secrets themselves
+        let secrets = match ctx.dry_run {
+            true => Secrets::default(),
+            false => self.resolve_secrets(&mut secret_vars).await?,
+        };
+        let run = self.rendered(&ctx, destination.as_ref(), shell_quote)?;
+        let invocation =
+            self.remote_invocation(&run, &env_vars, &secret_vars, &ctx);
+        let logged = secrets.scrub(&self.remote_invocation(
+            &run,
+            &redacted(&env_vars),
+            &secret_vars,
+            &ctx,
+        ));
+        trace!(invocation = %logged, "Built remote command");
+        if ctx.dry_run {
+            info!(
+                %self.name,
+                host = destination.as_ref(),
+                shell = %self.shell(&ctx.shell),
+                invocation = %logged,
+                "Dry run, not executing remote TaskCommand",
+            );
+            return Ok(self.dry_run_outcome());
+        }
+        ctx.paced(|| self.cancelled()).await?;
+        let _permit = tokio::select! {
+            permit = acquire(ctx.global_limit.clone()) => permit,
+            _ = ctx.cancel.cancelled() => return Err(self.cancelled()),
+        };
+        let result = self
+            .run_over_ssh(
+                destination.as_ref(),
+                invocation,
+                &secret_vars,
+                &secrets,
+                &ctx,
+            )
+            .await;
+        match result {
+            // The remote shell exits with 127 when it can't find a program,
+            // which is only unambiguous if it was running just the one
+            Err(CommandRunError {
+                r#type: CommandRunErrorType::ExitStatus(127, Some(stderr), ..),
+                ..
+            }) if !self.is_shell() && stderr.contains("not found") => {
+                // Otherwise the remote host's own PATH, which isn't known
+                let searched_path = env_vars
+                    .iter()
+                    .find(|EnvVar(key, _)| key == "PATH")
+                    .map(|EnvVar(_, path)| path.clone());
+                Err(self.not_found(&run.program, searched_path))
+            }
+            result => result,
+        }
+    }
+
+    /// Runs `invocation` on `destination` over SSH
+    #[cfg(unix)]
+    async fn run_over_ssh(
+        &self,
+        destination: &str,
+        invocation: String,
+        secret_vars: &[EnvVar],
+        secrets: &Secrets,
+        ctx: &Arc<RunContext>,
+    ) -> Result<CommandOutcome, CommandRunError> {
+        use tokio::io::AsyncBufReadExt;
+
+        let ssh = ctx.ssh_options(destination);
+        let connect = ssh.connect(destination);
+        let session = tokio::select! {
+            session = connect => session,
+            _ = ctx.cancel.cancelled() => return Err(self.cancelled()),
+        }
+        .map_err(|why| CommandRunError {
+            name: self.name.clone(),
+            r#type: CommandRunErrorType::Connect(destination.to_owned(), why),
+        })?;
+        let mut command = session.command(self.shell(&ctx.shell));
+        command.arg("-c").arg(invocation);
+
+        let mut input = self.stdin_bytes().await?;
+        // The invocation reads the secrets before anything else
+        if !secret_vars.is_empty() {
+            let mut fed = secrets_input(secret_vars);
+            fed.extend(input.unwrap_or_default());
+            input = Some(fed);
+        }
+        if input.is_some() {
+            command.stdin(openssh::Stdio::piped());
+        }
+        // Always piped, as the invocation reports its process group there
+        command.stdout(openssh::Stdio::piped());
+        // stderr is always captured (unless redirected), so failures can
+        // explain themselves
+        if self.stderr_file.is_none() {
+            command.stderr(openssh::Stdio::piped());
+        }
+        let started = Instant::now();
+        let mut child =
+            command.spawn().await.map_err(|ssh_err| CommandRunError {
+                name: self.name.clone(),
+                r#type: CommandRunErrorType::Ssh(ssh_err.to_string()),
+            })?;
+        let feed = feed_stdin(child.stdin().take(), input.as_deref());
+        let process_group = OnceLock::new();
+        let stdout_pipe = child.stdout().take();
+        let stdout = async {
+            let mut reader = match stdout_pipe {
+                Some(pipe) => tokio::io::BufReader::new(pipe),
+                None => return Ok(()),
+            };
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            if let Ok(id) = line.trim().parse::<u32>() {
+                let _ = process_group.set(id);
+            }
+            match ctx.output.is_some() && self.stdout_file.is_none() {
+                true => {
+                    forward_lines(
+                        Some(reader),
+                        ctx.output.as_ref(),
+                        &self.name,
+                        OutputStream::Stdout,
+                        self.output_encoding,
+                        None,
+                    )
+                    .await
+                }
+                false => {
+                    let mut sink = tokio::io::sink();
+                    tokio::io::copy(&mut reader, &mut sink).await.map(drop)
+                }
+            }
+        };
+        let mut stderr_tail = Tail::new(self.max_output_bytes());
+        let stderr = forward_lines(
+            child.stderr().take(),
+            ctx.output.as_ref(),
+            &self.name,
+            OutputStream::Stderr,
+            self.output_encoding,
+            Some(&mut stderr_tail),
+        );
+        let finished = tokio::select! {
+            finished = async {
+                tokio::join!(feed, stdout, stderr, child.wait())
+            } => Some(finished),
+            _ = ctx.cancel.cancelled() => None,
+        };
+        let (fed, stdout, stderr, exit) = match finished {
+            Some(finished) => finished,
+            None => {
+                // Closing the session leaves the remote process running, so
+                // its whole process group is killed first
+                warn!(%self.name, "TaskCommand cancelled, killing it");
+                let killed = match process_group.get() {
+                    Some(id) => session
+                        .command("kill")
+                        .args(["-KILL", "--", &format!("-{id}")])
+                        .status()
+                        .await
+                        .map_err(|why| why.to_string())
+                        .and_then(|status| match status.success() {
+                            true => Ok(()),
+                            false => Err(format!("kill exited with {status}")),
+                        }),
+                    None => Err(String::from("its process group isn't known")),
+                };
+                if let Err(why) = killed {
+                    error!(%self.name, "Failed to kill TaskCommand: {why}");
+                }
+                if let Err(why) = session.close().await {
+                    error!(%self.name, "Failed to close SSH session: {why}");
+                }
+                return Err(self.cancelled());
+            }
+        };
+        let exit = match (fed.and(stdout).and(stderr), exit) {
+            (Ok(()), Ok(exit)) => exit,
+            (Err(why), _) => {
+                return Err(CommandRunError {
+                    name: self.name.clone(),
+                    r#type: CommandRunErrorType::Io(why),
+                })
+            }
+            (_, Err(ssh_err)) => {
+                return Err(CommandRunError {
+                    name: self.name.clone(),
+                    r#type: CommandRunErrorType::Ssh(ssh_err.to_string()),
+                })
+            }
+        };
+        metrics().record_exit_code(&ctx.task_name, &self.name, exit.code());
+        stderr_tail.scrub(secrets);
+        self.check_exit(exit.code(), Some(stderr_tail), Some(started.elapsed()))
+    }
+
+    /// SSH needs Unix, and tasks with remote hosts don't load elsewhere
+    #[cfg(not(unix))]
+    async fn run_over_ssh(
+        &self,
+        destination: &str,
+        _: String,
+        _: &[EnvVar],
+        _: &Secrets,
+        _: &Arc<RunContext>,
+    ) -> Result<CommandOutcome, CommandRunError> {
+        Err(CommandRunError {
+            name: self.name.clone(),
+            r#type: CommandRunErrorType::Connect(
+                destination.to_owned(),
+                String::from("remote hosts are only supported on Unix"),
+            ),
+        })
+    }
+
+    /// Builds the shell invocation running `run` on the remote host
+    fn remote_invocation(
+        &self,
+        run: &MyCommand,
+        env_vars: &[EnvVar],
+        secret_vars: &[EnvVar],
+        ctx: &RunContext,
+    ) -> String {
+        /*
+        Making the openssh::Command - a short story
+        The problem is that unlike regular Command, we can't specify working
+        directory and environment variables easily. Running the invocation as
+        `[shell] -c` gets us most of the way, but we have to declare all
+        environment variables manually, and cd into the working directory.
+        This leads to a lot of hassle
+         */
+        // Report the process group before anything else, so the command can
+        // be killed if cancelled
+        let mut invocation =
+            String::from("echo \"$(ps -o pgid= -p $$ 2>/dev/null)\"; ");
+        invocation.push_str(&read_secrets(secret_vars));
+        // Add export command for environment variables, if any
+        if !env_vars.is_empty() {
+            invocation.push_str("export");
+            env_vars.iter().for_each(|EnvVar(key, value)| {
+                invocation.push(' ');
+                invocation.push_str(key);
+                invocation.push('=');
+                invocation.push_str(&shell_quote(value));
+            });
+            invocation.push_str(" && ");
+        }
+        // cd into custom working directory, if specified
+        if let Some(dir) = self.resolved_working_dir(ctx.base_dir.as_deref()) {
+            invocation.push_str("cd ");
+            invocation.push_str(&quote_remote_path(&dir));
+            invocation.push_str(" && ");
+        }
+        // add the command with its arguments, quoted so the shell doesn't
+        // interpret them, unless a shell script was wanted. Scripts are
+        // grouped so any redirection applies to the whole thing
+        if self.is_shell() {
+            invocation.push_str("{ ");
+            invocation.push_str(&run.raw);
+            invocation.push_str("\n}");
+        } else {
+            invocation.push_str(&shell_quote(&run.program));
+            run.args.iter().for_each(|arg| {
+                invocation.push(' ');
+                invocation.push_str(&shell_quote(arg));
+            });
+        }
+        // Redirect output on the remote host, if requested
+        let redirect = if self.append_output { ">>" } else { ">" };
+        if let Some(path) = &self.stdout_file {
+            let path = path.expand(ctx, &self.name);
+            invocation.push_str(&format!(" {redirect} "));
+            invocation.push_str(&quote_remote_path(&path));
+        }
+        if let Some(path) = &self.stderr_file {
+            let path = path.expand(ctx, &self.name);
+            invocation.push_str(&format!(" 2{redirect} "));
+            invocation.push_str(&quote_remote_path(&path));
+        }
+        invocation
+    }
+
+    /// The command's environment variables, including any from its
+    /// `env_file`, with inline ones taking precedence
+    async fn env_vars(&self) -> Result<Vec<EnvVar>, CommandRunError> {
+        let path = match &self.env_file {
+            Some(path) => path,
+            None => return Ok(self.env_vars.clone()),
+        };
+        let contents =
+            tokio::fs::read_to_string(path).await.map_err(|why| {
+                CommandRunError {
+                    name: self.name.clone(),
+                    r#type: CommandRunErrorType::EnvFile(
+                        path.clone(),
+                        why.to_string(),
+                    ),
+                }
+            })?;
+        let mut env_vars = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let EnvVar(key, val) =
+                EnvVar::parse(line).map_err(|why| CommandRunError {
+                    name: self.name.clone(),
+                    r#type: CommandRunErrorType::EnvFile(
+                        path.clone(),
+                        format!("line {}: {why}", index + 1),
+                    ),
+                })?;
+            env_vars.retain(|EnvVar(k, _)| *k != key);
+            env_vars.push(EnvVar(key, unquote(&val).to_owned()));
+        }
+        env_vars.retain(|EnvVar(k, _)| {
+            !self.env_vars.iter().any(|EnvVar(inline, _)| inline == k)
+        });
+        env_vars.extend(self.env_vars.iter().cloned());
+        Ok(env_vars)
+    }
+
+    /// The command with its `{{placeholders}}` substituted, for running on
+    /// `host`, values being quoted with `quote` in shell scripts
+    fn rendered(
+        &self,
+        ctx: &RunContext,
+        host: &str,
+        quote: fn(&str) -> String,
+    ) -> Result<MyCommand, CommandRunError> {
+        let vars = TemplateVars::new(ctx, host);
+        self.inner
+            .render(&vars, quote)
+            .map_err(|why| CommandRunError {
+                name: self.name.clone(),
+                r#type: CommandRunErrorType::Template(why),
+            })
+    }
+
+    /// Records how the command is executed on `host`, given its environment
+    /// before secrets are resolved
+    fn record_execution(
+        &self,
+        host: &str,
+        env_vars: &[EnvVar],
+        ctx: &RunContext,
+    ) {
+        let execution = CommandExecution {
+            command: self.name.clone(),
+            host: host.to_owned(),
+            working_dir: self.working_dir_opt().map(ToOwned::to_owned),
+            resolved_working_dir: self
+                .resolved_working_dir(ctx.base_dir.as_deref()),
+            env: redacted(env_vars)
+                .into_iter()
+                .map(|EnvVar(key, val)| (key, val))
+                .collect(),
+        };
+        ctx.executions().push(execution);
+    }
+
+    async fn stdin_bytes(&self) -> Result<Option<Vec<u8>>, CommandRunError> {
+        match &self.stdin {
+            Some(source) => {
+                source
+                    .read()
+                    .await
+                    .map(Some)
+                    .map_err(|why| CommandRunError {
+                        name: self.name.clone(),
+                        r#type: CommandRunErrorType::Io(why),
+                    })
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn open_output(
+        &self,
+        path: &OutputPath,
+        ctx: &RunContext,
+    ) -> Result<std::fs::File, CommandRunError> {
+        let path = expand_home(&path.expand(ctx, &self.name));
+        let mut options = tokio::fs::OpenOptions::new();
+        options.create(true);
+        if self.append_output {
+            options.append(true);
+        } else {
+            options.write(true).truncate(true);
+        }
+        match options.open(&path).await {
+            Ok(file) => Ok(file.into_std().await),
+            Err(why) => {
+                error!(%self.name, %path, "Couldn't open output file");
+                Err(CommandRunError {
+                    name: self.name.clone(),
+                    r#type: CommandRunErrorType::Io(why),
+                })
+            }
+        }
+    }
+
+    /// Decides from its exit code whether the command succeeded, going by
+    /// its `success_codes` and `expect_failure`
+    ///
+    /// Being killed by a signal (no exit code) is always a failure.
+    /// Any captured `stderr` is given according to the `output_encoding`.
+    /// `elapsed` is how long it ran, if it was actually run
+    fn check_exit(
+        &self,
+        exit_code: Option<i32>,
+        stderr: Option<Tail>,
+        elapsed: Option<Duration>,
+    ) -> Result<CommandOutcome, CommandRunError> {
+        let elapsed = elapsed.map(round_millis);
+        let logged = elapsed.map(tracing::field::debug);
+        let stderr_truncated = stderr.as_ref().is_some_and(Tail::truncated);
+        let stderr = stderr
+            .map(|stderr| self.output_encoding.encode(stderr.into_inner()));
+        let (stderr, stderr_raw) = match stderr {
+            Some((stderr, raw)) => (Some(stderr), raw),
+            None => (None, None),
+        };
+        let r#type = match exit_code {
+            Some(code) => {
+                let success_code = self.success_codes.contains(&code);
+                match (success_code, self.expect_failure) {
+                    (true, false) | (false, true) => {
+                        info!(%self.name, elapsed = logged, "TaskCommand completed successfully");
+                        return Ok(CommandOutcome {
+                            command: self.name.clone(),
+                            exit_code: Some(code),
+                            stderr,
+                            stderr_raw,
+                            stderr_truncated,
+                            elapsed,
+                        });
+                    }
+                    (false, false) => {
+                        error!(%self.name, elapsed = logged, "TaskCommand failed with exit code {code}");
+                        CommandRunErrorType::ExitStatus(
+                            code,
+                            stderr,
+                            stderr_truncated,
+                            elapsed,
+                        )
+                    }
+                    (true, true) => {
+                        error!(%self.name, elapsed = logged, "TaskCommand succeeded with exit code {code}, but was expected to fail");
+                        CommandRunErrorType::UnexpectedSuccess(code, elapsed)
+                    }
+                }
+            }
+            None => {
+                error!(%self.name, elapsed = logged, "TaskCommand was killed by a signal");
+                CommandRunErrorType::Killed(elapsed)
+            }
+        };
+        Err(CommandRunError {
+            name: self.name.clone(),
+            r#type,
+        })
+    }
+
+    fn dry_run_outcome(&self) -> CommandOutcome {
+        CommandOutcome {
+            command: self.name.clone(),
+            exit_code: None,
+            stderr: None,
+            stderr_raw: None,
+            stderr_truncated: false,
+            elapsed: None,
+        }
+    }
+
+    fn not_found(
+        &self,
+        program: &str,
+        searched_path: Option<String>,
+    ) -> CommandRunError {
+        CommandRunError {
+            name: self.name.clone(),
+            r#type: CommandRunErrorType::ProgramNotFound {
+                program: program.to_owned(),
+                searched_path,
+            },
+        }
+    }
+
+    fn cancelled(&self) -> CommandRunError {
+        CommandRunError {
+            name: self.name.clone(),
+            r#type: CommandRunErrorType::Cancelled,
+        }
+    }
+
+    fn working_dir_opt(&self) -> Option<&Utf8Path> {
+        if self.working_dir != Utf8PathBuf::default() {
+            Some(self.working_dir.as_path())
+        } else {
+            None
+        }
+    }
+
+    /// Where the command is run: its `working_dir` if that's absolute,
+    /// otherwise joined onto the task's `base_dir` (if set), otherwise
+    /// relative to Overseer's working directory
+    fn resolved_working_dir(
+        &self,
+        base_dir: Option<&Utf8Path>,
+    ) -> Option<Utf8PathBuf> {
+        match (self.working_dir_opt(), base_dir) {
+            (Some(dir), _) if dir.is_absolute() || is_home_relative(dir) => {
+                Some(dir.to_owned())
+            }
+            (Some(dir), Some(base)) => Some(base.join(dir)),
+            (None, Some(base)) => Some(base.to_owned()),
+            (dir, None) => dir.map(ToOwned::to_owned),
+        }
+    }
+
+    /// The files and directories the command refers to that don't exist
+    ///
+    /// `env_file` and `stdin` files are always checked, as they're read
+    /// locally, the working directory only if the command is run locally
+    fn missing_paths(
+        &self,
+        base_dir: Option<&Utf8Path>,
+        local: bool,
+    ) -> Vec<ReadErrorType> {
+        let mut missing = Vec::new();
+        if let Some(env_file) = &self.env_file {
+            if !env_file.exists() {
+                missing.push(ReadErrorType::MissingPath(
+                    "env_file",
+                    env_file.clone(),
+                ));
+            }
+        }
+        if let Some(StdinSource::File(file)) = &self.stdin {
+            if !file.exists() {
+                missing.push(ReadErrorType::MissingPath("stdin", file.clone()));
+            }
+        }
+        if local {
+            if let Some(dir) = self.resolved_working_dir(base_dir) {
+                if !expand_home(&dir).is_dir() {
+                    missing
+                        .push(ReadErrorType::MissingPath("working_dir", dir));
+                }
+            }
+        }
+        missing
+    }
+}
+
+/// Builds a single command of a task, for use with [`CronTaskBuilder`] or
+/// [`FileEventTaskBuilder`]
+///
+/// Each method corresponds to the command option of the same name in a task
+/// file (see the [task file reference](crate#task-files))
+#[derive(Debug)]
+pub struct CommandBuilder {
+    inner: TaskCommand,
+    invalid: Option<String>,
+}
+
+impl CommandBuilder {
+    /// Starts building a command that runs `run`
+    ///
+    /// `run` is interpreted in the same way as a task file's `run` option
+    pub fn new(run: impl Into<String>) -> Self {
+        let run = run.into();
+        CommandBuilder {
+            invalid: check_template(&run).err(),
+            inner: TaskCommand {
+                inner: MyCommand::from(run),
+                success_codes: default_success_codes(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Sets the name of the command, which must be unique within its task
+    ///
+    /// Surrounding whitespace is trimmed, and the name must not be empty
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        match check_name(&name.into()) {
+            Ok(name) => self.inner.name = name,
+            Err(why) => self.invalid = Some(why.to_owned()),
+        }
+        self
+    }
+
+    /// Sets the directory the command is run in
+    pub fn working_dir(mut self, dir: impl Into<Utf8PathBuf>) -> Self {
+        self.inner.working_dir = dir.into();
+        self
+    }
+
+    /// Adds an environment variable for the command
+    pub fn env_var(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.inner.env_vars.push(EnvVar(key.into(), value.into()));
+        self
+    }
+
+    /// Loads environment variables from a dotenv file each time the command
+    /// is run
+    pub fn env_file(mut self, path: impl Into<Utf8PathBuf>) -> Self {
+        self.inner.env_file = Some(path.into());
+        self
+    }
+
+    /// Runs the command on `host` instead of the task's hosts
+    pub fn host(mut self, host: impl AsRef<str>) -> Self {
+        self.inner.host = Some(Hosts::from_iter([host]));
+        self
+    }
+
+    /// Runs the command on each of `hosts` instead of the task's hosts
+    pub fn hosts<S: AsRef<str>>(
+        mut self,
+        hosts: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.inner.host = Some(Hosts::from_iter(hosts));
+        self
+    }
+
+    /// Kills the command, failing it, if it's still running after `limit`
+    pub fn timeout(mut self, limit: Duration) -> Self {
+        self.inner.timeout = Some(limit);
+        self
+    }
+
+    /// Writes `input` to the command's stdin
+    pub fn stdin(mut self, input: impl Into<String>) -> Self {
+        self.inner.stdin = Some(StdinSource::Literal(input.into()));
+        self
+    }
+
+    /// Writes the contents of the file at `path` to the command's stdin
+    pub fn stdin_file(mut self, path: impl Into<Utf8PathBuf>) -> Self {
+        self.inner.stdin = Some(StdinSource::File(path.into()));
+        self
+    }
+
+    /// Sets how the command's captured output is represented, lossy UTF-8
+    /// by default
+    pub fn output_encoding(mut self, encoding: OutputEncoding) -> Self {
+        self.inner.output_encoding = encoding;
+        self
+    }
+
+    /// Sets the most of the command's stderr kept, 4 MiB by default,
+    /// dropping older output past it
+    pub fn max_output_bytes(mut self, bytes: NonZeroUsize) -> Self {
+        self.inner.max_output_bytes = Some(bytes);
+        self
+    }
+
+    /// Writes the command's stdout to a file, which may contain placeholders
+    pub fn stdout_file(mut self, path: impl Into<String>) -> Self {
+        match OutputPath::try_from(path.into()) {
+            Ok(path) => self.inner.stdout_file = Some(path),
+            Err(why) => self.invalid = Some(why),
+        }
+        self
+    }
+
+    /// Caps the command's memory, e.g. `512M`
+    pub fn memory_limit(mut self, limit: impl Into<String>) -> Self {
+        match MemoryLimit::try_from(limit.into()) {
+            Ok(limit) => self.inner.memory_limit = Some(limit),
+            Err(why) => self.invalid = Some(why),
+        }
+        self
+    }
+
+    /// Caps the command's CPU usage, as a percentage of one CPU
+    pub fn cpu_quota(mut self, percent: u32) -> Self {
+        match CpuQuota::new(percent) {
+            Ok(quota) => self.inner.cpu_quota = Some(quota),
+            Err(why) => self.invalid = Some(why),
+        }
+        self
+    }
+
+    /// Sets the command's niceness, from -20 to 19 (Unix only)
+    pub fn nice(mut self, nice: i8) -> Self {
+        match Nice::try_from(i64::from(nice)) {
+            Ok(nice) => self.inner.nice = Some(nice),
+            Err(why) => self.invalid = Some(why),
+        }
+        self
+    }
+
+    /// Sets the command's umask, e.g. `0o027` (Unix only)
+    pub fn umask(mut self, mask: u32) -> Self {
+        match Umask::new(mask) {
+            Ok(mask) => self.inner.umask = Some(mask),
+            Err(why) => self.invalid = Some(why),
+        }
+        self
+    }
+
+    /// Runs the command as `user`, given by name or uid (Unix only)
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.inner.user = Some(user.into());
+        self
+    }
+
+    /// Runs the command as `group`, given by name or gid (Unix only)
+    pub fn group(mut self, group: impl Into<String>) -> Self {
+        self.inner.group = Some(group.into());
+        self
+    }
+
+    /// Writes the command's stderr to a file, which may contain placeholders
+    pub fn stderr_file(mut self, path: impl Into<String>) -> Self {
+        match OutputPath::try_from(path.into()) {
+            Ok(path) => self.inner.stderr_file = Some(path),
+            Err(why) => self.invalid = Some(why),
+        }
+        self
+    }
+
+    /// Appends to output files rather than truncating them
+    pub fn append_output(mut self, append: bool) -> Self {
+        self.inner.append_output = append;
+        self
+    }
+
+    /// Passes `run` verbatim to the task's shell instead of running it
+    /// directly
+    pub fn shell(mut self, shell: bool) -> Self {
+        self.inner.shell = CommandShell::Enabled(shell);
+        self
+    }
+
+    /// Passes `run` verbatim to `shell` (e.g. `bash`) instead of running it
+    /// directly, rather than the task's shell
+    pub fn shell_program(mut self, shell: impl Into<String>) -> Self {
+        self.inner.shell = CommandShell::Program(shell.into());
+        self
+    }
+
+    /// Sets the exit codes that count as success, replacing the default of
+    /// just `0`
+    pub fn success_codes(
+        mut self,
+        codes: impl IntoIterator<Item = i32>,
+    ) -> Self {
+        self.inner.success_codes = codes.into_iter().collect();
+        self
+    }
+
+    /// Inverts whether the command succeeded, so it fails if it exits with
+    /// one of its success codes, and succeeds otherwise
+    pub fn expect_failure(mut self, expect_failure: bool) -> Self {
+        self.inner.expect_failure = expect_failure;
+        self
+    }
+
+    /// Lets the command fail without stopping the commands after it in a
+    /// `before` or `after` hook
+    pub fn continue_on_error(mut self, continue_on_error: bool) -> Self {
+        self.inner.continue_on_error = continue_on_error;
+        self
+    }
+
+    pub(crate) fn finish(self) -> Result<TaskCommand, ReadErrorType> {
+        match self.invalid {
+            Some(why) => Err(ReadErrorType::InvalidCommand(
+                self.inner.inner.program,
+                why,
+            )),
+            None => Ok(self.inner),
+        }
+    }
+}
+
+/// Finishes building each command of a task, naming any unnamed ones
+pub(crate) fn finish_commands(
+    commands: Vec<CommandBuilder>,
+) -> Result<Commands, ReadErrorType> {
+    let commands = commands
+        .into_iter()
+        .map(CommandBuilder::finish)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(name_commands(commands))
+}
+
+/// What to do when an environment variable's key contains lowercase letters
+///
+/// Set with [`LowercaseEnvPolicy::set`], and checked whenever task files or
+/// `env_file`s are read
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum LowercaseEnvPolicy {
+    /// Allow it silently
+    Ignore,
+    /// Allow it, logging a warning
+    #[default]
+    Warn,
+    /// Refuse to load the task (or run the command, for `env_file`s)
+    Error,
+}
+
+static LOWERCASE_ENV_POLICY: AtomicU8 =
+    AtomicU8::new(LowercaseEnvPolicy::Warn as u8);
+
+impl LowercaseEnvPolicy {
+    /// Sets the policy for all tasks loaded from now on, `Warn` by default
+    pub fn set(self) {
+        LOWERCASE_ENV_POLICY.store(self as u8, Ordering::Relaxed);
+    }
+
+    /// The policy currently in effect
+    pub fn current() -> Self {
+        use LowercaseEnvPolicy::*;
+        match LOWERCASE_ENV_POLICY.load(Ordering::Relaxed) {
+            x if x == Ignore as u8 => Ignore,
+            x if x == Error as u8 => Error,
+            _ => Warn,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct EnvVar(String, String);
+
+impl EnvVar {
+    /// Parses a `KEY=value` line, checking the key against the
+    /// [`LowercaseEnvPolicy`]
+    fn parse(s: &str) -> Result<Self, String> {
+        match s.split_once('=') {
+            Some((key, val)) => {
+                let key = key.trim_end();
+                if key.chars().any(|c| c.is_ascii_lowercase()) {
+                    match LowercaseEnvPolicy::current() {
+                        LowercaseEnvPolicy::Ignore => {}
+                        LowercaseEnvPolicy::Warn => {
+                            warn!(%key, "Lowercase environment variable")
+                        }
+                        LowercaseEnvPolicy::Error => {
+                            return Err(format!(
+                                "environment variable {key:?} contains \
+                                lowercase letters"
+                            ))
+                        }
+                    }
+                }
+                Ok(EnvVar(key.to_owned(), val.trim_start().to_owned()))
+            }
+            None => Err(String::from(
+                "incorrect environment variable syntax: no = in line",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EnvVar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.0, self.1)
+    }
+}
+
+impl<'de> Deserialize<'de> for EnvVar {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        EnvVar::parse(&s).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for EnvVar {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+/// Where a command's standard input comes from
+///
+/// A plain string is written to stdin verbatim.
+/// A map with a `file` key reads that file (on the machine running Overseer,
+/// even for remote commands) and writes its contents instead.
+/// A plain string is never treated as a path, even if a file by that name
+/// exists, so `file` must be used explicitly to read from disk
+#[derive(Debug)]
+enum StdinSource {
+    Literal(String),
+    File(Utf8PathBuf),
+}
+
+impl StdinSource {
+    async fn read(&self) -> io::Result<Vec<u8>> {
+        match self {
+            StdinSource::Literal(s) => Ok(s.clone().into_bytes()),
+            StdinSource::File(path) => tokio::fs::read(path).await,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StdinSource {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged, deny_unknown_fields)]
+        enum Repr {
+            Literal(String),
+            File {
+                #[serde(deserialize_with = "deserialize_expanded")]
+                file: Utf8PathBuf,
+            },
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Literal(s) => Ok(StdinSource::Literal(s)),
+            Repr::File { file } => Ok(StdinSource::File(file)),
+        }
+    }
+}
+
+impl Serialize for StdinSource {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        #[serde(untagged)]
+        enum Repr<'a> {
+            Literal(&'a str),
+            File { file: &'a Utf8Path },
+        }
+
+        match self {
+            StdinSource::Literal(s) => Repr::Literal(s),
+            StdinSource::File(file) => Repr::File { file },
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A file path for command output, which may contain placeholders
+///
+/// Placeholders are checked when the task is loaded, and expanded each time
+/// the command is run
+#[derive(Debug)]
+struct OutputPath(String);
+
+impl OutputPath {
+    const TASK: &'static str = "${OVERSEER_TASK}";
+    const COMMAND: &'static str = "${OVERSEER_COMMAND}";
+    const TIMESTAMP: &'static str = "${OVERSEER_TIMESTAMP}";
+    const PLACEHOLDERS: [&'static str; 3] =
+        [Self::TASK, Self::COMMAND, Self::TIMESTAMP];
+
+    /// The path for a run of `ctx`'s task, with names made safe to use as
+    /// (part of) a single path component
+    fn expand(&self, ctx: &RunContext, command_name: &str) -> Utf8PathBuf {
+        let timestamp = ctx
+            .started
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.0
+            .replace(Self::TASK, &Self::component(&ctx.task_name))
+            .replace(Self::COMMAND, &Self::component(command_name))
+            .replace(Self::TIMESTAMP, &timestamp.to_string())
+            .into()
+    }
+
+    /// `name` with path separators and `..` replaced by underscores, so it
+    /// can't write outside the directory it's substituted into
+    fn component(name: &str) -> String {
+        name.replace(['/', '\\'], "_").replace("..", "__")
+    }
+}
+
+impl TryFrom<String> for OutputPath {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut rest = s.as_str();
+        while let Some(start) = rest.find("${OVERSEER_") {
+            let placeholder = match rest[start..].find('}') {
+                Some(end) => &rest[start..=start + end],
+                None => &rest[start..],
+            };
+            if !Self::PLACEHOLDERS.contains(&placeholder) {
+                return Err(format!(
+                    "unknown placeholder {placeholder} in output path"
+                ));
+            }
+            rest = &rest[start + placeholder.len()..];
+        }
+        Ok(OutputPath(s))
+    }
+}
+
+impl<'de> Deserialize<'de> for OutputPath {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        OutputPath::try_from(s).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for OutputPath {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+/// Removes one pair of matching quotes surrounding a dotenv value, if present
+fn unquote(val: &str) -> &str {
+    ['"', '\'']
+        .iter()
+        .find_map(|&q| val.strip_prefix(q).and_then(|v| v.strip_suffix(q)))
+        .unwrap_or(val)
+}
+
+/// `duration` to the millisecond, so it's logged readably
+pub(crate) fn round_millis(duration: Duration) -> Duration {
+    Duration::from_millis(duration.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+/// Quotes a string so it's passed as a single word to a POSIX shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Quotes a string so it's passed as a single argument by Windows' `cmd`
+fn cmd_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+/// Quotes a path for a POSIX shell, leaving a leading `~` (or `~user`)
+/// unquoted so the shell still expands it
+fn quote_remote_path(path: &Utf8Path) -> String {
+    let path = path.as_str();
+    let (home, rest) = match path.find('/') {
+        Some(slash) => path.split_at(slash),
+        None => (path, ""),
+    };
+    let is_user = |c: char| c.is_ascii_alphanumeric() || "._-".contains(c);
+    match home.strip_prefix('~') {
+        Some(user) if user.chars().all(is_user) => match rest {
+            "" | "/" => path.to_owned(),
+            rest => format!("{home}/{}", shell_quote(&rest[1..])),
+        },
+        _ => shell_quote(path),
+    }
+}
+
+/// Writes `input` to a child's stdin (if both are present), then closes it
+///
+/// A child closing its stdin before reading everything isn't considered an
+/// error, as plenty of programs stop reading once they have what they need
+async fn feed_stdin<W>(pipe: Option<W>, input: Option<&[u8]>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if let (Some(mut pipe), Some(input)) = (pipe, input) {
+        match pipe.write_all(input).await {
+            Err(why) if why.kind() == io::ErrorKind::BrokenPipe => {
+                trace!("Child closed stdin before all input was written");
+            }
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone)]
+struct MyCommand {
+    program: String,
+    args: Vec<String>,
+    /// The command as written, for running in a shell
+    raw: String,
+}
+
+impl From<String> for MyCommand {
+    fn from(input: String) -> Self {
+        match input.split_once(' ') {
+            Some((program, args)) => MyCommand {
+                program: program.to_owned(),
+                args: args.split_whitespace().map(ToOwned::to_owned).collect(),
+                raw: input.clone(),
+            },
+            None => MyCommand {
+                program: input.clone(),
+                args: Vec::new(),
+                raw: input,
+            },
+        }
+    }
+}
+
+impl MyCommand {
+    /// The command with its placeholders substituted from `vars`, values
+    /// being quoted with `quote` in the shell script
+    fn render(
+        &self,
+        vars: &TemplateVars,
+        quote: fn(&str) -> String,
+    ) -> Result<MyCommand, String> {
+        if !self.raw.contains("{{") {
+            return Ok(self.clone());
+        }
+        let value = |name: &str| vars.get(name);
+        Ok(MyCommand {
+            program: render(&self.program, value)?,
+            args: self
+                .args
+                .iter()
+                .map(|arg| render(arg, value))
+                .collect::<Result<_, _>>()?,
+            raw: render(&self.raw, |name| quote(&vars.get(name)))?,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for MyCommand {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let run = String::deserialize(deserializer)?;
+        check_template(&run).map_err(D::Error::custom)?;
+        Ok(MyCommand::from(run))
+    }
+}
+
+impl Serialize for MyCommand {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.raw)
+    }
+}
+
+/// A host commands are run on
+///
+/// In a task file, either its address, a map of its `address` and `ssh`
+/// options (see [`SshOptions`]), which are ignored for the local host, or a
+/// map naming a local Docker `container`
+#[derive(Debug, Clone, Default)]
+enum Host {
+    #[default]
+    Local,
+    Remote(String, SshOptions),
+    /// A running container on the local Docker instance, with how it's shown
+    /// in logs and reports (`container:[name]`)
+    Container {
+        name: String,
+        label: String,
+    },
+}
+
+impl From<&str> for Host {
+    fn from(s: &str) -> Self {
+        Host::with_ssh(s, SshOptions::default())
+    }
+}
+
+impl<'de> Deserialize<'de> for Host {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Address(String),
+            Detailed(Detailed),
+            Container(Container),
+        }
+
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Detailed {
+            address: String,
+            #[serde(default)]
+            ssh: SshOptions,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Container {
+            container: String,
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Address(address) => Ok(Host::from(address.as_str())),
+            Repr::Detailed(Detailed { address, ssh }) => {
+                Ok(Host::with_ssh(&address, ssh))
+            }
+            Repr::Container(Container { container }) => {
+                Ok(Host::container(container))
+            }
+        }
+    }
+}
+
+impl Serialize for Host {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Detailed<'a> {
+            address: &'a str,
+            ssh: &'a SshOptions,
+        }
+
+        #[derive(Serialize)]
+        struct Container<'a> {
+            container: &'a str,
+        }
+
+        match self {
+            Host::Remote(address, ssh) if *ssh != SshOptions::default() => {
+                Detailed { address, ssh }.serialize(serializer)
+            }
+            Host::Container { name, .. } => {
+                Container { container: name }.serialize(serializer)
+            }
+            _ => serializer.serialize_str(self.as_str()),
+        }
+    }
+}
+
+impl Host {
+    fn with_ssh(address: &str, ssh: SshOptions) -> Self {
+        let address = address.to_ascii_lowercase();
+        match address.as_str() {
+            "local" | "localhost" | "127.0.0.1" | "::1" => Host::Local,
+            _ => Host::Remote(address, ssh),
+        }
+    }
+
+    fn container(name: String) -> Self {
+        let label = format!("container:{name}");
+        Host::Container { name, label }
+    }
+
+    /// The host as it's shown in logs and reports
+    fn as_str(&self) -> &str {
+        match self {
+            Host::Local => "localhost",
+            Host::Remote(addr, _) => addr,
+            Host::Container { label, .. } => label,
+        }
+    }
+
+    /// The host as it's given to executors: the remote host's address, a
+    /// container's label, or `None` if it's local
+    fn remote(&self) -> Option<&str> {
+        match self {
+            Host::Local => None,
+            _ => Some(self.as_str()),
+        }
+    }
+}
+
+/// The hosts a task's commands are run on, each getting the whole batch
+///
+/// In a task file, either a single host or a list of them
+#[derive(Debug, Clone)]
+struct Hosts(Vec<Host>);
+
+impl Default for Hosts {
+    fn default() -> Self {
+        Hosts(vec![Host::Local])
+    }
+}
+
+impl<'de> Deserialize<'de> for Hosts {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            One(Host),
+            Many(Vec<Host>),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::One(host) => Ok(Hosts(vec![host])),
+            Repr::Many(hosts) if hosts.is_empty() => {
+                Err(D::Error::custom("host list must not be empty"))
+            }
+            Repr::Many(hosts) => Ok(Hosts(hosts)),
+        }
+    }
+}
+
+impl Serialize for Hosts {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match self.0.as_slice() {
+            [host] => host.serialize(serializer),
+            hosts => hosts.serialize(serializer),
+        }
+    }
+}
+
+impl<S: AsRef<str>> FromIterator<S> for Hosts {
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Self {
+        Hosts(iter.into_iter().map(|s| Host::from(s.as_ref())).collect())
+    }
+}
+
+impl Hosts {
+    fn contains(&self, host: &Host) -> bool {
+        self.iter().any(|h| h.as_str() == host.as_str())
+    }
+
+    /// Every host `commands` run on, given these are the task's hosts, in
+    /// the order they're first used
+    fn targets<'a>(
+        &self,
+        commands: impl IntoIterator<Item = &'a Arc<TaskCommand>>,
+    ) -> Hosts {
+        let mut targets = Vec::<Host>::new();
+        for cmd in commands {
+            for host in cmd.host.as_ref().unwrap_or(self).iter() {
+                if !targets.iter().any(|h| h.as_str() == host.as_str()) {
+                    targets.push(host.clone());
+                }
+            }
+        }
+        match targets.is_empty() {
+            true => self.clone(),
+            false => Hosts(targets),
+        }
+    }
+
+    /// Just the container `name`
+    fn container(name: String) -> Self {
+        Hosts(vec![Host::container(name)])
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Host> {
+        self.0.iter()
+    }
+
+    fn has_local(&self) -> bool {
+        self.iter().any(|host| matches!(host, Host::Local))
+    }
+
+    /// Whether any host isn't local, including containers
+    fn has_remote(&self) -> bool {
+        self.iter().any(|host| !matches!(host, Host::Local))
+    }
+
+    /// How to connect to each of the remote hosts, by address
+    fn ssh_options(&self) -> HashMap<String, SshOptions> {
+        self.iter()
+            .filter_map(|host| match host {
+                Host::Remote(addr, ssh) => Some((addr.clone(), ssh.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The names of the containers, by label
+    fn containers(&self) -> HashMap<String, String> {
+        self.iter()
+            .filter_map(|host| match host {
+                Host::Container { name, label } => {
+                    Some((label.clone(), name.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Ensures there's at least one host, none is given twice, so each has
+    /// its own entry in a run report, and remote hosts are only given on Unix
+    fn check(&self) -> Result<(), ReadErrorType> {
+        if self.0.is_empty() {
+            return Err(ReadErrorType::NoHosts);
+        }
+        if !cfg!(unix) {
+            if let Some(Host::Remote(host, _)) =
+                self.iter().find(|host| matches!(host, Host::Remote(..)))
+            {
+                return Err(ReadErrorType::UnsupportedRemoteHost(host.clone()));
+            }
+        }
+        let mut seen = HashSet::with_capacity(self.0.len());
+        match self.iter().find(|host| !seen.insert(host.as_str())) {
+            Some(duplicate) => {
+                Err(ReadErrorType::DuplicateHost(duplicate.as_str().to_owned()))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Checks every SSH control directory exists
+    fn check_environment(&self) -> Result<(), ReadErrorType> {
+        let control_dirs = self.iter().filter_map(|host| match host {
+            Host::Remote(_, ssh) => ssh.control_directory(),
+            _ => None,
+        });
+        for dir in control_dirs {
+            if !dir.is_dir() {
+                return Err(ReadErrorType::MissingPath(
+                    "SSH control directory",
+                    dir.to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    /// An empty directory for `test` to write files in, which is left behind
+    pub(crate) fn scratch_dir(test: &str) -> Utf8PathBuf {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!("overseer-{}-{test}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs `yaml` as a cron task, collecting its streamed output
+    async fn streamed(yaml: &str) -> Vec<OutputLine> {
+        let task = Arc::new(CronTask::from_yaml_str(yaml).unwrap());
+        let (tx, mut rx) = mpsc::channel(16);
+        task.run_streaming(tx).await.unwrap();
+        let mut lines = Vec::new();
+        while let Some(line) = rx.recv().await {
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// The context of a run of the task `name`, run with `sh`
+    pub(crate) fn context(name: &str) -> Arc<RunContext> {
+        RunContext::new(
+            name,
+            false,
+            None,
+            "sh",
+            None,
+            &Hosts::default(),
+            None,
+            CancellationToken::new(),
+            &TaskExecutor::default(),
+        )
+    }
+
+    #[test]
+    fn remote_paths_keep_home_unquoted() {
+        let quoted = |path: &str| quote_remote_path(Utf8Path::new(path));
+        assert_eq!(quoted("~"), "~");
+        assert_eq!(quoted("~/"), "~/");
+        assert_eq!(quoted("~/my logs"), "~/'my logs'");
+        assert_eq!(quoted("~deploy/it's"), r"~deploy/'it'\''s'");
+        assert_eq!(quoted("/srv/my app"), "'/srv/my app'");
+        assert_eq!(quoted("~$(reboot)/x"), "'~$(reboot)/x'");
+    }
+
+    #[test]
+    fn remote_invocation_quotes_working_dir_and_env() {
+        let command = CommandBuilder::new("ls -l")
+            .working_dir("/srv/my app; reboot")
+            .finish()
+            .unwrap();
+        let ctx = context("remote");
+        let run = command.rendered(&ctx, "remote", shell_quote).unwrap();
+        let env_vars = [EnvVar("GREETING".into(), "hello there".into())];
+        assert_eq!(
+            command.remote_invocation(&run, &env_vars, &[], &ctx),
+            "echo \"$(ps -o pgid= -p $$ 2>/dev/null)\"; \
+             export GREETING='hello there' && cd '/srv/my app; reboot' && \
+             'ls' '-l'",
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn remote_secrets_are_read_from_stdin() {
+        let command = CommandBuilder::new("true").finish().unwrap();
+        let ctx = context("remote");
+        let run = command.rendered(&ctx, "remote", shell_quote).unwrap();
+        let secret = "hunter2 \\n\n$(reboot)\n";
+        let secret_vars = [EnvVar("PASSWORD".into(), secret.into())];
+        let invocation =
+            command.remote_invocation(&run, &[], &secret_vars, &ctx);
+        assert!(!invocation.contains("hunter2"));
+
+        // Run it locally, as sh would on the remote host
+        let mut child = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{invocation} && printf %s \"$PASSWORD\""))
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(&secrets_input(&secret_vars)).await.unwrap();
+        drop(stdin);
+        let output = child.wait_with_output().await.unwrap();
+        let output = String::from_utf8(output.stdout).unwrap();
+        // After the process group
+        let (_, output) = output.split_once('\n').unwrap();
+        assert_eq!(output, secret);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn local_stderr_is_captured_without_streaming() {
+        let command = CommandBuilder::new("echo oops >&2; exit 3")
+            .shell(true)
+            .finish()
+            .unwrap();
+        let err = Arc::new(command)
+            .run_local(context("stderr"))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err.r#type,
+                CommandRunErrorType::ExitStatus(3, Some(stderr), ..)
+                    if stderr == "oops"
+            ),
+            "{err}",
+        );
+    }
+
+    #[test]
+    fn output_path_names_stay_in_their_directory() {
+        let path = OutputPath(
+            "logs/${OVERSEER_TASK}/${OVERSEER_COMMAND}.log".to_owned(),
+        );
+        let ctx = context("../../etc");
+        assert_eq!(
+            path.expand(&ctx, "cron.d/x"),
+            "logs/______etc/cron.d_x.log"
+        );
+        assert_eq!(
+            path.expand(&ctx, r"..\..\boot"),
+            "logs/______etc/______boot.log"
+        );
+        let ctx = context("nightly.backup");
+        assert_eq!(path.expand(&ctx, "dump"), "logs/nightly.backup/dump.log");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shell_command_pipes_output() {
+        let lines = streamed(
+            "name: pipe
+schedule: '@daily'
+commands:
+  - run: printf 'one\\ntwo\\n' | tr a-z A-Z
+    shell: true",
+        )
+        .await;
+        let lines = lines.iter().map(|l| l.line.as_str()).collect::<Vec<_>>();
+        assert_eq!(lines, ["ONE", "TWO"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn exec_command_passes_pipe_as_argument() {
+        let lines = streamed(
+            "name: pipe
+schedule: '@daily'
+commands:
+  - run: echo one | tr a-z A-Z",
+        )
+        .await;
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].line, "one | tr a-z A-Z");
+    }
+
+    #[tokio::test]
+    async fn inline_env_vars_override_env_file() {
+        let dir = scratch_dir("env-precedence");
+        let env_file = dir.join(".env");
+        std::fs::write(
+            &env_file,
+            "# comment\nSHARED=file\nexport FROM_FILE='quoted'\n",
+        )
+        .unwrap();
+        let command = CommandBuilder::new("env")
+            .env_file(&env_file)
+            .env_var("SHARED", "inline")
+            .finish()
+            .unwrap();
+        let mut env_vars = command
+            .env_vars()
+            .await
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        env_vars.sort();
+        assert_eq!(env_vars, ["FROM_FILE=quoted", "SHARED=inline"]);
+    }
+
+    #[tokio::test]
+    async fn missing_env_file_fails_command() {
+        let dir = scratch_dir("env-missing");
+        let command = CommandBuilder::new("env")
+            .env_file(dir.join("missing.env"))
+            .finish()
+            .unwrap();
+        let err = command.env_vars().await.unwrap_err();
+        assert!(
+            matches!(&err.r#type, CommandRunErrorType::EnvFile(path, _) if path.ends_with("missing.env")),
+            "{err}",
+        );
+    }
+
+    #[test]
+    fn cmd_is_recognised_by_name() {
+        assert!(names_cmd("cmd"));
+        assert!(names_cmd("CMD.EXE"));
+        assert!(names_cmd(r"C:\Windows\System32\cmd.exe"));
+        assert!(names_cmd("/mnt/c/Windows/System32/cmd.exe"));
+        assert!(!names_cmd("/bin/sh"));
+        assert!(!names_cmd("cmdx.exe"));
+        assert!(!names_cmd(r"C:\cmd\pwsh.exe"));
+    }
+
+    #[test]
+    fn cmd_script_follows_slash_c() {
+        let mut command = std::process::Command::new("cmd");
+        add_cmd_script(&mut command, "echo \"a b\" & dir");
+        let args = command.get_args().collect::<Vec<_>>();
+        assert_eq!(args, ["/C", "echo \"a b\" & dir"]);
+    }
+
+    #[test]
+    fn cmd_quote_doubles_quotes() {
+        assert_eq!(cmd_quote("plain"), r#""plain""#);
+        assert_eq!(cmd_quote("two words"), r#""two words""#);
+        assert_eq!(cmd_quote(r#"say "hi""#), r#""say ""hi""""#);
+        let command = CommandBuilder::new("echo {{task_name}}")
+            .shell(true)
+            .finish()
+            .unwrap();
+        let run = command
+            .rendered(&context("nightly backup"), "localhost", cmd_quote)
+            .unwrap();
+        assert_eq!(run.raw, r#"echo "nightly backup""#);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn run_report_shows_how_commands_ran() {
+        let dir = scratch_dir("executions");
+        std::fs::create_dir(dir.join("logs")).unwrap();
+        let task = CronTask::from_yaml_str(&format!(
+            "name: executions
+schedule: '@daily'
+base_dir: '{dir}'
+commands:
+  - name: listed
+    run: ls
+    working_dir: logs
+    env_vars:
+      - STAGE=prod
+      - API_TOKEN=hunter2"
+        ))
+        .unwrap();
+        let task = Arc::new(task);
+        task.clone().run().await.unwrap();
+        let report = task.last_run().unwrap();
+        let env = [("STAGE", "prod"), ("API_TOKEN", "***")]
+            .map(|(key, val)| (key.to_owned(), val.to_owned()));
+        assert_eq!(
+            report.executions,
+            [CommandExecution {
+                command: "listed".into(),
+                host: "localhost".into(),
+                working_dir: Some("logs".into()),
+                resolved_working_dir: Some(dir.join("logs")),
+                env: env.into(),
+            }],
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_program_reports_path_searched() {
+        let task = CronTask::from_yaml_str(
+            "name: missing
+schedule: '@daily'
+commands:
+  - name: missing
+    run: overseer-no-such-program --help
+    env_vars:
+      - PATH=/nonexistent/overseer/bin",
+        )
+        .unwrap();
+        let errors = Arc::new(task).run().await.unwrap_err();
+        let [err] = &errors[..] else {
+            panic!("{errors:?}");
+        };
+        assert!(
+            matches!(
+                &err.r#type,
+                CommandRunErrorType::ProgramNotFound { program, searched_path }
+                    if program == "overseer-no-such-program"
+                        && searched_path.as_deref() == Some("/nonexistent/overseer/bin"),
+            ),
+            "{err}",
+        );
+        assert!(
+            err.to_string().contains(
+                r#"not found (searched PATH "/nonexistent/overseer/bin")"#
+            ),
+            "{err}",
+        );
+    }
+
+    /// Whether a live process was started with `arg` as an argument
+    #[cfg(target_os = "linux")]
+    fn process_running_with(arg: &str) -> bool {
+        std::fs::read_dir("/proc").unwrap().flatten().any(|entry| {
+            std::fs::read(entry.path().join("cmdline")).is_ok_and(|cmdline| {
+                cmdline.split(|b| *b == 0).any(|a| a == arg.as_bytes())
+            })
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn dropping_run_kills_command() {
+        // Unique to this test, so it's only this test's child that's found
+        let arg = format!("{}.875", 30 + std::process::id() % 1000);
+        let task = CronTask::from_yaml_str(&format!(
+            "name: dropped
+schedule: '@daily'
+commands:
+  - run: sleep {arg}"
+        ))
+        .unwrap();
+        let run = tokio::spawn(Arc::new(task).run());
+        let started = tokio::time::Instant::now();
+        while !process_running_with(&arg) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        run.abort();
+        assert!(run.await.unwrap_err().is_cancelled());
+        let dropped = tokio::time::Instant::now();
+        while process_running_with(&arg) {
+            assert!(
+                dropped.elapsed() < Duration::from_secs(5),
+                "sleep {arg} outlived its run",
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn umask_applies_to_command() {
+        let lines = streamed(
+            "name: masked
+schedule: '@daily'
+commands:
+  - run: umask
+    shell: true
+    umask: '027'",
+        )
+        .await;
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].line, "0027");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn task_timeout_kills_commands_with_one_error() {
+        // Unique to this test, so it's only this test's children that are found
+        let arg = format!("{}.839", 30 + std::process::id() % 1000);
+        let task = CronTask::from_yaml_str(&format!(
+            "name: slow
+schedule: '@daily'
+task_timeout: 500ms
+commands:
+  - name: first
+    run: sleep {arg}
+  - name: second
+    run: sleep {arg}"
+        ))
+        .unwrap();
+        let started = tokio::time::Instant::now();
+        let errors = Arc::new(task).run().await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert_eq!(errors[0].name, "slow");
+        assert!(
+            matches!(
+                &errors[0].r#type,
+                CommandRunErrorType::TaskTimeout(limit, running)
+                    if *limit == Duration::from_millis(500)
+                        && running == &["first", "second"]
+            ),
+            "{}",
+            errors[0],
+        );
+        let timed_out = tokio::time::Instant::now();
+        while process_running_with(&arg) {
+            assert!(
+                timed_out.elapsed() < Duration::from_secs(5),
+                "sleep {arg} outlived its run",
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn defaults_fill_in_unset_command_settings() {
+        let defaults: CommandDefaults = serde_yaml::from_str(
+            "working_dir: /srv
+env_vars:
+  - SHARED=default
+  - ONLY_DEFAULT=yes
+host: web1
+timeout: 30s",
+        )
+        .unwrap();
+        let mut plain: TaskCommand = serde_yaml::from_str("run: ls").unwrap();
+        let mut own: TaskCommand = serde_yaml::from_str(
+            "run: ls
+working_dir: /tmp
+env_vars:
+  - SHARED=own
+host: web2
+timeout: 1s",
+        )
+        .unwrap();
+        defaults.apply_to(&mut plain);
+        defaults.apply_to(&mut own);
+        let env = |cmd: &TaskCommand| {
+            cmd.env_vars
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        let hosts = |cmd: &TaskCommand| {
+            cmd.host
+                .iter()
+                .flat_map(Hosts::iter)
+                .map(|host| host.as_str().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(plain.working_dir, "/srv");
+        assert_eq!(env(&plain), ["SHARED=default", "ONLY_DEFAULT=yes"]);
+        assert_eq!(hosts(&plain), ["web1"]);
+        assert_eq!(plain.timeout, Some(Duration::from_secs(30)));
+
+        assert_eq!(own.working_dir, "/tmp");
+        assert_eq!(env(&own), ["ONLY_DEFAULT=yes", "SHARED=own"]);
+        assert_eq!(hosts(&own), ["web2"]);
+        assert_eq!(own.timeout, Some(Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn command_host_replaces_task_hosts() {
+        let executor = Arc::new(MockExecutor::new());
+        let task = CronTask::from_yaml_str(
+            "name: hosts
+schedule: '@daily'
+host: [web1, web2]
+commands:
+  - name: everywhere
+    run: uptime
+  - name: db only
+    run: pg_dump
+    host: db",
+        )
+        .unwrap()
+        .with_executor(executor.clone());
+        Arc::new(task).run().await.unwrap();
+        let mut executed = executor.executed();
+        executed.sort();
+        let expected = [
+            ("db only", "db"),
+            ("everywhere", "web1"),
+            ("everywhere", "web2"),
+        ]
+        .map(|(name, host)| (name.to_owned(), host.to_owned()));
+        assert_eq!(executed, expected);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_timeout_kills_command() {
+        let task = CronTask::from_yaml_str(
+            "name: timeout
+schedule: '@daily'
+commands:
+  - name: slow
+    run: sleep 5
+    timeout: 100ms",
+        )
+        .unwrap();
+        let started = std::time::Instant::now();
+        let errors = Arc::new(task).run().await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert!(
+            matches!(
+                errors[..],
+                [CommandRunError {
+                    r#type: CommandRunErrorType::Timeout(_),
+                    ..
+                }]
+            ),
+            "{errors:?}",
+        );
     }
 }
@@ -0,0 +1,355 @@
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use crate::{is_root, on_path, Identity, ReadErrorType, TaskCommand};
+
+/// The most memory a command may use, as understood by systemd's
+/// `MemoryMax`: a number of bytes with an optional `K`, `M`, `G`, or `T`
+/// suffix, or a percentage of physical memory
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct MemoryLimit(String);
+
+impl TryFrom<String> for MemoryLimit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let digits = s.strip_suffix(['K', 'M', 'G', 'T', '%']).unwrap_or(&s);
+        match !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+            true => Ok(MemoryLimit(s)),
+            false => Err(format!(
+                "invalid memory limit {s:?}, expected bytes with an optional \
+                K, M, G, or T suffix (e.g. 512M), or a percentage"
+            )),
+        }
+    }
+}
+
+impl From<MemoryLimit> for String {
+    fn from(limit: MemoryLimit) -> Self {
+        limit.0
+    }
+}
+
+/// The share of one CPU a command may use, as a percentage, which can be
+/// over 100% to allow more than one CPU
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct CpuQuota(u32);
+
+impl CpuQuota {
+    pub(crate) fn new(percent: u32) -> Result<Self, String> {
+        match percent {
+            0 => Err(String::from("CPU quota must be more than 0%")),
+            percent => Ok(CpuQuota(percent)),
+        }
+    }
+}
+
+impl TryFrom<String> for CpuQuota {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.strip_suffix('%')
+            .and_then(|percent| percent.parse().ok())
+            .ok_or_else(|| {
+                format!("invalid CPU quota {s:?}, expected a percentage")
+            })
+            .and_then(CpuQuota::new)
+    }
+}
+
+impl From<CpuQuota> for String {
+    fn from(quota: CpuQuota) -> Self {
+        quota.to_string()
+    }
+}
+
+impl fmt::Display for CpuQuota {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+/// A command's niceness, from -20 (scheduled most favourably) to 19 (least)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "i64", into = "i64")]
+pub(crate) struct Nice(i8);
+
+impl TryFrom<i64> for Nice {
+    type Error = String;
+
+    fn try_from(nice: i64) -> Result<Self, Self::Error> {
+        match nice {
+            -20..=19 => Ok(Nice(nice as i8)),
+            _ => Err(format!("invalid nice {nice}, expected -20 to 19")),
+        }
+    }
+}
+
+impl From<Nice> for i64 {
+    fn from(Nice(nice): Nice) -> Self {
+        nice.into()
+    }
+}
+
+/// The file mode creation mask for a command, given in octal (e.g. `"027"`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct Umask(u32);
+
+impl Umask {
+    pub(crate) fn new(mask: u32) -> Result<Self, String> {
+        match mask {
+            0..=0o777 => Ok(Umask(mask)),
+            _ => Err(format!("invalid umask {mask:o}, expected at most 777")),
+        }
+    }
+}
+
+impl TryFrom<String> for Umask {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let digits = s.strip_prefix("0o").unwrap_or(&s);
+        if digits.is_empty()
+            || digits.len() > 4
+            || !digits.bytes().all(|b| matches!(b, b'0'..=b'7'))
+        {
+            return Err(format!(
+                "invalid umask {s:?}, expected up to four octal digits"
+            ));
+        }
+        // Can't fail, having checked the digits
+        let mask = u32::from_str_radix(digits, 8).unwrap();
+        Umask::new(mask)
+            .map_err(|_| format!("invalid umask {s:?}, expected at most 777"))
+    }
+}
+
+impl From<Umask> for String {
+    fn from(Umask(mask): Umask) -> Self {
+        format!("{mask:03o}")
+    }
+}
+
+/// Ensures any resource limits set on `commands` can be enforced on this
+/// kind of host, as they're only enforced locally on Linux
+pub(crate) fn check_limits<'a>(
+    commands: impl IntoIterator<Item = &'a std::sync::Arc<TaskCommand>>,
+    remote: bool,
+) -> Result<(), ReadErrorType> {
+    let limited = match commands.into_iter().find(|cmd| cmd.has_limits()) {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    let unsupported = if remote {
+        Some("they're only supported on localhost")
+    } else if !cfg!(target_os = "linux") {
+        Some("they're only supported on Linux")
+    } else {
+        None
+    };
+    match unsupported {
+        Some(why) => {
+            Err(ReadErrorType::UnsupportedLimits(limited.name.clone(), why))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Ensures any resource limits set on `commands` can be enforced on this
+/// machine, which needs it booted with systemd, with `systemd-run`
+/// available
+pub(crate) fn check_limits_enforceable<'a>(
+    commands: impl IntoIterator<Item = &'a std::sync::Arc<TaskCommand>>,
+) -> Result<(), ReadErrorType> {
+    let limited = match commands.into_iter().find(|cmd| cmd.has_limits()) {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    let unsupported = if !on_path("systemd-run") {
+        Some("systemd-run couldn't be found to enforce them")
+    } else if !Utf8Path::new("/run/systemd/system").is_dir() {
+        // The same check as sd_booted(3)
+        Some("systemd isn't running to enforce them")
+    } else {
+        None
+    };
+    match unsupported {
+        Some(why) => {
+            Err(ReadErrorType::UnsupportedLimits(limited.name.clone(), why))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Ensures any niceness set on `commands` can be applied, which is only
+/// supported locally on Unix
+pub(crate) fn check_nice<'a>(
+    commands: impl IntoIterator<Item = &'a std::sync::Arc<TaskCommand>>,
+    remote: bool,
+) -> Result<(), ReadErrorType> {
+    let niced = match commands.into_iter().find(|cmd| cmd.nice.is_some()) {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    match local_unix_only(remote) {
+        Some(why) => {
+            Err(ReadErrorType::UnsupportedNice(niced.name.clone(), why))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Ensures any umask set on `commands` can be applied, which is only
+/// supported locally on Unix
+pub(crate) fn check_umask<'a>(
+    commands: impl IntoIterator<Item = &'a std::sync::Arc<TaskCommand>>,
+    remote: bool,
+) -> Result<(), ReadErrorType> {
+    let masked = match commands.into_iter().find(|cmd| cmd.umask.is_some()) {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    match local_unix_only(remote) {
+        Some(why) => {
+            Err(ReadErrorType::UnsupportedUmask(masked.name.clone(), why))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Why a setting that's only applied to local processes on Unix can't be
+fn local_unix_only(remote: bool) -> Option<&'static str> {
+    if remote {
+        Some("it's only supported on localhost")
+    } else if !cfg!(unix) {
+        Some("it's only supported on Unix")
+    } else {
+        None
+    }
+}
+
+impl TaskCommand {
+    pub(crate) fn has_limits(&self) -> bool {
+        self.memory_limit.is_some() || self.cpu_quota.is_some()
+    }
+
+    /// Builds a command running `program` within a transient systemd scope
+    /// enforcing the command's limits, or just `program` if it has none,
+    /// as `identity` if given, and with the command's niceness and umask
+    pub(crate) fn limited_command(
+        &self,
+        program: &str,
+        identity: Option<Identity>,
+    ) -> Command {
+        let mut command = match self.has_limits() {
+            true => self.scoped_command(program, identity),
+            false => {
+                let mut command = Command::new(program);
+                #[cfg(unix)]
+                if let Some(Identity { uid, gid }) = identity {
+                    command.uid(uid).gid(gid);
+                }
+                command
+            }
+        };
+        #[cfg(unix)]
+        if let Some(Nice(nice)) = self.nice {
+            // SAFETY: setpriority is async-signal-safe, and nothing is
+            // allocated
+            unsafe {
+                command.pre_exec(move || set_nice(nice));
+            }
+        }
+        #[cfg(unix)]
+        if let Some(Umask(mask)) = self.umask {
+            // SAFETY: umask is async-signal-safe, can't fail, and nothing is
+            // allocated
+            unsafe {
+                command.pre_exec(move || {
+                    libc::umask(mask as libc::mode_t);
+                    Ok(())
+                });
+            }
+        }
+        command
+    }
+
+    fn scoped_command(
+        &self,
+        program: &str,
+        identity: Option<Identity>,
+    ) -> Command {
+        let mut command = Command::new("systemd-run");
+        command.args(["--scope", "--quiet", "--collect"]);
+        if !is_root() {
+            command.arg("--user");
+        }
+        // systemd-run needs root to create the scope, so switches identity
+        // itself
+        if let Some(Identity { uid, gid }) = identity {
+            command
+                .arg(format!("--uid={uid}"))
+                .arg(format!("--gid={gid}"));
+        }
+        if let Some(MemoryLimit(limit)) = &self.memory_limit {
+            command.arg("-p").arg(format!("MemoryMax={limit}"));
+        }
+        if let Some(quota) = &self.cpu_quota {
+            command.arg("-p").arg(format!("CPUQuota={quota}"));
+        }
+        command.arg("--").arg(program);
+        command
+    }
+}
+
+/// Sets the calling process's niceness, for use between fork and exec
+#[cfg(unix)]
+fn set_nice(nice: i8) -> std::io::Result<()> {
+    // SAFETY: no preconditions, failure is reported by the return value
+    match unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice.into()) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CronTask;
+
+    #[test]
+    fn umask_is_parsed_as_octal() {
+        let parse = |s: &str| Umask::try_from(s.to_owned());
+        assert_eq!(parse("027"), Ok(Umask(0o27)));
+        assert_eq!(parse("0o077"), Ok(Umask(0o77)));
+        assert_eq!(parse("0777"), Ok(Umask(0o777)));
+        assert_eq!(String::from(Umask(0o27)), "027");
+        for invalid in ["", "8", "00777", "1000", "rwx"] {
+            assert!(parse(invalid).is_err(), "{invalid:?} parsed");
+        }
+    }
+
+    #[test]
+    fn umask_is_rejected_remotely() {
+        let err = CronTask::from_yaml_str(
+            "name: masked
+schedule: '@daily'
+host: web1
+commands:
+  - run: touch file
+    umask: '027'",
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err.r#type, ReadErrorType::UnsupportedUmask(..)),
+            "{err}",
+        );
+    }
+}
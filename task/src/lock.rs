@@ -0,0 +1,140 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use tracing::{debug, info};
+
+use crate::error::{LockError, LockErrorType};
+
+/// The lock files held by this process, so tasks sharing one (or a task
+/// being reloaded) share its lock rather than contending for it
+static HELD: Mutex<BTreeMap<Utf8PathBuf, Weak<LockFile>>> =
+    Mutex::new(BTreeMap::new());
+
+/// An advisory lock on a file, stopping other processes from taking it until
+/// it's dropped
+///
+/// The lock is taken with `flock` on Unix (`LockFileEx` on Windows), so
+/// only guards against other processes that take it the same way, such as
+/// another Overseer.
+/// It's released by the OS when the process exits, even if it crashes, so a
+/// stale lock never needs clearing up.
+/// The file itself is left behind, holding the PID of the process that last
+/// locked it
+///
+/// Within a process, locks on the same path are shared:
+/// [`LockFile::acquire`] hands back the lock already held, which is released
+/// once every handle to it is dropped.
+/// A supervisor can take a lock of its own (e.g. before loading any tasks)
+/// to guard against running twice at all, rather than per task
+#[derive(Debug)]
+pub struct LockFile {
+    path: Utf8PathBuf,
+    _file: File,
+}
+
+impl LockFile {
+    /// Locks the file at `path`, creating it if needed, without waiting
+    ///
+    /// Errors if another process holds the lock, or the file can't be opened
+    pub fn acquire(path: impl AsRef<Utf8Path>) -> Result<Arc<Self>, LockError> {
+        let path = path.as_ref();
+        let error = |r#type| LockError {
+            path: path.to_owned(),
+            r#type,
+        };
+        let key = std::path::absolute(path)
+            .ok()
+            .and_then(|key| Utf8PathBuf::from_path_buf(key).ok())
+            .unwrap_or_else(|| path.to_owned());
+        let mut held = HELD.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(lock) = held.get(&key).and_then(Weak::upgrade) {
+            debug!(%path, "Sharing lock file already held");
+            return Ok(lock);
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|why| error(LockErrorType::Io(why)))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(error(LockErrorType::Held(read_pid(path))));
+            }
+            Err(TryLockError::Error(why)) => {
+                return Err(error(LockErrorType::Io(why)));
+            }
+        }
+        // Only rewritten once locked, so the holder's PID is never clobbered
+        file.set_len(0)
+            .and_then(|()| writeln!(file, "{}", std::process::id()))
+            .map_err(|why| error(LockErrorType::Io(why)))?;
+        info!(%path, "Acquired lock file");
+        let lock = Arc::new(LockFile {
+            path: path.to_owned(),
+            _file: file,
+        });
+        held.retain(|_, lock| lock.strong_count() > 0);
+        held.insert(key, Arc::downgrade(&lock));
+        Ok(lock)
+    }
+
+    /// The path of the locked file
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // Closing the file releases the lock
+        info!(path = %self.path, "Released lock file");
+    }
+}
+
+/// The PID written to a lock file by its holder, if it can be read
+fn read_pid(path: &Utf8Path) -> Option<u32> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+
+    #[test]
+    fn lock_is_shared_within_process() {
+        let path = scratch_dir("lock-shared").join("task.lock");
+        let lock = LockFile::acquire(&path).unwrap();
+        let again = LockFile::acquire(&path).unwrap();
+        assert!(Arc::ptr_eq(&lock, &again));
+        assert_eq!(read_pid(&path), Some(std::process::id()));
+        // A separate open file has to contend for it, as another process does
+        let other = File::open(&path).unwrap();
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(lock);
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(again);
+        other.try_lock().unwrap();
+    }
+
+    #[test]
+    fn held_lock_names_its_holder() {
+        let path = scratch_dir("lock-held").join("task.lock");
+        std::fs::write(&path, "4242\n").unwrap();
+        let holder = File::open(&path).unwrap();
+        holder.try_lock().unwrap();
+        let err = LockFile::acquire(&path).unwrap_err();
+        assert!(
+            matches!(err.r#type, LockErrorType::Held(Some(4242))),
+            "{err}",
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "4242\n");
+        drop(holder);
+        LockFile::acquire(&path).unwrap();
+    }
+}
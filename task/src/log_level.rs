@@ -0,0 +1,156 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+use tracing::Level;
+
+/// Deserialises a task's `log_level` from its name, e.g. `debug`
+pub(crate) fn deserialize_log_level<'de, D>(
+    deserializer: D,
+) -> Result<Option<Level>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let level = match Option::<String>::deserialize(deserializer)? {
+        Some(level) => level,
+        None => return Ok(None),
+    };
+    level.parse().map(Some).map_err(|_| {
+        D::Error::custom(format!(
+            "invalid log level {level:?}, expected one of trace, debug, \
+            info, warn, or error"
+        ))
+    })
+}
+
+/// Serialises a task's `log_level` as its lowercase name
+pub(crate) fn serialize_log_level<S: Serializer>(
+    level: &Option<Level>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match level {
+        Some(level) => {
+            serializer.serialize_str(&level.as_str().to_ascii_lowercase())
+        }
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(feature = "log-filter")]
+pub use filter::*;
+
+#[cfg(feature = "log-filter")]
+mod filter {
+    use std::fmt;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id};
+    use tracing::subscriber::Interest;
+    use tracing::{Level, Metadata, Subscriber};
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::layer::{Context, Filter};
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// The span field a task's `log_level` is recorded in
+    const FIELD: &str = "log_level";
+
+    /// A per-layer filter that honours each task's `log_level`
+    ///
+    /// Requires the `log-filter` feature
+    ///
+    /// Events are enabled up to `default`, or up to a task's `log_level`
+    /// while that task is running, so one task can be traced without
+    /// tracing everything else:
+    /// ```ignore
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// tracing_subscriber::registry()
+    ///     .with(fmt::layer().with_filter(TaskLevelFilter::new(Level::INFO)))
+    ///     .init();
+    /// ```
+    ///
+    /// This filter must decide the layer's maximum level.
+    /// Anything that caps the level before it is consulted still applies,
+    /// so a task can't be more verbose than a global maximum (e.g. from
+    /// `fmt().with_max_level()`) or `tracing`'s compile-time level features
+    #[derive(Debug, Copy, Clone)]
+    pub struct TaskLevelFilter {
+        default: LevelFilter,
+    }
+
+    impl TaskLevelFilter {
+        /// Creates a filter enabling events up to `default` outside of tasks
+        /// that set their own `log_level`
+        pub fn new(default: impl Into<LevelFilter>) -> Self {
+            TaskLevelFilter {
+                default: default.into(),
+            }
+        }
+    }
+
+    /// A task's `log_level`, kept in its span's extensions
+    struct Override(Level);
+
+    impl<S> Filter<S> for TaskLevelFilter
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn enabled(&self, meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+            // Task spans are always enabled so their level can be known
+            if *meta.level() <= self.default || has_override_field(meta) {
+                return true;
+            }
+            match cx.lookup_current() {
+                Some(span) => span.scope().any(|span| {
+                    span.extensions()
+                        .get::<Override>()
+                        .is_some_and(|Override(level)| meta.level() <= level)
+                }),
+                None => false,
+            }
+        }
+
+        fn callsite_enabled(
+            &self,
+            meta: &'static Metadata<'static>,
+        ) -> Interest {
+            if *meta.level() <= self.default || has_override_field(meta) {
+                Interest::always()
+            } else {
+                // Depends on which task is running
+                Interest::sometimes()
+            }
+        }
+
+        fn max_level_hint(&self) -> Option<LevelFilter> {
+            Some(LevelFilter::TRACE)
+        }
+
+        fn on_new_span(
+            &self,
+            attrs: &Attributes<'_>,
+            id: &Id,
+            cx: Context<'_, S>,
+        ) {
+            let mut visitor = LevelVisitor(None);
+            attrs.record(&mut visitor);
+            if let (Some(level), Some(span)) = (visitor.0, cx.span(id)) {
+                span.extensions_mut().insert(Override(level));
+            }
+        }
+    }
+
+    fn has_override_field(meta: &Metadata<'_>) -> bool {
+        meta.is_span() && meta.fields().field(FIELD).is_some()
+    }
+
+    /// Finds a task span's `log_level`, if it was set
+    struct LevelVisitor(Option<Level>);
+
+    impl Visit for LevelVisitor {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == FIELD {
+                self.0 = value.parse().ok();
+            }
+        }
+
+        fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+    }
+}
@@ -0,0 +1,209 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use std::str::FromStr;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{
+    FmtContext, FormatEvent, FormatFields, FormattedFields,
+};
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::{SubscriberInitExt, TryInitError};
+
+use crate::TaskLevelFilter;
+
+/// The environment variable [`LogFormat::from_env`] reads
+pub const LOG_FORMAT_VAR: &str = "OVERSEER_LOG_FORMAT";
+
+/// How [`init_logging`] writes logs
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable, over multiple lines
+    #[default]
+    Pretty,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+impl LogFormat {
+    /// The format named by `OVERSEER_LOG_FORMAT` (`pretty` or `json`),
+    /// pretty if it's unset or not one of them
+    pub fn from_env() -> Self {
+        std::env::var(LOG_FORMAT_VAR)
+            .ok()
+            .and_then(|format| format.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "invalid log format {s:?}, expected pretty or json"
+            )),
+        }
+    }
+}
+
+/// Installs a global subscriber writing logs to stdout in `format`
+///
+/// Requires the `logging` feature.
+/// Library users wanting anything else can install their own subscriber
+/// instead; this is only a convenience.
+///
+/// Events are logged up to `level`, or a task's own `log_level` while it's
+/// running (see [`TaskLevelFilter`]).
+/// Fails if a global subscriber has already been installed
+///
+/// JSON logs have one object per line, with the event's `timestamp`,
+/// `level`, `target`, and `fields` (including its `message`), and the
+/// `spans` it's in, outermost first, each with its `name` and fields.
+/// Task runs are in a `task` span with fields:
+/// - `task`: the task's name
+/// - `trigger`: `cron` or `file`
+/// - `log_level`: the task's `log_level`, if it has one
+///
+/// Within which each command is in a `command` span with fields:
+/// - `command`: the command's name
+/// - `host`: where it's running, `localhost` if it's local
+pub fn init_logging(
+    format: LogFormat,
+    level: Level,
+) -> Result<(), TryInitError> {
+    let filter = TaskLevelFilter::new(level);
+    let layer = match format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .pretty()
+            .with_filter(filter)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields)
+            .event_format(JsonEvent)
+            .with_filter(filter)
+            .boxed(),
+    };
+    tracing_subscriber::registry().with(layer).try_init()
+}
+
+/// Records span fields as a JSON object, for [`JsonEvent`] to include
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor(
+            serde_json::from_str(&current.fields).unwrap_or_default(),
+        );
+        fields.record(&mut visitor);
+        current.fields = Value::Object(visitor.0).to_string();
+        Ok(())
+    }
+}
+
+/// Writes each event as a line of JSON
+struct JsonEvent;
+
+impl<S> FormatEvent<S, JsonFields> for JsonEvent
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+        let spans = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let mut object = span
+                    .extensions()
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|fields| {
+                        serde_json::from_str(&fields.fields).ok()
+                    })
+                    .unwrap_or_else(Map::new);
+                object.insert("name".into(), span.name().into());
+                Value::Object(object)
+            })
+            .collect::<Vec<_>>();
+        let meta = event.metadata();
+
+        // RFC 3339, which never needs escaping
+        write!(writer, "{{\"timestamp\":\"")?;
+        SystemTime.format_time(&mut writer)?;
+        write!(writer, "\",")?;
+        let mut rest = Map::new();
+        rest.insert("level".into(), meta.level().as_str().into());
+        rest.insert("target".into(), meta.target().into());
+        rest.insert("fields".into(), Value::Object(fields.0));
+        rest.insert("spans".into(), Value::Array(spans));
+        // Less the opening brace, which was written with the timestamp
+        let rest = Value::Object(rest).to_string();
+        writeln!(writer, "{}", &rest[1..])
+    }
+}
+
+/// Collects fields into a JSON object
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_error(
+        &mut self,
+        field: &Field,
+        value: &(dyn std::error::Error + 'static),
+    ) {
+        self.0.insert(field.name().into(), value.to_string().into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
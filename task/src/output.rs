@@ -0,0 +1,344 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::mpsc::Sender;
+use tracing::trace;
+
+use crate::Secrets;
+
+/// The longest piece of a line that's read at once, so longer lines are
+/// forwarded in pieces rather than held in full
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// A single line of output from a command, forwarded as soon as it's read
+///
+/// See [`CronTask::run_streaming`](crate::CronTask::run_streaming)
+///
+/// Lines from the same stream of the same command are sent in the order they
+/// were written.
+/// There is no ordering guarantee between stdout and stderr of a command, nor
+/// between different commands, as each stream is read independently
+#[derive(Debug, Clone)]
+pub struct OutputLine {
+    /// The name of the command that produced the line
+    pub command: String,
+    /// Which stream the line was read from
+    pub stream: OutputStream,
+    /// The line itself, without its trailing newline, as text according to
+    /// the command's [`OutputEncoding`]
+    pub line: String,
+    /// The line's bytes exactly as they were read, only kept with
+    /// [`OutputEncoding::RawBytes`]
+    pub raw: Option<Vec<u8>>,
+}
+
+/// How a command's captured output is represented, set with a command's
+/// `output_encoding`
+///
+/// Output is read as bytes, so this only affects how it's given in
+/// [`OutputLine`]s, errors, and [`CommandOutcome`](crate::CommandOutcome)s
+#[derive(
+    Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize, Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputEncoding {
+    /// Decoded as UTF-8, with anything invalid replaced by `�`, which is
+    /// readable, but loses the original bytes of non-UTF-8 output
+    #[default]
+    Utf8Lossy,
+    /// Kept intact alongside the lossy UTF-8 text (which errors still use),
+    /// for forwarding or inspecting binary output
+    RawBytes,
+    /// Encoded as base64 text, keeping the bytes intact though unreadable
+    /// without decoding, e.g. for logging binary output as text
+    Base64,
+}
+
+impl OutputEncoding {
+    /// Represents `bytes` as text, also giving them back if they're kept
+    pub(crate) fn encode(self, bytes: Vec<u8>) -> (String, Option<Vec<u8>>) {
+        match self {
+            OutputEncoding::Utf8Lossy => {
+                (String::from_utf8_lossy(&bytes).into_owned(), None)
+            }
+            OutputEncoding::RawBytes => {
+                (String::from_utf8_lossy(&bytes).into_owned(), Some(bytes))
+            }
+            OutputEncoding::Base64 => (base64::encode(&bytes), None),
+        }
+    }
+}
+
+/// The standard stream an [`OutputLine`] came from
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OutputStream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    Stderr,
+}
+
+impl fmt::Display for OutputStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use OutputStream::*;
+        match *self {
+            Stdout => write!(f, "stdout"),
+            Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// Reads `reader` line-by-line (if present), sending each line down `tx` and
+/// keeping the end of the output in `tail`, if either is given
+///
+/// Lines longer than [`MAX_LINE_BYTES`] are split (between UTF-8
+/// characters, if it is UTF-8), so no more than that is ever held at once.
+/// If the receiver has gone away, the rest of the output is still read (and
+/// discarded) so the child doesn't block on a full pipe
+pub(crate) async fn forward_lines<R>(
+    reader: Option<R>,
+    tx: Option<&Sender<OutputLine>>,
+    command: &str,
+    stream: OutputStream,
+    encoding: OutputEncoding,
+    mut tail: Option<&mut Tail>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let reader = match reader {
+        Some(reader) if tx.is_some() || tail.is_some() => reader,
+        _ => return Ok(()),
+    };
+    let mut reader = BufReader::new(reader);
+    let mut receiving = tx.is_some();
+    // The start of a character cut off by splitting a long line, which
+    // begins the next piece
+    let mut carried = Vec::new();
+    let mut starts_line = true;
+    loop {
+        // Not lines(), which fails on output that isn't UTF-8
+        let mut line = std::mem::take(&mut carried);
+        let budget = MAX_LINE_BYTES.saturating_sub(line.len()).max(1);
+        let mut limited = (&mut reader).take(budget as u64);
+        let read = limited.read_until(b'\n', &mut line).await?;
+        if read == 0 && line.is_empty() {
+            break;
+        }
+        let ends_line = line.ends_with(b"\n");
+        if ends_line {
+            line.pop();
+            // Only text drops the carriage return of Windows line endings
+            if encoding == OutputEncoding::Utf8Lossy && line.ends_with(b"\r") {
+                line.pop();
+            }
+        } else if read != 0 {
+            carried = line.split_off(line.len() - cut_off_char(&line));
+        }
+        // The end of a line that was split just before its newline
+        if line.is_empty() && ends_line && !starts_line {
+            starts_line = true;
+            continue;
+        }
+        if let Some(tail) = tail.as_deref_mut() {
+            tail.push(&line, starts_line);
+        }
+        starts_line = ends_line;
+        if let (true, Some(tx)) = (receiving, tx) {
+            let (line, raw) = encoding.encode(line);
+            let line = OutputLine {
+                command: command.to_owned(),
+                stream,
+                line,
+                raw,
+            };
+            if tx.send(line).await.is_err() {
+                trace!(%command, %stream, "Output receiver dropped");
+                receiving = false;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How many bytes at the end of `bytes` begin a UTF-8 character that's cut
+/// off, if it is UTF-8
+fn cut_off_char(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - back];
+        if byte & 0xC0 != 0x80 {
+            let len = match byte {
+                0xF0.. => 4,
+                0xE0.. => 3,
+                0xC0.. => 2,
+                _ => 1,
+            };
+            return if len > back { back } else { 0 };
+        }
+    }
+    0
+}
+
+/// The end of a stream's output, up to a command's `max_output_bytes`, for
+/// including in errors and reports
+#[derive(Debug)]
+pub(crate) struct Tail {
+    bytes: Vec<u8>,
+    limit: usize,
+    truncated: bool,
+}
+
+impl Tail {
+    /// How much output is kept by default, older output is dropped first
+    pub(crate) const DEFAULT_LIMIT: usize = 4 * 1024 * 1024;
+
+    /// Keeps at most `limit` bytes of output
+    pub(crate) fn new(limit: usize) -> Self {
+        Tail {
+            bytes: Vec::new(),
+            limit,
+            truncated: false,
+        }
+    }
+
+    /// Keeps `line`, which may only be a piece of a line, continuing the
+    /// last unless it `starts_line`
+    fn push(&mut self, line: &[u8], starts_line: bool) {
+        if starts_line && !self.bytes.is_empty() {
+            self.bytes.push(b'\n');
+        }
+        self.bytes.extend_from_slice(line);
+        if self.bytes.len() > self.limit {
+            let mut start = self.bytes.len() - self.limit;
+            // Not starting partway through a UTF-8 character, if it is UTF-8
+            while self
+                .bytes
+                .get(start)
+                .is_some_and(|byte| byte & 0xC0 == 0x80)
+            {
+                start += 1;
+            }
+            self.bytes.drain(..start);
+            self.truncated = true;
+        }
+    }
+
+    /// Removes `secrets` from the output kept
+    pub(crate) fn scrub(&mut self, secrets: &Secrets) {
+        self.bytes = secrets.scrub_bytes(std::mem::take(&mut self.bytes));
+    }
+
+    /// Whether older output was dropped to stay within the limit
+    pub(crate) fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub(crate) fn into_inner(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    /// The lines `output` is forwarded as, encoded with `encoding`
+    async fn lines(output: &[u8], encoding: OutputEncoding) -> Vec<OutputLine> {
+        let (tx, mut rx) = mpsc::channel(16);
+        forward_lines(
+            Some(output),
+            Some(&tx),
+            "test",
+            OutputStream::Stdout,
+            encoding,
+            None,
+        )
+        .await
+        .unwrap();
+        drop(tx);
+        let mut lines = Vec::new();
+        while let Some(line) = rx.recv().await {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn encodings_represent_invalid_utf8() {
+        let bytes = b"ok \xff".to_vec();
+        assert_eq!(
+            OutputEncoding::Utf8Lossy.encode(bytes.clone()),
+            ("ok \u{FFFD}".to_owned(), None),
+        );
+        assert_eq!(
+            OutputEncoding::RawBytes.encode(bytes.clone()),
+            ("ok \u{FFFD}".to_owned(), Some(bytes.clone())),
+        );
+        assert_eq!(
+            OutputEncoding::Base64.encode(bytes),
+            ("b2sg/w==".to_owned(), None),
+        );
+    }
+
+    #[tokio::test]
+    async fn only_text_drops_carriage_returns() {
+        let output = b"one\r\ntwo\xff\n";
+        let text = lines(output, OutputEncoding::Utf8Lossy).await;
+        let text = text.iter().map(|l| l.line.as_str()).collect::<Vec<_>>();
+        assert_eq!(text, ["one", "two\u{FFFD}"]);
+        let raw = lines(output, OutputEncoding::RawBytes).await;
+        let raw = raw.into_iter().map(|l| l.raw.unwrap()).collect::<Vec<_>>();
+        assert_eq!(raw, [b"one\r".to_vec(), b"two\xff".to_vec()]);
+    }
+
+    #[test]
+    fn tail_keeps_the_end_of_output() {
+        let mut tail = Tail::new(8);
+        tail.push(b"one", true);
+        tail.push(b"two", true);
+        assert!(!tail.truncated());
+        tail.push(b"thr", true);
+        tail.push(b"ee", false);
+        assert!(tail.truncated());
+        assert_eq!(tail.into_inner(), b"wo\nthree");
+    }
+
+    #[test]
+    fn tail_never_starts_mid_character() {
+        let mut tail = Tail::new(3);
+        tail.push("aé£".as_bytes(), true);
+        assert_eq!(tail.into_inner(), "£".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_forwarded_in_pieces() {
+        let long = "é".repeat(MAX_LINE_BYTES);
+        let output = format!("{long}\nshort\n");
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut tail = Tail::new(Tail::DEFAULT_LIMIT);
+        forward_lines(
+            Some(output.as_bytes()),
+            Some(&tx),
+            "test",
+            OutputStream::Stderr,
+            OutputEncoding::Utf8Lossy,
+            Some(&mut tail),
+        )
+        .await
+        .unwrap();
+        drop(tx);
+        let mut pieces = Vec::new();
+        while let Some(line) = rx.recv().await {
+            pieces.push(line.line);
+        }
+        assert_eq!(pieces.len(), 3);
+        assert!(pieces.iter().all(|p| p.len() <= MAX_LINE_BYTES));
+        assert_eq!(pieces[..2].concat(), long);
+        assert_eq!(pieces[2], "short");
+        // The tail has the line whole, as it was written
+        assert!(!tail.truncated());
+        assert_eq!(tail.into_inner(), output.trim_end().as_bytes());
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+use tokio::sync::watch;
+use tracing::{info, trace};
+
+use crate::{shutdown_started, shutting_down, CommandRunErrorType, RunContext};
+
+/// When a task last succeeded, as published by its [`LastRunCell`]
+///
+/// [`LastRunCell`]: crate::LastRunCell
+pub(crate) type Successes = watch::Receiver<Option<Instant>>;
+
+/// The tasks a task runs after (its `depends_on`), linked by the registry
+///
+/// Each run waits for every prerequisite to have succeeded since the last
+/// run did (or since the task was linked, for its first run)
+#[derive(Debug, Default)]
+pub(crate) struct Prerequisites {
+    links: Mutex<HashMap<String, Link>>,
+    /// Held by the waiting run, so each success lets only one run go ahead
+    waiting: tokio::sync::Mutex<()>,
+}
+
+#[derive(Debug, Clone)]
+struct Link {
+    successes: Successes,
+    /// The success the last run waited for
+    consumed: Option<Instant>,
+}
+
+impl Prerequisites {
+    /// Links the task to its prerequisites by name, replacing any previous
+    /// links, but remembering which successes were waited for
+    pub(crate) fn link(
+        &self,
+        prerequisites: impl IntoIterator<Item = (String, Successes)>,
+    ) {
+        let mut links = self.lock();
+        let linked = prerequisites
+            .into_iter()
+            .map(|(name, successes)| {
+                let consumed = match links.get(&name) {
+                    Some(link) => link.consumed,
+                    // Only successes from now on count
+                    None => *successes.borrow(),
+                };
+                (
+                    name,
+                    Link {
+                        successes,
+                        consumed,
+                    },
+                )
+            })
+            .collect();
+        *links = linked;
+    }
+
+    /// Waits until each of `depends_on` has succeeded since the last run,
+    /// or the run is cancelled or the process starts shutting down
+    ///
+    /// Dry runs don't record successes, so don't wait
+    pub(crate) async fn wait(
+        &self,
+        task: &str,
+        depends_on: &[String],
+        ctx: &RunContext,
+    ) -> Result<(), CommandRunErrorType> {
+        if ctx.dry_run {
+            if !depends_on.is_empty() {
+                info!(%task, ?depends_on, "Would wait for prerequisite tasks");
+            }
+            return Ok(());
+        }
+        if depends_on.is_empty() {
+            return Ok(());
+        }
+        if shutting_down() {
+            return Err(CommandRunErrorType::Cancelled);
+        }
+        // The waiting run goes ahead on the next success, which covers this
+        // trigger too
+        let Ok(_waiting) = self.waiting.try_lock() else {
+            return Err(CommandRunErrorType::AlreadyWaiting);
+        };
+        for name in depends_on {
+            let succeeded = tokio::select! {
+                biased;
+                _ = ctx.cancel.cancelled() => {
+                    return Err(CommandRunErrorType::Cancelled);
+                }
+                _ = shutdown_started() => {
+                    return Err(CommandRunErrorType::Cancelled);
+                }
+                succeeded = self.wait_for(name) => succeeded?,
+            };
+            if let Some(link) = self.lock().get_mut(name) {
+                link.consumed = link.consumed.max(Some(succeeded));
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for a success of `name` newer than the one last waited for
+    async fn wait_for(
+        &self,
+        name: &str,
+    ) -> Result<Instant, CommandRunErrorType> {
+        loop {
+            let Link {
+                mut successes,
+                consumed,
+            } = self.lock().get(name).cloned().ok_or_else(|| {
+                CommandRunErrorType::Prerequisite(name.to_owned())
+            })?;
+            trace!(prerequisite = %name, "Waiting for prerequisite task");
+            loop {
+                let succeeded = *successes.borrow_and_update();
+                match succeeded {
+                    Some(succeeded) if Some(succeeded) > consumed => {
+                        return Ok(succeeded);
+                    }
+                    _ => {}
+                }
+                if successes.changed().await.is_err() {
+                    break;
+                }
+            }
+            // The prerequisite was unloaded, but may have been reloaded,
+            // relinking this task to its replacement
+            let relinked = self
+                .lock()
+                .get(name)
+                .is_some_and(|link| !link.successes.same_channel(&successes));
+            if !relinked {
+                return Err(CommandRunErrorType::Prerequisite(name.to_owned()));
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Link>> {
+        self.links.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Finds the cycles in the graph of tasks' `depends_on`, each as the names
+/// of the tasks in it, in order
+///
+/// Names that aren't tasks in `graph` are ignored
+pub(crate) fn find_cycles<'a>(
+    graph: &HashMap<&'a str, &'a [String]>,
+) -> Vec<Vec<String>> {
+    fn visit<'a>(
+        name: &'a str,
+        graph: &HashMap<&'a str, &'a [String]>,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+        cycles: &mut Vec<Vec<String>>,
+    ) {
+        if done.contains(name) {
+            return;
+        }
+        if let Some(start) = path.iter().position(|&seen| seen == name) {
+            cycles.push(path[start..].iter().map(|&n| n.to_owned()).collect());
+            return;
+        }
+        let Some(depends_on) = graph.get(name) else {
+            return;
+        };
+        path.push(name);
+        for prerequisite in depends_on.iter() {
+            visit(prerequisite.as_str(), graph, path, done, cycles);
+        }
+        path.pop();
+        done.insert(name);
+    }
+
+    let mut names = graph.keys().copied().collect::<Vec<_>>();
+    // Consistent errors across loads
+    names.sort_unstable();
+    let mut done = HashSet::new();
+    let mut cycles = Vec::new();
+    for name in names {
+        visit(name, graph, &mut Vec::new(), &mut done, &mut cycles);
+    }
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The cycles in a graph where each `(task, depends_on)` is a task
+    fn cycles(tasks: &[(&str, &[&str])]) -> Vec<Vec<String>> {
+        let depends_on = tasks
+            .iter()
+            .map(|(name, on)| {
+                (*name, on.iter().map(|&n| n.to_owned()).collect())
+            })
+            .collect::<Vec<(&str, Vec<String>)>>();
+        let graph = depends_on
+            .iter()
+            .map(|(name, on)| (*name, on.as_slice()))
+            .collect();
+        find_cycles(&graph)
+    }
+
+    #[test]
+    fn two_tasks_depending_on_each_other() {
+        assert_eq!(cycles(&[("a", &["b"]), ("b", &["a"])]), [["a", "b"]]);
+    }
+
+    #[test]
+    fn task_depending_on_itself() {
+        assert_eq!(cycles(&[("a", &["a"]), ("b", &["a"])]), [["a"]]);
+    }
+
+    #[test]
+    fn disjoint_cycles_are_all_found() {
+        let found = cycles(&[
+            ("d", &["c"]),
+            ("c", &["d"]),
+            ("b", &["a"]),
+            ("a", &["b"]),
+            ("e", &["a", "c"]),
+        ]);
+        assert_eq!(found, [["a", "b"], ["c", "d"]]);
+    }
+
+    #[test]
+    fn diamond_isnt_a_cycle() {
+        let found = cycles(&[
+            ("top", &["left", "right"]),
+            ("left", &["bottom"]),
+            ("right", &["bottom"]),
+            ("bottom", &[]),
+        ]);
+        assert!(found.is_empty(), "{found:?}");
+    }
+
+    #[test]
+    fn unknown_names_are_ignored() {
+        assert!(cycles(&[("a", &["elsewhere"])]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn triggers_while_waiting_are_skipped() {
+        let prerequisites = Prerequisites::default();
+        let (succeeded, successes) = watch::channel(None);
+        prerequisites.link([("first".to_owned(), successes)]);
+        let depends_on = ["first".to_owned()];
+        let ctx = crate::tests::context("second");
+        let waiting = prerequisites.wait("second", &depends_on, &ctx);
+        tokio::pin!(waiting);
+        // Polled until it's waiting for the success
+        assert!(futures::poll!(&mut waiting).is_pending());
+        assert!(matches!(
+            prerequisites.wait("second", &depends_on, &ctx).await,
+            Err(CommandRunErrorType::AlreadyWaiting),
+        ));
+        succeeded.send(Some(Instant::now())).unwrap();
+        waiting.await.unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use futures::future;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::{Host, Hosts};
+
+/// What to do when a task is activated while any of its hosts can't be
+/// reached, set with `require_host_reachable`
+///
+/// Remote hosts are reached by connecting over SSH (within the host's
+/// `connect_timeout`), and containers by finding them running on the local
+/// Docker instance.
+/// The local host is always reachable
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnreachablePolicy {
+    /// Don't activate the task, reporting which hosts couldn't be reached
+    Skip,
+    /// Activate the task once every host can be reached, checking again
+    /// every 15 seconds in the background
+    Defer,
+}
+
+impl UnreachablePolicy {
+    /// How long to wait between checks while deferring activation
+    const RETRY: Duration = Duration::from_secs(15);
+}
+
+impl Host {
+    /// Checks the host can be reached, failing with why not
+    async fn probe(&self) -> Result<(), String> {
+        match self {
+            Host::Local => Ok(()),
+            Host::Remote(address, ssh) => probe_ssh(address, ssh).await,
+            Host::Container { name, .. } => probe_container(name).await,
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn probe_ssh(
+    address: &str,
+    ssh: &crate::SshOptions,
+) -> Result<(), String> {
+    let session = ssh.connect(address).await?;
+    if let Err(why) = session.close().await {
+        debug!(%address, "Failed to close probing SSH session: {why}");
+    }
+    Ok(())
+}
+
+/// Remote hosts don't load elsewhere
+#[cfg(not(unix))]
+async fn probe_ssh(_: &str, _: &crate::SshOptions) -> Result<(), String> {
+    Err(String::from("remote hosts are only supported on Unix"))
+}
+
+async fn probe_container(name: &str) -> Result<(), String> {
+    let docker = service::docker::connect_local()
+        .await
+        .map_err(|why| format!("couldn't connect to Docker: {why}"))?;
+    let container = docker
+        .inspect_container(name, None)
+        .await
+        .map_err(|why| why.to_string())?;
+    match container.state.and_then(|state| state.running) {
+        Some(true) => Ok(()),
+        _ => Err(String::from("container isn't running")),
+    }
+}
+
+impl Hosts {
+    /// The hosts that can't be reached, and why, checked concurrently
+    pub(crate) async fn unreachable(&self) -> Vec<(String, String)> {
+        let probes = self.iter().map(|host| async move {
+            host.probe()
+                .await
+                .err()
+                .map(|why| (host.as_str().to_owned(), why))
+        });
+        future::join_all(probes)
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Whether every host can be reached, as the task `name` requires
+    /// according to `policy`
+    ///
+    /// Returns straight away if they're all reachable, or with `false` if
+    /// they aren't and activation should be skipped.
+    /// Otherwise it waits for them all to be reachable, returning `true`
+    pub(crate) async fn await_reachable(
+        &self,
+        name: &str,
+        policy: UnreachablePolicy,
+    ) -> bool {
+        let mut unreachable = self.unreachable().await;
+        if unreachable.is_empty() {
+            return true;
+        }
+        if policy == UnreachablePolicy::Skip {
+            warn!(%name, ?unreachable, "Hosts unreachable, not activating task");
+            return false;
+        }
+        warn!(%name, ?unreachable, "Hosts unreachable, deferring task activation");
+        while !unreachable.is_empty() {
+            tokio::time::sleep(UnreachablePolicy::RETRY).await;
+            unreachable = self.unreachable().await;
+            debug!(%name, ?unreachable, "Checked hosts again");
+        }
+        info!(%name, "Hosts reachable, activating task");
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_is_snake_case() {
+        let policy = |s| serde_yaml::from_str::<UnreachablePolicy>(s).unwrap();
+        assert_eq!(policy("skip"), UnreachablePolicy::Skip);
+        assert_eq!(policy("defer"), UnreachablePolicy::Defer);
+    }
+
+    #[tokio::test]
+    async fn localhost_is_always_reachable() {
+        let hosts = Hosts::default();
+        assert!(hosts.unreachable().await.is_empty());
+        assert!(
+            hosts
+                .await_reachable("local", UnreachablePolicy::Skip)
+                .await
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn skip_gives_up_on_unreachable_hosts() {
+        let control = crate::tests::scratch_dir("reachability");
+        let hosts: Hosts = serde_yaml::from_str(&format!(
+            "- localhost
+- address: overseer.invalid
+  ssh:
+    connect_timeout: 2s
+    control_directory: '{control}'"
+        ))
+        .unwrap();
+        let unreachable = hosts.unreachable().await;
+        let labels = unreachable.iter().map(|(host, _)| host.as_str());
+        assert_eq!(labels.collect::<Vec<_>>(), ["overseer.invalid"]);
+        assert!(!hosts.await_reachable("gone", UnreachablePolicy::Skip).await);
+    }
+}
@@ -0,0 +1,719 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use service::registry::ServiceRegistry;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{error, info, trace, warn};
+
+use crate::{
+    find_cycles, read_bytes, AnyTask, CronScheduler, CronTask, FileEventTask,
+    ReadError, ReadErrorType, Successes, Task, Validate, WatchHandle,
+};
+
+/// A collection of tasks loaded from a directory of task files, keyed by
+/// task name
+///
+/// Each `.yml`/`.yaml` file in the directory should contain a single task,
+/// formatted as described in the [task file reference](crate#task-files).
+/// Whether a file is a cron or file task is determined by whether it has a
+/// `schedule` or `triggers`
+///
+/// The registry can be shared between threads, and kept up to date with the
+/// task files using [`TaskRegistry::watch_and_reload`].
+/// Tasks' dependencies are resolved through the services given with
+/// [`TaskRegistry::provide_services`]
+#[derive(Debug)]
+pub struct TaskRegistry {
+    dir: Utf8PathBuf,
+    recursive: bool,
+    tasks: Mutex<HashMap<String, Registered>>,
+    services: Mutex<Arc<ServiceRegistry>>,
+}
+
+#[derive(Debug)]
+struct Registered {
+    path: Utf8PathBuf,
+    task: TaskHandle,
+    activation: Option<Activation>,
+}
+
+/// How to stop an activated task
+#[derive(Debug)]
+enum Activation {
+    Cron(CronScheduler, u64),
+    FileEvent(WatchHandle),
+}
+
+impl Activation {
+    fn deactivate(self, name: &str) {
+        match self {
+            Activation::Cron(scheduler, id) => {
+                if let Err(why) = scheduler.deactivate(id) {
+                    error!(%name, %id, "Failed to deactivate task: {why}");
+                }
+            }
+            Activation::FileEvent(watch) => watch.stop(),
+        }
+        info!(%name, "Deactivated task");
+    }
+}
+
+#[derive(Debug, Clone)]
+enum TaskHandle {
+    Cron(Arc<CronTask>),
+    FileEvent(Arc<FileEventTask>),
+}
+
+impl From<AnyTask> for TaskHandle {
+    fn from(task: AnyTask) -> Self {
+        match task {
+            AnyTask::Cron(task) => TaskHandle::Cron(Arc::new(task)),
+            AnyTask::FileEvent(task) => TaskHandle::FileEvent(Arc::new(task)),
+        }
+    }
+}
+
+impl TaskRegistry {
+    /// Loads every task file directly inside the directory at `path`
+    ///
+    /// Files without a `.yml` or `.yaml` extension are skipped.
+    /// All files are loaded even if some fail, with every failure (including
+    /// two files using the same task name) returned together
+    pub async fn load_dir<P>(path: P) -> Result<Self, Vec<ReadError>>
+    where
+        P: AsRef<Utf8Path> + Send + Sync,
+    {
+        TaskRegistry::load(path.as_ref(), false).await
+    }
+
+    /// Loads every task file in the directory at `path`, and all its
+    /// subdirectories
+    ///
+    /// Otherwise the same as [`TaskRegistry::load_dir`]
+    pub async fn load_dir_recursive<P>(path: P) -> Result<Self, Vec<ReadError>>
+    where
+        P: AsRef<Utf8Path> + Send + Sync,
+    {
+        TaskRegistry::load(path.as_ref(), true).await
+    }
+
+    /// Checks every task file directly inside the directory at `path`,
+    /// without activating or running anything, returning how many tasks
+    /// there are
+    ///
+    /// On top of the checks [`TaskRegistry::load_dir`] makes (including
+    /// schedules and command names), every path a task refers to (the
+    /// `base_dir`, `triggers`, `env_file`s, `stdin` files, and local working
+    /// directories) must exist.
+    /// Relative paths are checked against the current working directory.
+    /// Nothing is connected to, and no commands are spawned, so this is safe
+    /// to use as a pre-flight check (e.g. in CI).
+    /// All problems are returned together
+    pub async fn validate_dir<P>(path: P) -> Result<usize, Vec<ReadError>>
+    where
+        P: AsRef<Utf8Path> + Send + Sync,
+    {
+        TaskRegistry::validate(path.as_ref(), false).await
+    }
+
+    /// Checks every task file in the directory at `path`, and all its
+    /// subdirectories
+    ///
+    /// Otherwise the same as [`TaskRegistry::validate_dir`]
+    pub async fn validate_dir_recursive<P>(
+        path: P,
+    ) -> Result<usize, Vec<ReadError>>
+    where
+        P: AsRef<Utf8Path> + Send + Sync,
+    {
+        TaskRegistry::validate(path.as_ref(), true).await
+    }
+
+    async fn load(
+        dir: &Utf8Path,
+        recursive: bool,
+    ) -> Result<Self, Vec<ReadError>> {
+        let (dir, tasks, errors) = load_tasks(dir, recursive).await?;
+        if errors.is_empty() {
+            link_prerequisites(&tasks);
+            info!(%dir, count = tasks.len(), "Loaded task registry");
+            Ok(TaskRegistry {
+                dir,
+                recursive,
+                tasks: Mutex::new(tasks),
+                services: Mutex::default(),
+            })
+        } else {
+            Err(errors)
+        }
+    }
+
+    async fn validate(
+        dir: &Utf8Path,
+        recursive: bool,
+    ) -> Result<usize, Vec<ReadError>> {
+        let (dir, tasks, mut errors) = load_tasks(dir, recursive).await?;
+        for registered in tasks.values() {
+            errors.extend(registered.task.missing_paths().into_iter().map(
+                |r#type| ReadError {
+                    path: registered.path.clone(),
+                    r#type,
+                },
+            ));
+        }
+        if errors.is_empty() {
+            info!(%dir, count = tasks.len(), "Validated task directory");
+            Ok(tasks.len())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Activates every task, then watches the task directory, reloading
+    /// tasks as their files change
+    ///
+    /// When a task file is created or modified, it's loaded, and if loading
+    /// succeeds, the new task is activated and the old one (if any)
+    /// deactivated.
+    /// If the file can't be loaded, or its task can't be activated, the
+    /// failure is logged and the previous version of the task keeps running.
+    /// A file being deleted deactivates its task
+    ///
+    /// Cron tasks are activated using `scheduler`, so they're listed by
+    /// [`CronScheduler::scheduled`] while they're active.
+    /// Dropping or aborting the returned handle stops reloading, but leaves
+    /// the current tasks active
+    ///
+    /// Errors only if the directory couldn't be watched
+    pub async fn watch_and_reload(
+        self: &Arc<Self>,
+        scheduler: &CronScheduler,
+    ) -> Result<JoinHandle<()>, notify::Error> {
+        let (tx, mut rx) = mpsc::channel::<Event>(16);
+        let mut watcher = RecommendedWatcher::new(
+            move |event_result: Result<Event, notify::Error>| match event_result
+            {
+                Ok(event) => {
+                    if tx.blocking_send(event).is_err() {
+                        trace!("Registry watcher outlived its reloader");
+                    }
+                }
+                Err(why) => warn!("Registry watcher event error: {why}"),
+            },
+        )?;
+        let mode = match self.recursive {
+            true => RecursiveMode::Recursive,
+            false => RecursiveMode::NonRecursive,
+        };
+        watcher.watch(self.dir.as_std_path(), mode)?;
+        info!(dir = %self.dir, "Watching task directory");
+
+        let tasks = self
+            .lock()
+            .iter()
+            .map(|(name, registered)| (name.clone(), registered.task.clone()))
+            .collect::<Vec<_>>();
+        for (name, task) in tasks {
+            match self.activate(&task, scheduler).await {
+                Some(activation) => {
+                    if let Some(registered) = self.lock().get_mut(&name) {
+                        registered.activation = Some(activation);
+                    }
+                }
+                None => error!(%name, "Task won't run until it's reloaded"),
+            }
+        }
+
+        let registry = self.clone();
+        let scheduler = scheduler.clone();
+        Ok(tokio::spawn(async move {
+            // Keep the watcher alive for as long as we're reloading
+            let _watcher = watcher;
+            while let Some(event) = rx.recv().await {
+                registry.handle_event(event, &scheduler).await;
+            }
+        }))
+    }
+
+    async fn handle_event(&self, event: Event, scheduler: &CronScheduler) {
+        use notify::event::ModifyKind;
+        use notify::EventKind::*;
+        let removed = match event.kind {
+            Create(_) | Modify(ModifyKind::Data(_) | ModifyKind::Name(_)) => {
+                false
+            }
+            Remove(_) => true,
+            _ => return,
+        };
+        for path in event.paths {
+            let path = match Utf8PathBuf::from_path_buf(path) {
+                Ok(path) if is_task_file(&path) => path,
+                _ => continue,
+            };
+            // Renames don't say which side of the rename a path is
+            if removed || !path.exists() {
+                self.remove(&path);
+            } else {
+                self.reload(path, scheduler).await;
+            }
+        }
+    }
+
+    /// Deactivates and forgets the task loaded from `path`, if any
+    fn remove(&self, path: &Utf8Path) {
+        let removed = {
+            let mut tasks = self.lock();
+            let name = tasks
+                .iter()
+                .find(|(_, registered)| registered.path == path)
+                .map(|(name, _)| name.clone());
+            let removed = name.and_then(|name| tasks.remove_entry(&name));
+            if removed.is_some() {
+                link_prerequisites(&tasks);
+            }
+            removed
+        };
+        if let Some((name, registered)) = removed {
+            info!(%name, %path, "Task file removed");
+            if let Some(activation) = registered.activation {
+                activation.deactivate(&name);
+            }
+        }
+    }
+
+    async fn reload(&self, path: Utf8PathBuf, scheduler: &CronScheduler) {
+        let task = match load_file(&path).await {
+            Ok(task) => TaskHandle::from(task),
+            Err(why) => {
+                error!("Keeping previous version of task: {why}");
+                return;
+            }
+        };
+        task.provide_services(&self.services());
+        let name = task.name().to_owned();
+        let activation = match self.activate(&task, scheduler).await {
+            Some(activation) => activation,
+            None => {
+                error!(%name, "Keeping previous version of task");
+                return;
+            }
+        };
+
+        let previous = {
+            let mut tasks = self.lock();
+            if let Some(existing) = tasks.get(&name) {
+                if existing.path != path {
+                    let error = ReadError {
+                        r#type: ReadErrorType::DuplicateTaskName(
+                            name.clone(),
+                            existing.path.clone(),
+                        ),
+                        path,
+                    };
+                    drop(tasks);
+                    error!("Not reloading task: {error}");
+                    activation.deactivate(&name);
+                    return;
+                }
+            }
+            // The task may have been renamed, so look for its old name
+            let old_name = tasks
+                .iter()
+                .find(|(_, registered)| registered.path == path)
+                .map(|(old_name, _)| old_name.clone());
+            let previous =
+                old_name.and_then(|old_name| tasks.remove_entry(&old_name));
+            let registered = Registered {
+                path,
+                task,
+                activation: Some(activation),
+            };
+            tasks.insert(name.clone(), registered);
+            let errors = check_prerequisites(&tasks, Some(&name));
+            if !errors.is_empty() {
+                // Put the previous version back
+                let rejected = tasks.remove(&name);
+                if let Some((old_name, registered)) = previous {
+                    tasks.insert(old_name, registered);
+                }
+                drop(tasks);
+                for error in errors {
+                    error!("Not reloading task: {error}");
+                }
+                if let Some(activation) =
+                    rejected.and_then(|rejected| rejected.activation)
+                {
+                    activation.deactivate(&name);
+                }
+                return;
+            }
+            link_prerequisites(&tasks);
+            previous
+        };
+        match previous {
+            Some((old_name, registered)) => {
+                if let Some(activation) = registered.activation {
+                    activation.deactivate(&old_name);
+                }
+                info!(%name, "Reloaded task");
+            }
+            None => info!(%name, "Loaded new task"),
+        }
+    }
+
+    /// Activates a task, logging and returning `None` on failure
+    async fn activate(
+        &self,
+        task: &TaskHandle,
+        scheduler: &CronScheduler,
+    ) -> Option<Activation> {
+        let result = match task {
+            TaskHandle::Cron(task) => scheduler
+                .activate(task)
+                .await
+                .map(|id| Activation::Cron(scheduler.clone(), id))
+                .map_err(|why| why.to_string()),
+            TaskHandle::FileEvent(task) => task
+                .activate()
+                .await
+                .map(|watch| {
+                    if !watch.failed().is_empty() {
+                        warn!(
+                            name = task.name(),
+                            failed = ?watch.failed(),
+                            "Some paths couldn't be watched",
+                        );
+                    }
+                    Activation::FileEvent(watch)
+                })
+                .map_err(|why| why.to_string()),
+        };
+        match result {
+            Ok(activation) => Some(activation),
+            Err(why) => {
+                error!(name = task.name(), "Failed to activate task: {why}");
+                None
+            }
+        }
+    }
+
+    /// Resolves the dependencies of every task through `services`,
+    /// including those of tasks loaded later
+    ///
+    /// A dependency `services` doesn't have is never healthy
+    pub fn provide_services(&self, services: Arc<ServiceRegistry>) {
+        *self
+            .services
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+            services.clone();
+        for registered in self.lock().values() {
+            registered.task.provide_services(&services);
+        }
+    }
+
+    fn services(&self) -> Arc<ServiceRegistry> {
+        self.services
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Registered>> {
+        // A panic while holding the lock can't leave the map inconsistent
+        self.tasks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Gets the task called `name`, if there is one
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Task>> {
+        self.lock()
+            .get(name)
+            .map(|registered| registered.task.as_dyn())
+    }
+
+    /// Gets the path of the file the task called `name` was loaded from
+    pub fn path_of(&self, name: &str) -> Option<Utf8PathBuf> {
+        self.lock()
+            .get(name)
+            .map(|registered| registered.path.clone())
+    }
+
+    /// The names of all the tasks, in no particular order
+    pub fn names(&self) -> Vec<String> {
+        self.lock().keys().cloned().collect()
+    }
+
+    /// All the cron tasks
+    pub fn cron_tasks(&self) -> Vec<Arc<CronTask>> {
+        self.lock()
+            .values()
+            .filter_map(|registered| match &registered.task {
+                TaskHandle::Cron(task) => Some(task.clone()),
+                TaskHandle::FileEvent(_) => None,
+            })
+            .collect()
+    }
+
+    /// All the file tasks
+    pub fn file_tasks(&self) -> Vec<Arc<FileEventTask>> {
+        self.lock()
+            .values()
+            .filter_map(|registered| match &registered.task {
+                TaskHandle::FileEvent(task) => Some(task.clone()),
+                TaskHandle::Cron(_) => None,
+            })
+            .collect()
+    }
+
+    /// The number of tasks
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Whether there are no tasks
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+}
+
+impl TaskHandle {
+    fn depends_on(&self) -> &[String] {
+        match self {
+            TaskHandle::Cron(task) => task.depends_on(),
+            TaskHandle::FileEvent(task) => task.depends_on(),
+        }
+    }
+
+    fn link_prerequisites(
+        &self,
+        prerequisites: impl IntoIterator<Item = (String, Successes)>,
+    ) {
+        match self {
+            TaskHandle::Cron(task) => task.link_prerequisites(prerequisites),
+            TaskHandle::FileEvent(task) => {
+                task.link_prerequisites(prerequisites)
+            }
+        }
+    }
+
+    fn successes(&self) -> Successes {
+        match self {
+            TaskHandle::Cron(task) => task.successes(),
+            TaskHandle::FileEvent(task) => task.successes(),
+        }
+    }
+
+    fn provide_services(&self, services: &ServiceRegistry) {
+        match self {
+            TaskHandle::Cron(task) => task.provide_services(services),
+            TaskHandle::FileEvent(task) => task.provide_services(services),
+        }
+    }
+
+    fn missing_paths(&self) -> Vec<ReadErrorType> {
+        match self {
+            TaskHandle::Cron(task) => task.missing_paths(),
+            TaskHandle::FileEvent(task) => task.missing_paths(),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            TaskHandle::Cron(task) => task.name(),
+            TaskHandle::FileEvent(task) => task.name(),
+        }
+    }
+
+    fn as_dyn(&self) -> Arc<dyn Task> {
+        match self {
+            TaskHandle::Cron(task) => task.clone(),
+            TaskHandle::FileEvent(task) => task.clone(),
+        }
+    }
+}
+
+/// Loads every task file in `dir`, keyed by task name, along with all the
+/// errors from files that failed to load
+///
+/// Only errors outright if `dir` itself couldn't be resolved
+async fn load_tasks(
+    dir: &Utf8Path,
+    recursive: bool,
+) -> Result<
+    (Utf8PathBuf, HashMap<String, Registered>, Vec<ReadError>),
+    Vec<ReadError>,
+> {
+    // Absolute paths are needed to match up with watcher events later
+    let dir = canonicalize(dir).await.map_err(|why| vec![why])?;
+    let mut tasks = HashMap::<String, Registered>::new();
+    let mut errors = Vec::new();
+    for path in task_files(&dir, recursive, &mut errors).await {
+        let task = match load_file(&path).await {
+            Ok(task) => TaskHandle::from(task),
+            Err(why) => {
+                errors.push(why);
+                continue;
+            }
+        };
+        let name = task.name().to_owned();
+        match tasks.get(&name) {
+            Some(existing) => errors.push(ReadError {
+                r#type: ReadErrorType::DuplicateTaskName(
+                    name,
+                    existing.path.clone(),
+                ),
+                path,
+            }),
+            None => {
+                let registered = Registered {
+                    path,
+                    task,
+                    activation: None,
+                };
+                tasks.insert(name, registered);
+            }
+        }
+    }
+    errors.extend(check_prerequisites(&tasks, None));
+    Ok((dir, tasks, errors))
+}
+
+/// Checks that each task's `depends_on` names tasks in `tasks`, and that no
+/// tasks depend on each other in a cycle
+///
+/// If `only` is given, just the problems involving that task are returned
+fn check_prerequisites(
+    tasks: &HashMap<String, Registered>,
+    only: Option<&str>,
+) -> Vec<ReadError> {
+    let involved = |name: &str| only.is_none_or(|only| only == name);
+    let mut errors = Vec::new();
+    for (_, registered) in tasks.iter().filter(|(name, _)| involved(name)) {
+        for prerequisite in registered.task.depends_on() {
+            if !tasks.contains_key(prerequisite) {
+                errors.push(ReadError {
+                    path: registered.path.clone(),
+                    r#type: ReadErrorType::UnknownPrerequisite(
+                        prerequisite.clone(),
+                    ),
+                });
+            }
+        }
+    }
+    let graph = tasks
+        .iter()
+        .map(|(name, registered)| (name.as_str(), registered.task.depends_on()))
+        .collect();
+    for cycle in find_cycles(&graph) {
+        if cycle.iter().any(|name| involved(name)) {
+            errors.push(ReadError {
+                path: tasks[&cycle[0]].path.clone(),
+                r#type: ReadErrorType::PrerequisiteCycle(cycle),
+            });
+        }
+    }
+    errors
+}
+
+/// Links every task in `tasks` to the tasks it depends on
+fn link_prerequisites(tasks: &HashMap<String, Registered>) {
+    for registered in tasks.values() {
+        let prerequisites =
+            registered.task.depends_on().iter().filter_map(|name| {
+                Some((name.clone(), tasks.get(name)?.task.successes()))
+            });
+        registered.task.link_prerequisites(prerequisites);
+    }
+}
+
+pub(crate) async fn load_file(path: &Utf8Path) -> Result<AnyTask, ReadError> {
+    let bytes = read_bytes(path).await?;
+    serde_yaml::from_slice(&bytes)
+        .map_err(ReadErrorType::De)
+        .and_then(AnyTask::from_value)
+        .map_err(|r#type| ReadError {
+            path: path.to_owned(),
+            r#type,
+        })
+}
+
+async fn canonicalize(path: &Utf8Path) -> Result<Utf8PathBuf, ReadError> {
+    let read_error = |why| ReadError {
+        path: path.to_owned(),
+        r#type: ReadErrorType::Io(why),
+    };
+    let canonical = tokio::fs::canonicalize(path).await.map_err(read_error)?;
+    Utf8PathBuf::from_path_buf(canonical).map_err(|_| {
+        read_error(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "canonical path isn't valid UTF-8",
+        ))
+    })
+}
+
+pub(crate) fn is_task_file(path: &Utf8Path) -> bool {
+    matches!(path.extension(), Some("yml" | "yaml"))
+}
+
+/// Finds all the task files in `dir`, noting any directories that couldn't
+/// be read in `errors`
+async fn task_files(
+    dir: &Utf8Path,
+    recursive: bool,
+    errors: &mut Vec<ReadError>,
+) -> Vec<Utf8PathBuf> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(why) => {
+                errors.push(ReadError {
+                    path: dir,
+                    r#type: ReadErrorType::Io(why),
+                });
+                continue;
+            }
+        };
+        loop {
+            let entry = match entries.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(why) => {
+                    errors.push(ReadError {
+                        path: dir.clone(),
+                        r#type: ReadErrorType::Io(why),
+                    });
+                    break;
+                }
+            };
+            let path = match Utf8PathBuf::from_path_buf(entry.path()) {
+                Ok(path) => path,
+                Err(path) => {
+                    warn!(?path, "Skipping non-UTF-8 path");
+                    continue;
+                }
+            };
+            match entry.file_type().await {
+                Ok(kind) if kind.is_dir() => {
+                    if recursive {
+                        dirs.push(path);
+                    }
+                }
+                Ok(_) if is_task_file(&path) => files.push(path),
+                Ok(_) => trace!(%path, "Skipping non-YAML file"),
+                Err(why) => errors.push(ReadError {
+                    path,
+                    r#type: ReadErrorType::Io(why),
+                }),
+            }
+        }
+    }
+    files.sort();
+    files
+}
@@ -0,0 +1,222 @@
+use futures::future;
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{error, info, trace, warn, Instrument, Level};
+
+use crate::{
+    check_dependencies, commands_on, run_limited, run_sequentially,
+    with_task_timeout, CommandOutcome, CommandRunError, CommandRunErrorType,
+    Commands, ConcurrencyLimit, Dependency, Host, HostRunReport, Hosts,
+    LastRunCell, Prerequisites, RunContext, RunGuard, ServiceMap, TaskKind,
+    TaskRunReport,
+};
+
+/// What running a task needs from it, shared by every kind of task so each
+/// run-related feature only has to be implemented once
+pub(crate) struct TaskRun<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) kind: TaskKind,
+    pub(crate) depends_on: &'a [String],
+    pub(crate) prerequisites: &'a Prerequisites,
+    pub(crate) dependencies: &'a [Dependency],
+    pub(crate) services: &'a ServiceMap,
+    /// Every host the task's commands run on
+    pub(crate) hosts: &'a Hosts,
+    /// The hosts commands run on unless they give their own
+    pub(crate) task_hosts: &'a Hosts,
+    pub(crate) max_concurrent: Option<NonZeroUsize>,
+    pub(crate) task_timeout: Option<Duration>,
+    pub(crate) limit: &'a ConcurrencyLimit,
+    pub(crate) last_run: &'a LastRunCell,
+    pub(crate) before: &'a Commands,
+    pub(crate) commands: &'a Commands,
+    pub(crate) after: &'a Commands,
+    /// The level failed hooks and runs are logged at
+    pub(crate) failure_level: Level,
+}
+
+impl TaskRun<'_> {
+    /// Runs the task on each of its hosts, once its prerequisite tasks have
+    /// succeeded and if its dependencies are healthy, recording a report
+    /// unless it's a dry run
+    pub(crate) async fn run(
+        &self,
+        ctx: &Arc<RunContext>,
+    ) -> Result<(), Vec<CommandRunError>> {
+        // Time spent waiting isn't part of the run
+        let waited = self
+            .prerequisites
+            .wait(self.name, self.depends_on, ctx)
+            .await;
+        let started = Instant::now();
+        if let Err(r#type) = waited {
+            warn!(name = %self.name, "Stopped waiting for prerequisite tasks, skipping task: {type}");
+            return Err(self.skip(r#type, started, ctx).await);
+        }
+        // Only counted once it's going ahead, so a run that's waiting can't
+        // hold up shutting down
+        let _in_flight = RunGuard::new();
+        let dependencies =
+            check_dependencies(self.name, self.dependencies, self.services)
+                .await;
+        if !dependencies.all_healthy() {
+            let unhealthy =
+                dependencies.unhealthy().map(ToString::to_string).collect();
+            warn!(name = %self.name, "Dependencies aren't healthy, skipping task");
+            let r#type = CommandRunErrorType::Dependencies(unhealthy);
+            return Err(self.skip(r#type, started, ctx).await);
+        }
+        let runs = future::join_all(
+            self.hosts.iter().map(|host| self.run_on(host, ctx)),
+        );
+        let (runs, timed_out) =
+            with_task_timeout(runs, self.task_timeout, ctx).await;
+        let mut errors = Vec::new();
+        if let Some(r#type) = timed_out {
+            errors.push(CommandRunError {
+                name: self.name.to_owned(),
+                r#type,
+            });
+        }
+        let task_errors = errors.len();
+        let mut hosts = BTreeMap::new();
+        for (host, (mut host_errors, outcomes)) in self.hosts.iter().zip(runs) {
+            if task_errors > 0 {
+                // The timeout explains these
+                host_errors.retain(|err| {
+                    !matches!(err.r#type, CommandRunErrorType::Cancelled)
+                });
+            }
+            let executions = ctx.take_executions(host.as_str());
+            let report = HostRunReport::new(&host_errors, outcomes, executions);
+            hosts.insert(host.as_str().to_owned(), report);
+            errors.extend(host_errors);
+        }
+        if !ctx.dry_run {
+            let report = TaskRunReport::new(
+                self.name,
+                self.kind,
+                ctx.started,
+                started,
+                &errors[..task_errors],
+                hosts,
+            );
+            self.last_run.record(report).await;
+        }
+        if errors.is_empty() {
+            info!(name = %self.name, "Task completed successfully");
+            Ok(())
+        } else {
+            self.log_failure(None, "Task completed with errors");
+            Err(errors)
+        }
+    }
+
+    /// Runs `before`, the main batch, then `after` on one of the task's
+    /// hosts, returning the errors and the outcomes of the commands that
+    /// succeeded
+    async fn run_on(
+        &self,
+        host: &Host,
+        ctx: &Arc<RunContext>,
+    ) -> (Vec<CommandRunError>, Vec<CommandOutcome>) {
+        let mut errors = Vec::new();
+        let mut outcomes = Vec::new();
+        let before = commands_on(self.before, host, self.task_hosts);
+        let commands = commands_on(self.commands, host, self.task_hosts);
+        let after = commands_on(self.after, host, self.task_hosts);
+        if let Err(why) =
+            run_sequentially(&before, host, ctx, &mut outcomes, &mut errors)
+                .await
+        {
+            self.log_failure(
+                Some(host),
+                "Before hook failed, skipping task commands",
+            );
+            errors.push(why);
+        } else {
+            let semaphore = self.limit.semaphore(self.max_concurrent);
+            let handle_iter = commands.into_iter().map(|cmd| {
+                let semaphore = semaphore.clone();
+                let ctx = ctx.clone();
+                let host = host.remote().map(ToOwned::to_owned);
+                tokio::spawn(
+                    run_limited(semaphore, async move {
+                        ctx.execute(cmd, host.as_deref()).await
+                    })
+                    .in_current_span(),
+                )
+            });
+
+            // Aborted if this run is dropped, killing the commands rather
+            // than leaving them running detached
+            let mut handles = AbortOnDrop(handle_iter.collect());
+            let results = future::join_all(handles.0.iter_mut()).await;
+            trace!(name = %self.name, "Processing task command results");
+            for nested_result in results {
+                match nested_result {
+                    Ok(Ok(outcome)) => outcomes.push(outcome),
+                    Ok(Err(cre)) => errors.push(cre),
+                    Err(join_err) => errors.push(CommandRunError {
+                        name: self.name.to_owned(),
+                        r#type: CommandRunErrorType::Async(join_err),
+                    }),
+                }
+            }
+        }
+        if let Err(why) =
+            run_sequentially(&after, host, ctx, &mut outcomes, &mut errors)
+                .await
+        {
+            self.log_failure(Some(host), "After hook failed");
+            errors.push(why);
+        }
+        (errors, outcomes)
+    }
+
+    /// Records a run that was skipped because of `r#type`, returning it as
+    /// the run's errors
+    async fn skip(
+        &self,
+        r#type: CommandRunErrorType,
+        started: Instant,
+        ctx: &RunContext,
+    ) -> Vec<CommandRunError> {
+        let errors = vec![CommandRunError {
+            name: self.name.to_owned(),
+            r#type,
+        }];
+        if !ctx.dry_run {
+            let report = TaskRunReport::new(
+                self.name,
+                self.kind,
+                ctx.started,
+                started,
+                &errors,
+                BTreeMap::new(),
+            );
+            self.last_run.record(report).await;
+        }
+        errors
+    }
+
+    fn log_failure(&self, host: Option<&Host>, message: &str) {
+        let host = host.map(Host::as_str);
+        match self.failure_level {
+            Level::ERROR => error!(name = %self.name, host, "{message}"),
+            _ => warn!(name = %self.name, host, "{message}"),
+        }
+    }
+}
+
+/// Spawned command runs, aborted when dropped
+struct AbortOnDrop<T>(Vec<JoinHandle<T>>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.iter().for_each(JoinHandle::abort);
+    }
+}
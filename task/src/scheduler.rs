@@ -0,0 +1,150 @@
+use delay_timer::prelude::{DelayTimer, TaskError};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tracing::error;
+
+use crate::error::ActivateError;
+use crate::{CronTask, Task};
+
+/// A [`DelayTimer`] that keeps track of the cron tasks scheduled on it
+///
+/// Tasks activated with [`CronScheduler::activate`] are given an unused ID,
+/// and listed by [`CronScheduler::scheduled`] until they're deactivated
+/// (including by reaching their `max_consecutive_failures`).
+/// Clones share the same timer and listing.
+/// Tasks activated on the timer directly aren't listed, and could clash with
+/// the IDs the scheduler gives out, so every task should be activated
+/// through the scheduler
+#[derive(Debug, Clone)]
+pub struct CronScheduler {
+    delay_timer: DelayTimer,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_id: u64,
+    scheduled: BTreeMap<u64, ScheduledTask>,
+    /// The tasks themselves, to tidy up after when they're deactivated
+    tasks: BTreeMap<u64, Arc<CronTask>>,
+}
+
+impl State {
+    /// Forgets tasks that have deactivated themselves after failing too many
+    /// times in a row
+    fn prune(&mut self) {
+        let tripped = self
+            .tasks
+            .iter()
+            .filter(|(_, task)| task.tripped())
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in tripped {
+            self.tasks.remove(&id);
+            self.scheduled.remove(&id);
+        }
+    }
+}
+
+/// A cron task scheduled by a [`CronScheduler`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ScheduledTask {
+    /// The ID the task was scheduled with
+    pub id: u64,
+    /// The task's name
+    pub task: String,
+    /// The task's cron schedule
+    pub schedule: String,
+}
+
+impl CronScheduler {
+    /// Creates a scheduler activating tasks on `delay_timer`
+    pub fn new(delay_timer: DelayTimer) -> Self {
+        CronScheduler {
+            delay_timer,
+            state: Arc::default(),
+        }
+    }
+
+    /// The timer tasks are scheduled on
+    pub fn delay_timer(&self) -> &DelayTimer {
+        &self.delay_timer
+    }
+
+    /// Schedules `task` with a new ID, returning the ID
+    ///
+    /// See [`CronTask::activate`].
+    /// A task that fails to activate isn't listed, and its ID isn't reused
+    pub async fn activate(
+        &self,
+        task: &Arc<CronTask>,
+    ) -> Result<u64, ActivateError> {
+        let id = {
+            let mut state = self.lock();
+            state.next_id += 1;
+            state.next_id - 1
+        };
+        task.activate(&self.delay_timer, id).await?;
+        let mut state = self.lock();
+        let scheduled = ScheduledTask {
+            id,
+            task: task.name().to_owned(),
+            schedule: task.schedule().to_owned(),
+        };
+        state.scheduled.insert(id, scheduled);
+        state.tasks.insert(id, task.clone());
+        Ok(id)
+    }
+
+    /// Stops the task with the given `id` from being run, returning it if it
+    /// was scheduled by this scheduler
+    ///
+    /// Runs already in progress aren't stopped.
+    /// The task's `lock_file` is released, and if it's still waiting for its
+    /// hosts to be reachable, it stops waiting.
+    /// If the task can't be removed from the timer, it's still listed, and
+    /// keeps its lock
+    pub fn deactivate(
+        &self,
+        id: u64,
+    ) -> Result<Option<ScheduledTask>, TaskError> {
+        let mut state = self.lock();
+        self.delay_timer.remove_task(id)?;
+        if let Some(task) = state.tasks.remove(&id) {
+            task.deactivated();
+        }
+        Ok(state.scheduled.remove(&id))
+    }
+
+    /// Deactivates every task scheduled, returning them
+    ///
+    /// Tasks that can't be removed from the timer are still returned, as
+    /// this is only done when shutting down
+    pub(crate) fn deactivate_all(&self) -> Vec<Arc<CronTask>> {
+        let mut state = self.lock();
+        state.scheduled.clear();
+        let tasks = std::mem::take(&mut state.tasks);
+        tasks
+            .into_iter()
+            .map(|(id, task)| {
+                if let Err(why) = self.delay_timer.remove_task(id) {
+                    error!(%id, name = %task.name(), "Couldn't remove task from the timer: {why}");
+                }
+                task.deactivated();
+                task
+            })
+            .collect()
+    }
+
+    /// Every task currently scheduled, in order of ID
+    pub fn scheduled(&self) -> Vec<ScheduledTask> {
+        self.lock().scheduled.values().cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        let mut state =
+            self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.prune();
+        state
+    }
+}
@@ -0,0 +1,310 @@
+use async_trait::async_trait;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use std::error::Error;
+use std::fmt;
+use std::sync::{OnceLock, PoisonError, RwLock};
+
+use crate::error::{CommandRunErrorType, SecretProviderInstallError};
+use crate::{CommandRunError, EnvVar, TaskCommand};
+
+static PROVIDER: OnceLock<Box<dyn SecretProvider>> = OnceLock::new();
+
+/// Marks an environment variable's value as a reference to a secret
+const SCHEME: &str = "secret://";
+
+/// Somewhere to look up secrets referenced by tasks
+///
+/// An environment variable whose value is a reference like
+/// `DB_PASSWORD=secret://db/password` has its value replaced by the secret at
+/// `db/password` each time the command runs, just before it's spawned.
+/// Install a provider with [`install_secret_provider`].
+/// Until one is installed, commands referencing secrets fail to run
+#[async_trait]
+pub trait SecretProvider: Send + Sync {
+    /// Looks up the secret at `path`, the part of the reference after
+    /// `secret://`
+    async fn secret(
+        &self,
+        path: &str,
+    ) -> Result<String, Box<dyn Error + Send + Sync>>;
+}
+
+/// Installs the global [`SecretProvider`]
+///
+/// This can only be done once, subsequent calls will error
+pub fn install_secret_provider(
+    provider: impl SecretProvider + 'static,
+) -> Result<(), SecretProviderInstallError> {
+    PROVIDER
+        .set(Box::new(provider))
+        .map_err(|_| SecretProviderInstallError)
+}
+
+/// A [`SecretProvider`] reading each secret from a file in a directory, as
+/// mounted by Docker or Kubernetes
+///
+/// `secret://db/password` is read from `db/password` in the directory, with
+/// a single trailing newline removed.
+/// Paths can't be absolute or contain `..`, so can't escape the directory
+#[derive(Debug, Clone)]
+pub struct FileSecrets {
+    dir: Utf8PathBuf,
+}
+
+impl FileSecrets {
+    /// Creates a provider reading secrets from files in `dir`
+    pub fn new(dir: impl Into<Utf8PathBuf>) -> Self {
+        FileSecrets { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl SecretProvider for FileSecrets {
+    async fn secret(
+        &self,
+        path: &str,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let path = Utf8Path::new(path);
+        let contained = path
+            .components()
+            .all(|component| matches!(component, Utf8Component::Normal(_)));
+        if path.as_str().is_empty() || !contained {
+            return Err(
+                format!("{path:?} isn't a path within {}", self.dir).into()
+            );
+        }
+        let file = self.dir.join(path);
+        let mut secret = tokio::fs::read_to_string(&file)
+            .await
+            .map_err(|why| format!("couldn't read {file}: {why}"))?;
+        if secret.ends_with('\n') {
+            secret.pop();
+            if secret.ends_with('\r') {
+                secret.pop();
+            }
+        }
+        Ok(secret)
+    }
+}
+
+/// A [`SecretProvider`] reading secrets from Overseer's own environment
+///
+/// `secret://db/password` is read from `DB_PASSWORD`, the path uppercased
+/// with anything other than letters and digits replaced by underscores,
+/// after an optional prefix (e.g. `OVERSEER_SECRET_DB_PASSWORD`)
+#[derive(Debug, Clone, Default)]
+pub struct EnvSecrets {
+    prefix: String,
+}
+
+impl EnvSecrets {
+    /// Creates a provider reading secrets from unprefixed variables
+    pub fn new() -> Self {
+        EnvSecrets::default()
+    }
+
+    /// Reads secrets from variables starting with `prefix`
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+}
+
+#[async_trait]
+impl SecretProvider for EnvSecrets {
+    async fn secret(
+        &self,
+        path: &str,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let mut key = self.prefix.clone();
+        key.extend(path.chars().map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        }));
+        std::env::var(&key).map_err(|why| format!("{key}: {why}").into())
+    }
+}
+
+/// The secrets resolved for one run of a command, kept so they can be
+/// scrubbed from anything logged
+#[derive(Default)]
+pub(crate) struct Secrets(Vec<String>);
+
+impl Secrets {
+    /// Replaces every secret in `s` with `***`
+    pub(crate) fn scrub(&self, s: &str) -> String {
+        let mut scrubbed = s.to_owned();
+        for secret in &self.0 {
+            scrubbed = scrubbed.replace(secret.as_str(), "***");
+        }
+        scrubbed
+    }
+
+    /// Replaces every secret in `bytes` with `***`, for output that might
+    /// not be UTF-8
+    pub(crate) fn scrub_bytes(&self, bytes: Vec<u8>) -> Vec<u8> {
+        let mut scrubbed = bytes;
+        for secret in self.0.iter().filter(|secret| !secret.is_empty()) {
+            let secret = secret.as_bytes();
+            let mut replaced = Vec::with_capacity(scrubbed.len());
+            let mut rest = scrubbed.as_slice();
+            while let Some(at) = rest
+                .windows(secret.len())
+                .position(|window| window == secret)
+            {
+                replaced.extend_from_slice(&rest[..at]);
+                replaced.extend_from_slice(b"***");
+                rest = &rest[at + secret.len()..];
+            }
+            replaced.extend_from_slice(rest);
+            scrubbed = replaced;
+        }
+        scrubbed
+    }
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secrets({} hidden)", self.0.len())
+    }
+}
+
+impl TaskCommand {
+    /// Replaces the value of each of `env_vars` that references a secret
+    /// with the secret itself
+    pub(crate) async fn resolve_secrets(
+        &self,
+        env_vars: &mut [EnvVar],
+    ) -> Result<Secrets, CommandRunError> {
+        let mut secrets = Vec::new();
+        for EnvVar(key, val) in env_vars {
+            let path = match val.strip_prefix(SCHEME) {
+                Some(path) => path,
+                None => continue,
+            };
+            let resolved = match PROVIDER.get() {
+                Some(provider) => {
+                    provider.secret(path).await.map_err(|why| why.to_string())
+                }
+                None => Err(String::from("no secret provider is installed")),
+            };
+            *val = resolved.map_err(|why| CommandRunError {
+                name: self.name.clone(),
+                r#type: CommandRunErrorType::Secret(key.clone(), why),
+            })?;
+            if !val.is_empty() {
+                secrets.push(val.clone());
+            }
+        }
+        // Longest first, so no secret is left partly visible by a shorter
+        // one inside it being scrubbed first
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        Ok(Secrets(secrets))
+    }
+}
+
+/// Whether `env_var`'s value references a secret
+pub(crate) fn references_secret(EnvVar(_, val): &EnvVar) -> bool {
+    val.starts_with(SCHEME)
+}
+
+/// Shell script reading the values of `env_vars` from stdin and exporting
+/// them, so remote commands get their secrets without them ever being on a
+/// command line (where any user on the remote host could see them).
+/// The values are fed with [`secrets_input`]
+pub(crate) fn read_secrets(env_vars: &[EnvVar]) -> String {
+    // printf %b undoes the escaping, and the x stops the command
+    // substitution from eating trailing newlines
+    env_vars
+        .iter()
+        .map(|EnvVar(key, _)| {
+            format!(
+                "IFS= read -r {key} && {key}=$(printf '%bx' \"${key}\") && \
+                 export {key}=\"${{{key}%x}}\" && "
+            )
+        })
+        .collect()
+}
+
+/// The values of `env_vars` for [`read_secrets`], one per line, with
+/// backslashes and newlines escaped
+pub(crate) fn secrets_input(env_vars: &[EnvVar]) -> Vec<u8> {
+    let mut input = String::new();
+    for EnvVar(_, val) in env_vars {
+        input.push_str(&val.replace('\\', r"\\").replace('\n', r"\n"));
+        input.push('\n');
+    }
+    input.into_bytes()
+}
+
+/// The key patterns [`set_sensitive_env_keys`] starts with
+pub const DEFAULT_SENSITIVE_ENV_KEYS: [&str; 4] =
+    ["*PASSWORD*", "*TOKEN*", "*SECRET*", "*KEY*"];
+
+static SENSITIVE_ENV_KEYS: RwLock<Option<Vec<String>>> = RwLock::new(None);
+
+/// Sets which environment variables have their values redacted (shown as
+/// `KEY=***`) wherever commands are logged, replacing the defaults
+/// ([`DEFAULT_SENSITIVE_ENV_KEYS`])
+///
+/// Patterns match whole keys, ignoring case, with `*` matching any run of
+/// characters.
+/// Only what's logged is redacted, commands still get the real values
+pub fn set_sensitive_env_keys<I, S>(patterns: I)
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let patterns = patterns.into_iter().map(Into::into).collect();
+    *SENSITIVE_ENV_KEYS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = Some(patterns);
+}
+
+/// Copies `env_vars`, with the values of those with sensitive keys replaced
+/// by `***`, for logging
+pub(crate) fn redacted(env_vars: &[EnvVar]) -> Vec<EnvVar> {
+    let patterns = SENSITIVE_ENV_KEYS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner);
+    let sensitive = |key: &str| match patterns.as_deref() {
+        Some(patterns) => patterns.iter().any(|p| glob_match(p, key)),
+        None => DEFAULT_SENSITIVE_ENV_KEYS
+            .iter()
+            .any(|p| glob_match(p, key)),
+    };
+    env_vars
+        .iter()
+        .map(|EnvVar(key, val)| match sensitive(key) {
+            true => EnvVar(key.clone(), String::from("***")),
+            false => EnvVar(key.clone(), val.clone()),
+        })
+        .collect()
+}
+
+/// Whether `pattern` matches all of `key`, ignoring ASCII case, where `*`
+/// matches any run of characters
+fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern = pattern.to_ascii_uppercase();
+    let key = key.to_ascii_uppercase();
+    let mut parts = pattern.split('*');
+    // split always gives at least one part
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match key.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    // Without a wildcard, the whole key must have matched the first part
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
@@ -0,0 +1,236 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{CronScheduler, FileEventTask, WatchHandle};
+
+/// How many task runs are in progress, across every task
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Notified when the last run in progress finishes
+fn idle() -> &'static Notify {
+    static IDLE: OnceLock<Notify> = OnceLock::new();
+    IDLE.get_or_init(Notify::new)
+}
+
+/// Cancelled once a [`Shutdown`] has started, after which no new runs are
+/// triggered
+fn stopping() -> &'static CancellationToken {
+    static STOPPING: OnceLock<CancellationToken> = OnceLock::new();
+    STOPPING.get_or_init(CancellationToken::new)
+}
+
+/// Whether the process is shutting down, so triggers should be ignored
+pub(crate) fn shutting_down() -> bool {
+    stopping().is_cancelled()
+}
+
+/// Waits until the process starts shutting down
+pub(crate) async fn shutdown_started() {
+    stopping().cancelled().await;
+}
+
+/// Counts a task run as in progress until it's dropped, so shutting down can
+/// wait for it
+#[derive(Debug)]
+pub(crate) struct RunGuard(());
+
+impl RunGuard {
+    pub(crate) fn new() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        RunGuard(())
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        if IN_FLIGHT.fetch_sub(1, Ordering::SeqCst) == 1 {
+            idle().notify_waiters();
+        }
+    }
+}
+
+/// Waits until no task runs are in progress
+async fn runs_finished() {
+    loop {
+        // Created before checking, so the last run finishing can't be missed
+        let notified = idle().notified();
+        if IN_FLIGHT.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Shuts a supervisor down gracefully, letting runs in progress finish
+/// rather than killing them
+///
+/// Give it whatever triggers the supervisor's tasks, then call
+/// `Shutdown::on_signal` (with the `shutdown-signals` feature) to shut down
+/// on `SIGTERM`, or [`Shutdown::now`].
+/// Shutting down:
+/// 1. stops any task being triggered: the tasks on each
+///    [`CronScheduler`] are deactivated, file events are ignored, and runs
+///    waiting for their `depends_on` tasks give up.
+///    This applies to every task in the process, given or not, and can't be
+///    undone
+/// 2. waits for every run in progress (in any task) to finish, for up to
+///    the grace period, 30 seconds by default
+/// 3. cancels the runs of the tasks given that are still going once it's
+///    over, killing their commands (see
+///    [`CronTask::cancellation_token`](crate::CronTask::cancellation_token))
+/// 4. deactivates the file tasks given, by dropping their [`WatchHandle`]s
+///
+/// The [`ShutdownOutcome`] says which exit code to exit with
+#[derive(Debug)]
+#[must_use = "nothing is shut down until Shutdown::on_signal or Shutdown::now is called"]
+pub struct Shutdown {
+    grace: Duration,
+    schedulers: Vec<CronScheduler>,
+    watches: Vec<WatchHandle>,
+    tokens: Vec<CancellationToken>,
+}
+
+impl Shutdown {
+    /// How long runs are given to finish by default
+    pub const DEFAULT_GRACE: Duration = Duration::from_secs(30);
+
+    /// How long cancelled runs are given to kill their commands
+    const CANCEL_WAIT: Duration = Duration::from_secs(5);
+
+    /// Creates a coordinator with nothing to shut down yet
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Sets how long runs in progress are given to finish before they're
+    /// cancelled
+    pub fn grace_period(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    /// Deactivates every task on `scheduler` when shutting down, cancelling
+    /// their runs if they don't finish in time
+    pub fn scheduler(mut self, scheduler: &CronScheduler) -> Self {
+        self.schedulers.push(scheduler.clone());
+        self
+    }
+
+    /// Deactivates the file `task` when shutting down, by dropping its
+    /// `handle` once its runs have finished (or been cancelled)
+    pub fn watch(
+        mut self,
+        task: &Arc<FileEventTask>,
+        handle: WatchHandle,
+    ) -> Self {
+        self.tokens.push(task.cancellation_token());
+        self.watches.push(handle);
+        self
+    }
+
+    /// Cancels `token` if runs are still in progress once the grace period
+    /// is over, e.g. the token of a task run some other way
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.tokens.push(token);
+        self
+    }
+
+    /// Waits for the process to be told to stop, by `SIGTERM` or `SIGINT`
+    /// (Ctrl-C), then shuts down
+    ///
+    /// Requires the `shutdown-signals` feature.
+    /// On Windows, only Ctrl-C is waited for.
+    /// Errors if the signal handlers couldn't be registered
+    #[cfg(feature = "shutdown-signals")]
+    pub async fn on_signal(self) -> std::io::Result<ShutdownOutcome> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut terminate = signal(SignalKind::terminate())?;
+            tokio::select! {
+                _ = terminate.recv() => info!("Received SIGTERM"),
+                interrupted = tokio::signal::ctrl_c() => {
+                    interrupted?;
+                    info!("Received SIGINT");
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            tokio::signal::ctrl_c().await?;
+            info!("Received Ctrl-C");
+        }
+        Ok(self.now().await)
+    }
+
+    /// Shuts down straight away
+    pub async fn now(self) -> ShutdownOutcome {
+        stopping().cancel();
+        info!(grace = ?self.grace, "Shutting down, waiting for runs in progress");
+        let mut tokens = self.tokens;
+        for scheduler in &self.schedulers {
+            let deactivated = scheduler.deactivate_all();
+            tokens.extend(deactivated.iter().map(|t| t.cancellation_token()));
+        }
+        let outcome =
+            match tokio::time::timeout(self.grace, runs_finished()).await {
+                Ok(()) => {
+                    info!("Every run finished");
+                    ShutdownOutcome::Finished
+                }
+                Err(_) => {
+                    let running = IN_FLIGHT.load(Ordering::SeqCst);
+                    warn!(%running, "Grace period over, cancelling runs");
+                    tokens.iter().for_each(CancellationToken::cancel);
+                    let cancelled = Self::CANCEL_WAIT;
+                    if tokio::time::timeout(cancelled, runs_finished())
+                        .await
+                        .is_err()
+                    {
+                        let running = IN_FLIGHT.load(Ordering::SeqCst);
+                        warn!(%running, "Runs still in progress, leaving them");
+                    }
+                    ShutdownOutcome::Cancelled
+                }
+            };
+        drop(self.watches);
+        info!("Shut down");
+        outcome
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            grace: Shutdown::DEFAULT_GRACE,
+            schedulers: Vec::new(),
+            watches: Vec::new(),
+            tokens: Vec::new(),
+        }
+    }
+}
+
+/// How a [`Shutdown`] went
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShutdownOutcome {
+    /// Every run in progress finished within the grace period
+    Finished,
+    /// Runs were still in progress after the grace period, and were
+    /// cancelled
+    Cancelled,
+}
+
+impl ShutdownOutcome {
+    /// The code the process should exit with: `0` if every run finished,
+    /// `1` if any were cancelled
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ShutdownOutcome::Finished => 0,
+            ShutdownOutcome::Cancelled => 1,
+        }
+    }
+}
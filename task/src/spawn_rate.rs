@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::error::ReadErrorType;
+
+/// How fast a task's commands may be started, set by `spawn_rate`
+///
+/// A token bucket holding up to `burst` (default 1) tokens, refilled at
+/// `per_second` tokens a second, with each command taking one as it starts
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SpawnRate {
+    per_second: f64,
+    #[serde(default = "default_burst")]
+    burst: NonZeroU32,
+}
+
+impl SpawnRate {
+    pub(crate) fn new(per_second: f64, burst: NonZeroU32) -> Self {
+        SpawnRate { per_second, burst }
+    }
+
+    pub(crate) fn check(&self) -> Result<(), ReadErrorType> {
+        match self.per_second.is_finite() && self.per_second > 0.0 {
+            true => Ok(()),
+            false => Err(ReadErrorType::InvalidSpawnRate(self.per_second)),
+        }
+    }
+}
+
+/// Limits how fast a task's commands start
+///
+/// The bucket is created on first use and shared by every run of the task,
+/// so the rate holds even when runs overlap
+#[derive(Debug, Default)]
+pub(crate) struct SpawnLimit(OnceLock<Arc<SpawnLimiter>>);
+
+impl SpawnLimit {
+    pub(crate) fn limiter(
+        &self,
+        rate: Option<SpawnRate>,
+    ) -> Option<Arc<SpawnLimiter>> {
+        rate.map(|rate| {
+            self.0
+                .get_or_init(|| Arc::new(SpawnLimiter::new(rate)))
+                .clone()
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SpawnLimiter {
+    rate: SpawnRate,
+    /// The tokens left, negative when they're owed to commands waiting, as
+    /// of when it was last updated
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl SpawnLimiter {
+    fn new(rate: SpawnRate) -> Self {
+        SpawnLimiter {
+            rate,
+            bucket: Mutex::new((rate.burst.get() as f64, Instant::now())),
+        }
+    }
+
+    /// Takes a token, waiting until it's due if the bucket's empty
+    ///
+    /// Tokens are handed out in the order they're asked for.
+    /// One taken by a command that gives up waiting isn't returned
+    pub(crate) async fn wait(&self) {
+        let wait = {
+            let mut bucket =
+                self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+            let (tokens, updated) = &mut *bucket;
+            let now = Instant::now();
+            let refilled = now.duration_since(*updated).as_secs_f64()
+                * self.rate.per_second;
+            *tokens = (*tokens + refilled).min(self.rate.burst.get() as f64);
+            *updated = now;
+            *tokens -= 1.0;
+            match *tokens < 0.0 {
+                true => -*tokens / self.rate.per_second,
+                false => return,
+            }
+        };
+        tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+    }
+}
+
+fn default_burst() -> NonZeroU32 {
+    NonZeroU32::MIN
+}
@@ -0,0 +1,75 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use service::path::deserialize_expanded_opt;
+use std::time::Duration;
+
+/// How SSH connections to a remote host are made, set per host with `ssh`
+///
+/// `connect_timeout` (default `10s`) bounds how long connecting (including
+/// authenticating) takes before the command fails, rather than hanging on an
+/// unreachable host.
+/// `keepalive_interval` (default `30s`) is how often the server is checked
+/// on when nothing else has been heard from it, so a dead connection fails
+/// a long-running command instead of leaving it waiting; `null` turns
+/// keepalives off.
+/// `control_directory` is where the control socket of each connection's
+/// master process is made (in a temporary directory of its own), instead of
+/// Overseer's working directory
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SshOptions {
+    #[serde(with = "humantime_serde")]
+    connect_timeout: Duration,
+    #[serde(with = "humantime_serde")]
+    keepalive_interval: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_expanded_opt")]
+    control_directory: Option<Utf8PathBuf>,
+}
+
+impl SshOptions {
+    /// Where control sockets are made, if not the default
+    pub(crate) fn control_directory(&self) -> Option<&Utf8Path> {
+        self.control_directory.as_deref()
+    }
+
+    /// Connects to `destination`, failing with why if it couldn't within the
+    /// connect timeout
+    #[cfg(unix)]
+    pub(crate) async fn connect(
+        &self,
+        destination: &str,
+    ) -> Result<openssh::Session, String> {
+        use openssh::{KnownHosts, SessionBuilder};
+
+        let mut builder = SessionBuilder::default();
+        builder
+            .known_hosts_check(KnownHosts::Strict)
+            // SSH only takes whole seconds, with 0 meaning no timeout
+            .connect_timeout(self.connect_timeout.max(Duration::from_secs(1)));
+        if let Some(interval) = self.keepalive_interval {
+            builder.server_alive_interval(interval);
+        }
+        if let Some(dir) = &self.control_directory {
+            builder.control_directory(dir);
+        }
+        // SSH's own timeout doesn't cover everything (e.g. authenticating),
+        // so this makes sure it's kept to
+        let connect = builder.connect(destination);
+        match tokio::time::timeout(self.connect_timeout, connect).await {
+            Ok(connected) => connected.map_err(|ssh_err| ssh_err.to_string()),
+            Err(_) => {
+                Err(format!("timed out after {:?}", self.connect_timeout))
+            }
+        }
+    }
+}
+
+impl Default for SshOptions {
+    fn default() -> Self {
+        SshOptions {
+            connect_timeout: Duration::from_secs(10),
+            keepalive_interval: Some(Duration::from_secs(30)),
+            control_directory: None,
+        }
+    }
+}
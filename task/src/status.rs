@@ -0,0 +1,291 @@
+use futures::future::{self, BoxFuture};
+use serde::Serialize;
+use service::{ConnectionInfo, Service, ServiceStatus};
+use std::io;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::{Task, TaskRegistry, TaskRunReport};
+
+/// The most a request (line and headers) may be before it's rejected
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// A service's status, or why it couldn't be checked, and its connection
+type Probed = (Result<ServiceStatus, String>, Option<ConnectionInfo>);
+
+type StatusProbe = Box<dyn Fn() -> BoxFuture<'static, Probed> + Send + Sync>;
+
+/// A minimal HTTP server reporting on Overseer's tasks and services, for
+/// health checks by external orchestration
+///
+/// Requires the `status-server` feature
+///
+/// All responses are JSON, and the connection is closed after each one.
+/// Two routes are served, anything else gets a 404:
+///
+/// `GET /health` always responds `200` with `{"status": "ok"}` while the
+/// server is running
+///
+/// `GET /status` responds `200` with:
+/// ```json
+/// {
+///   "tasks": [
+///     {
+///       "name": "Hello world",
+///       "kind": "cron",
+///       "path": "/etc/overseer/hello.yml",
+///       "last_run": {
+///         "outcome": "success",
+///         "finished": 1700000000,
+///         "duration_ms": 1523
+///       },
+///       "next_run": 1700000060
+///     }
+///   ],
+///   "services": [
+///     {
+///       "name": "jellyfin",
+///       "status": "healthy",
+///       "error": null,
+///       "connection": { "api_version": "1.41", "ping_ms": 2 }
+///     }
+///   ]
+/// }
+/// ```
+/// - `kind` is `cron` or `file`
+/// - `last_run` is `null` if the task hasn't completed a run yet, `outcome`
+///   being `success` or `failure`
+/// - `next_run` is `null` for file tasks
+/// - `status` is `healthy`, `starting`, `unhealthy`, `offline`, or `unknown`
+///   if it couldn't be checked, in which case `error` says why
+/// - `connection` is `null` for services that don't keep a connection to
+///   their host (only Docker ones do), `api_version` being the negotiated
+///   Docker API version, and `ping_ms` `null` if Docker didn't respond
+/// - all times are Unix timestamps, in seconds
+pub struct StatusServer {
+    registry: Arc<TaskRegistry>,
+    services: Vec<(String, StatusProbe)>,
+}
+
+impl StatusServer {
+    /// Creates a server reporting on the tasks in `registry`
+    pub fn new(registry: Arc<TaskRegistry>) -> Self {
+        StatusServer {
+            registry,
+            services: Vec::new(),
+        }
+    }
+
+    /// Adds a service to report the status of, checked on each request
+    pub fn service<S>(
+        mut self,
+        name: impl Into<String>,
+        service: Arc<S>,
+    ) -> Self
+    where
+        S: Service + Send + Sync + 'static,
+    {
+        let probe: StatusProbe = Box::new(move || {
+            let service = service.clone();
+            Box::pin(async move {
+                let (status, connection) =
+                    future::join(service.status(), service.connection_info())
+                        .await;
+                (status.map_err(|why| why.to_string()), connection)
+            })
+        });
+        self.services.push((name.into(), probe));
+        self
+    }
+
+    /// Binds to `addr` and serves requests in a separate green thread
+    ///
+    /// Errors only if the address couldn't be bound
+    pub async fn serve(
+        self,
+        addr: impl ToSocketAddrs,
+    ) -> io::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(addr).await?;
+        info!(addr = ?listener.local_addr()?, "Status server listening");
+        let server = Arc::new(self);
+        Ok(tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let server = server.clone();
+                        tokio::spawn(async move {
+                            if let Err(why) = server.respond(stream).await {
+                                debug!(%peer, "Status request failed: {why}");
+                            }
+                        });
+                    }
+                    Err(why) => warn!("Couldn't accept connection: {why}"),
+                }
+            }
+        }))
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        let (code, body) = match read_request_path(&mut stream).await? {
+            Some(path) if path == "/health" => {
+                ("200 OK", String::from(r#"{"status":"ok"}"#))
+            }
+            Some(path) if path == "/status" => {
+                let report = self.report().await;
+                match serde_json::to_string(&report) {
+                    Ok(json) => ("200 OK", json),
+                    Err(why) => {
+                        warn!("Couldn't serialise status report: {why}");
+                        ("500 Internal Server Error", String::from("{}"))
+                    }
+                }
+            }
+            Some(_) => ("404 Not Found", String::from("{}")),
+            None => ("400 Bad Request", String::from("{}")),
+        };
+        let response = format!(
+            "HTTP/1.1 {code}\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n\r\n\
+            {body}",
+            body.len(),
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    async fn report(&self) -> StatusReport {
+        let mut tasks = self
+            .registry
+            .cron_tasks()
+            .into_iter()
+            .map(|task| TaskReport {
+                name: task.name().to_owned(),
+                kind: task.kind().as_str(),
+                path: self.registry.path_of(task.name()),
+                last_run: task.last_run().map(LastRunReport::from),
+                next_run: task.next_run().map(unix_secs),
+            })
+            .collect::<Vec<_>>();
+        tasks.extend(self.registry.file_tasks().into_iter().map(|task| {
+            TaskReport {
+                name: task.name().to_owned(),
+                kind: task.kind().as_str(),
+                path: self.registry.path_of(task.name()),
+                last_run: task.last_run().map(LastRunReport::from),
+                next_run: None,
+            }
+        }));
+        tasks.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut services = Vec::with_capacity(self.services.len());
+        for (name, probe) in &self.services {
+            let (status, connection) = probe().await;
+            let (status, error) = match status {
+                Ok(status) => (status.to_string(), None),
+                Err(why) => (String::from("unknown"), Some(why)),
+            };
+            services.push(ServiceReport {
+                name: name.clone(),
+                status,
+                error,
+                connection: connection.map(ConnectionReport::from),
+            });
+        }
+        StatusReport { tasks, services }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StatusReport {
+    tasks: Vec<TaskReport>,
+    services: Vec<ServiceReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct TaskReport {
+    name: String,
+    kind: &'static str,
+    path: Option<camino::Utf8PathBuf>,
+    last_run: Option<LastRunReport>,
+    next_run: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct LastRunReport {
+    outcome: &'static str,
+    finished: u64,
+    duration_ms: u128,
+}
+
+impl From<TaskRunReport> for LastRunReport {
+    fn from(run: TaskRunReport) -> Self {
+        LastRunReport {
+            outcome: run.outcome.as_str(),
+            finished: unix_secs(run.finished),
+            duration_ms: run.duration.as_millis(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ServiceReport {
+    name: String,
+    status: String,
+    error: Option<String>,
+    connection: Option<ConnectionReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConnectionReport {
+    api_version: String,
+    ping_ms: Option<u128>,
+}
+
+impl From<ConnectionInfo> for ConnectionReport {
+    fn from(info: ConnectionInfo) -> Self {
+        ConnectionReport {
+            api_version: info.api_version,
+            ping_ms: info.ping_latency.ok().map(|ping| ping.as_millis()),
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Reads a request's head, returning the path if it's a well-formed `GET`
+async fn read_request_path(
+    stream: &mut TcpStream,
+) -> io::Result<Option<String>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+    let head = String::from_utf8_lossy(&buf);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) => {
+            // Ignore any query string
+            let path = path.split('?').next().unwrap_or(path);
+            Ok(Some(path.to_owned()))
+        }
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,347 @@
+use crate::error::MetricsInstallError;
+use crate::{report_failure, CommandRunError, Successes, TaskKind};
+use camino::Utf8PathBuf;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::watch;
+
+static SINK: OnceLock<Box<dyn Metrics>> = OnceLock::new();
+
+/// A sink for metrics about task runs
+///
+/// Install one with [`install_metrics`].
+/// Until one is installed, metrics are discarded
+pub trait Metrics: Send + Sync {
+    /// Records a completed run of a task, and how long it took
+    fn record_run(&self, task: &str, outcome: RunOutcome, duration: Duration);
+
+    /// Records the exit code of a command that ran to completion
+    ///
+    /// `exit_code` is `None` if the process was killed by a signal.
+    /// Commands that couldn't be started aren't recorded
+    fn record_exit_code(
+        &self,
+        task: &str,
+        command: &str,
+        exit_code: Option<i32>,
+    ) {
+        let _ = (task, command, exit_code);
+    }
+}
+
+/// Whether all the commands in a task run succeeded
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RunOutcome {
+    /// Every command succeeded
+    Success,
+    /// At least one command failed
+    Failure,
+}
+
+impl RunOutcome {
+    /// The outcome as a lowercase string, suitable for a metric label
+    pub fn as_str(&self) -> &'static str {
+        use RunOutcome::*;
+        match *self {
+            Success => "success",
+            Failure => "failure",
+        }
+    }
+}
+
+impl RunOutcome {
+    fn from_errors(errors: &[CommandRunError]) -> Self {
+        match errors.is_empty() {
+            true => RunOutcome::Success,
+            false => RunOutcome::Failure,
+        }
+    }
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Details of a completed (non-dry) run of a task
+///
+/// Kept as the task's last run, and given to each
+/// [`TaskFailureHandler`](crate::TaskFailureHandler) if the run failed
+#[derive(Debug, Clone)]
+pub struct TaskRunReport {
+    /// The name of the task
+    pub task: String,
+    /// What triggers the task
+    pub kind: TaskKind,
+    /// Whether the run succeeded
+    pub outcome: RunOutcome,
+    /// When the run was triggered
+    pub started: SystemTime,
+    /// When the run finished
+    pub finished: SystemTime,
+    /// How long the run took
+    pub duration: Duration,
+    /// Each error that caused the run to fail, as it would be displayed.
+    /// If the task runs on more than one host, each is prefixed with the
+    /// host it occurred on
+    pub errors: Vec<String>,
+    /// How each command that succeeded finished, including hooks, across
+    /// every host
+    pub commands: Vec<CommandOutcome>,
+    /// How each command was executed, including hooks, across every host
+    pub executions: Vec<CommandExecution>,
+    /// The run on each host (`localhost` for local commands).
+    /// Empty if the run was skipped before reaching any host, e.g. for
+    /// unhealthy dependencies
+    pub hosts: BTreeMap<String, HostRunReport>,
+}
+
+/// Details of a task's run on one of its hosts
+#[derive(Debug, Clone)]
+pub struct HostRunReport {
+    /// Whether every command succeeded on this host
+    pub outcome: RunOutcome,
+    /// Each error that occurred on this host, as it would be displayed
+    pub errors: Vec<String>,
+    /// How each command that succeeded on this host finished
+    pub commands: Vec<CommandOutcome>,
+    /// How each command was executed on this host, whether it succeeded or
+    /// not
+    pub executions: Vec<CommandExecution>,
+}
+
+impl HostRunReport {
+    pub(crate) fn new(
+        errors: &[CommandRunError],
+        commands: Vec<CommandOutcome>,
+        executions: Vec<CommandExecution>,
+    ) -> Self {
+        HostRunReport {
+            outcome: RunOutcome::from_errors(errors),
+            errors: errors.iter().map(ToString::to_string).collect(),
+            commands,
+            executions,
+        }
+    }
+}
+
+/// How a command that succeeded finished
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CommandOutcome {
+    /// The name of the command
+    pub command: String,
+    /// The exit code, which may be any of the command's `success_codes` (or
+    /// any other if it's expected to fail).
+    /// `None` in a dry run, as nothing was executed
+    pub exit_code: Option<i32>,
+    /// The tail of the command's stderr, unless it was redirected with
+    /// `stderr_file`, as text according to its
+    /// [`OutputEncoding`](crate::OutputEncoding)
+    pub stderr: Option<String>,
+    /// The bytes of `stderr` exactly as they were read, only kept with
+    /// [`OutputEncoding::RawBytes`](crate::OutputEncoding::RawBytes)
+    pub stderr_raw: Option<Vec<u8>>,
+    /// Whether older stderr was dropped to keep within the command's
+    /// `max_output_bytes`, so `stderr` is only the end of it
+    pub stderr_truncated: bool,
+    /// How long the command ran for, from being started to exiting, to the
+    /// millisecond.
+    /// `None` if it wasn't actually run, in a dry run or by a
+    /// [`MockExecutor`](crate::MockExecutor)
+    pub elapsed: Option<Duration>,
+}
+
+/// How a command was executed, with its configuration fully resolved, so a
+/// report can be understood without the task file
+///
+/// Commands that failed before their environment could be read (e.g. from
+/// an unreadable `env_file`) aren't included
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CommandExecution {
+    /// The name of the command
+    pub command: String,
+    /// The host it was executed on (`localhost` for local commands)
+    pub host: String,
+    /// Its `working_dir`, as configured, if set
+    pub working_dir: Option<Utf8PathBuf>,
+    /// Where it was run, after joining a relative `working_dir` onto the
+    /// task's `base_dir`.
+    /// `None` if it inherited Overseer's working directory (or the remote
+    /// user's login directory)
+    pub resolved_working_dir: Option<Utf8PathBuf>,
+    /// The variables set in its environment, including those from its
+    /// `env_file`, in the order they're set.
+    /// Sensitive values (see
+    /// [`set_sensitive_env_keys`](crate::set_sensitive_env_keys)) are shown
+    /// as `***`, and secrets by their `secret://` reference
+    pub env: Vec<(String, String)>,
+}
+
+impl TaskRunReport {
+    /// Reports on a run that's just finished, timed from `timer`
+    ///
+    /// `errors` are those that affected the whole run, rather than one host
+    pub(crate) fn new(
+        task: &str,
+        kind: TaskKind,
+        started: SystemTime,
+        timer: Instant,
+        errors: &[CommandRunError],
+        hosts: BTreeMap<String, HostRunReport>,
+    ) -> Self {
+        let failed = hosts
+            .values()
+            .any(|host| host.outcome == RunOutcome::Failure);
+        let outcome = match failed {
+            true => RunOutcome::Failure,
+            false => RunOutcome::from_errors(errors),
+        };
+        let mut all_errors: Vec<String> =
+            errors.iter().map(ToString::to_string).collect();
+        let mut commands = Vec::new();
+        let mut executions = Vec::new();
+        for (name, host) in &hosts {
+            match hosts.len() {
+                1 => all_errors.extend(host.errors.iter().cloned()),
+                _ => all_errors.extend(
+                    host.errors.iter().map(|err| format!("{name}: {err}")),
+                ),
+            }
+            commands.extend(host.commands.iter().cloned());
+            executions.extend(host.executions.iter().cloned());
+        }
+        TaskRunReport {
+            task: task.to_owned(),
+            kind,
+            outcome,
+            started,
+            finished: SystemTime::now(),
+            duration: timer.elapsed(),
+            errors: all_errors,
+            commands,
+            executions,
+            hosts,
+        }
+    }
+}
+
+/// Keeps the last [`TaskRunReport`] of a task, safe to update and read from
+/// concurrent runs, and publishes when the task last succeeded, for the
+/// tasks that depend on it
+#[derive(Debug)]
+pub(crate) struct LastRunCell {
+    last: Mutex<Option<TaskRunReport>>,
+    successes: watch::Sender<Option<Instant>>,
+}
+
+impl LastRunCell {
+    pub(crate) fn get(&self) -> Option<TaskRunReport> {
+        self.last
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Records a run with the installed [`Metrics`] sink, keeps it as the
+    /// last run, and if it failed, reports it to the failure handlers
+    pub(crate) async fn record(&self, report: TaskRunReport) {
+        metrics().record_run(&report.task, report.outcome, report.duration);
+        *self.last.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(report.clone());
+        match report.outcome {
+            RunOutcome::Success => {
+                self.successes.send_replace(Some(Instant::now()));
+            }
+            RunOutcome::Failure => report_failure(&report).await,
+        }
+    }
+
+    /// When the task succeeds
+    pub(crate) fn successes(&self) -> Successes {
+        self.successes.subscribe()
+    }
+}
+
+impl Default for LastRunCell {
+    fn default() -> Self {
+        LastRunCell {
+            last: Mutex::default(),
+            successes: watch::channel(None).0,
+        }
+    }
+}
+
+/// A [`Metrics`] sink that discards everything, used if none is installed
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+    fn record_run(&self, _: &str, _: RunOutcome, _: Duration) {}
+}
+
+/// Installs the global [`Metrics`] sink
+///
+/// This can only be done once, subsequent calls will error
+pub fn install_metrics(
+    sink: impl Metrics + 'static,
+) -> Result<(), MetricsInstallError> {
+    SINK.set(Box::new(sink)).map_err(|_| MetricsInstallError)
+}
+
+/// The installed [`Metrics`] sink, or [`NoopMetrics`]
+pub(crate) fn metrics() -> &'static dyn Metrics {
+    match SINK.get() {
+        Some(sink) => sink.as_ref(),
+        None => &NoopMetrics,
+    }
+}
+
+/// Forwards task metrics to the [`metrics`](https://docs.rs/metrics) facade
+///
+/// Emits:
+/// - `overseer_task_runs_total` (counter, labelled by `task` and `outcome`)
+/// - `overseer_task_run_duration_seconds` (histogram, labelled by `task`)
+/// - `overseer_command_exits_total` (counter, labelled by `task`, `command`,
+///   and `exit_code`, the latter being `signal` if the process was killed)
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct MetricsCrateSink;
+
+#[cfg(feature = "metrics")]
+impl Metrics for MetricsCrateSink {
+    fn record_run(&self, task: &str, outcome: RunOutcome, duration: Duration) {
+        ::metrics::counter!(
+            "overseer_task_runs_total",
+            "task" => task.to_owned(),
+            "outcome" => outcome.as_str(),
+        )
+        .increment(1);
+        ::metrics::histogram!(
+            "overseer_task_run_duration_seconds",
+            "task" => task.to_owned(),
+        )
+        .record(duration.as_secs_f64());
+    }
+
+    fn record_exit_code(
+        &self,
+        task: &str,
+        command: &str,
+        exit_code: Option<i32>,
+    ) {
+        let exit_code = match exit_code {
+            Some(code) => code.to_string(),
+            None => String::from("signal"),
+        };
+        ::metrics::counter!(
+            "overseer_command_exits_total",
+            "task" => task.to_owned(),
+            "command" => command.to_owned(),
+            "exit_code" => exit_code,
+        )
+        .increment(1);
+    }
+}
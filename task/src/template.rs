@@ -0,0 +1,189 @@
+use notify::{Event, EventKind};
+use std::time::SystemTime;
+
+use crate::RunContext;
+
+/// The variables a command's `run` can reference as `{{name}}`
+const VARIABLES: [&str; 5] =
+    ["task_name", "now", "event_path", "event_kind", "host"];
+
+/// The file event that triggered a run, for substituting into commands
+#[derive(Debug, Clone)]
+pub(crate) struct TriggerEvent {
+    path: String,
+    kind: &'static str,
+}
+
+impl TriggerEvent {
+    /// Describes `event`, by its last path (where a rename ended up)
+    pub(crate) fn new(event: &Event) -> Self {
+        let path = event
+            .paths
+            .last()
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let kind = match event.kind {
+            EventKind::Any => "any",
+            EventKind::Access(_) => "access",
+            EventKind::Create(_) => "create",
+            EventKind::Modify(_) => "modify",
+            EventKind::Remove(_) => "remove",
+            EventKind::Other => "other",
+        };
+        TriggerEvent { path, kind }
+    }
+}
+
+/// The values of the variables for a command being run on `host`
+pub(crate) struct TemplateVars<'a> {
+    ctx: &'a RunContext,
+    host: &'a str,
+    now: String,
+}
+
+impl<'a> TemplateVars<'a> {
+    pub(crate) fn new(ctx: &'a RunContext, host: &'a str) -> Self {
+        TemplateVars {
+            ctx,
+            host,
+            now: humantime::format_rfc3339_seconds(SystemTime::now())
+                .to_string(),
+        }
+    }
+
+    /// The value of the variable `name`, which must be one of [`VARIABLES`]
+    pub(crate) fn get(&self, name: &str) -> String {
+        let event = self.ctx.event();
+        match name {
+            "task_name" => self.ctx.task_name().to_owned(),
+            "now" => self.now.clone(),
+            "event_path" => event.map(|e| e.path.clone()).unwrap_or_default(),
+            "event_kind" => {
+                event.map(|e| e.kind.to_owned()).unwrap_or_default()
+            }
+            "host" => self.host.to_owned(),
+            _ => unreachable!("unknown variable {name}"),
+        }
+    }
+}
+
+/// Checks every placeholder in `template` names a known variable
+pub(crate) fn check_template(template: &str) -> Result<(), String> {
+    render(template, |_| String::new()).map(drop)
+}
+
+/// Substitutes each `{{name}}` in `template` with `value(name)`, `\{{` being
+/// a literal `{{`
+///
+/// Errors on a placeholder that isn't one of the known variables, or isn't
+/// closed
+pub(crate) fn render(
+    template: &str,
+    value: impl Fn(&str) -> String,
+) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        if let Some(before) = rest[..start].strip_suffix('\\') {
+            rendered.push_str(before);
+            rendered.push_str("{{");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            return Err(format!("unclosed placeholder in {template:?}"));
+        };
+        let name = &after[..end];
+        if !VARIABLES.contains(&name) {
+            return Err(format!(
+                "unknown placeholder {{{{{name}}}}} (expected one of {})",
+                VARIABLES.join(", "),
+            ));
+        }
+        rendered.push_str(&value(name));
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::context;
+    use crate::{shell_quote, CommandBuilder};
+    use notify::event::CreateKind;
+
+    #[test]
+    fn placeholders_are_substituted() {
+        let rendered = render("echo {{task_name}} on {{host}}", |name| {
+            name.to_uppercase()
+        });
+        assert_eq!(rendered.unwrap(), "echo TASK_NAME on HOST");
+    }
+
+    #[test]
+    fn unknown_placeholders_error() {
+        let err = check_template("echo {{task}}").unwrap_err();
+        assert!(err.contains("unknown placeholder {{task}}"), "{err}");
+        // Spaces aren't trimmed, so this isn't {{host}}
+        assert!(check_template("echo {{ host }}").is_err());
+        assert!(CommandBuilder::new("echo {{nope}}").finish().is_err());
+    }
+
+    #[test]
+    fn unclosed_placeholders_error() {
+        let err = check_template("echo {{host").unwrap_err();
+        assert!(err.starts_with("unclosed placeholder"), "{err}");
+        assert!(check_template("echo {{host} }").is_err());
+    }
+
+    #[test]
+    fn escaped_braces_are_literal() {
+        let template = r"docker ps --format '\{{.Names}}' on {{host}}";
+        assert_eq!(check_template(template), Ok(()));
+        let rendered = render(template, |_| "box".to_owned()).unwrap();
+        assert_eq!(rendered, "docker ps --format '{{.Names}}' on box");
+        // Unescaped, it's an unknown placeholder
+        assert!(check_template("docker ps --format '{{.Names}}'").is_err());
+    }
+
+    #[test]
+    fn shell_values_are_quoted() {
+        let command = CommandBuilder::new("cat {{event_path}} # {{host}}")
+            .shell(true)
+            .finish()
+            .unwrap();
+        let ctx = context("quoting");
+        let event = Event::new(EventKind::Create(CreateKind::File))
+            .add_path("/tmp/it's; reboot".into());
+        ctx.triggered_by(TriggerEvent::new(&event));
+        let run = command.rendered(&ctx, "$(hostname)", shell_quote).unwrap();
+        assert_eq!(run.raw, r"cat '/tmp/it'\''s; reboot' # '$(hostname)'");
+    }
+
+    #[test]
+    fn direct_values_stay_one_argument() {
+        let command = CommandBuilder::new("ls {{event_path}} {{event_kind}}")
+            .finish()
+            .unwrap();
+        let ctx = context("direct");
+        let event = Event::new(EventKind::Create(CreateKind::File))
+            .add_path("/tmp/two words".into());
+        ctx.triggered_by(TriggerEvent::new(&event));
+        let run = command.rendered(&ctx, "localhost", shell_quote).unwrap();
+        assert_eq!(run.program, "ls");
+        assert_eq!(run.args, ["/tmp/two words", "create"]);
+    }
+
+    #[test]
+    fn event_values_are_empty_without_an_event() {
+        let ctx = context("no event");
+        let vars = TemplateVars::new(&ctx, "localhost");
+        assert_eq!(vars.get("event_path"), "");
+        assert_eq!(vars.get("event_kind"), "");
+        assert_eq!(vars.get("task_name"), "no event");
+    }
+}
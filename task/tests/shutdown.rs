@@ -0,0 +1,41 @@
+//! Shutting down can't be undone, and stops every task in the process, so
+//! these tests get a process of their own
+
+use camino::Utf8PathBuf;
+use std::time::Duration;
+use task::{Shutdown, ShutdownOutcome, TaskRegistry};
+
+#[tokio::test]
+async fn waiting_for_a_prerequisite_doesnt_delay_shutdown() {
+    let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+        .unwrap()
+        .join(format!("overseer-{}-shutdown", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let task = |name: &str, depends_on: &str| {
+        format!(
+            "name: {name}
+schedule: '@yearly'
+depends_on: [{depends_on}]
+commands:
+  - run: 'true'"
+        )
+    };
+    std::fs::write(dir.join("first.yml"), task("first", "")).unwrap();
+    std::fs::write(dir.join("second.yml"), task("second", "first")).unwrap();
+    let registry = TaskRegistry::load_dir(&dir).await.unwrap();
+
+    // Never goes ahead, as first never runs
+    let waiting = tokio::spawn(registry.get("second").unwrap().run());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let outcome = Shutdown::new()
+        .grace_period(Duration::from_secs(1))
+        .now()
+        .await;
+    assert_eq!(outcome, ShutdownOutcome::Finished);
+    let waited = tokio::time::timeout(Duration::from_secs(1), waiting)
+        .await
+        .expect("the waiting run should stop when shutting down")
+        .unwrap();
+    assert!(waited.is_err());
+}